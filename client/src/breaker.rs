@@ -0,0 +1,161 @@
+use crate::logging;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct State {
+    /// Transport failures in a row since the last success.
+    failures: u32,
+    /// Set while open; requests fail fast until the cooldown has passed.
+    opened_at: Option<Instant>,
+    /// A half-open probe request is in flight.
+    probing: bool,
+}
+
+/// Circuit breaker shared by all client clones.
+///
+/// After `threshold` consecutive transport failures the circuit opens and
+/// requests fail fast for `cooldown`. The first request after that is let
+/// through as a probe: success closes the circuit, failure reopens it.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    /// Opens after `threshold` failures in a row; 0 disables the breaker.
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Decides whether a request may go out now.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => true,
+            Some(opened) if !state.probing && opened.elapsed() >= self.cooldown => {
+                state.probing = true;
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// `closed`, `open` with the time left until the next probe, or
+    /// `half-open` while a probe is in flight.
+    pub fn describe(&self) -> String {
+        if self.threshold == 0 {
+            return "disabled".to_string();
+        }
+        let state = self.state.lock().unwrap();
+        match state.opened_at {
+            None => format!("closed ({} failures)", state.failures),
+            Some(_) if state.probing => "half-open".to_string(),
+            Some(opened) => format!(
+                "open (probe in {}s)",
+                self.cooldown.saturating_sub(opened.elapsed()).as_secs()
+            ),
+        }
+    }
+
+    /// Records a request that reached the server, whatever its status.
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.opened_at.is_some() {
+            logging::info("breaker", "Server reachable again, circuit closed").emit();
+        }
+        *state = State::default();
+    }
+
+    /// Records a connect or timeout failure.
+    pub fn record_failure(&self) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.failures += 1;
+        let reopen = state.probing;
+        if reopen || (state.opened_at.is_none() && state.failures >= self.threshold) {
+            if !reopen {
+                logging::warn(
+                    "breaker",
+                    format!(
+                        "{} consecutive request failures, circuit open for {}s",
+                        state.failures,
+                        self.cooldown.as_secs()
+                    ),
+                )
+                .emit();
+            }
+            state.opened_at = Some(Instant::now());
+            state.probing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_millis(50);
+
+    fn fail(breaker: &CircuitBreaker, times: u32) {
+        for _ in 0..times {
+            breaker.record_failure();
+        }
+    }
+
+    #[test]
+    fn opens_after_threshold_failures_in_a_row() {
+        let breaker = CircuitBreaker::new(3, COOLDOWN);
+        fail(&breaker, 2);
+        breaker.record_success();
+        fail(&breaker, 2);
+        assert!(breaker.allow());
+        assert_eq!(breaker.describe(), "closed (2 failures)");
+        fail(&breaker, 1);
+        assert!(!breaker.allow());
+        assert!(breaker.describe().starts_with("open"));
+    }
+
+    #[test]
+    fn one_probe_after_cooldown_then_success_closes() {
+        let breaker = CircuitBreaker::new(1, COOLDOWN);
+        fail(&breaker, 1);
+        assert!(!breaker.allow());
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        assert_eq!(breaker.describe(), "half-open");
+        // Only the probe goes out until it settles.
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert_eq!(breaker.describe(), "closed (0 failures)");
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_reopens_for_another_cooldown() {
+        let breaker = CircuitBreaker::new(2, COOLDOWN);
+        fail(&breaker, 2);
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+        fail(&breaker, 1);
+        assert!(!breaker.allow());
+        assert!(breaker.describe().starts_with("open"));
+        std::thread::sleep(COOLDOWN);
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = CircuitBreaker::new(0, COOLDOWN);
+        fail(&breaker, 100);
+        assert!(breaker.allow());
+        assert_eq!(breaker.describe(), "disabled");
+    }
+}
@@ -1,12 +1,27 @@
-use clap::Parser;
+use crate::hooks::CommandHooks;
+use crate::logging::LogFormat;
+use crate::mount::MountConfig;
+use crate::name_cipher::NameCipher;
+use crate::overlay::Overlay;
+use crate::progress::{default_progress, Progress};
+use crate::protect::Protected;
+use crate::remote_client::{DeletedWhileOpen, RemoteClient};
 use crate::types::CacheConfig;
+use clap::{Parser, Subcommand};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Remote File System — mount a remote filesystem via FUSE
 #[derive(Parser, Debug)]
-#[command(name = "remote-fs", version, about, long_about = None)]
+#[command(name = "remote-fs", version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Cli {
     /// Local path where the filesystem will be mounted (e.g. /tmp/mnt)
-    pub mountpoint: String,
+    #[arg(required = true)]
+    mountpoint: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 
     /// URL of the remote server
     #[arg(long, default_value = "http://127.0.0.1:8000")]
@@ -16,6 +31,10 @@ pub struct Cli {
     #[arg(long, default_value = "5")]
     pub dir_cache_ttl: u64,
 
+    /// Cache TTL of the mount root listing in seconds (default: twice --dir-cache-ttl)
+    #[arg(long, value_name = "SEC")]
+    pub root_cache_ttl: Option<u64>,
+
     /// File cache TTL in seconds
     #[arg(long, default_value = "10")]
     pub file_cache_ttl: u64,
@@ -24,27 +43,624 @@ pub struct Cli {
     #[arg(long, default_value = "64")]
     pub max_cache_mb: usize,
 
+    /// Maximum number of files in the file cache, whatever their size (0 = unlimited)
+    #[arg(long, default_value = "4096")]
+    pub max_cache_files: usize,
+
+    /// Maximum number of directory listings in the directory cache (0 = unlimited)
+    #[arg(long, default_value = "10000")]
+    pub max_cache_dirs: usize,
+
+    /// Files up to this many bytes are downloaded whole and cached on first read (0 disables)
+    #[arg(long, default_value = "8192")]
+    pub small_file_threshold: u64,
+
     /// Disable caching entirely
     #[arg(long, default_value = "false")]
     pub no_cache: bool,
 
+    /// Seconds an expired directory listing is still served while it is refreshed in the background (0 disables)
+    #[arg(long, value_name = "SEC", default_value = "0")]
+    pub swr_window: u64,
+
+    /// Refuse writes that would make a file larger than this many bytes (EFBIG)
+    #[arg(long, value_name = "BYTES")]
+    pub max_file_size: Option<u64>,
+
+    /// Most KB a sequential read prefetches past the requested range (0 disables
+    /// readahead). Ctrl+C cannot cut a fetch short, so this also bounds how long an
+    /// interrupted read takes to stop
+    #[arg(long, value_name = "KB", default_value = "4096")]
+    pub readahead_kb: u64,
+
+    /// Seconds the kernel may cache file attributes (default: --file-cache-ttl, 0 with --no-cache)
+    #[arg(long, alias = "attr-ttl", value_name = "SEC")]
+    pub attr_timeout: Option<u64>,
+
+    /// Seconds the kernel may cache name lookups (default: --dir-cache-ttl, 0 with --no-cache)
+    #[arg(long, alias = "entry-ttl", value_name = "SEC")]
+    pub entry_timeout: Option<u64>,
+
+    /// Seconds the kernel may remember that a name does not exist (default: 0, never)
+    #[arg(long, alias = "negative-ttl", value_name = "SEC")]
+    pub negative_timeout: Option<u64>,
+
+    /// Remote subtree to prefetch into the cache at mount time (repeatable)
+    #[arg(long = "warm", value_name = "PATH")]
+    pub warm: Vec<String>,
+
+    /// List the tree this many levels deep into the cache at mount time (0 = root only)
+    #[arg(long, value_name = "DEPTH")]
+    pub warm_cache: Option<usize>,
+
+    /// Parallel listing requests made by --warm-cache
+    #[arg(long, value_name = "N", default_value = "4")]
+    pub warm_workers: usize,
+
+    /// Skip the background request that opens a server connection at mount
+    #[arg(long)]
+    pub no_warmup: bool,
+
+    /// Encrypt file and directory names with the key material stored in this file
+    #[arg(long, value_name = "PATH")]
+    pub name_key_file: Option<String>,
+
+    /// User-Agent sent with every request (default: none)
+    #[arg(long, value_name = "UA")]
+    pub user_agent: Option<String>,
+
+    /// Extra header sent with every request, as NAME=VALUE (repeatable)
+    #[arg(long = "header", value_name = "NAME=VALUE")]
+    pub headers: Vec<String>,
+
+    /// Read the server access token from this file (default: $REMOTE_FS_TOKEN)
+    #[arg(long, value_name = "PATH")]
+    pub token_file: Option<String>,
+
+    /// Revalidate a file against the server every time it is opened (one extra round trip)
+    #[arg(long, default_value = "false")]
+    pub consistent_open: bool,
+
+    /// Keep writes in a local overlay while the server is unreachable and sync them later
+    #[arg(long, default_value = "false")]
+    pub offline_writes: bool,
+
+    /// Directory for the offline-writes overlay (default: <tmp>/remote-fs-overlay)
+    #[arg(long, value_name = "DIR")]
+    pub overlay_dir: Option<String>,
+
+    /// Max MB of offline writes waiting for upload before writers block (0 = unlimited)
+    #[arg(long, default_value = "1024")]
+    pub max_pending_upload_mb: u64,
+
+    /// Max number of offline files waiting for upload before writers block (0 = unlimited)
+    #[arg(long, default_value = "1000")]
+    pub max_pending_uploads: usize,
+
+    /// Max concurrent requests to the server; excess operations wait in turn (0 = unlimited)
+    #[arg(long, alias = "max-concurrent-requests", default_value = "16")]
+    pub max_inflight: usize,
+
+    /// Consecutive connection failures before requests fail fast (0 disables)
+    #[arg(long, default_value = "5")]
+    pub breaker_threshold: u32,
+
+    /// Seconds to fail fast before probing the server again
+    #[arg(long, default_value = "10")]
+    pub breaker_cooldown: u64,
+
+    /// Silence upload and download progress bars
+    #[arg(long, default_value = "false")]
+    pub quiet: bool,
+
+    /// Diagnostic output: text, or json (one object per line; hides progress bars)
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Shell command run after each upload (env: REMOTE_PATH, SIZE, DURATION_MS, STATUS)
+    #[arg(long, value_name = "CMD")]
+    pub on_upload: Option<String>,
+
+    /// Shell command run after each delete (env: REMOTE_PATH, SIZE, DURATION_MS, STATUS)
+    #[arg(long, value_name = "CMD")]
+    pub on_delete: Option<String>,
+
+    /// Mount the named server-side snapshot, read-only
+    #[arg(long, value_name = "ID")]
+    pub snapshot: Option<String>,
+
+    /// Server directory to mount instead of the storage root; must be a directory
+    #[arg(long, value_name = "PATH")]
+    pub remote_root: Option<String>,
+
+    /// Refuse changes to paths matching this glob and everything below them (repeatable)
+    #[arg(long = "protect", value_name = "GLOB")]
+    pub protect: Vec<String>,
+
+    /// Move deleted files to the server trash instead of removing them
+    #[arg(long, default_value = "false")]
+    pub use_trash: bool,
+
+    /// Before uploading a file on close, check it still exists on the server;
+    /// if it was deleted: fail (ESTALE, the default here) or conflict (save as <name>.conflict)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "fail"
+    )]
+    pub strict_consistency: Option<DeletedWhileOpen>,
+
+    /// Upload without a Content-Type guessed from the file extension
+    #[arg(long, default_value = "false")]
+    pub no_content_type: bool,
+
+    /// Content-Type for uploads whose extension is not recognised
+    #[arg(long, value_name = "MIME", value_parser = parse_mime)]
+    pub default_content_type: Option<String>,
+
     /// Run as a background daemon
     #[arg(long, default_value = "false")]
     pub daemon: bool,
 
+    #[cfg(unix)]
+    /// Report every file as owned by this uid (default: server owner, else the caller)
+    #[arg(long)]
+    pub uid: Option<u32>,
+
+    #[cfg(unix)]
+    /// Report every file as owned by this gid (default: server group, else the caller)
+    #[arg(long)]
+    pub gid: Option<u32>,
+
+    #[cfg(unix)]
+    /// Create every file with these octal permission bits, whatever the application asks
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub force_file_mode: Option<u32>,
+
+    #[cfg(unix)]
+    /// Create every directory with these octal permission bits, whatever the application asks
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub force_dir_mode: Option<u32>,
+
+    #[cfg(unix)]
+    /// Mount again when the FUSE session ends without a clean unmount
+    #[arg(long, default_value = "false")]
+    pub auto_remount: bool,
+
+    #[cfg(unix)]
+    /// Remount attempts in a row before giving up (with --auto-remount)
+    #[arg(long, value_name = "N", default_value = "5")]
+    pub remount_attempts: u32,
+
+    #[cfg(unix)]
+    /// Lazily unmount a stale or busy mount at <MOUNTPOINT> before mounting
+    #[arg(long, default_value = "false")]
+    pub force: bool,
+
+    #[cfg(unix)]
+    /// Fail instead of mounting for your user only when other users cannot be let in (allow_other)
+    #[arg(long, default_value = "false")]
+    pub allow_other: bool,
+
+    #[cfg(unix)]
+    /// Re-check files open for reading this often, so `tail -f` sees appends (0, the default, disables)
+    #[arg(long, value_name = "SECONDS", default_value = "0")]
+    pub watch_interval: u64,
+
+    #[cfg(unix)]
+    /// Upload files created or truncated write-only in pieces as they are written, keeping at most one piece on local disk
+    #[arg(long, default_value = "false")]
+    pub stream_writes: bool,
+
+    #[cfg(unix)]
+    /// Size of each piece uploaded by --stream-writes, in MB
+    #[arg(long, value_name = "MB", default_value = "64")]
+    pub stream_chunk_mb: u64,
+
+    #[cfg(unix)]
+    /// Preferred I/O size reported to applications (st_blksize), in bytes; a power of two
+    #[arg(long, value_name = "BYTES", default_value = "131072", value_parser = parse_blksize)]
+    pub blksize: u32,
+
+    #[cfg(unix)]
+    /// Open paths matching this glob uncached, as if with O_DIRECT: every read goes to the server (repeatable)
+    #[arg(long = "direct-io-for", value_name = "GLOB")]
+    pub direct_io_for: Vec<String>,
+
+    #[cfg(unix)]
+    /// Give paths the same inode numbers across remounts, saving them in --overlay-dir
+    #[arg(long, default_value = "false")]
+    pub persist_inodes: bool,
+
+    #[cfg(windows)]
+    /// Owner SID reported for every file (default: the mounting user)
+    #[arg(long, value_name = "SID")]
+    pub windows_owner: Option<String>,
+
+    #[cfg(windows)]
+    /// Access granted on every file: everyone, current-user or custom-sddl=<SDDL>
+    #[arg(long, value_name = "DACL", default_value = "current-user")]
+    pub windows_dacl: String,
+
+    #[cfg(windows)]
+    /// Volume size in GB reported to Explorer and installers (default: server quota, else 1)
+    #[arg(long, value_name = "GB")]
+    pub volume_total_gb: Option<u64>,
+
+    #[cfg(windows)]
+    /// Free space in GB reported to Explorer; writes needing more fail with a full disk
+    #[arg(long, value_name = "GB")]
+    pub volume_free_gb: Option<u64>,
+
     #[cfg(windows)]
     /// Request clean unmount of an existing daemon mount at <MOUNTPOINT> (e.g. R:)
     #[arg(long, default_value = "false")]
     pub unmount: bool,
+
+    #[cfg(windows)]
+    /// Run under the Windows service control manager (set by `service install`)
+    #[arg(long, default_value = "false", conflicts_with = "daemon")]
+    pub service: bool,
+}
+
+/// Maintenance commands that talk to the server without mounting.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Inspect and manage files soft-deleted with --use-trash
+    Trash {
+        #[command(subcommand)]
+        action: TrashAction,
+    },
+    /// List server-side snapshots, or create one
+    Snapshots {
+        /// Create a new snapshot of the current tree
+        #[arg(long)]
+        create: bool,
+        /// Optional name for the snapshot being created
+        #[arg(long, requires = "create")]
+        name: Option<String>,
+    },
+    /// Print the server's audit log of changes (needs an admin token)
+    Audit {
+        /// Only records after this time, in seconds since the Unix epoch
+        #[arg(long, value_name = "SECS")]
+        since: Option<f64>,
+        /// Keep polling for new records
+        #[arg(long, short)]
+        follow: bool,
+    },
+    #[cfg(unix)]
+    /// Send a control request to the mount at <MOUNTPOINT>
+    Control {
+        #[command(subcommand)]
+        action: ControlAction,
+    },
+    #[cfg(windows)]
+    /// Register or remove a Windows service that mounts <MOUNTPOINT> with the given options
+    Service {
+        #[command(subcommand)]
+        action: ServiceAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TrashAction {
+    /// List trashed entries
+    List,
+    /// Restore a trashed entry to its original path
+    Restore {
+        /// Entry id as shown by `trash list`
+        id: String,
+    },
+    /// Permanently delete everything in the trash
+    Empty,
+}
+
+#[cfg(unix)]
+#[derive(Subcommand, Debug)]
+pub enum ControlAction {
+    /// Drop every cached listing and file
+    FlushCaches,
+    /// Print the version file report with the current activity
+    Stats,
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum ServiceAction {
+    /// Install an auto-start service running this command line with --service
+    Install,
+    /// Stop and remove the service for <MOUNTPOINT>
+    Uninstall,
+}
+
+/// Parses octal permission bits such as `600` or `0o750`.
+#[cfg(unix)]
+fn parse_mode(s: &str) -> Result<u32, String> {
+    let digits = s.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal mode between 000 and 777", s)),
+    }
+}
+
+/// Parses an I/O size: a power of two from 512 bytes to 16 MiB.
+#[cfg(unix)]
+fn parse_blksize(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(n) if n.is_power_of_two() && (512..=16 << 20).contains(&n) => Ok(n),
+        _ => Err(format!(
+            "'{}' is not a power of two between 512 and 16777216",
+            s
+        )),
+    }
+}
+
+/// Accepts a `type/subtype` MIME type that can be sent as a header value.
+fn parse_mime(s: &str) -> Result<String, String> {
+    let valid = s
+        .split_once('/')
+        .is_some_and(|(t, sub)| !t.is_empty() && !sub.is_empty())
+        && HeaderValue::from_str(s).is_ok();
+    if valid {
+        Ok(s.to_string())
+    } else {
+        Err(format!("'{}' is not a MIME type such as text/plain", s))
+    }
+}
+
+/// Returns `url` rewritten as an `http://` URL, for error messages.
+fn suggest_url(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    format!("http://{}", rest.trim_start_matches('/'))
 }
 
 impl Cli {
+    /// Rejects a `--server-url` that is not an absolute http(s) URL, before
+    /// anything is mounted or daemonized, and suggests the corrected form.
+    pub fn check_server_url(&self) -> Result<(), anyhow::Error> {
+        let url = self.server_url.trim();
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {
+                Ok(())
+            }
+            Ok(parsed) if parsed.scheme() == "unix" => anyhow::bail!(
+                "--server-url '{}': unix sockets are not supported, serve the server over http",
+                url
+            ),
+            _ => anyhow::bail!(
+                "--server-url '{}' is not an absolute http(s) URL; did you mean '{}'?",
+                url,
+                suggest_url(url)
+            ),
+        }
+    }
+
+    /// `--overlay-dir`, or its default under the temp directory.
+    fn overlay_dir(&self) -> std::path::PathBuf {
+        self.overlay_dir
+            .clone()
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(|| std::env::temp_dir().join("remote-fs-overlay"))
+    }
+
+    /// `--persist-inodes` file in the overlay dir, named after the server,
+    /// remote root and mount point, so no two mounts share one.
+    #[cfg(unix)]
+    fn inode_file(&self) -> std::path::PathBuf {
+        let mountpoint =
+            std::fs::canonicalize(self.mountpoint()).unwrap_or_else(|_| self.mountpoint().into());
+        let key = format!(
+            "{}\n{}\n{}",
+            self.server_url.trim().trim_end_matches('/'),
+            crate::types::normalize_path(self.remote_root.as_deref().unwrap_or_default()),
+            mountpoint.display()
+        );
+        let digest = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        let id: String = digest.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.overlay_dir().join(format!("inodes-{}", id))
+    }
+
+    /// Mount point given on the command line; always present when mounting.
+    pub fn mountpoint(&self) -> &str {
+        self.mountpoint.as_deref().unwrap_or_default()
+    }
+
     pub fn cache_config(&self) -> CacheConfig {
-        CacheConfig::from_cli(
+        let mut config = CacheConfig::from_cli(
             self.no_cache,
             self.dir_cache_ttl,
             self.file_cache_ttl,
             self.max_cache_mb,
-        )
+            self.small_file_threshold,
+        );
+        config.consistent_open = self.consistent_open;
+        config.max_file_entries = self.max_cache_files;
+        config.max_dir_entries = self.max_cache_dirs;
+        config.max_readahead = self.readahead_kb.saturating_mul(1024);
+        if let Some(secs) = self.root_cache_ttl.filter(|_| !self.no_cache) {
+            config.root_dir_ttl = Duration::from_secs(secs);
+        }
+        if !self.no_cache {
+            config.swr_window = Duration::from_secs(self.swr_window);
+        }
+        if let Some(secs) = self.attr_timeout {
+            config.attr_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.entry_timeout {
+            config.entry_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.negative_timeout {
+            config.negative_timeout = Duration::from_secs(secs);
+        }
+        config
+    }
+
+    /// Selects the progress sink, honoring `--quiet` and non-TTY stderr.
+    ///
+    /// JSON logs share stderr, so bars are hidden to keep every line parseable.
+    pub fn progress(&self) -> Arc<dyn Progress> {
+        default_progress(self.quiet || self.log_format == LogFormat::Json)
+    }
+
+    /// Builds the HTTP client with cache policy, progress sink and event hooks applied.
+    pub fn remote_client(&self) -> Result<RemoteClient, anyhow::Error> {
+        let mut rc = RemoteClient::new(&self.server_url, self.cache_config());
+        rc.set_default_headers(self.default_headers()?);
+        // Before the first request: servers with AUTH_CONFIG refuse anonymous ones.
+        rc.set_token(self.token()?);
+        rc.probe_capabilities();
+        let caps = rc.capabilities();
+        let missing = |flag: &str, feature: &str| match &caps.unreachable {
+            Some(e) => anyhow::anyhow!(
+                "{}: cannot check server support for {}, server unreachable: {}",
+                flag,
+                feature,
+                e
+            ),
+            None => anyhow::anyhow!("{}: the server does not support {}", flag, feature),
+        };
+        if self.use_trash && !caps.trash {
+            return Err(missing("--use-trash", "the trash"));
+        }
+        if self.snapshot.is_some() && !caps.snapshots {
+            return Err(missing("--snapshot", "snapshots"));
+        }
+        rc.set_progress(self.progress());
+        rc.set_use_trash(self.use_trash);
+        rc.set_content_types(!self.no_content_type);
+        rc.set_default_content_type(self.default_content_type.clone());
+        rc.set_deleted_while_open(self.strict_consistency.unwrap_or_default());
+        rc.set_protected(Protected::new(&self.protect));
+        rc.set_max_inflight(self.max_inflight);
+        rc.set_max_file_size(self.max_file_size);
+        rc.set_circuit_breaker(
+            self.breaker_threshold,
+            Duration::from_secs(self.breaker_cooldown),
+        );
+        if let Some(id) = &self.snapshot {
+            rc.set_snapshot(id.clone());
+        }
+        if let Some(root) = &self.remote_root {
+            rc.set_remote_root(root);
+            if self.command.is_none() {
+                rc.check_remote_root()
+                    .map_err(|e| anyhow::anyhow!("--remote-root: {}", e))?;
+            }
+        }
+        if let Some(hooks) = CommandHooks::spawn(self.on_upload.clone(), self.on_delete.clone()) {
+            rc.set_event_hook(Arc::new(hooks));
+        }
+        if self.offline_writes {
+            let dir = self.overlay_dir();
+            let mut overlay = Overlay::open(&dir).map_err(|e| {
+                anyhow::anyhow!("Failed to open overlay at {}: {}", dir.display(), e)
+            })?;
+            overlay.set_limits(
+                self.max_pending_upload_mb * 1024 * 1024,
+                self.max_pending_uploads,
+            );
+            rc.set_overlay(Arc::new(overlay));
+        }
+        if let Some(key_file) = &self.name_key_file {
+            let cipher = NameCipher::from_key_file(key_file)
+                .map_err(|e| anyhow::anyhow!("Failed to load name key: {}", e))?;
+            rc.set_name_cipher(Arc::new(cipher));
+        }
+        Ok(rc)
+    }
+
+    /// The access token from `--token-file`, else from `REMOTE_FS_TOKEN`.
+    fn token(&self) -> Result<Option<String>, anyhow::Error> {
+        let token = match &self.token_file {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read token file {}: {}", path, e))?,
+            None => std::env::var("REMOTE_FS_TOKEN").unwrap_or_default(),
+        };
+        let token = token.trim();
+        Ok((!token.is_empty()).then(|| token.to_string()))
+    }
+
+    /// Parses `--user-agent` and `--header` into the headers sent with every request.
+    fn default_headers(&self) -> Result<HeaderMap, anyhow::Error> {
+        let mut headers = HeaderMap::new();
+        if let Some(agent) = &self.user_agent {
+            let value = HeaderValue::from_str(agent)
+                .map_err(|_| anyhow::anyhow!("--user-agent: invalid header value"))?;
+            headers.insert(USER_AGENT, value);
+        }
+        for header in &self.headers {
+            let Some((name, value)) = header.split_once('=') else {
+                anyhow::bail!("--header {}: expected NAME=VALUE", header);
+            };
+            let name = HeaderName::from_bytes(name.trim().as_bytes())
+                .map_err(|_| anyhow::anyhow!("--header {}: invalid header name", header))?;
+            let value = HeaderValue::from_str(value.trim())
+                .map_err(|_| anyhow::anyhow!("--header {}: invalid header value", header))?;
+            headers.append(name, value);
+        }
+        Ok(headers)
+    }
+
+    /// Collects the mount settings, connecting to the server for the client.
+    pub fn mount_config(&self) -> Result<MountConfig, anyhow::Error> {
+        let mut config = MountConfig::new(self.remote_client()?, self.mountpoint());
+        config.warm = self.warm.clone();
+        config.warm_depth = self.warm_cache;
+        config.warm_workers = self.warm_workers;
+        config.warm_up = !self.no_warmup;
+        #[cfg(unix)]
+        {
+            config.uid = self.uid;
+            config.gid = self.gid;
+            config.file_mode = self.force_file_mode;
+            config.dir_mode = self.force_dir_mode;
+            config.auto_remount = self.auto_remount;
+            config.remount_attempts = self.remount_attempts;
+            config.force = self.force;
+            config.allow_other = self.allow_other;
+            config.watch_interval = Duration::from_secs(self.watch_interval);
+            config.blksize = self.blksize;
+            config.direct_io_for = self.direct_io_for.clone();
+            if self.persist_inodes {
+                config.inode_file = Some(self.inode_file());
+            }
+            if self.stream_writes {
+                if !config.client.capabilities().appends {
+                    anyhow::bail!("--stream-writes: the server does not support appends");
+                }
+                config.stream_chunk = Some(self.stream_chunk_mb.max(1) * 1024 * 1024);
+            }
+        }
+        #[cfg(windows)]
+        {
+            let security = crate::windows::SecurityDescriptor::from_options(
+                self.windows_owner.as_deref(),
+                &self.windows_dacl,
+            )
+            .map_err(|e| anyhow::anyhow!("Failed to build security descriptor: {}", e))?;
+            config.security = Some(security);
+            const GB: u64 = 1024 * 1024 * 1024;
+            if let (Some(total), Some(free)) = (self.volume_total_gb, self.volume_free_gb) {
+                if free > total {
+                    anyhow::bail!(
+                        "--volume-free-gb: {} GB free exceeds the {} GB volume",
+                        free,
+                        total
+                    );
+                }
+            }
+            config.volume = crate::windows::VolumeSize {
+                total: self.volume_total_gb.map(|gb| gb * GB),
+                free: self.volume_free_gb.map(|gb| gb * GB),
+            };
+        }
+        Ok(config)
     }
 }
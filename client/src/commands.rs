@@ -0,0 +1,104 @@
+use crate::cli::{Cli, Command, TrashAction};
+use crate::error::RemoteError;
+use crate::remote_client::RemoteClient;
+use std::time::Duration;
+
+/// How often `audit --follow` asks for new records.
+const AUDIT_POLL: Duration = Duration::from_secs(2);
+
+/// Runs a maintenance subcommand against the server.
+pub fn run(cli: &Cli, command: &Command) -> Result<(), anyhow::Error> {
+    // Service management only talks to the local service manager.
+    #[cfg(windows)]
+    if let Command::Service { action } = command {
+        return crate::windows::service::command(cli, action);
+    }
+    // Control requests go to the running mount, not the server.
+    #[cfg(unix)]
+    if let Command::Control { action } = command {
+        return crate::unix::control::command(cli.mountpoint(), action);
+    }
+
+    let rc = cli.remote_client()?;
+    let result = match command {
+        Command::Trash { action } => match action {
+            TrashAction::List => rc.list_trash().map(|entries| {
+                if entries.is_empty() {
+                    println!("Trash is empty");
+                }
+                for entry in entries {
+                    println!(
+                        "{}  {}  {}{}",
+                        entry.id,
+                        format_time(entry.deleted_at),
+                        entry.path,
+                        if entry.is_dir() { "/" } else { "" }
+                    );
+                }
+            }),
+            TrashAction::Restore { id } => {
+                rc.restore_trash(id).map(|_| println!("Restored {}", id))
+            }
+            TrashAction::Empty => rc.empty_trash().map(|_| println!("Trash emptied")),
+        },
+        Command::Snapshots { create: true, name } => rc
+            .create_snapshot(name.as_deref())
+            .map(|snapshot| println!("Created snapshot {}", snapshot.id)),
+        Command::Snapshots { create: false, .. } => rc.list_snapshots().map(|snapshots| {
+            if snapshots.is_empty() {
+                println!("No snapshots");
+            }
+            for snapshot in snapshots {
+                println!("{}  {}", snapshot.id, format_time(snapshot.created_at));
+            }
+        }),
+        Command::Audit { since, follow } => print_audit(&rc, since.unwrap_or(0.0), *follow),
+        #[cfg(windows)]
+        Command::Service { .. } => unreachable!("handled before connecting"),
+        #[cfg(unix)]
+        Command::Control { .. } => unreachable!("handled before connecting"),
+    };
+
+    Ok(result?)
+}
+
+/// Prints audit records after `since`, then keeps polling when `follow` is set.
+fn print_audit(rc: &RemoteClient, mut since: f64, follow: bool) -> Result<(), RemoteError> {
+    loop {
+        let records = rc.audit_since(since)?;
+        for record in &records {
+            let target = match &record.to {
+                Some(to) => format!(" -> /{}", to),
+                None => String::new(),
+            };
+            println!(
+                "{}  {}  {} {}{}  {}  {}",
+                format_time(record.ts),
+                record
+                    .principal
+                    .as_deref()
+                    .or(record.client.as_deref())
+                    .unwrap_or("-"),
+                record.method,
+                record.path,
+                target,
+                record.status,
+                record.request_id.as_deref().unwrap_or("-")
+            );
+        }
+        if let Some(last) = records.last() {
+            since = last.ts;
+        }
+        if !follow {
+            return Ok(());
+        }
+        std::thread::sleep(AUDIT_POLL);
+    }
+}
+
+/// Renders a server timestamp (seconds since the epoch) in UTC.
+fn format_time(secs: f64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(secs as i64)
+        .map(|t| t.to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,491 @@
+use crate::logging;
+use reqwest::blocking::Response;
+use reqwest::header::LOCATION;
+use serde::Deserialize;
+use std::fmt;
+
+/// Longest server error message kept in a `RemoteError`.
+const MAX_BODY: usize = 512;
+
+/// Failure of a `RemoteClient` call, classified so frontends can map it to
+/// errno/NTSTATUS values and decide whether a retry can help.
+#[derive(Debug)]
+pub enum RemoteError {
+    /// 404: the path does not exist on the server.
+    NotFound,
+    /// 401 or 403.
+    Unauthorized,
+    /// 409: the path already exists or changed underneath us.
+    Conflict,
+    /// 412: a conditional request lost a race.
+    PreconditionFailed,
+    /// 413: the upload exceeds the server's size limit; carries the server's message.
+    TooLarge(String),
+    /// 507: the server is out of storage space; carries the server's message.
+    NoSpace(String),
+    /// 3xx the client would not follow: another host, too many hops, or a
+    /// write that would have been replayed as a GET. Carries the target.
+    Redirected(String),
+    /// The request timed out.
+    Timeout,
+    /// The circuit breaker refused the request without contacting the server.
+    Unreachable,
+    /// The server lacks an optional feature, probed at mount.
+    Unsupported(String),
+    /// Connection or protocol failure before a usable response arrived.
+    Transport(reqwest::Error),
+    /// Any other error status, with the server's message (or the start of the body).
+    Server { status: u16, body: String },
+    /// Local I/O failed (temp files, overlay, download sink).
+    Io(std::io::Error),
+    /// The request cannot succeed as asked; the message says why.
+    Invalid(String),
+    /// The server answered with a body the client cannot parse.
+    Decode(String),
+    /// The upload was aborted because its file was deleted meanwhile.
+    Cancelled,
+}
+
+impl RemoteError {
+    /// Builds the error for a non-success response from the server's error
+    /// envelope, logging its message under the request id.
+    pub fn from_response(resp: Response) -> Self {
+        let status = resp.status().as_u16();
+        if resp.status().is_redirection() {
+            let target = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            logging::debug("http", format!("server answered {} to {}", status, target))
+                .errno(logging::status_errno(status))
+                .emit();
+            return RemoteError::Redirected(target);
+        }
+        let reply = ServerReply::read(resp);
+        reply.log(status);
+        RemoteError::from_status(status, reply.message)
+    }
+
+    /// Classifies an error status carrying the server's message.
+    fn from_status(status: u16, message: String) -> Self {
+        match status {
+            404 => RemoteError::NotFound,
+            401 | 403 => RemoteError::Unauthorized,
+            409 => RemoteError::Conflict,
+            412 => RemoteError::PreconditionFailed,
+            413 => RemoteError::TooLarge(message),
+            507 => RemoteError::NoSpace(message),
+            _ => RemoteError::Server {
+                status,
+                body: message,
+            },
+        }
+    }
+
+    /// True when the server answered: retrying the same request will not help,
+    /// and the failure is no reason to keep data offline.
+    pub fn is_status(&self) -> bool {
+        matches!(
+            self,
+            RemoteError::NotFound
+                | RemoteError::Unauthorized
+                | RemoteError::Conflict
+                | RemoteError::PreconditionFailed
+                | RemoteError::TooLarge(_)
+                | RemoteError::NoSpace(_)
+                | RemoteError::Redirected(_)
+                | RemoteError::Server { .. }
+        )
+    }
+
+    /// True for failures that may go away if the same request is sent again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            RemoteError::Timeout | RemoteError::Transport(_) => true,
+            RemoteError::Server { status, .. } => matches!(status, 502..=504),
+            _ => false,
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn to_errno(&self) -> i32 {
+        match self {
+            RemoteError::NotFound => libc::ENOENT,
+            RemoteError::Unauthorized => libc::EACCES,
+            RemoteError::Conflict => libc::EEXIST,
+            RemoteError::PreconditionFailed => libc::ESTALE,
+            RemoteError::TooLarge(_) => libc::EFBIG,
+            RemoteError::NoSpace(_) => libc::ENOSPC,
+            RemoteError::Redirected(_) => libc::EREMOTE,
+            RemoteError::Timeout => libc::ETIMEDOUT,
+            RemoteError::Unreachable => libc::EHOSTUNREACH,
+            RemoteError::Unsupported(_) => libc::ENOTSUP,
+            RemoteError::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
+            RemoteError::Invalid(_) => libc::EINVAL,
+            RemoteError::Decode(_) => libc::EIO,
+            RemoteError::Cancelled => libc::ECANCELED,
+            RemoteError::Transport(_) | RemoteError::Server { .. } => libc::EIO,
+        }
+    }
+
+    #[cfg(windows)]
+    pub fn to_ntstatus(&self) -> i32 {
+        const STATUS_UNSUCCESSFUL: u32 = 0xC000_0001;
+        const STATUS_ACCESS_DENIED: u32 = 0xC000_0022;
+        const STATUS_OBJECT_NAME_NOT_FOUND: u32 = 0xC000_0034;
+        const STATUS_OBJECT_NAME_COLLISION: u32 = 0xC000_0035;
+        const STATUS_IO_TIMEOUT: u32 = 0xC000_00B5;
+        const STATUS_NOT_SUPPORTED: u32 = 0xC000_00BB;
+        const STATUS_UNEXPECTED_NETWORK_ERROR: u32 = 0xC000_00C4;
+        const STATUS_HOST_UNREACHABLE: u32 = 0xC000_023D;
+        const STATUS_INVALID_PARAMETER: u32 = 0xC000_000D;
+        const STATUS_DISK_FULL: u32 = 0xC000_007F;
+        const STATUS_FILE_TOO_LARGE: u32 = 0xC000_0904;
+        const STATUS_PATH_NOT_COVERED: u32 = 0xC000_0257;
+        const STATUS_CANCELLED: u32 = 0xC000_0120;
+        const STATUS_INVALID_NETWORK_RESPONSE: u32 = 0xC000_00C3;
+        let status = match self {
+            RemoteError::NotFound => STATUS_OBJECT_NAME_NOT_FOUND,
+            RemoteError::Unauthorized => STATUS_ACCESS_DENIED,
+            RemoteError::Conflict => STATUS_OBJECT_NAME_COLLISION,
+            RemoteError::Timeout => STATUS_IO_TIMEOUT,
+            RemoteError::Unreachable => STATUS_HOST_UNREACHABLE,
+            RemoteError::Unsupported(_) => STATUS_NOT_SUPPORTED,
+            RemoteError::Transport(_) => STATUS_UNEXPECTED_NETWORK_ERROR,
+            RemoteError::Invalid(_) => STATUS_INVALID_PARAMETER,
+            RemoteError::Decode(_) => STATUS_INVALID_NETWORK_RESPONSE,
+            RemoteError::TooLarge(_) => STATUS_FILE_TOO_LARGE,
+            RemoteError::NoSpace(_) => STATUS_DISK_FULL,
+            RemoteError::Redirected(_) => STATUS_PATH_NOT_COVERED,
+            RemoteError::Cancelled => STATUS_CANCELLED,
+            RemoteError::PreconditionFailed | RemoteError::Server { .. } | RemoteError::Io(_) => {
+                STATUS_UNSUCCESSFUL
+            }
+        };
+        status as i32
+    }
+}
+
+impl fmt::Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteError::NotFound => write!(f, "not found"),
+            RemoteError::Unauthorized => write!(f, "access denied by the server"),
+            RemoteError::Conflict => write!(f, "conflict with the server state"),
+            RemoteError::PreconditionFailed => write!(f, "the file changed on the server"),
+            RemoteError::TooLarge(msg) => write!(f, "file too large for the server: {}", msg),
+            RemoteError::NoSpace(msg) => write!(f, "server out of space: {}", msg),
+            RemoteError::Redirected(target) if target.is_empty() => {
+                write!(f, "redirect not followed")
+            }
+            RemoteError::Redirected(target) => write!(f, "redirect to {} not followed", target),
+            RemoteError::Timeout => write!(f, "request timed out"),
+            RemoteError::Unreachable => write!(
+                f,
+                "server unreachable, failing fast until the circuit breaker cools down"
+            ),
+            RemoteError::Unsupported(feature) => {
+                write!(f, "server does not support {}", feature)
+            }
+            RemoteError::Transport(e) => write!(f, "{}", e),
+            RemoteError::Server { status, body } if body.is_empty() => {
+                write!(f, "server error {}", status)
+            }
+            RemoteError::Server { status, body } => write!(f, "server error {}: {}", status, body),
+            RemoteError::Io(e) => write!(f, "{}", e),
+            RemoteError::Invalid(msg) => write!(f, "{}", msg),
+            RemoteError::Decode(msg) => write!(f, "malformed server response: {}", msg),
+            RemoteError::Cancelled => write!(f, "upload cancelled: the file was deleted"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RemoteError::Transport(e) => Some(e),
+            RemoteError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for RemoteError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            RemoteError::Timeout
+        } else if e.is_decode() {
+            RemoteError::Decode(e.to_string())
+        } else {
+            RemoteError::Transport(e)
+        }
+    }
+}
+
+impl From<std::io::Error> for RemoteError {
+    fn from(e: std::io::Error) -> Self {
+        // Body readers surface network failures as io errors wrapping reqwest's.
+        match e
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+        {
+            Some(_) => match e
+                .into_inner()
+                .map(|inner| inner.downcast::<reqwest::Error>())
+            {
+                Some(Ok(inner)) => RemoteError::from(*inner),
+                _ => RemoteError::Decode("unreadable body".to_string()),
+            },
+            None => RemoteError::Io(e),
+        }
+    }
+}
+
+/// Error envelope the server sends with every failure.
+#[derive(Deserialize)]
+struct Envelope {
+    code: Option<String>,
+    message: Option<String>,
+    path: Option<String>,
+    request_id: Option<String>,
+    /// FastAPI's default body, sent by servers that predate the envelope.
+    detail: Option<serde_json::Value>,
+}
+
+/// What the server said about a failed request.
+struct ServerReply {
+    code: Option<String>,
+    message: String,
+    path: Option<String>,
+    request_id: Option<String>,
+}
+
+impl ServerReply {
+    /// Reads the body of a failed response.
+    fn read(resp: Response) -> Self {
+        let header = |name: &str| {
+            resp.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let is_json = header("content-type").is_some_and(|t| t.contains("json"));
+        let header_id = header("x-request-id");
+        let body = resp.text().unwrap_or_default();
+        ServerReply::parse(body, is_json, header_id)
+    }
+
+    /// Parses a JSON error envelope; any other body becomes the message as is.
+    fn parse(body: String, is_json: bool, header_id: Option<String>) -> Self {
+        let envelope = if is_json {
+            serde_json::from_str::<Envelope>(&body).ok()
+        } else {
+            None
+        };
+        let reply = match envelope {
+            Some(env) => {
+                let message = env.message.or_else(|| match env.detail? {
+                    serde_json::Value::String(s) => Some(s),
+                    other => Some(other.to_string()),
+                });
+                ServerReply {
+                    code: env.code,
+                    message: message.unwrap_or(body),
+                    path: env.path,
+                    request_id: env.request_id.or(header_id),
+                }
+            }
+            None => ServerReply {
+                code: None,
+                message: body,
+                path: None,
+                request_id: header_id,
+            },
+        };
+        ServerReply {
+            message: truncate(reply.message),
+            ..reply
+        }
+    }
+
+    /// Logs the server's explanation at debug level, under the id both sides log.
+    fn log(&self, status: u16) {
+        if self.message.is_empty() {
+            return;
+        }
+        let text = match &self.code {
+            Some(code) => format!("server answered {} ({}): {}", status, code, self.message),
+            None => format!("server answered {}: {}", status, self.message),
+        };
+        let mut record = logging::debug("http", text).errno(logging::status_errno(status));
+        if let Some(path) = &self.path {
+            record = record.path(path);
+        }
+        if let Some(id) = self.request_id.as_deref().filter(|id| *id != "-") {
+            record = record.request_id(id);
+        }
+        record.emit();
+    }
+}
+
+/// Keeps the start of a server message.
+fn truncate(mut text: String) -> String {
+    if text.len() > MAX_BODY {
+        let mut end = MAX_BODY;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+/// Turns error statuses into `RemoteError`, in place of `error_for_status`.
+pub trait CheckStatus: Sized {
+    fn check_status(self) -> Result<Self, RemoteError>;
+}
+
+impl CheckStatus for Response {
+    fn check_status(self) -> Result<Self, RemoteError> {
+        if self.status().is_success() {
+            Ok(self)
+        } else {
+            Err(RemoteError::from_response(self))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(status: u16) -> RemoteError {
+        RemoteError::from_status(status, String::new())
+    }
+
+    #[test]
+    fn statuses_map_to_variants() {
+        assert!(matches!(server(404), RemoteError::NotFound));
+        assert!(matches!(server(401), RemoteError::Unauthorized));
+        assert!(matches!(server(403), RemoteError::Unauthorized));
+        assert!(matches!(server(409), RemoteError::Conflict));
+        assert!(matches!(server(412), RemoteError::PreconditionFailed));
+        assert!(matches!(server(413), RemoteError::TooLarge(_)));
+        assert!(matches!(server(507), RemoteError::NoSpace(_)));
+        assert!(matches!(
+            server(500),
+            RemoteError::Server { status: 500, .. }
+        ));
+    }
+
+    #[test]
+    fn retryable_errors() {
+        assert!(RemoteError::Timeout.is_retryable());
+        for status in [502, 503, 504] {
+            assert!(server(status).is_retryable(), "{}", status);
+        }
+        for status in [400, 404, 409, 412, 413, 500, 507] {
+            assert!(!server(status).is_retryable(), "{}", status);
+        }
+        assert!(!RemoteError::Unreachable.is_retryable());
+        assert!(!RemoteError::Cancelled.is_retryable());
+        assert!(!RemoteError::Decode(String::new()).is_retryable());
+        assert!(!RemoteError::Io(std::io::ErrorKind::Other.into()).is_retryable());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn errno_table() {
+        let cases = [
+            (server(404), libc::ENOENT),
+            (server(403), libc::EACCES),
+            (server(409), libc::EEXIST),
+            (server(412), libc::ESTALE),
+            (server(413), libc::EFBIG),
+            (server(507), libc::ENOSPC),
+            (server(500), libc::EIO),
+            (RemoteError::Redirected(String::new()), libc::EREMOTE),
+            (RemoteError::Timeout, libc::ETIMEDOUT),
+            (RemoteError::Unreachable, libc::EHOSTUNREACH),
+            (RemoteError::Unsupported(String::new()), libc::ENOTSUP),
+            (RemoteError::Invalid(String::new()), libc::EINVAL),
+            (RemoteError::Decode(String::new()), libc::EIO),
+            (RemoteError::Cancelled, libc::ECANCELED),
+            (
+                RemoteError::Io(std::io::Error::from_raw_os_error(libc::EROFS)),
+                libc::EROFS,
+            ),
+            (RemoteError::Io(std::io::ErrorKind::Other.into()), libc::EIO),
+        ];
+        for (error, errno) in cases {
+            assert_eq!(error.to_errno(), errno, "{}", error);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn ntstatus_table() {
+        let cases = [
+            (server(404), 0xC000_0034u32),
+            (server(403), 0xC000_0022),
+            (server(409), 0xC000_0035),
+            (server(412), 0xC000_0001),
+            (server(413), 0xC000_0904),
+            (server(507), 0xC000_007F),
+            (server(500), 0xC000_0001),
+            (RemoteError::Redirected(String::new()), 0xC000_0257),
+            (RemoteError::Timeout, 0xC000_00B5),
+            (RemoteError::Unreachable, 0xC000_023D),
+            (RemoteError::Unsupported(String::new()), 0xC000_00BB),
+            (RemoteError::Invalid(String::new()), 0xC000_000D),
+            (RemoteError::Decode(String::new()), 0xC000_00C3),
+            (RemoteError::Cancelled, 0xC000_0120),
+        ];
+        for (error, status) in cases {
+            assert_eq!(error.to_ntstatus(), status as i32, "{}", error);
+        }
+    }
+
+    #[test]
+    fn envelope_fields_are_read() {
+        let body =
+            r#"{"code":"not_found","message":"no such file","path":"a/b","request_id":"r1"}"#;
+        let reply = ServerReply::parse(body.to_string(), true, Some("h1".to_string()));
+        assert_eq!(reply.code.as_deref(), Some("not_found"));
+        assert_eq!(reply.message, "no such file");
+        assert_eq!(reply.path.as_deref(), Some("a/b"));
+        assert_eq!(reply.request_id.as_deref(), Some("r1"));
+    }
+
+    #[test]
+    fn fastapi_detail_is_the_message() {
+        let reply = ServerReply::parse(r#"{"detail":"gone"}"#.to_string(), true, None);
+        assert_eq!(reply.message, "gone");
+        let reply = ServerReply::parse(r#"{"detail":[1,2]}"#.to_string(), true, None);
+        assert_eq!(reply.message, "[1,2]");
+    }
+
+    #[test]
+    fn header_id_fills_in_for_the_envelope() {
+        let reply = ServerReply::parse(r#"{"message":"x"}"#.to_string(), true, Some("h1".into()));
+        assert_eq!(reply.request_id.as_deref(), Some("h1"));
+    }
+
+    #[test]
+    fn other_bodies_are_kept_as_is() {
+        let reply = ServerReply::parse("Bad Gateway".to_string(), false, None);
+        assert_eq!(reply.message, "Bad Gateway");
+        assert!(reply.code.is_none());
+        let reply = ServerReply::parse("{not json".to_string(), true, None);
+        assert_eq!(reply.message, "{not json");
+    }
+
+    #[test]
+    fn long_messages_are_cut_on_a_char_boundary() {
+        let reply = ServerReply::parse("é".repeat(MAX_BODY), false, None);
+        assert!(reply.message.len() <= MAX_BODY);
+        assert!(reply.message.chars().all(|c| c == 'é'));
+    }
+}
@@ -0,0 +1,108 @@
+//! Wildcard matching shared by `--protect` patterns and Windows directory
+//! enumeration. Callers parse their own pattern syntax into [`Token`]s.
+
+/// One element of a parsed pattern.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Token {
+    /// This character.
+    Char(char),
+    /// Any one character other than `stop`.
+    One { stop: Option<char> },
+    /// Any run of characters without `stop`, including none.
+    Run { stop: Option<char> },
+    /// Nothing, or any run ending in `/` (a `**/` prefix).
+    Dirs,
+    /// Any run that may span earlier dots, but never the name's last one.
+    #[cfg(windows)]
+    RunToLastDot,
+    /// Any one character, or nothing before a `.` or at the end.
+    #[cfg(windows)]
+    OneOrNone,
+    /// A `.`, or nothing at the end.
+    #[cfg(windows)]
+    DotOrEnd,
+}
+
+/// True when `tokens` match all of `name`.
+///
+/// Fills in, for each suffix of the pattern, which suffixes of the name it
+/// matches. Quadratic at worst, where backtracking on every run blows up on
+/// patterns like `*a*a*a*b`.
+pub(crate) fn matches(tokens: &[Token], name: &[char]) -> bool {
+    #[cfg(windows)]
+    let last_dot = name.iter().rposition(|&c| c == '.');
+    // `next[j]`: the rest of the pattern matches `name[j..]`.
+    let mut next: Vec<bool> = (0..=name.len()).map(|j| j == name.len()).collect();
+    for &token in tokens.iter().rev() {
+        let mut cur = vec![false; name.len() + 1];
+        // Some `/` at or after `j` is followed by a match of the rest.
+        let mut slash_after = false;
+        for j in (0..=name.len()).rev() {
+            let c = name.get(j).copied();
+            cur[j] = match token {
+                Token::Char(p) => c == Some(p) && next[j + 1],
+                Token::One { stop } => c.is_some() && c != stop && next[j + 1],
+                Token::Run { stop } => next[j] || (c.is_some() && c != stop && cur[j + 1]),
+                Token::Dirs => {
+                    slash_after |= c == Some('/') && next[j + 1];
+                    next[j] || slash_after
+                }
+                #[cfg(windows)]
+                Token::RunToLastDot => {
+                    let limit = last_dot.filter(|&d| d >= j).unwrap_or(name.len());
+                    next[j] || (j < limit && cur[j + 1])
+                }
+                #[cfg(windows)]
+                Token::OneOrNone => match c {
+                    None | Some('.') => next[j],
+                    Some(_) => next[j + 1],
+                },
+                #[cfg(windows)]
+                Token::DotOrEnd => match c {
+                    Some('.') => next[j + 1],
+                    None => next[j],
+                    Some(_) => false,
+                },
+            };
+        }
+        next = cur;
+    }
+    next[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn runs_stop_where_told() {
+        let star = [Token::Run { stop: Some('/') }, Token::Char('x')];
+        assert!(matches(&star, &chars("abx")));
+        assert!(!matches(&star, &chars("a/bx")));
+        let any = [Token::Run { stop: None }, Token::Char('x')];
+        assert!(matches(&any, &chars("a/bx")));
+    }
+
+    #[test]
+    fn dirs_match_nothing_or_whole_directories() {
+        let tokens = [Token::Dirs, Token::Char('x')];
+        assert!(matches(&tokens, &chars("x")));
+        assert!(matches(&tokens, &chars("a/b/x")));
+        assert!(!matches(&tokens, &chars("ax")));
+    }
+
+    #[test]
+    fn many_runs_stay_fast() {
+        let mut tokens = Vec::new();
+        for _ in 0..12 {
+            tokens.push(Token::Run { stop: None });
+            tokens.push(Token::Char('a'));
+        }
+        tokens.push(Token::Char('b'));
+        assert!(!matches(&tokens, &chars(&"a".repeat(4096))));
+    }
+}
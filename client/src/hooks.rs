@@ -0,0 +1,133 @@
+use crate::logging;
+use crate::types::RemoteEntry;
+use std::process::Command;
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::time::Duration;
+
+/// Maximum number of hook invocations waiting to run before new ones are dropped.
+const HOOK_QUEUE_LEN: usize = 64;
+
+/// Kind of remote mutation that triggered an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Upload,
+    Delete,
+}
+
+/// Outcome of a completed upload or delete, passed to event hooks.
+#[derive(Debug, Clone)]
+pub struct TransferEvent {
+    pub kind: EventKind,
+    pub path: String,
+    pub size: u64,
+    pub duration: Duration,
+    pub success: bool,
+}
+
+/// Receives transfer events from `RemoteClient`; must return quickly.
+pub trait EventHook: Send + Sync {
+    fn on_event(&self, event: &TransferEvent);
+}
+
+/// Told when a background revalidation finds that a cached listing changed;
+/// must return quickly.
+pub trait ListingHook: Send + Sync {
+    fn on_listing_changed(&self, path: &str, old: &[RemoteEntry], new: &[RemoteEntry]);
+}
+
+/// Runs user-supplied shell commands for events on a background worker.
+pub struct CommandHooks {
+    queue: SyncSender<(String, TransferEvent)>,
+    on_upload: Option<String>,
+    on_delete: Option<String>,
+}
+
+impl CommandHooks {
+    /// Starts the worker thread; returns `None` when no command is configured.
+    pub fn spawn(on_upload: Option<String>, on_delete: Option<String>) -> Option<Self> {
+        if on_upload.is_none() && on_delete.is_none() {
+            return None;
+        }
+        let (queue, rx) = sync_channel::<(String, TransferEvent)>(HOOK_QUEUE_LEN);
+        std::thread::spawn(move || {
+            for (cmd, event) in rx {
+                run_hook(&cmd, &event);
+            }
+        });
+        Some(Self {
+            queue,
+            on_upload,
+            on_delete,
+        })
+    }
+}
+
+impl EventHook for CommandHooks {
+    fn on_event(&self, event: &TransferEvent) {
+        let cmd = match event.kind {
+            EventKind::Upload => &self.on_upload,
+            EventKind::Delete => &self.on_delete,
+        };
+        let Some(cmd) = cmd else {
+            return;
+        };
+        match self.queue.try_send((cmd.clone(), event.clone())) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                logging::warn(
+                    "hook",
+                    format!("Hook queue full, skipping hook for {}", event.path),
+                )
+                .path(&event.path)
+                .emit();
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                logging::warn(
+                    "hook",
+                    format!("Hook worker stopped, skipping hook for {}", event.path),
+                )
+                .path(&event.path)
+                .emit();
+            }
+        }
+    }
+}
+
+/// Executes one hook command with the event exposed through environment variables.
+fn run_hook(cmd: &str, event: &TransferEvent) {
+    #[cfg(unix)]
+    let mut command = {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(cmd);
+        c
+    };
+    #[cfg(windows)]
+    let mut command = {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(cmd);
+        c
+    };
+
+    let result = command
+        .env("REMOTE_PATH", &event.path)
+        .env("SIZE", event.size.to_string())
+        .env("DURATION_MS", event.duration.as_millis().to_string())
+        .env("STATUS", if event.success { "ok" } else { "error" })
+        .status();
+
+    match result {
+        Ok(status) if status.success() => {}
+        Ok(status) => logging::warn(
+            "hook",
+            format!("Hook for {} exited with {}", event.path, status),
+        )
+        .path(&event.path)
+        .emit(),
+        Err(e) => logging::warn(
+            "hook",
+            format!("Hook for {} failed to start: {}", event.path, e),
+        )
+        .path(&event.path)
+        .emit(),
+    }
+}
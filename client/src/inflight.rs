@@ -0,0 +1,165 @@
+use std::sync::{Condvar, Mutex};
+
+/// What a request carries; data transfers leave a reserve of slots to metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Traffic {
+    /// Listings, stats and other small requests that block `getattr` and `readdir`.
+    Metadata = 0,
+    /// Downloads and uploads of file content.
+    Data = 1,
+}
+
+/// Counting semaphore bounding the HTTP requests in flight across all client clones.
+///
+/// Permits are never held while calling back into `RemoteClient`, so an
+/// operation that issues several requests takes them one after another and
+/// cannot deadlock against itself. Waiters of each kind are served in arrival
+/// order, and data transfers never take the last few slots, so a flood of
+/// reads cannot starve metadata requests.
+pub struct Inflight {
+    max: usize,
+    /// Slots only metadata requests may take.
+    reserve: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    queued: usize,
+    /// Per traffic kind: the next ticket handed out and the next one let through.
+    next_ticket: [u64; 2],
+    serving: [u64; 2],
+}
+
+/// Slot taken from `Inflight`; released on drop.
+pub struct Permit<'a> {
+    owner: &'a Inflight,
+}
+
+impl Inflight {
+    /// Allows `max` concurrent requests; 0 disables the limit.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            reserve: if max > 1 { (max / 8).max(1) } else { 0 },
+            state: Mutex::new(State::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a slot is free for `traffic` and every earlier waiter of
+    /// the same kind has been served.
+    pub fn acquire(&self, traffic: Traffic) -> Permit<'_> {
+        let kind = traffic as usize;
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket[kind];
+        state.next_ticket[kind] += 1;
+        state.queued += 1;
+        while state.serving[kind] != ticket || !self.has_room(&state, traffic) {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.serving[kind] += 1;
+        state.queued -= 1;
+        state.active += 1;
+        // The next ticket in line may fit as well.
+        self.freed.notify_all();
+        Permit { owner: self }
+    }
+
+    /// Requests in flight and requests waiting for a slot.
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.active, state.queued)
+    }
+
+    fn has_room(&self, state: &State, traffic: Traffic) -> bool {
+        let limit = match traffic {
+            Traffic::Metadata => self.max,
+            Traffic::Data => self.max - self.reserve,
+        };
+        self.max == 0 || state.active < limit
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.owner.state.lock().unwrap().active -= 1;
+        // Waiters are woken in bulk: only the head of each queue may proceed.
+        self.owner.freed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::time::Duration;
+
+    /// Highest number of `traffic` requests in flight at once when `threads`
+    /// of them race through `inflight`.
+    fn peak(
+        inflight: &Inflight,
+        threads: usize,
+        traffic: impl Fn(usize) -> Traffic + Sync,
+    ) -> usize {
+        let (active, peak) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        std::thread::scope(|s| {
+            for i in 0..threads {
+                let (active, peak, traffic) = (&active, &peak, &traffic);
+                s.spawn(move || {
+                    let _permit = inflight.acquire(traffic(i));
+                    let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        peak.into_inner()
+    }
+
+    #[test]
+    fn requests_in_flight_never_exceed_max() {
+        let inflight = Inflight::new(4);
+        let mixed = peak(&inflight, 64, |i| {
+            if i % 2 == 0 {
+                Traffic::Data
+            } else {
+                Traffic::Metadata
+            }
+        });
+        assert!(mixed <= 4, "peak {}", mixed);
+        // One slot stays reserved for metadata.
+        let data = peak(&inflight, 64, |_| Traffic::Data);
+        assert!(data <= 3, "peak {}", data);
+        assert_eq!(inflight.counts(), (0, 0));
+    }
+
+    #[test]
+    fn metadata_sub_request_gets_through_while_data_slots_are_held() {
+        let inflight = Arc::new(Inflight::new(8));
+        let shared = Arc::clone(&inflight);
+        let (done, finished) = mpsc::channel();
+        // Not scoped: a deadlock must fail the test, not hang it.
+        std::thread::spawn(move || {
+            // A transfer holding every data slot stats a file on the way.
+            let held: Vec<_> = (0..7).map(|_| shared.acquire(Traffic::Data)).collect();
+            let stat = shared.acquire(Traffic::Metadata);
+            done.send(shared.counts()).unwrap();
+            drop((stat, held));
+        });
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok((8, 0)));
+    }
+
+    #[test]
+    fn zero_max_is_unbounded() {
+        let inflight = Inflight::new(0);
+        let permits: Vec<_> = (0..100).map(|_| inflight.acquire(Traffic::Data)).collect();
+        assert_eq!(inflight.counts(), (100, 0));
+        drop(permits);
+        assert_eq!(inflight.counts(), (0, 0));
+    }
+}
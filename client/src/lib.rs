@@ -0,0 +1,72 @@
+//! Client for the remote file system server.
+//!
+//! The `remote-fs` binary is a thin wrapper around [`run`]. Applications that
+//! embed the filesystem build a [`RemoteClient`] and pass it to [`mount`]:
+//!
+//! ```no_run
+//! use client::{mount, MountConfig, RemoteClient};
+//! use client::types::CacheConfig;
+//!
+//! let cache = CacheConfig::from_cli(false, 5, 10, 64, 8192);
+//! let mut rc = RemoteClient::new("http://127.0.0.1:8000", cache);
+//! rc.probe_capabilities();
+//! let handle = mount(MountConfig::new(rc, "/tmp/mnt"))?;
+//! // ... later, possibly from another thread via `handle.unmounter()`:
+//! handle.unmount()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod breaker;
+pub mod cli;
+mod commands;
+mod content_type;
+pub mod error;
+mod glob;
+pub mod hooks;
+mod inflight;
+pub mod logging;
+mod mount;
+pub mod name_cipher;
+pub mod overlay;
+pub mod progress;
+pub mod protect;
+mod raw_names;
+pub mod remote_client;
+mod singleflight;
+#[cfg(test)]
+mod test_server;
+pub mod types;
+mod uploads;
+mod version_file;
+mod warm;
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+use unix as platform;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+use windows as platform;
+
+pub use error::RemoteError;
+pub use mount::{mount, MountConfig, MountHandle, Unmounter};
+pub use remote_client::RemoteClient;
+
+#[cfg(unix)]
+pub use unix::RemoteFS;
+
+#[cfg(windows)]
+pub use windows::{RemoteFS, SecurityDescriptor};
+
+/// Runs the command line: a maintenance subcommand, or a mount that is
+/// served until unmounted.
+pub fn run(cli: &cli::Cli) -> Result<(), anyhow::Error> {
+    cli.check_server_url()?;
+    if let Some(command) = &cli.command {
+        return commands::run(cli, command);
+    }
+
+    platform::run(cli)
+}
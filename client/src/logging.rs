@@ -0,0 +1,169 @@
+use clap::ValueEnum;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Output format of diagnostic messages, selected with `--log-format`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain messages, one per line
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Selects the log format; the first call wins, later calls are ignored.
+pub fn init(format: LogFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> LogFormat {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Returns a process-unique id sent as `X-Request-ID` and echoed in the server log.
+pub fn next_request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    format!(
+        "{:x}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed) + 1
+    )
+}
+
+/// Maps an HTTP status to the errno the filesystem layer reports for it.
+pub fn status_errno(status: u16) -> i32 {
+    match status {
+        404 => 2,        // ENOENT
+        401 | 403 => 13, // EACCES
+        409 => 17,       // EEXIST
+        413 => 27,       // EFBIG
+        507 => 28,       // ENOSPC
+        300..=399 => 66, // EREMOTE
+        _ => 5,          // EIO
+    }
+}
+
+/// One log record; built with the setters below and written by `emit`.
+///
+/// Text output prints only the message, as before; debug records are only
+/// written in JSON mode, where pipelines filter by level themselves.
+pub struct Record {
+    level: Level,
+    operation: &'static str,
+    message: String,
+    path: Option<String>,
+    duration: Option<Duration>,
+    errno: Option<i32>,
+    request_id: Option<String>,
+}
+
+pub fn debug(operation: &'static str, message: impl Into<String>) -> Record {
+    Record::new(Level::Debug, operation, message.into())
+}
+
+pub fn info(operation: &'static str, message: impl Into<String>) -> Record {
+    Record::new(Level::Info, operation, message.into())
+}
+
+pub fn warn(operation: &'static str, message: impl Into<String>) -> Record {
+    Record::new(Level::Warn, operation, message.into())
+}
+
+pub fn error(operation: &'static str, message: impl Into<String>) -> Record {
+    Record::new(Level::Error, operation, message.into())
+}
+
+impl Record {
+    fn new(level: Level, operation: &'static str, message: String) -> Self {
+        Self {
+            level,
+            operation,
+            message,
+            path: None,
+            duration: None,
+            errno: None,
+            request_id: None,
+        }
+    }
+
+    pub fn path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration);
+        self
+    }
+
+    pub fn errno(mut self, errno: i32) -> Self {
+        self.errno = Some(errno);
+        self
+    }
+
+    pub fn request_id(mut self, id: &str) -> Self {
+        self.request_id = Some(id.to_string());
+        self
+    }
+
+    /// Writes the record to stderr as one line.
+    pub fn emit(self) {
+        let line = match format() {
+            LogFormat::Text if self.level == Level::Debug => return,
+            LogFormat::Text => self.message,
+            LogFormat::Json => self.to_json().to_string(),
+        };
+        let mut err = std::io::stderr().lock();
+        let _ = writeln!(err, "{}", line);
+    }
+
+    fn to_json(&self) -> Value {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut record = Map::new();
+        record.insert("timestamp".into(), json!(timestamp));
+        record.insert("level".into(), json!(self.level.as_str()));
+        record.insert("operation".into(), json!(self.operation));
+        record.insert("message".into(), json!(self.message));
+        if let Some(path) = &self.path {
+            record.insert("path".into(), json!(path));
+        }
+        if let Some(duration) = self.duration {
+            record.insert("duration_ms".into(), json!(duration.as_millis() as u64));
+        }
+        if let Some(errno) = self.errno {
+            record.insert("errno".into(), json!(errno));
+        }
+        if let Some(id) = &self.request_id {
+            record.insert("request_id".into(), json!(id));
+        }
+        Value::Object(record)
+    }
+}
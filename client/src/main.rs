@@ -1,21 +1,13 @@
 use clap::Parser;
-
-mod cli;
-mod remote_client;
-mod types;
-
-#[cfg(unix)]
-mod unix;
-
-#[cfg(windows)]
-mod windows;
+use client::cli::Cli;
+use client::logging;
 
 fn main() {
-    let cli = cli::Cli::parse();
-
-    #[cfg(unix)]
-    unix::run(&cli);
+    let cli = Cli::parse();
+    logging::init(cli.log_format);
 
-    #[cfg(windows)]
-    windows::run(&cli);
+    if let Err(e) = client::run(&cli) {
+        logging::error("main", e.to_string()).emit();
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,210 @@
+use crate::logging;
+use crate::remote_client::RemoteClient;
+use std::fmt;
+use std::thread::JoinHandle;
+
+#[cfg(unix)]
+pub use crate::unix::Unmounter;
+#[cfg(windows)]
+pub use crate::windows::Unmounter;
+
+/// What to mount and where; build with `new` and adjust the public fields.
+pub struct MountConfig {
+    /// Client the filesystem serves from; its caches outlive remounts.
+    pub client: RemoteClient,
+    /// Local directory (Unix) or drive letter (Windows) to mount at.
+    pub mountpoint: String,
+    /// Remote subtrees prefetched into the cache once mounted.
+    pub warm: Vec<String>,
+    /// Directory levels listed into the cache once mounted (`--warm-cache`).
+    pub warm_depth: Option<usize>,
+    /// Listings the metadata warm-up runs in parallel.
+    pub warm_workers: usize,
+    /// Open a connection to the server in the background once mounted
+    /// (off with `--no-warmup`).
+    pub warm_up: bool,
+    /// Owner reported for every file (default: server owner, else the caller).
+    #[cfg(unix)]
+    pub uid: Option<u32>,
+    #[cfg(unix)]
+    pub gid: Option<u32>,
+    /// Permission bits of every file/directory created through the mount
+    /// (default: what the creating process asks for, less its umask).
+    #[cfg(unix)]
+    pub file_mode: Option<u32>,
+    #[cfg(unix)]
+    pub dir_mode: Option<u32>,
+    /// Mount again when the FUSE session ends without a clean unmount.
+    #[cfg(unix)]
+    pub auto_remount: bool,
+    /// Remount attempts in a row before giving up.
+    #[cfg(unix)]
+    pub remount_attempts: u32,
+    /// Lazily unmount whatever is mounted at `mountpoint` before mounting.
+    #[cfg(unix)]
+    pub force: bool,
+    /// Require `allow_other`; without it the mount falls back to the
+    /// mounting user only when the system does not permit it.
+    #[cfg(unix)]
+    pub allow_other: bool,
+    /// How often files open for reading are re-checked for growth; zero disables.
+    #[cfg(unix)]
+    pub watch_interval: std::time::Duration,
+    /// Upload sequential writes in pieces of this many bytes instead of at
+    /// close (`--stream-writes`); `None` buffers the whole file.
+    #[cfg(unix)]
+    pub stream_chunk: Option<u64>,
+    /// Preferred I/O size reported in `st_blksize` and `statfs`.
+    #[cfg(unix)]
+    pub blksize: u32,
+    /// Globs of paths always opened uncached, as if with `O_DIRECT`.
+    #[cfg(unix)]
+    pub direct_io_for: Vec<String>,
+    /// Keeps path-to-inode numbers in this file across mounts (`--persist-inodes`).
+    #[cfg(unix)]
+    pub inode_file: Option<std::path::PathBuf>,
+    /// Security descriptor reported for every file (default: current user only).
+    #[cfg(windows)]
+    pub security: Option<crate::windows::SecurityDescriptor>,
+    /// Volume size and free space reported to Explorer; unset ones come from
+    /// the server quota, else 1 GiB with 512 MiB free.
+    #[cfg(windows)]
+    pub volume: crate::windows::VolumeSize,
+}
+
+impl MountConfig {
+    pub fn new(client: RemoteClient, mountpoint: &str) -> Self {
+        Self {
+            client,
+            mountpoint: mountpoint.to_string(),
+            warm: Vec::new(),
+            warm_depth: None,
+            warm_workers: 4,
+            warm_up: true,
+            #[cfg(unix)]
+            uid: None,
+            #[cfg(unix)]
+            gid: None,
+            #[cfg(unix)]
+            file_mode: None,
+            #[cfg(unix)]
+            dir_mode: None,
+            #[cfg(unix)]
+            auto_remount: false,
+            #[cfg(unix)]
+            remount_attempts: 5,
+            #[cfg(unix)]
+            force: false,
+            #[cfg(unix)]
+            allow_other: false,
+            #[cfg(unix)]
+            watch_interval: std::time::Duration::ZERO,
+            #[cfg(unix)]
+            stream_chunk: None,
+            #[cfg(unix)]
+            blksize: crate::unix::DEFAULT_BLKSIZE,
+            #[cfg(unix)]
+            direct_io_for: Vec::new(),
+            #[cfg(unix)]
+            inode_file: None,
+            #[cfg(windows)]
+            security: None,
+            #[cfg(windows)]
+            volume: crate::windows::VolumeSize::default(),
+        }
+    }
+}
+
+/// Everything the mount runs with, logged at mount so a report shows the
+/// effective settings; the client leaves out its token.
+impl fmt::Debug for MountConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("MountConfig");
+        s.field("mountpoint", &self.mountpoint)
+            .field("client", &self.client)
+            .field("warm", &self.warm)
+            .field("warm_depth", &self.warm_depth)
+            .field("warm_workers", &self.warm_workers)
+            .field("warm_up", &self.warm_up);
+        #[cfg(unix)]
+        s.field("uid", &self.uid)
+            .field("gid", &self.gid)
+            .field("file_mode", &self.file_mode.map(|m| format!("{:o}", m)))
+            .field("dir_mode", &self.dir_mode.map(|m| format!("{:o}", m)))
+            .field("auto_remount", &self.auto_remount)
+            .field("remount_attempts", &self.remount_attempts)
+            .field("force", &self.force)
+            .field("allow_other", &self.allow_other)
+            .field("watch_interval", &self.watch_interval)
+            .field("stream_chunk", &self.stream_chunk)
+            .field("blksize", &self.blksize)
+            .field("direct_io_for", &self.direct_io_for)
+            .field("inode_file", &self.inode_file);
+        #[cfg(windows)]
+        s.field("custom_security", &self.security.is_some())
+            .field("volume", &self.volume);
+        s.finish()
+    }
+}
+
+/// A mounted filesystem, served on a background thread.
+///
+/// Dropping the handle unmounts the filesystem and waits for it to finish.
+pub struct MountHandle {
+    unmounter: Unmounter,
+    thread: Option<JoinHandle<Result<(), anyhow::Error>>>,
+}
+
+/// Mounts `config.client` at `config.mountpoint`.
+///
+/// Returns once the filesystem is mounted, or with the error that kept it
+/// from mounting; requests are then served until `unmount` or an external
+/// unmount.
+pub fn mount(config: MountConfig) -> Result<MountHandle, anyhow::Error> {
+    logging::info("mount", format!("Mounting with {:?}", config)).emit();
+    #[cfg(unix)]
+    let (unmounter, thread) = crate::unix::spawn_mount(config)?;
+    #[cfg(windows)]
+    let (unmounter, thread) = crate::windows::spawn_mount(config)?;
+    Ok(MountHandle {
+        unmounter,
+        thread: Some(thread),
+    })
+}
+
+impl MountHandle {
+    /// Returns a handle that can unmount from another thread while `wait` blocks.
+    pub fn unmounter(&self) -> Unmounter {
+        self.unmounter.clone()
+    }
+
+    /// Unmounts the filesystem and waits until pending work has drained.
+    pub fn unmount(mut self) -> Result<(), anyhow::Error> {
+        self.unmounter.unmount();
+        self.join()
+    }
+
+    /// Blocks until the filesystem is unmounted, by `Unmounter::unmount` or
+    /// from outside; errors when serving stopped for any other reason.
+    pub fn wait(mut self) -> Result<(), anyhow::Error> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), anyhow::Error> {
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("filesystem thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.unmounter.unmount();
+            let _ = self.join();
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::hmac;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// Longest encoded name most filesystems accept for a single path component.
+const MAX_ENCODED_NAME: usize = 255;
+/// AES-GCM tag appended to every encrypted name.
+const TAG_LEN: usize = 16;
+/// RFC 4648 base32 alphabet, lowercased so names survive case-insensitive servers.
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Deterministic (SIV-style) encryption of individual path components.
+///
+/// The nonce is an HMAC of the plaintext name, so the same name always maps to
+/// the same ciphertext and lookups by name keep working on the server.
+pub struct NameCipher {
+    aead: LessSafeKey,
+    siv: hmac::Key,
+    /// Plaintext paths that were listed under an unencrypted name (mixed mode).
+    raw_paths: Mutex<HashSet<String>>,
+}
+
+impl NameCipher {
+    /// Derives the encryption and SIV keys from arbitrary key material.
+    pub fn new(secret: &[u8]) -> Self {
+        let master = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let enc = hmac::sign(&master, b"remote-fs name encryption");
+        let siv = hmac::sign(&master, b"remote-fs name siv");
+        Self {
+            aead: LessSafeKey::new(
+                UnboundKey::new(&AES_256_GCM, enc.as_ref()).expect("32-byte AES key"),
+            ),
+            siv: hmac::Key::new(hmac::HMAC_SHA256, siv.as_ref()),
+            raw_paths: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Loads key material from a file; the whole file content is the secret.
+    pub fn from_key_file(path: &str) -> Result<Self, anyhow::Error> {
+        let secret = std::fs::read(path)?;
+        if secret.is_empty() {
+            anyhow::bail!("name key file '{}' is empty", path);
+        }
+        Ok(Self::new(&secret))
+    }
+
+    /// Returns true when `name` would exceed the component limit once encrypted.
+    pub fn name_too_long(name: &str) -> bool {
+        ((NONCE_LEN + name.len() + TAG_LEN) * 8).div_ceil(5) > MAX_ENCODED_NAME
+    }
+
+    /// Encrypts one path component.
+    pub fn encrypt_name(&self, name: &str) -> String {
+        let tag = hmac::sign(&self.siv, name.as_bytes());
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.copy_from_slice(&tag.as_ref()[..NONCE_LEN]);
+
+        let mut buf = name.as_bytes().to_vec();
+        self.aead
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut buf)
+            .expect("AES-GCM seal cannot fail for short names");
+
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&buf);
+        base32_encode(&out)
+    }
+
+    /// Decrypts one path component, or returns `None` if it is not ours.
+    pub fn decrypt_name(&self, encoded: &str) -> Option<String> {
+        let raw = base32_decode(encoded)?;
+        if raw.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce_bytes, sealed) = raw.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce_bytes).ok()?;
+        let mut buf = sealed.to_vec();
+        let plain = self
+            .aead
+            .open_in_place(nonce, Aad::empty(), &mut buf)
+            .ok()?;
+        let name = String::from_utf8(plain.to_vec()).ok()?;
+
+        // The nonce must be the SIV of the plaintext, otherwise the name was forged.
+        let tag = hmac::sign(&self.siv, name.as_bytes());
+        (tag.as_ref()[..NONCE_LEN] == *nonce_bytes).then_some(name)
+    }
+
+    /// Maps a listed name to what the mount shows, remembering unencrypted ones.
+    pub fn reveal(&self, parent: &str, encoded: &str) -> String {
+        match self.decrypt_name(encoded) {
+            Some(name) => name,
+            None => {
+                let full = if parent.is_empty() {
+                    encoded.to_string()
+                } else {
+                    format!("{}/{}", parent, encoded)
+                };
+                self.raw_paths.lock().unwrap().insert(full);
+                encoded.to_string()
+            }
+        }
+    }
+
+    /// Encrypts every component of a normalized path, except those known to be plaintext.
+    pub fn encrypt_path(&self, path: &str) -> String {
+        if path.is_empty() {
+            return String::new();
+        }
+        let raw_paths = self.raw_paths.lock().unwrap();
+        let mut plain_prefix = String::new();
+        let mut out = Vec::new();
+        for seg in path.split('/') {
+            if !plain_prefix.is_empty() {
+                plain_prefix.push('/');
+            }
+            plain_prefix.push_str(seg);
+            if raw_paths.contains(&plain_prefix) {
+                out.push(seg.to_string());
+            } else {
+                out.push(self.encrypt_name(seg));
+            }
+        }
+        out.join("/")
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_lowercase())? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip_deterministically() {
+        let cipher = NameCipher::new(b"secret");
+        for name in ["a", "report.txt", "Ünïcödé 名前", "with space"] {
+            let encoded = cipher.encrypt_name(name);
+            assert_ne!(encoded, name);
+            assert_eq!(cipher.encrypt_name(name), encoded);
+            assert!(encoded.bytes().all(|b| BASE32.contains(&b)));
+            assert_eq!(cipher.decrypt_name(&encoded).as_deref(), Some(name));
+            // Case-insensitive servers may hand the name back in capitals.
+            assert_eq!(
+                cipher.decrypt_name(&encoded.to_uppercase()).as_deref(),
+                Some(name)
+            );
+        }
+        let path = cipher.encrypt_path("dir/sub/file");
+        let parts: Vec<_> = path.split('/').collect();
+        assert_eq!(
+            parts,
+            [
+                cipher.encrypt_name("dir"),
+                cipher.encrypt_name("sub"),
+                cipher.encrypt_name("file")
+            ]
+        );
+    }
+
+    #[test]
+    fn other_keys_and_forgeries_do_not_decrypt() {
+        let cipher = NameCipher::new(b"secret");
+        let encoded = cipher.encrypt_name("file");
+        assert_eq!(NameCipher::new(b"other").decrypt_name(&encoded), None);
+        assert_eq!(cipher.decrypt_name("plain-name.txt"), None);
+        assert_eq!(cipher.decrypt_name("abc"), None);
+        // Flip a bit in the middle: the last character may hold only padding.
+        let mut tampered = encoded.into_bytes();
+        let mid = tampered.len() / 2;
+        tampered[mid] = if tampered[mid] == b'a' { b'b' } else { b'a' };
+        assert_eq!(
+            cipher.decrypt_name(std::str::from_utf8(&tampered).unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn longest_name_still_fits_a_component() {
+        let cipher = NameCipher::new(b"secret");
+        let longest = (1..)
+            .take_while(|&n| !NameCipher::name_too_long(&"x".repeat(n)))
+            .last()
+            .unwrap();
+        assert!(cipher.encrypt_name(&"x".repeat(longest)).len() <= MAX_ENCODED_NAME);
+        assert!(cipher.encrypt_name(&"x".repeat(longest + 1)).len() > MAX_ENCODED_NAME);
+        assert!(NameCipher::name_too_long(&"x".repeat(longest + 1)));
+    }
+
+    #[test]
+    fn listed_plaintext_names_stay_plaintext_in_paths() {
+        let cipher = NameCipher::new(b"secret");
+        let encrypted = cipher.encrypt_name("new");
+        // A directory created before encryption was turned on.
+        assert_eq!(cipher.reveal("", "legacy"), "legacy");
+        assert_eq!(cipher.reveal("legacy", &encrypted), "new");
+        assert_eq!(
+            cipher.encrypt_path("legacy/new"),
+            format!("legacy/{}", encrypted)
+        );
+        assert_eq!(cipher.encrypt_path("legacy"), "legacy");
+        // Only the listed path is known raw, not the same name elsewhere.
+        assert_eq!(
+            cipher.encrypt_path("other/legacy"),
+            format!(
+                "{}/{}",
+                cipher.encrypt_name("other"),
+                cipher.encrypt_name("legacy")
+            )
+        );
+    }
+}
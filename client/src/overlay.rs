@@ -0,0 +1,536 @@
+use crate::logging;
+use crate::remote_client::RemoteClient;
+use crate::types::{parent_of, EntryKind, RemoteEntry};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the reconciler retries pushing pending overlay files.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(5);
+/// How long unmount waits for pending uploads before giving up.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a writer blocked on a full overlay checks for free room.
+const BACKPRESSURE_POLL: Duration = Duration::from_millis(500);
+
+/// Local directory holding edits that could not reach the server yet.
+///
+/// Every pending file is recorded in a journal next to its data, so edits made
+/// while offline survive a client restart and are pushed once the server is back.
+pub struct Overlay {
+    root: PathBuf,
+    pending: Mutex<BTreeMap<String, Pending>>,
+    /// Last generation handed out by `store`.
+    generation: AtomicU64,
+    conflicts: AtomicU64,
+    /// Limits on queued bytes and files; 0 means unbounded.
+    max_bytes: u64,
+    max_files: usize,
+}
+
+/// A pending file.
+struct Pending {
+    /// ETag of the server copy the edits started from, if it had one.
+    base: Option<String>,
+    /// Bumped by every `store`, so an upload only retires the edit it read.
+    generation: u64,
+}
+
+impl Overlay {
+    /// Opens (or creates) the overlay at `root`, replaying its journal.
+    pub fn open(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join("data"))?;
+        // Content of stores cut off by a crash; never journaled.
+        let _ = std::fs::remove_dir_all(root.join("incoming"));
+        std::fs::create_dir_all(root.join("incoming"))?;
+        let mut pending = BTreeMap::new();
+        if let Ok(journal) = std::fs::read_to_string(root.join("journal")) {
+            for line in journal.lines() {
+                let (path, base) = line.split_once('\t').unwrap_or((line, ""));
+                if root.join("data").join(path).is_file() {
+                    let base = Some(base.to_string()).filter(|b| !b.is_empty());
+                    let generation = pending.len() as u64 + 1;
+                    pending.insert(path.to_string(), Pending { base, generation });
+                }
+            }
+        }
+        Ok(Self {
+            root,
+            generation: AtomicU64::new(pending.len() as u64),
+            pending: Mutex::new(pending),
+            conflicts: AtomicU64::new(0),
+            max_bytes: 0,
+            max_files: 0,
+        })
+    }
+
+    /// Bounds the pending queue; `store` blocks while it is full.
+    pub fn set_limits(&mut self, max_bytes: u64, max_files: usize) {
+        self.max_bytes = max_bytes;
+        self.max_files = max_files;
+    }
+
+    fn data_path(&self, path: &str) -> PathBuf {
+        self.root.join("data").join(path)
+    }
+
+    /// Rewrites the journal atomically from the in-memory pending set.
+    fn persist(&self, pending: &BTreeMap<String, Pending>) -> std::io::Result<()> {
+        let tmp = self.root.join("journal.tmp");
+        let mut f = File::create(&tmp)?;
+        for (path, entry) in pending {
+            match &entry.base {
+                Some(etag) => writeln!(f, "{}\t{}", path, etag)?,
+                None => writeln!(f, "{}", path)?,
+            }
+        }
+        f.sync_all()?;
+        std::fs::rename(tmp, self.root.join("journal"))
+    }
+
+    /// Stores the full content of `path` locally and marks it pending.
+    ///
+    /// The content is written aside and renamed into place, so a file already
+    /// opened for an upload keeps the edit it was opened at.
+    ///
+    /// Blocks while the queue is over its limits, until the reconciler drains it.
+    /// On Unix the caller is the FUSE session thread, so the whole mount waits.
+    pub fn store(
+        &self,
+        path: &str,
+        mut data: impl Read,
+        size: u64,
+        base: Option<String>,
+    ) -> std::io::Result<()> {
+        self.wait_for_room(path, size);
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let incoming = self.root.join("incoming").join(generation.to_string());
+        let mut f = File::create(&incoming)?;
+        std::io::copy(&mut data, &mut f)?;
+        f.sync_all()?;
+
+        let target = self.data_path(path);
+        if let Some(dir) = target.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut pending = self.pending.lock().unwrap();
+        std::fs::rename(&incoming, &target)?;
+        // Keep the base of the first offline edit: it is what the server had before.
+        let base = match pending.remove(path) {
+            Some(earlier) => earlier.base,
+            None => base,
+        };
+        pending.insert(path.to_string(), Pending { base, generation });
+        self.persist(&pending)
+    }
+
+    /// Drops a pending entry after it reached the server (or was deleted).
+    pub fn remove(&self, path: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        self.remove_locked(&mut pending, path);
+    }
+
+    /// Drops `path` after the edit at `generation` reached the server; a
+    /// newer edit stays pending.
+    pub fn remove_generation(&self, path: &str, generation: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if at_generation(&pending, path, generation) {
+            self.remove_locked(&mut pending, path);
+        }
+    }
+
+    fn remove_locked(&self, pending: &mut BTreeMap<String, Pending>, path: &str) {
+        if pending.remove(path).is_some() {
+            let _ = std::fs::remove_file(self.data_path(path));
+            let _ = self.persist(pending);
+        }
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.pending.lock().unwrap().contains_key(path)
+    }
+
+    /// True while the pending edit of `path` is still the one at `generation`.
+    pub fn is_generation(&self, path: &str, generation: u64) -> bool {
+        at_generation(&self.pending.lock().unwrap(), path, generation)
+    }
+
+    /// Opens the local copy of `path` if its pending edit is still the one at
+    /// `generation`. Checked under the lock `store` renames under, so the file
+    /// is that edit's content.
+    fn open_generation(&self, path: &str, generation: u64) -> Option<File> {
+        let pending = self.pending.lock().unwrap();
+        if !at_generation(&pending, path, generation) {
+            return None;
+        }
+        File::open(self.data_path(path)).ok()
+    }
+
+    /// Opens the local copy of a pending file for reading.
+    pub fn open_file(&self, path: &str) -> Option<File> {
+        if !self.contains(path) {
+            return None;
+        }
+        File::open(self.data_path(path)).ok()
+    }
+
+    /// Reads up to `size` bytes at `offset` from a pending file.
+    pub fn read_range(&self, path: &str, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let mut f = self.open_file(path)?;
+        f.seek(SeekFrom::Start(offset)).ok()?;
+        let mut data = Vec::with_capacity(size as usize);
+        f.take(size as u64).read_to_end(&mut data).ok()?;
+        Some(data)
+    }
+
+    /// Applies pending files below `dir` to a listing, adding overlay-only entries.
+    pub fn merge_listing(&self, dir: &str, entries: &mut Vec<RemoteEntry>) {
+        let pending = self.pending.lock().unwrap();
+        for path in pending.keys() {
+            let rest = if dir.is_empty() {
+                path.as_str()
+            } else {
+                match path.strip_prefix(dir).and_then(|r| r.strip_prefix('/')) {
+                    Some(rest) => rest,
+                    None => continue,
+                }
+            };
+            let (name, is_dir) = match rest.split_once('/') {
+                Some((first, _)) => (first, true),
+                None => (rest, false),
+            };
+            let size = if is_dir { 0 } else { self.data_len(path) };
+            match entries.iter_mut().find(|e| e.name == name) {
+                Some(entry) if !is_dir => entry.size = size,
+                Some(_) => {}
+                None => entries.push(RemoteEntry {
+                    name: name.to_string(),
+                    kind: if is_dir {
+                        EntryKind::Dir
+                    } else {
+                        EntryKind::File
+                    },
+                    size,
+                    ..Default::default()
+                }),
+            }
+        }
+    }
+
+    /// Waits until `size` more bytes for `path` fit within the queue limits.
+    fn wait_for_room(&self, path: &str, size: u64) {
+        let mut warned = false;
+        while !self.has_room(path, size) {
+            if !warned {
+                logging::warn(
+                    "overlay",
+                    format!(
+                        "Offline overlay full ({} files, {}MB pending), waiting to queue {}",
+                        self.pending_count(),
+                        self.pending_bytes() / 1024 / 1024,
+                        path
+                    ),
+                )
+                .path(path)
+                .emit();
+                warned = true;
+            }
+            std::thread::sleep(BACKPRESSURE_POLL);
+        }
+    }
+
+    fn has_room(&self, path: &str, size: u64) -> bool {
+        let pending = self.pending.lock().unwrap();
+        // A replaced entry frees its own slot and bytes.
+        let (files, bytes) = pending
+            .keys()
+            .filter(|p| p.as_str() != path)
+            .fold((0, 0), |(files, bytes), p| {
+                (files + 1, bytes + self.data_len(p))
+            });
+        // An empty queue always accepts one file, however large.
+        files == 0
+            || ((self.max_files == 0 || files < self.max_files)
+                && (self.max_bytes == 0 || bytes + size <= self.max_bytes))
+    }
+
+    fn data_len(&self, path: &str) -> u64 {
+        std::fs::metadata(self.data_path(path))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Total size of the files waiting to be uploaded.
+    pub fn pending_bytes(&self) -> u64 {
+        let pending = self.pending.lock().unwrap();
+        pending.keys().map(|p| self.data_len(p)).sum()
+    }
+
+    pub fn conflict_count(&self) -> u64 {
+        self.conflicts.load(Ordering::SeqCst)
+    }
+
+    fn pending_snapshot(&self) -> Vec<(String, Option<String>, u64)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, p)| (path.clone(), p.base.clone(), p.generation))
+            .collect()
+    }
+}
+
+fn at_generation(pending: &BTreeMap<String, Pending>, path: &str, generation: u64) -> bool {
+    pending
+        .get(path)
+        .is_some_and(|p| p.generation == generation)
+}
+
+/// Background thread pushing overlay entries to the server when it is reachable.
+pub struct Reconciler {
+    stop: Arc<AtomicBool>,
+}
+
+impl Reconciler {
+    pub fn spawn(rc: RemoteClient, overlay: Arc<Overlay>) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let mut rc = rc;
+            let mut last_report = (0, 0, 0);
+            while !flag.load(Ordering::SeqCst) {
+                reconcile_once(&mut rc, &overlay);
+                let report = (
+                    overlay.pending_count(),
+                    overlay.pending_bytes(),
+                    overlay.conflict_count(),
+                );
+                if report != last_report {
+                    logging::info(
+                        "overlay",
+                        format!(
+                            "Offline overlay: {} pending ({}KB), {} conflicts",
+                            report.0,
+                            report.1 / 1024,
+                            report.2
+                        ),
+                    )
+                    .emit();
+                    last_report = report;
+                }
+                std::thread::sleep(RECONCILE_INTERVAL);
+            }
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for Reconciler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Pushes pending files until the overlay is empty or `timeout` expires.
+///
+/// Called on unmount; whatever is left stays in the journal for the next mount.
+pub fn drain(rc: &mut RemoteClient, overlay: &Overlay, timeout: Duration) {
+    if overlay.pending_count() == 0 {
+        return;
+    }
+    logging::info(
+        "drain",
+        format!(
+            "Uploading {} pending files ({}KB) before exit...",
+            overlay.pending_count(),
+            overlay.pending_bytes() / 1024
+        ),
+    )
+    .emit();
+    let deadline = Instant::now() + timeout;
+    loop {
+        reconcile_once(rc, overlay);
+        let now = Instant::now();
+        if overlay.pending_count() == 0 || now >= deadline {
+            break;
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_secs(1)));
+    }
+    let left = overlay.pending_snapshot();
+    if left.is_empty() {
+        logging::info("drain", "All pending uploads reached the server").emit();
+        return;
+    }
+    logging::warn(
+        "drain",
+        format!(
+            "Timed out after {}s; not uploaded (kept in {} for the next mount):",
+            timeout.as_secs(),
+            overlay.root.display()
+        ),
+    )
+    .duration(timeout)
+    .emit();
+    for (path, _, _) in left {
+        logging::warn(
+            "drain",
+            format!("  {} ({} bytes)", path, overlay.data_len(&path)),
+        )
+        .path(&path)
+        .emit();
+    }
+}
+
+/// Tries to upload every pending file once; `upload_pending` clears reconciled entries.
+///
+/// If the server copy's ETag changed since the edit was made, the server copy
+/// is kept and the local version is uploaded next to it as `<name>.conflict`.
+fn reconcile_once(rc: &mut RemoteClient, overlay: &Overlay) {
+    for (path, base, generation) in overlay.pending_snapshot() {
+        rc.invalidate(&path);
+        let remote = match rc.fetch_listing(&parent_of(&path)) {
+            Ok(entries) => {
+                let name = path.rsplit('/').next().unwrap_or(&path);
+                entries.into_iter().find(|e| e.name == name && !e.is_dir())
+            }
+            // The parent only exists in the overlay so far; PUT creates it.
+            Err(e) if e.is_status() => None,
+            // Still offline: try again on the next round.
+            Err(_) => return,
+        };
+
+        // Gone or edited again since the snapshot: a newer round handles it.
+        let Some(file) = overlay.open_generation(&path, generation) else {
+            continue;
+        };
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+
+        let changed_remotely = match (base, remote) {
+            (Some(base), Some(entry)) => entry.etag.as_ref() != Some(&base),
+            _ => false,
+        };
+        let target = if changed_remotely {
+            format!("{}.conflict", path)
+        } else {
+            path.clone()
+        };
+
+        if rc
+            .upload_pending(&path, &target, file, size, generation)
+            .is_ok()
+        {
+            if changed_remotely {
+                overlay.conflicts.fetch_add(1, Ordering::SeqCst);
+                logging::warn(
+                    "reconcile",
+                    format!("Conflict on {}: local edits saved as {}", path, target),
+                )
+                .path(&path)
+                .emit();
+            }
+            rc.invalidate(&target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use crate::types::CacheConfig;
+    use std::sync::mpsc;
+
+    /// A client of `url` writing offline into `overlay`.
+    fn client(url: &str, overlay: &Arc<Overlay>) -> RemoteClient {
+        let mut rc = RemoteClient::new(url, CacheConfig::default());
+        rc.set_overlay(Arc::clone(overlay));
+        rc
+    }
+
+    fn accepting_server() -> TestServer {
+        TestServer::start(|r| match r.method.as_str() {
+            "PUT" => Response::json("{}"),
+            _ => Response::json("[]"),
+        })
+    }
+
+    #[test]
+    fn drain_uploads_the_latest_edit_of_each_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let overlay = Arc::new(Overlay::open(dir.path()).unwrap());
+        overlay.store("docs/b", &b"first"[..], 5, None).unwrap();
+        overlay.store("docs/a", &b"A"[..], 1, None).unwrap();
+        overlay.store("docs/b", &b"second"[..], 6, None).unwrap();
+        assert_eq!(overlay.read_range("docs/b", 0, 64).unwrap(), b"second");
+
+        let server = accepting_server();
+        drain(&mut client(server.url(), &overlay), &overlay, DRAIN_TIMEOUT);
+        assert_eq!(overlay.pending_count(), 0);
+        let puts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "PUT")
+            .map(|r| (r.path, r.body))
+            .collect();
+        assert_eq!(
+            puts,
+            [
+                ("/files/docs/a".to_string(), b"A".to_vec()),
+                ("/files/docs/b".to_string(), b"second".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn store_blocks_while_the_queue_is_full() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut overlay = Overlay::open(dir.path()).unwrap();
+        overlay.set_limits(10, 0);
+        overlay.store("docs/a", &[0u8; 8][..], 8, None).unwrap();
+        let (done, finished) = mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                overlay.store("docs/b", &[0u8; 8][..], 8, None).unwrap();
+                done.send(()).unwrap();
+            });
+            assert!(finished.recv_timeout(Duration::from_millis(300)).is_err());
+            // As if the reconciler had uploaded it.
+            overlay.remove("docs/a");
+            assert!(finished.recv_timeout(BACKPRESSURE_POLL * 4).is_ok());
+        });
+        assert!(overlay.contains("docs/b"));
+        // Replacing a queued file needs no extra room.
+        overlay.store("docs/b", &[1u8; 9][..], 9, None).unwrap();
+    }
+
+    #[test]
+    fn drain_timeout_keeps_the_rest_for_the_next_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let overlay = Arc::new(Overlay::open(dir.path()).unwrap());
+        overlay.store("docs/a", &b"offline"[..], 7, None).unwrap();
+        // Nothing listens on port 1: the server stays unreachable.
+        let mut rc = client("http://127.0.0.1:1", &overlay);
+        drain(&mut rc, &overlay, Duration::ZERO);
+        assert!(overlay.contains("docs/a"));
+        drop((rc, overlay));
+
+        let reopened = Arc::new(Overlay::open(dir.path()).unwrap());
+        assert_eq!(reopened.read_range("docs/a", 0, 64).unwrap(), b"offline");
+        let server = accepting_server();
+        drain(
+            &mut client(server.url(), &reopened),
+            &reopened,
+            DRAIN_TIMEOUT,
+        );
+        assert_eq!(reopened.pending_count(), 0);
+        assert_eq!(server.count("PUT", "/files/docs/a"), 1);
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{IsTerminal, Read, Write};
+use std::sync::{Arc, Mutex};
+
+/// Receives transfer progress notifications from `RemoteClient`.
+///
+/// Embedding applications implement this to drive their own progress UI.
+pub trait Progress: Send + Sync {
+    /// Called once before the first byte of a transfer is moved.
+    fn start(&self, name: &str, total: u64);
+    /// Called after each chunk with the cumulative number of bytes moved.
+    fn update(&self, name: &str, done: u64, total: u64);
+    /// Called once when the transfer ends, successfully or not.
+    fn finish(&self, name: &str);
+}
+
+/// Progress sink that discards every notification (used by `--quiet`).
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn start(&self, _name: &str, _total: u64) {}
+    fn update(&self, _name: &str, _done: u64, _total: u64) {}
+    fn finish(&self, _name: &str) {}
+}
+
+/// Returns the default progress sink: console bars on a TTY, silence otherwise.
+pub fn default_progress(quiet: bool) -> Arc<dyn Progress> {
+    if quiet || !std::io::stderr().is_terminal() {
+        Arc::new(NoProgress)
+    } else {
+        Arc::new(ConsoleProgress::default())
+    }
+}
+
+/// One in-flight transfer drawn by `ConsoleProgress`.
+struct Bar {
+    name: String,
+    pct: u64,
+    done: u64,
+    total: u64,
+}
+
+/// Renders named progress bars on stderr, one line per active transfer.
+#[derive(Default)]
+pub struct ConsoleProgress {
+    bars: Mutex<Vec<Bar>>,
+    drawn: Mutex<usize>,
+}
+
+impl ConsoleProgress {
+    fn render(&self, bars: &[Bar]) {
+        let mut drawn = self.drawn.lock().unwrap();
+        let mut err = std::io::stderr().lock();
+        if *drawn > 1 {
+            let _ = write!(err, "\x1b[{}A", *drawn - 1);
+        }
+        for (i, bar) in bars.iter().enumerate() {
+            let filled = (bar.pct as usize * 30) / 100;
+            let _ = write!(
+                err,
+                "\r\x1b[K  {} [{}>{} ] {}% ({}/{}MB)",
+                bar.name,
+                "=".repeat(filled),
+                " ".repeat(30 - filled),
+                bar.pct,
+                bar.done / (1024 * 1024),
+                bar.total / (1024 * 1024),
+            );
+            if i + 1 < bars.len() {
+                let _ = writeln!(err);
+            }
+        }
+        let _ = err.flush();
+        *drawn = bars.len();
+    }
+}
+
+impl Progress for ConsoleProgress {
+    fn start(&self, name: &str, total: u64) {
+        let mut bars = self.bars.lock().unwrap();
+        bars.push(Bar {
+            name: name.to_string(),
+            pct: 0,
+            done: 0,
+            total,
+        });
+        if bars.len() > 1 {
+            eprintln!();
+            *self.drawn.lock().unwrap() += 1;
+        }
+        self.render(&bars);
+    }
+
+    fn update(&self, name: &str, done: u64, total: u64) {
+        let mut bars = self.bars.lock().unwrap();
+        let pct = (done * 100).checked_div(total).map_or(100, |p| p.min(100));
+        let changed = match bars.iter_mut().find(|b| b.name == name) {
+            Some(bar) if bar.pct != pct => {
+                bar.pct = pct;
+                bar.done = done;
+                bar.total = total;
+                true
+            }
+            _ => false,
+        };
+        if changed {
+            self.render(&bars);
+        }
+    }
+
+    fn finish(&self, name: &str) {
+        let mut bars = self.bars.lock().unwrap();
+        let Some(pos) = bars.iter().position(|b| b.name == name) else {
+            return;
+        };
+        let bar = bars.remove(pos);
+        let mut drawn = self.drawn.lock().unwrap();
+        let mut err = std::io::stderr().lock();
+        if *drawn > 1 {
+            let _ = write!(err, "\x1b[{}A", *drawn - 1);
+        }
+        // Print the finished bar on its own line, then redraw the remaining ones below it.
+        let _ = writeln!(
+            err,
+            "\r\x1b[K  {} done ({}MB)",
+            bar.name,
+            bar.done.max(bar.total) / (1024 * 1024)
+        );
+        let _ = err.flush();
+        *drawn = 0;
+        drop(err);
+        drop(drawn);
+        if !bars.is_empty() {
+            self.render(&bars);
+        }
+    }
+}
+
+/// Reader wrapper that reports bytes read to a `Progress` sink while streaming.
+pub struct ProgressReader<R: Read> {
+    inner: R,
+    progress: Arc<dyn Progress>,
+    name: String,
+    total: u64,
+    done: u64,
+    finished: bool,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Arc<dyn Progress>, name: &str, total: u64) -> Self {
+        progress.start(name, total);
+        Self {
+            inner,
+            progress,
+            name: name.to_string(),
+            total,
+            done: 0,
+            finished: false,
+        }
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.progress.finish(&self.name);
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        self.progress.update(&self.name, self.done, self.total);
+        if n == 0 || (self.total > 0 && self.done >= self.total) {
+            self.finish();
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Read> Drop for ProgressReader<R> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
@@ -0,0 +1,68 @@
+//! Client-side write protection for the paths given with `--protect`.
+
+use crate::glob::{self, Token};
+use crate::types::normalize_path;
+
+/// Glob patterns whose matches, and everything below them, refuse changes.
+///
+/// Patterns use the mount's path form (`etc/*.conf`, no leading `/` needed):
+/// `*` and `?` stay within one path segment, `**` spans any number of them.
+#[derive(Clone, Debug, Default)]
+pub struct Protected {
+    patterns: Vec<Vec<Token>>,
+}
+
+impl Protected {
+    pub fn new(patterns: &[String]) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .map(|p| parse(&fold_case(&normalize_path(p))))
+                .filter(|p| !p.is_empty())
+                .collect(),
+        }
+    }
+
+    /// True when `path` or one of its ancestors matches a pattern.
+    pub fn covers(&self, path: &str) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+        let path = fold_case(&normalize_path(path));
+        let mut ends = (0..path.len())
+            .filter(|&i| path[i] == '/')
+            .chain(std::iter::once(path.len()));
+        ends.any(|end| {
+            self.patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern, &path[..end]))
+        })
+    }
+}
+
+/// Windows mounts resolve names case-insensitively, so patterns match that way too.
+fn fold_case(s: &str) -> Vec<char> {
+    if cfg!(windows) {
+        s.chars().flat_map(char::to_lowercase).collect()
+    } else {
+        s.chars().collect()
+    }
+}
+
+/// Parses a folded pattern into tokens for the shared matcher.
+fn parse(mut pattern: &[char]) -> Vec<Token> {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    while let Some(&c) = pattern.first() {
+        let (token, len) = match pattern {
+            // `**/` also matches no directory at all.
+            ['*', '*', '/', ..] => (Token::Dirs, 3),
+            ['*', '*', ..] => (Token::Run { stop: None }, 2),
+            ['*', ..] => (Token::Run { stop: Some('/') }, 1),
+            ['?', ..] => (Token::One { stop: Some('/') }, 1),
+            _ => (Token::Char(c), 1),
+        };
+        tokens.push(token);
+        pattern = &pattern[len..];
+    }
+    tokens
+}
@@ -0,0 +1,122 @@
+//! Lossless handling of file names whose bytes are not valid UTF-8.
+//!
+//! Paths are `String`s throughout the client. A byte that is not part of a
+//! valid UTF-8 sequence is carried as the private-use character
+//! `U+10FF00 + byte`, which is also how the server spells such names in its
+//! listings. The raw byte comes back at the edges: in names handed to the
+//! kernel and in the URLs sent to the server. A name that really contains
+//! one of those characters carries its UTF-8 bytes escaped the same way, so
+//! it comes back unchanged too.
+
+/// Escaped bytes are always >= 0x80, so they land in U+10FF80..=U+10FFFF.
+const ESCAPE_BASE: u32 = 0x10_FF00;
+
+fn escape(byte: u8) -> char {
+    char::from_u32(ESCAPE_BASE + byte as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+fn unescape(c: char) -> Option<u8> {
+    (c as u32)
+        .checked_sub(ESCAPE_BASE)
+        .filter(|b| (0x80..=0xFF).contains(b))
+        .map(|b| b as u8)
+}
+
+/// Appends `valid`, escaping the bytes of any character `unescape` would
+/// otherwise take for an escaped byte.
+fn push_valid(out: &mut String, valid: &str) {
+    for c in valid.chars() {
+        match unescape(c) {
+            Some(_) => out.extend(c.encode_utf8(&mut [0; 4]).bytes().map(escape)),
+            None => out.push(c),
+        }
+    }
+}
+
+/// Decodes `bytes` as UTF-8, escaping every byte that does not decode.
+pub fn from_bytes(mut bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        match std::str::from_utf8(bytes) {
+            Ok(valid) => {
+                push_valid(&mut out, valid);
+                return out;
+            }
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                push_valid(&mut out, std::str::from_utf8(valid).unwrap_or_default());
+                let bad = e.error_len().unwrap_or(rest.len());
+                out.extend(rest[..bad].iter().map(|&b| escape(b)));
+                bytes = &rest[bad..];
+            }
+        }
+    }
+}
+
+/// The original bytes of a name produced by [`from_bytes`].
+pub fn to_bytes(name: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(name.len());
+    for c in name.chars() {
+        match unescape(c) {
+            Some(byte) => out.push(byte),
+            None => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    out
+}
+
+/// Percent-encodes a `/`-separated path for a URL, sending escaped bytes raw.
+pub fn url_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in to_bytes(path) {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+#[cfg(unix)]
+pub fn from_os(name: &std::ffi::OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    from_bytes(name.as_bytes())
+}
+
+#[cfg(unix)]
+pub fn to_os(name: &str) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStringExt;
+    std::ffi::OsString::from_vec(to_bytes(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_utf8_round_trips() {
+        let bytes = b"caf\xe9 \xff\xfe.txt";
+        let name = from_bytes(bytes);
+        assert_eq!(name, "caf\u{10FFE9} \u{10FFFF}\u{10FFFE}.txt");
+        assert_eq!(to_bytes(&name), bytes);
+        assert_eq!(url_path(&name), "caf%E9%20%FF%FE.txt");
+    }
+
+    #[test]
+    fn existing_escape_characters_round_trip() {
+        for original in ["a\u{10FF80}b", "\u{10FFFF}", "\u{10FF41}x"] {
+            let name = from_bytes(original.as_bytes());
+            assert_eq!(to_bytes(&name), original.as_bytes());
+        }
+        assert_eq!(from_bytes("\u{10FF41}".as_bytes()), "\u{10FF41}");
+    }
+
+    #[test]
+    fn ascii_is_unchanged() {
+        let name = from_bytes(b"notes/readme.txt");
+        assert_eq!(name, "notes/readme.txt");
+        assert_eq!(to_bytes(&name), b"notes/readme.txt");
+        assert_eq!(url_path(&name), "notes/readme.txt");
+    }
+}
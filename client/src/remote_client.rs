@@ -1,8 +1,31 @@
-use crate::types::{parent_of, CacheConfig, RemoteEntry};
-use reqwest::blocking::Client;
-use std::collections::HashMap;
-use std::io::Read;
-use std::time::Instant;
+use crate::breaker::CircuitBreaker;
+use crate::content_type;
+use crate::error::{CheckStatus, RemoteError};
+use crate::hooks::{EventHook, ListingHook, TransferEvent};
+use crate::inflight::{Inflight, Traffic};
+use crate::logging;
+use crate::name_cipher::NameCipher;
+use crate::overlay::Overlay;
+use crate::progress::{NoProgress, Progress, ProgressReader};
+use crate::protect::Protected;
+use crate::raw_names;
+use crate::singleflight::SingleFlight;
+use crate::types::{
+    join_path, normalize_path, parent_of, AuditRecord, CacheConfig, Capabilities, QuotaInfo,
+    RemoteEntry, SearchHit, SnapshotInfo, TrashEntry, UploadReceipt,
+};
+use crate::uploads::{Upload, Uploads};
+use clap::ValueEnum;
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, ACCEPT, CONTENT_RANGE, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::{Method, StatusCode};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Cached directory listing with insertion timestamp.
 struct CachedDir {
@@ -12,76 +35,728 @@ struct CachedDir {
 
 /// Cached file payload with insertion timestamp.
 struct CachedFile {
-    data: Vec<u8>,
+    data: Arc<Vec<u8>>,
     cached_at: Instant,
 }
 
-#[allow(dead_code)]
-/// Reader wrapper used to print upload progress while streaming.
-pub struct ProgressReader<R: Read> {
-    pub inner: R,
-    pub total: u64,
-    pub sent: u64,
-    pub name: String,
-    pub last_pct: u64,
+/// Directory and file caches, shared by every clone of a `RemoteClient`.
+///
+/// One mutex guards both maps and the byte count, and files are only added
+/// or removed through the methods below, so `file_cache_size` always equals
+/// the bytes held, however many threads share the caches.
+#[derive(Default)]
+struct Caches {
+    dir_cache: HashMap<String, CachedDir>,
+    file_cache: HashMap<String, CachedFile>,
+    file_cache_size: usize,
+    /// Last `/quota` answer for the mount, with when it was fetched.
+    quota: Option<(Instant, QuotaInfo)>,
+    /// What the server stored on the last upload of each path, for `unchanged_since_upload`.
+    receipts: HashMap<String, UploadReceipt>,
+    /// Flushes that found the content already on the server and sent nothing.
+    uploads_skipped: u64,
+    /// Listings being refreshed in the background; see `serve_stale`.
+    revalidating: HashSet<String>,
+}
+
+/// In-flight listing and download requests, shared by every clone of a `RemoteClient`.
+struct Flights {
+    listings: SingleFlight<Vec<RemoteEntry>>,
+    files: SingleFlight<Arc<Vec<u8>>>,
+}
+
+impl Caches {
+    /// Drops the oldest listings until one more fits under `max` (0 = unlimited).
+    fn make_room_for_dir(&mut self, max: usize) {
+        while max != 0 && self.dir_cache.len() >= max {
+            let oldest = self
+                .dir_cache
+                .iter()
+                .min_by_key(|(_, v)| v.cached_at)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(key) => {
+                    self.dir_cache.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Caches `file` under `key`, replacing an older copy and evicting the
+    /// oldest files so the cache stays within its limits.
+    ///
+    /// A file larger than the whole budget (any file, with a budget of 0) is
+    /// not cached: evicting everything else for it would only empty the cache.
+    fn insert_file(&mut self, key: String, file: CachedFile, max_bytes: usize, max_entries: usize) {
+        self.remove_file(&key);
+        if file.data.len() > max_bytes {
+            return;
+        }
+        self.make_room_for_file(file.data.len(), max_bytes, max_entries);
+        self.file_cache_size += file.data.len();
+        self.file_cache.insert(key, file);
+    }
+
+    fn remove_file(&mut self, key: &str) {
+        if let Some(evicted) = self.file_cache.remove(key) {
+            self.file_cache_size -= evicted.data.len();
+        }
+    }
+
+    /// Drops every cached file whose key starts with `prefix`.
+    fn remove_files_under(&mut self, prefix: &str) {
+        let keys: Vec<String> = self
+            .file_cache
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.remove_file(&key);
+        }
+    }
+
+    /// Drops the oldest files until `size` more bytes and one more entry fit.
+    fn make_room_for_file(&mut self, size: usize, max_bytes: usize, max_entries: usize) {
+        while self.file_cache_size + size > max_bytes
+            || (max_entries != 0 && self.file_cache.len() >= max_entries)
+        {
+            let oldest = self
+                .file_cache
+                .iter()
+                .min_by_key(|(_, v)| v.cached_at)
+                .map(|(k, _)| k.clone());
+            match oldest {
+                Some(key) => self.remove_file(&key),
+                None => break,
+            }
+        }
+    }
+}
+
+/// Number of tries `download_to` and `upload_file` make before giving up on a transfer.
+const TRANSFER_ATTEMPTS: u32 = 3;
+
+/// Wait before the second try of a transfer; later tries wait proportionally
+/// longer, giving a restarting server time to come back.
+const TRANSFER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Header carrying the per-request id that the server echoes in its log.
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+
+/// Media type of MessagePack listings.
+const MSGPACK_TYPE: &str = "application/msgpack";
+
+/// `Accept` header of listing requests: MessagePack preferred, JSON understood.
+const LISTING_ACCEPT: &str = "application/msgpack, application/json;q=0.9";
+
+/// Entries asked for per page of a paginated listing.
+const LIST_PAGE_SIZE: usize = 1000;
+
+/// Response header naming the page after this one; absent on the last page.
+const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+/// Longest path component the mount stores: bytes on Unix, UTF-16 units on
+/// Windows, as local filesystems count them.
+pub const NAME_MAX: usize = 255;
+
+/// Longest mount path the mount stores, in bytes.
+pub const PATH_MAX: usize = 4096;
+
+/// Tries of a mount's first root listing. A cold server or slow DNS gets a
+/// second chance before the mount reports an error, where later listings
+/// fail at once.
+const FIRST_ROOT_ATTEMPTS: u32 = 2;
+
+/// Upload receipts kept before the table is reset.
+const MAX_RECEIPTS: usize = 10_000;
+
+/// Destination for `download_to` that can be rewound when a resume is refused.
+pub trait DownloadSink: Write {
+    /// Discards everything written so far.
+    fn restart(&mut self) -> std::io::Result<()>;
+}
+
+impl DownloadSink for Vec<u8> {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl DownloadSink for std::fs::File {
+    fn restart(&mut self) -> std::io::Result<()> {
+        self.set_len(0)?;
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+/// Checks that a ranged response is a `206` whose `Content-Range` starts at `offset`.
+fn resumes_at(resp: &Response, offset: u64) -> bool {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    resp.headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes "))
+        .and_then(|v| v.split('-').next())
+        .and_then(|start| start.parse::<u64>().ok())
+        == Some(offset)
+}
+
+/// Surfaces the server's reason when it refuses an upload for its size or free space.
+fn log_refused_upload(path: &str, e: &RemoteError) {
+    if matches!(e, RemoteError::TooLarge(_) | RemoteError::NoSpace(_)) {
+        logging::warn("upload", format!("Upload of {} refused: {}", path, e))
+            .path(path)
+            .emit();
+    }
+}
+
+/// Most redirect hops followed for one request.
+const MAX_REDIRECTS: usize = 5;
+
+/// How long establishing a connection may take before the request fails.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Long-lived HTTP session without a total timeout, so large transfers can take their time.
+fn build_http_client(headers: HeaderMap) -> Client {
+    Client::builder()
+        .timeout(None)
+        .connect_timeout(CONNECT_TIMEOUT)
+        .default_headers(headers)
+        .redirect(same_origin_redirects())
+        .build()
+        .expect("failed to build HTTP client")
 }
 
-impl<R: Read> Read for ProgressReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let n = self.inner.read(buf)?;
-        self.sent += n as u64;
-        let pct = if self.total > 0 {
-            self.sent * 100 / self.total
+/// Follows redirects that stay on the scheme, host and port of the original
+/// request. reqwest keeps the bearer token and `Range` on such hops; anything
+/// leaving the server is handed back as the 3xx itself.
+fn same_origin_redirects() -> Policy {
+    Policy::custom(|attempt| {
+        let same_origin = attempt
+            .previous()
+            .first()
+            .is_some_and(|first| first.origin() == attempt.url().origin());
+        if !same_origin || attempt.previous().len() > MAX_REDIRECTS {
+            attempt.stop()
         } else {
-            100
-        };
-        if pct != self.last_pct {
-            self.last_pct = pct;
-            let filled = (pct as usize * 30) / 100;
-            eprint!(
-                "\r\x1b[K  {} [{}>{} ] {}% ({}/{}MB)",
-                self.name,
-                "=".repeat(filled),
-                " ".repeat(30 - filled),
-                pct,
-                self.sent / (1024 * 1024),
-                self.total / (1024 * 1024),
-            );
+            attempt.follow()
         }
-        if n == 0 && self.sent >= self.total {
-            eprintln!(" done");
+    })
+}
+
+/// Hex SHA-256 of the whole of `file`, read from the start; leaves it rewound.
+fn content_digest(file: &mut std::fs::File) -> std::io::Result<String> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
         }
-        Ok(n)
+        context.update(&buf[..n]);
     }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(context
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Returns the last path component, used to label progress bars.
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// What happens to the edits of a file that was deleted on the server while open.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum DeletedWhileOpen {
+    /// Upload anyway, creating the file again (no extra request)
+    #[default]
+    Recreate,
+    /// Fail the flush with ESTALE and keep the edits in the open handle
+    Fail,
+    /// Upload the edits as `<name>.conflict` next to where the file was
+    Conflict,
 }
 
 /// HTTP client and local caches used by both Unix and Windows filesystem backends.
+///
+/// Clones share the HTTP session and caches, so background workers can fill them.
+#[derive(Clone)]
 pub struct RemoteClient {
     client: Client,
     base_url: String,
     pub cache_config: CacheConfig,
-    dir_cache: HashMap<String, CachedDir>,
-    file_cache: HashMap<String, CachedFile>,
-    file_cache_size: usize,
+    caches: Arc<Mutex<Caches>>,
+    flights: Arc<Flights>,
+    progress: Arc<dyn Progress>,
+    event_hook: Option<Arc<dyn EventHook>>,
+    listing_hook: Option<Arc<dyn ListingHook>>,
+    names: Option<Arc<NameCipher>>,
+    overlay: Option<Arc<Overlay>>,
+    use_trash: bool,
+    /// Label uploads with a `Content-Type` guessed from the file extension.
+    content_types: bool,
+    /// Type sent when the extension is not recognised (`--default-content-type`).
+    default_content_type: Option<String>,
+    deleted_while_open: DeletedWhileOpen,
+    /// Paths that refuse changes before any request is sent (`--protect`).
+    protected: Protected,
+    snapshot: Option<String>,
+    /// Bearer token sent with every request, for servers with `AUTH_CONFIG`.
+    token: Option<String>,
+    /// Server directory the mount is rooted at; empty for the storage root.
+    remote_root: String,
+    /// Largest file writes may produce (`--max-file-size`).
+    max_file_size: Option<u64>,
+    inflight: Arc<Inflight>,
+    /// Uploads in flight, so deletes can abort them.
+    uploads: Arc<Uploads>,
+    breaker: Arc<CircuitBreaker>,
+    capabilities: Capabilities,
+    /// Set once the root has been listed; see `FIRST_ROOT_ATTEMPTS`.
+    root_listed: Arc<AtomicBool>,
+}
+
+/// The settings worth logging at mount; the token is never printed.
+impl fmt::Debug for RemoteClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteClient")
+            .field("base_url", &self.base_url)
+            .field("remote_root", &self.remote_root)
+            .field("snapshot", &self.snapshot)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("encrypted_names", &self.names.is_some())
+            .field("offline_writes", &self.overlay.is_some())
+            .field("use_trash", &self.use_trash)
+            .field("content_types", &self.content_types)
+            .field("default_content_type", &self.default_content_type)
+            .field("max_file_size", &self.max_file_size)
+            .field("capabilities", &self.capabilities.names())
+            .field("cache", &self.cache_config)
+            .finish_non_exhaustive()
+    }
 }
 
 impl RemoteClient {
     /// Creates a new remote client with cache policy and long-lived HTTP session.
     pub fn new(base_url: &str, cache_config: CacheConfig) -> Self {
         Self {
-            client: Client::builder()
-                .timeout(None)
-                .build()
-                .expect("failed to build HTTP client"),
+            client: build_http_client(HeaderMap::new()),
             base_url: base_url.to_string(),
             cache_config,
-            dir_cache: HashMap::new(),
-            file_cache: HashMap::new(),
-            file_cache_size: 0,
+            caches: Arc::new(Mutex::new(Caches::default())),
+            flights: Arc::new(Flights {
+                listings: SingleFlight::new(),
+                files: SingleFlight::new(),
+            }),
+            progress: Arc::new(NoProgress),
+            event_hook: None,
+            listing_hook: None,
+            names: None,
+            overlay: None,
+            use_trash: false,
+            content_types: true,
+            default_content_type: None,
+            deleted_while_open: DeletedWhileOpen::Recreate,
+            protected: Protected::default(),
+            snapshot: None,
+            token: None,
+            remote_root: String::new(),
+            max_file_size: None,
+            inflight: Arc::new(Inflight::new(0)),
+            uploads: Arc::new(Uploads::new()),
+            breaker: Arc::new(CircuitBreaker::new(0, Duration::ZERO)),
+            capabilities: Capabilities::default(),
+            root_listed: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    #[allow(dead_code)]
+    /// Replaces the sink that receives upload and download progress.
+    pub fn set_progress(&mut self, progress: Arc<dyn Progress>) {
+        self.progress = progress;
+    }
+
+    /// Installs a callback notified after uploads and deletes made through the mount.
+    pub fn set_event_hook(&mut self, hook: Arc<dyn EventHook>) {
+        self.event_hook = Some(hook);
+    }
+
+    /// Installs a callback told when a background refresh changes a listing.
+    pub fn set_listing_hook(&mut self, hook: Arc<dyn ListingHook>) {
+        self.listing_hook = Some(hook);
+    }
+
+    /// Enables filename encryption for every path sent to the server.
+    pub fn set_name_cipher(&mut self, cipher: Arc<NameCipher>) {
+        self.names = Some(cipher);
+    }
+
+    /// The cipher set by `set_name_cipher`, if names are encrypted.
+    pub fn name_cipher(&self) -> Option<Arc<NameCipher>> {
+        self.names.clone()
+    }
+
+    /// Returns true when `name` cannot be stored: over `NAME_MAX`, or too
+    /// long once encrypted.
+    pub fn name_too_long(&self, name: &str) -> bool {
+        let len = if cfg!(windows) {
+            name.encode_utf16().count()
+        } else {
+            raw_names::to_bytes(name).len()
+        };
+        len > NAME_MAX || (self.names.is_some() && NameCipher::name_too_long(name))
+    }
+
+    /// Returns true when `path` cannot be stored: its last component is
+    /// `name_too_long`, or the whole path is over `PATH_MAX`. Checked before
+    /// any request, so the caller gets `ENAMETOOLONG` instead of whatever the
+    /// server makes of it.
+    pub fn path_too_long(&self, path: &str) -> bool {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.name_too_long(name) || raw_names::to_bytes(path).len() > PATH_MAX
+    }
+
+    /// Sends `headers` (`--user-agent`, `--header`) with every request.
+    ///
+    /// Rebuilds the HTTP session, so call it before cloning the client.
+    pub fn set_default_headers(&mut self, headers: HeaderMap) {
+        self.client = build_http_client(headers);
+    }
+
+    /// Authenticates every request with `token` (`--token-file`).
+    pub fn set_token(&mut self, token: Option<String>) {
+        self.token = token;
+    }
+
+    /// Caps the size files may grow to through the mount (`--max-file-size`).
+    pub fn set_max_file_size(&mut self, max: Option<u64>) {
+        self.max_file_size = max;
+    }
+
+    /// Largest file that can be written: the lower of `--max-file-size` and
+    /// the server's upload limit.
+    pub fn file_size_limit(&self) -> Option<u64> {
+        match (self.max_file_size, self.capabilities.max_upload_bytes) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Fails with `TooLarge` when a file of `size` bytes is over `file_size_limit`,
+    /// so oversized writes stop before anything is transferred.
+    pub fn check_file_size(&self, size: u64) -> Result<(), RemoteError> {
+        match self.file_size_limit() {
+            Some(limit) if size > limit => Err(RemoteError::TooLarge(format!(
+                "{} bytes exceeds the {} byte limit",
+                size, limit
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Makes `remove_entry` move paths to the server trash instead of deleting them.
+    pub fn set_use_trash(&mut self, use_trash: bool) {
+        self.use_trash = use_trash;
+    }
+
+    /// Turns off `Content-Type` labels on uploads (`--no-content-type`).
+    pub fn set_content_types(&mut self, enabled: bool) {
+        self.content_types = enabled;
+    }
+
+    /// Labels uploads with an unknown extension as `mime` instead of sending no type.
+    pub fn set_default_content_type(&mut self, mime: Option<String>) {
+        self.default_content_type = mime;
+    }
+
+    /// Sets what `upload_target` does when an open file vanished from the server.
+    pub fn set_deleted_while_open(&mut self, policy: DeletedWhileOpen) {
+        self.deleted_while_open = policy;
+    }
+
+    /// Picks where the edits of an open file are uploaded on flush.
+    ///
+    /// Unless the policy is `Recreate`, this asks the server whether `path`
+    /// still exists: if not, the flush fails with `PreconditionFailed` (ESTALE)
+    /// or goes to `<path>.conflict`. When the server cannot be reached the
+    /// upload itself decides what happens.
+    pub fn upload_target(&self, path: &str) -> Result<String, RemoteError> {
+        let path = normalize_path(path);
+        if self.deleted_while_open == DeletedWhileOpen::Recreate
+            || self.overlay.as_ref().is_some_and(|o| o.contains(&path))
+        {
+            return Ok(path);
+        }
+        let exists = match self.fetch_listing(&parent_of(&path)) {
+            Ok(entries) => entries
+                .iter()
+                .any(|e| e.name == file_name(&path) && !e.is_dir()),
+            Err(RemoteError::NotFound) => false,
+            Err(_) => return Ok(path),
+        };
+        match self.deleted_while_open {
+            _ if exists => Ok(path),
+            DeletedWhileOpen::Fail => Err(RemoteError::PreconditionFailed),
+            DeletedWhileOpen::Conflict => {
+                let target = format!("{}.conflict", path);
+                logging::warn(
+                    "upload",
+                    format!(
+                        "{} was deleted on the server, edits saved as {}",
+                        path, target
+                    ),
+                )
+                .path(&path)
+                .emit();
+                Ok(target)
+            }
+            DeletedWhileOpen::Recreate => Ok(path),
+        }
+    }
+
+    /// Starts a PUT of `path`, typed by its extension when labels are enabled.
+    ///
+    /// Encrypted names get no label, since the type would reveal the extension.
+    fn put_request(&self, url: &str, path: &str) -> RequestBuilder {
+        let request = self.client.put(url);
+        let labelled = self.content_types && self.names.is_none();
+        let mime = content_type::for_path(path).or(self.default_content_type.as_deref());
+        match mime.filter(|_| labelled) {
+            Some(mime) => request.header(CONTENT_TYPE, mime),
+            None => request,
+        }
+    }
+
+    /// Serves every read from the named server-side snapshot; the mount becomes read-only.
+    pub fn set_snapshot(&mut self, id: String) {
+        self.snapshot = Some(id);
+    }
+
+    pub fn snapshot(&self) -> Option<&str> {
+        self.snapshot.as_deref()
+    }
+
+    /// Roots every mount path under `root` on the server.
+    ///
+    /// The root itself is sent as is; with a name key only the paths below it
+    /// are encrypted.
+    pub fn set_remote_root(&mut self, root: &str) {
+        self.remote_root = normalize_path(root);
+    }
+
+    pub fn remote_root(&self) -> &str {
+        &self.remote_root
+    }
+
+    /// Confirms the remote root is an existing directory.
+    ///
+    /// Mounting a single file is not supported: the root of a mount must be a
+    /// directory, so a file root fails here instead of mounting an empty tree.
+    pub fn check_remote_root(&self) -> Result<(), RemoteError> {
+        if self.remote_root.is_empty() {
+            return Ok(());
+        }
+        // The root is a server path as given, so it is escaped but never encrypted.
+        let url = format!(
+            "{}/list/{}",
+            self.base_url.trim_end_matches('/'),
+            raw_names::url_path(&parent_of(&self.remote_root))
+        );
+        let entries: Vec<RemoteEntry> =
+            self.send(self.read_request(&url))?.check_status()?.json()?;
+        let name = file_name(&self.remote_root);
+        match entries.iter().find(|e| e.name == name) {
+            Some(entry) if entry.is_dir() => Ok(()),
+            Some(_) => Err(RemoteError::Invalid(format!(
+                "'{}' is a file; a mount needs a directory, so mount its parent instead",
+                self.remote_root
+            ))),
+            None => Err(RemoteError::Invalid(format!(
+                "'{}' does not exist",
+                self.remote_root
+            ))),
+        }
+    }
+
+    /// True when mutations must be refused (snapshot mounts).
+    pub fn read_only(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// Refuses changes to paths matching `protected`, while reads still work.
+    pub fn set_protected(&mut self, protected: Protected) {
+        self.protected = protected;
+    }
+
+    /// True when `path` must not be changed: a snapshot mount or a `--protect` match.
+    pub fn is_protected(&self, path: &str) -> bool {
+        self.read_only() || self.protected.covers(path)
+    }
+
+    /// Key under which `path` is cached; includes the snapshot id so views never mix.
+    fn cache_key(&self, path: &str) -> String {
+        match &self.snapshot {
+            Some(id) => format!("@{}:{}", id, path),
+            None => path.to_string(),
+        }
+    }
+
+    /// Caps concurrent requests across this client and its clones; 0 means unlimited.
+    pub fn set_max_inflight(&mut self, max: usize) {
+        self.inflight = Arc::new(Inflight::new(max));
+    }
+
+    /// Requests in flight and requests waiting for a slot, across all clones.
+    pub fn inflight_counts(&self) -> (usize, usize) {
+        self.inflight.counts()
+    }
+
+    /// Fails requests fast for `cooldown` after `threshold` consecutive
+    /// connection failures; 0 disables the breaker.
+    pub fn set_circuit_breaker(&mut self, threshold: u32, cooldown: Duration) {
+        self.breaker = Arc::new(CircuitBreaker::new(threshold, cooldown));
+    }
+
+    /// Circuit breaker state, as shown in the stats file.
+    pub fn breaker_state(&self) -> String {
+        self.breaker.describe()
+    }
+
+    /// Sends `request` once an in-flight slot is free.
+    ///
+    /// The slot covers the request body and response headers; callers that read
+    /// a large response body take the permit themselves instead.
+    fn send(&self, request: RequestBuilder) -> Result<Response, RemoteError> {
+        let _permit = self.inflight.acquire(Traffic::Metadata);
+        self.dispatch(request)
+    }
+
+    /// Like `send`, for requests carrying file content; these leave the
+    /// metadata reserve of in-flight slots alone.
+    fn send_data(&self, request: RequestBuilder) -> Result<Response, RemoteError> {
+        let _permit = self.inflight.acquire(Traffic::Data);
+        self.dispatch(request)
+    }
+
+    /// Sends `request` tagged with a fresh `X-Request-ID`; failures are logged
+    /// at debug level with that id so they can be matched in the server log.
+    ///
+    /// While the circuit breaker is open this fails with `RemoteError::Unreachable` without
+    /// touching the network.
+    fn dispatch(&self, request: RequestBuilder) -> Result<Response, RemoteError> {
+        let request_id = logging::next_request_id();
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let (client, request) = request.header(REQUEST_ID_HEADER, &request_id).build_split();
+        let request = request?;
+        // Past the last early return: a probe let through must get its outcome recorded.
+        if !self.breaker.allow() {
+            return Err(RemoteError::Unreachable);
+        }
+        let operation = request.method().to_string();
+        let path = request.url().path().to_string();
+        // reqwest replays a write redirected with 301-303 as a bodiless GET,
+        // which would pass for a successful write.
+        let is_read = matches!(request.method(), &Method::GET | &Method::HEAD);
+        let requested = request.url().clone();
+        let started = Instant::now();
+        let result = client.execute(request);
+        match &result {
+            Err(e) if e.is_connect() || e.is_timeout() => self.breaker.record_failure(),
+            _ => self.breaker.record_success(),
+        }
+        let result = match result {
+            Ok(resp) if !is_read && *resp.url() != requested => {
+                Err(RemoteError::Redirected(resp.url().to_string()))
+            }
+            other => other.map_err(RemoteError::from),
+        };
+        let failure = match &result {
+            Ok(resp)
+                if resp.status().is_redirection()
+                    || resp.status().is_client_error()
+                    || resp.status().is_server_error() =>
+            {
+                Some((
+                    resp.status().to_string(),
+                    logging::status_errno(resp.status().as_u16()),
+                ))
+            }
+            Ok(_) => None,
+            Err(RemoteError::Redirected(target)) => Some((
+                format!("redirected to {}", target),
+                logging::status_errno(300),
+            )),
+            Err(e) => Some((e.to_string(), logging::status_errno(0))),
+        };
+        if let Some((reason, errno)) = failure {
+            logging::debug("http", format!("{} {} failed: {}", operation, path, reason))
+                .path(&path)
+                .duration(started.elapsed())
+                .errno(errno)
+                .request_id(&request_id)
+                .emit();
+        }
+        result
+    }
+
+    /// Starts a GET for the read path, pinned to the snapshot when one is mounted.
+    fn read_request(&self, url: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match &self.snapshot {
+            Some(id) => request.query(&[("snapshot", id)]),
+            None => request,
+        }
+    }
+
+    /// Enables offline writes: failed uploads are kept in `overlay` until reconciled.
+    pub fn set_overlay(&mut self, overlay: Arc<Overlay>) {
+        self.overlay = Some(overlay);
+    }
+
+    pub fn overlay(&self) -> Option<Arc<Overlay>> {
+        self.overlay.clone()
+    }
+
+    /// Forwards a transfer event to the installed hook, if any.
+    pub fn emit(&self, event: TransferEvent) {
+        if let Some(hook) = &self.event_hook {
+            hook.on_event(&event);
+        }
+    }
+
+    /// Builds the URL for `route` (e.g. `files`) and an already-normalized remote path.
+    /// This is the single place where plaintext names are turned into server names.
+    fn url(&self, route: &str, path: &str) -> String {
+        format!(
+            "{}/{}/{}",
+            self.base_url.trim_end_matches('/'),
+            route,
+            raw_names::url_path(&self.server_path(path))
+        )
+    }
+
+    /// Path of `path` as the server stores it: under the remote root, names encrypted.
+    fn server_path(&self, path: &str) -> String {
+        let remote = match &self.names {
+            Some(cipher) => cipher.encrypt_path(path),
+            None => path.to_string(),
+        };
+        join_path(&self.remote_root, &remote)
+    }
+
     pub fn base_url(&self) -> &str {
         &self.base_url
     }
@@ -91,168 +766,1186 @@ impl RemoteClient {
         &self.client
     }
 
-    pub fn list_dir(&mut self, path: &str) -> Result<Vec<RemoteEntry>, anyhow::Error> {
-        if !self.cache_config.dir_ttl.is_zero() {
-            if let Some(cached) = self.dir_cache.get(path) {
-                if cached.cached_at.elapsed() < self.cache_config.dir_ttl {
-                    return Ok(cached.entries.clone());
+    pub fn list_dir(&mut self, path: &str) -> Result<Vec<RemoteEntry>, RemoteError> {
+        let path = &normalize_path(path);
+        let cached = self
+            .cached_listing(path, true)
+            .or_else(|| self.serve_stale(path));
+        let mut entries = match cached {
+            Some(entries) => entries,
+            None => match self.fetch_listing_shared(path) {
+                Ok(entries) => {
+                    if !self.dir_ttl(path).is_zero() {
+                        let key = self.cache_key(path);
+                        let mut caches = self.caches.lock().unwrap();
+                        if !caches.dir_cache.contains_key(&key) {
+                            caches.make_room_for_dir(self.cache_config.max_dir_entries);
+                        }
+                        caches.dir_cache.insert(
+                            key,
+                            CachedDir {
+                                entries: entries.clone(),
+                                cached_at: Instant::now(),
+                            },
+                        );
+                    }
+                    entries
                 }
-            }
+                Err(e) => match &self.overlay {
+                    // Offline (or overlay-only directory): use the last listing we saw.
+                    Some(overlay) => {
+                        let mut entries = self.cached_listing(path, false).unwrap_or_default();
+                        overlay.merge_listing(path, &mut entries);
+                        if entries.is_empty() && e.is_status() {
+                            return Err(e);
+                        }
+                        return Ok(entries);
+                    }
+                    None => return Err(e),
+                },
+            },
+        };
+        if let Some(overlay) = &self.overlay {
+            overlay.merge_listing(path, &mut entries);
         }
+        Ok(entries)
+    }
 
-        let url = format!("{}/list/{}", self.base_url, path);
-        let entries: Vec<RemoteEntry> = self.client.get(&url).send()?.error_for_status()?.json()?;
+    /// Returns the cached listing of `path`, optionally ignoring its TTL.
+    fn cached_listing(&self, path: &str, fresh_only: bool) -> Option<Vec<RemoteEntry>> {
+        let ttl = self.dir_ttl(path);
+        if fresh_only && ttl.is_zero() {
+            return None;
+        }
+        let caches = self.caches.lock().unwrap();
+        let cached = caches.dir_cache.get(&self.cache_key(path))?;
+        if fresh_only && cached.cached_at.elapsed() >= ttl {
+            return None;
+        }
+        Some(cached.entries.clone())
+    }
 
-        if !self.cache_config.dir_ttl.is_zero() {
-            self.dir_cache.insert(
-                path.to_string(),
-                CachedDir {
-                    entries: entries.clone(),
-                    cached_at: Instant::now(),
-                },
-            );
+    /// Returns an expired listing still inside the stale-while-revalidate
+    /// window, and starts refreshing it in the background unless that is
+    /// already under way.
+    fn serve_stale(&self, path: &str) -> Option<Vec<RemoteEntry>> {
+        let window = self.cache_config.swr_window;
+        let ttl = self.dir_ttl(path);
+        if window.is_zero() || ttl.is_zero() {
+            return None;
         }
-        Ok(entries)
+        let key = self.cache_key(path);
+        let mut caches = self.caches.lock().unwrap();
+        let cached = caches.dir_cache.get(&key)?;
+        if cached.cached_at.elapsed() >= ttl + window {
+            return None;
+        }
+        let entries = cached.entries.clone();
+        if caches.revalidating.insert(key.clone()) {
+            let rc = self.clone();
+            let path = path.to_string();
+            std::thread::spawn(move || rc.revalidate(&path, &key));
+        }
+        Some(entries)
     }
 
-    pub fn fetch_file(&mut self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
-        if !self.cache_config.file_ttl.is_zero() {
-            if let Some(cached) = self.file_cache.get(path) {
-                if cached.cached_at.elapsed() < self.cache_config.file_ttl {
-                    return Ok(cached.data.clone());
+    /// Refetches the listing of `path` for `serve_stale` and stores it,
+    /// telling the listing hook when it differs from what was served.
+    fn revalidate(&self, path: &str, key: &str) {
+        let result = self.fetch_listing_shared(path);
+        let mut caches = self.caches.lock().unwrap();
+        caches.revalidating.remove(key);
+        let entries = match result {
+            Ok(entries) => entries,
+            Err(e) => {
+                logging::debug("list", format!("Background refresh failed: {}", e))
+                    .path(path)
+                    .emit();
+                return;
+            }
+        };
+        // A mutation that invalidated the listing meanwhile wins: the next
+        // `list_dir` fetches it again.
+        let Some(cached) = caches.dir_cache.get_mut(key) else {
+            return;
+        };
+        let old = std::mem::replace(&mut cached.entries, entries.clone());
+        cached.cached_at = Instant::now();
+        // Children that changed or went away lose whatever was cached for them.
+        for entry in old.iter().filter(|entry| !entries.contains(entry)) {
+            let child = self.cache_key(&join_path(path, &entry.name));
+            caches.remove_file(&child);
+            caches.dir_cache.remove(&child);
+        }
+        drop(caches);
+        if old != entries {
+            if let Some(hook) = &self.listing_hook {
+                hook.on_listing_changed(path, &old, &entries);
+            }
+        }
+    }
+
+    /// How long the listing of `path` stays fresh; the root has its own TTL.
+    fn dir_ttl(&self, path: &str) -> Duration {
+        if path.is_empty() {
+            self.cache_config.root_dir_ttl
+        } else {
+            self.cache_config.dir_ttl
+        }
+    }
+
+    /// `fetch_listing`, shared with concurrent callers listing the same directory.
+    fn fetch_listing_shared(&self, path: &str) -> Result<Vec<RemoteEntry>, RemoteError> {
+        self.flights
+            .listings
+            .run(&self.cache_key(path), || self.fetch_listing(path))
+    }
+
+    /// Lists a directory straight from the server, bypassing caches and the overlay.
+    ///
+    /// Asks for MessagePack, which is smaller and faster to parse for large
+    /// directories, and reads JSON from servers that do not offer it.
+    pub fn fetch_listing(&self, path: &str) -> Result<Vec<RemoteEntry>, RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("list", path);
+        self.request_listing(path, || self.read_request(&url))
+            .map(|(entries, _)| entries)
+    }
+
+    /// True when `path` should be listed page by page as it is read: the
+    /// server paginates, no fresh listing is cached, and no offline copies
+    /// need merging into it.
+    pub fn pages_listing(&self, path: &str) -> bool {
+        let path = &normalize_path(path);
+        self.capabilities.list_pages
+            && self.overlay.is_none()
+            && self.cached_listing(path, true).is_none()
+    }
+
+    /// Lists up to `LIST_PAGE_SIZE` entries of a directory after `cursor`
+    /// (`None` for the first page), with the cursor of the next page when
+    /// there is one. Bypasses caches and the overlay like `fetch_listing`.
+    pub fn fetch_listing_page(
+        &self,
+        path: &str,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<RemoteEntry>, Option<String>), RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("list", path);
+        self.request_listing(path, || {
+            let request = self
+                .read_request(&url)
+                .query(&[("limit", LIST_PAGE_SIZE.to_string())]);
+            match cursor {
+                Some(cursor) => request.query(&[("cursor", cursor)]),
+                None => request,
+            }
+        })
+    }
+
+    /// Sends the listing request built by `request`, retrying the first
+    /// root listing of the mount once when it did not reach the server.
+    fn request_listing(
+        &self,
+        path: &str,
+        request: impl Fn() -> RequestBuilder,
+    ) -> Result<(Vec<RemoteEntry>, Option<String>), RemoteError> {
+        let first_contact = path.is_empty() && !self.root_listed.load(Ordering::SeqCst);
+        let attempts = if first_contact {
+            FIRST_ROOT_ATTEMPTS
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        let resp = loop {
+            let sent = self.send(request().header(ACCEPT, LISTING_ACCEPT));
+            match sent.and_then(|resp| resp.check_status()) {
+                Err(e) if e.is_retryable() && attempt < attempts => {
+                    logging::info(
+                        "list",
+                        format!("First root listing failed, retrying: {}", e),
+                    )
+                    .emit();
+                    std::thread::sleep(TRANSFER_RETRY_DELAY);
+                    attempt += 1;
                 }
+                result => break result?,
+            }
+        };
+        if path.is_empty() {
+            self.root_listed.store(true, Ordering::SeqCst);
+        }
+        let next = resp
+            .headers()
+            .get(NEXT_CURSOR_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let is_msgpack = resp
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|t| t.starts_with(MSGPACK_TYPE));
+        let mut entries: Vec<RemoteEntry> = if is_msgpack {
+            rmp_serde::from_slice(&resp.bytes()?).map_err(|e| RemoteError::Decode(e.to_string()))?
+        } else {
+            resp.json()?
+        };
+        if let Some(cipher) = &self.names {
+            for entry in &mut entries {
+                entry.name = cipher.reveal(path, &entry.name);
             }
         }
+        Ok((entries, next))
+    }
 
-        let url = format!("{}/files/{}", self.base_url, path);
-        let data = self
-            .client
-            .get(&url)
-            .send()?
-            .error_for_status()?
-            .bytes()?
-            .to_vec();
-
-        if !self.cache_config.file_ttl.is_zero() {
-            while self.file_cache_size + data.len() > self.cache_config.max_file_cache_bytes {
-                let oldest = self
-                    .file_cache
-                    .iter()
-                    .min_by_key(|(_, v)| v.cached_at)
-                    .map(|(k, _)| k.clone());
-                match oldest {
-                    Some(key) => {
-                        if let Some(evicted) = self.file_cache.remove(&key) {
-                            self.file_cache_size -= evicted.data.len();
-                        }
-                    }
-                    None => break,
+    pub fn fetch_file(&mut self, path: &str) -> Result<Vec<u8>, RemoteError> {
+        let path = &normalize_path(path);
+        if let Some(mut file) = self.overlay.as_ref().and_then(|o| o.open_file(path)) {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            return Ok(data);
+        }
+        if self.caches_files() {
+            let key = self.cache_key(path);
+            if let Some(cached) = self.caches.lock().unwrap().file_cache.get(&key) {
+                if cached.cached_at.elapsed() < self.cache_config.file_ttl {
+                    return Ok(cached.data.to_vec());
                 }
             }
+        }
 
-            self.file_cache_size += data.len();
-            self.file_cache.insert(
-                path.to_string(),
+        // Concurrent readers of the same cold file share one download.
+        let data = self.flights.files.run(&self.cache_key(path), || {
+            let mut data = Vec::new();
+            self.download_to(path, &mut data)?;
+            Ok(Arc::new(data))
+        })?;
+
+        if self.caches_files() {
+            self.caches.lock().unwrap().insert_file(
+                self.cache_key(path),
                 CachedFile {
-                    data: data.clone(),
+                    data: Arc::clone(&data),
                     cached_at: Instant::now(),
                 },
+                self.cache_config.max_file_cache_bytes,
+                self.cache_config.max_file_entries,
             );
         }
-        Ok(data)
+        Ok(Arc::unwrap_or_clone(data))
     }
 
-    pub fn fetch_range(
+    /// False when file bodies are never kept (`--no-cache`, or a zero TTL or budget).
+    fn caches_files(&self) -> bool {
+        !self.cache_config.file_ttl.is_zero() && self.cache_config.max_file_cache_bytes > 0
+    }
+
+    /// Streams a whole remote file into `out`, reporting progress, without caching it.
+    ///
+    /// If the connection drops mid-transfer, the download is resumed with a
+    /// `Range` request from the last byte written, or restarted from scratch when
+    /// the server does not answer with a matching partial response.
+    pub fn download_to(&self, path: &str, out: &mut impl DownloadSink) -> Result<u64, RemoteError> {
+        let path = &normalize_path(path);
+        if let Some(mut file) = self.overlay.as_ref().and_then(|o| o.open_file(path)) {
+            return Ok(std::io::copy(&mut file, out)?);
+        }
+        let url = self.url("files", path);
+        let mut written = 0;
+        let mut attempt = 1;
+        loop {
+            match self.download_attempt(&url, path, out, &mut written) {
+                Ok(()) => return Ok(written),
+                Err(e) if attempt < TRANSFER_ATTEMPTS && e.is_retryable() => {
+                    // Only network failures get here; local I/O errors are `Io`.
+                    logging::warn(
+                        "download",
+                        format!(
+                            "Download of {} interrupted after {} bytes, retrying: {}",
+                            path, written, e
+                        ),
+                    )
+                    .path(path)
+                    .emit();
+                    std::thread::sleep(TRANSFER_RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Performs one GET, resuming at `written` when it is non-zero.
+    fn download_attempt(
         &self,
+        url: &str,
         path: &str,
-        offset: u64,
-        size: u32,
-    ) -> Result<Vec<u8>, anyhow::Error> {
-        let url = format!("{}/files/{}", self.base_url, path);
+        out: &mut impl DownloadSink,
+        written: &mut u64,
+    ) -> Result<(), RemoteError> {
+        let mut request = self.read_request(url);
+        if *written > 0 && self.capabilities.range {
+            request = request.header("Range", format!("bytes={}-", written));
+        }
+        let _permit = self.inflight.acquire(Traffic::Data);
+        let resp = self.dispatch(request)?.check_status()?;
+
+        if *written > 0 {
+            if resumes_at(&resp, *written) {
+                logging::info(
+                    "download",
+                    format!("Resumed after connection reset at byte {}", written),
+                )
+                .path(path)
+                .emit();
+            } else {
+                out.restart().map_err(RemoteError::Io)?;
+                *written = 0;
+            }
+        }
+
+        let total = resp.content_length().unwrap_or(0);
+        let mut reader =
+            ProgressReader::new(resp, Arc::clone(&self.progress), file_name(path), total);
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            // A failed local write is never retried: part of the chunk may
+            // already be in `out`, past what `written` counts.
+            out.write_all(&buf[..n]).map_err(RemoteError::Io)?;
+            *written += n as u64;
+        }
+    }
+
+    pub fn fetch_range(&self, path: &str, offset: u64, size: u32) -> Result<Vec<u8>, RemoteError> {
+        // There is no Range header for zero bytes: `bytes=N-(N-1)` is invalid.
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let path = &normalize_path(path);
+        if let Some(overlay) = &self.overlay {
+            if let Some(data) = overlay.read_range(path, offset, size) {
+                return Ok(data);
+            }
+        }
+        let url = self.url("files", path);
+        if !self.capabilities.range {
+            // Without Range support the whole body comes back anyway; cut it locally.
+            let mut data = Vec::new();
+            self.download_to(path, &mut data)?;
+            let start = (offset as usize).min(data.len());
+            let end = (start + size as usize).min(data.len());
+            return Ok(data[start..end].to_vec());
+        }
         let end = offset + (size as u64) - 1;
         let range_header = format!("bytes={}-{}", offset, end);
+        // Hold the slot until the body is read: the connection stays busy until then.
+        let _permit = self.inflight.acquire(Traffic::Data);
         let resp = self
-            .client
-            .get(&url)
-            .header("Range", range_header)
-            .send()?
-            .error_for_status()?;
+            .dispatch(self.read_request(&url).header("Range", range_header))?
+            .check_status()?;
         Ok(resp.bytes()?.to_vec())
     }
 
-    pub fn upload(&self, path: &str, data: Vec<u8>) -> Result<(), anyhow::Error> {
-        let url = format!("{}/files/{}", self.base_url, path);
-        self.client
-            .put(&url)
-            .body(data)
-            .send()?
-            .error_for_status()?;
+    pub fn upload(&self, path: &str, data: Vec<u8>) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("files", path);
+        let resp = self
+            .send_data(self.put_request(&url, path).body(data))?
+            .check_status()
+            .inspect_err(|e| log_refused_upload(path, e))?;
+        self.finish_upload(path, resp);
         Ok(())
     }
 
-    #[allow(dead_code)]
     pub fn upload_streamed(
         &self,
         path: &str,
         reader: impl Read + Send + 'static,
         size: u64,
-    ) -> Result<(), anyhow::Error> {
-        let url = format!("{}/files/{}", self.base_url, path);
+    ) -> Result<(), RemoteError> {
+        self.upload_at(path, 0, reader, size)
+    }
+
+    /// Appends `size` bytes from `reader` to `path`, which must be exactly
+    /// `offset` bytes long on the server (`PreconditionFailed` otherwise);
+    /// offset 0 replaces the file.
+    pub fn upload_at(
+        &self,
+        path: &str,
+        offset: u64,
+        reader: impl Read + Send + 'static,
+        size: u64,
+    ) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let upload = self.uploads.begin(&self.cache_key(path));
+        let resp = self.send_upload(path, offset, reader, size, &upload)?;
+        self.finish_upload(path, resp);
+        Ok(())
+    }
+
+    /// Uploads the offline copy of `path`, read at `generation`, to `target`,
+    /// which differs for a conflict copy. Fails with `Cancelled` when `path`
+    /// left the overlay or was edited again before the upload started; only
+    /// that generation is retired once it is stored.
+    pub fn upload_pending(
+        &self,
+        path: &str,
+        target: &str,
+        reader: impl Read + Send + 'static,
+        size: u64,
+        generation: u64,
+    ) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let target = &normalize_path(target);
+        let upload = self.uploads.begin(&self.cache_key(path));
+        // Checked once registered: a delete either finds this upload to
+        // cancel or has already taken the path out of the overlay, and a
+        // direct upload or newer edit has replaced the generation.
+        let Some(overlay) = self
+            .overlay
+            .as_ref()
+            .filter(|o| o.is_generation(path, generation))
+        else {
+            return Err(RemoteError::Cancelled);
+        };
+        let resp = self.send_upload(target, 0, reader, size, &upload)?;
+        self.record_receipt(target, resp);
+        overlay.remove_generation(path, generation);
+        Ok(())
+    }
+
+    fn send_upload(
+        &self,
+        path: &str,
+        offset: u64,
+        reader: impl Read + Send + 'static,
+        size: u64,
+        upload: &Upload<'_>,
+    ) -> Result<Response, RemoteError> {
+        let url = self.url("files", path);
+        let reader = ProgressReader::new(
+            upload.body(reader),
+            Arc::clone(&self.progress),
+            file_name(path),
+            size,
+        );
         let body = reqwest::blocking::Body::sized(reader, size);
-        self.client
-            .put(&url)
-            .body(body)
-            .send()?
-            .error_for_status()?;
+        let mut request = self.put_request(&url, path).body(body);
+        if offset > 0 {
+            request = request.query(&[("offset", offset)]);
+        }
+        let resp = match self.send_data(request) {
+            Err(_) if upload.is_cancelled() => return Err(RemoteError::Cancelled),
+            result => result?,
+        };
+        resp.check_status()
+            .inspect_err(|e| log_refused_upload(path, e))
+    }
+
+    /// Creates an empty file with permission bits `mode` (server default when `None`).
+    pub fn create_file(&self, path: &str, mode: Option<u32>) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("files", path);
+        let mut request = self.put_request(&url, path).body(Vec::new());
+        if let Some(mode) = mode {
+            request = request.query(&[("mode", mode)]);
+        }
+        let resp = self
+            .send(request)?
+            .check_status()
+            .inspect_err(|e| log_refused_upload(path, e))?;
+        self.finish_upload(path, resp);
+        Ok(())
+    }
+
+    /// Records the server's receipt for an accepted upload and retires any offline copy.
+    fn finish_upload(&self, path: &str, resp: Response) {
+        self.record_receipt(path, resp);
+        if let Some(overlay) = &self.overlay {
+            overlay.remove(path);
+        }
+    }
+
+    fn record_receipt(&self, path: &str, resp: Response) {
+        let receipt: UploadReceipt = resp.json().unwrap_or_default();
+        let mut caches = self.caches.lock().unwrap();
+        if receipt.sha256.is_some() {
+            if caches.receipts.len() >= MAX_RECEIPTS {
+                caches.receipts.clear();
+            }
+            caches.receipts.insert(self.cache_key(path), receipt);
+        } else {
+            caches.receipts.remove(&self.cache_key(path));
+        }
+    }
+
+    /// True when `file` hashes to what the last upload of `path` stored and the
+    /// server copy still has that upload's size and mtime, so uploading it again
+    /// would change nothing. Counts the upload as skipped; `file` is left rewound.
+    pub fn unchanged_since_upload(&self, path: &str, file: &mut std::fs::File) -> bool {
+        let path = &normalize_path(path);
+        let receipt = self
+            .caches
+            .lock()
+            .unwrap()
+            .receipts
+            .get(&self.cache_key(path))
+            .cloned();
+        let Some(UploadReceipt {
+            sha256: Some(sha256),
+            size: Some(size),
+            mtime: Some(mtime),
+        }) = receipt
+        else {
+            return false;
+        };
+        match content_digest(file) {
+            Ok(digest) if digest == sha256 => {}
+            _ => return false,
+        }
+        // Uncached: another client may have replaced the file since the upload.
+        let current = self
+            .fetch_listing(&parent_of(path))
+            .ok()
+            .and_then(|entries| {
+                entries
+                    .into_iter()
+                    .find(|e| e.name == file_name(path) && !e.is_dir())
+            });
+        if !current.is_some_and(|e| e.size == size && e.mtime == Some(mtime)) {
+            return false;
+        }
+        self.caches.lock().unwrap().uploads_skipped += 1;
+        logging::debug(
+            "upload",
+            "Content unchanged since the last upload, not sending",
+        )
+        .path(path)
+        .emit();
+        true
+    }
+
+    /// Uploads skipped by `unchanged_since_upload` since the client was created.
+    pub fn uploads_skipped(&self) -> u64 {
+        self.caches.lock().unwrap().uploads_skipped
+    }
+
+    /// Uploads `file`, or keeps it in the offline overlay when the server is unreachable.
+    ///
+    /// An upload cut off by a connection failure, such as a server restart,
+    /// is sent again from the start.
+    pub fn upload_file(
+        &self,
+        path: &str,
+        file: std::fs::File,
+        size: u64,
+    ) -> Result<(), RemoteError> {
+        let backup = file.try_clone()?;
+        match self.upload_retrying(path, file, size) {
+            Err(e) if !e.is_status() && !matches!(e, RemoteError::Cancelled) => {
+                if !self.save_offline(path, backup)? {
+                    return Err(e);
+                }
+                logging::warn(
+                    "upload",
+                    format!("Server unreachable, kept {} in the offline overlay", path),
+                )
+                .path(path)
+                .emit();
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn upload_retrying(
+        &self,
+        path: &str,
+        file: std::fs::File,
+        size: u64,
+    ) -> Result<(), RemoteError> {
+        let mut attempt = 1;
+        loop {
+            let mut body = file.try_clone()?;
+            body.seek(SeekFrom::Start(0))?;
+            match self.upload_streamed(path, body, size) {
+                Err(e) if attempt < TRANSFER_ATTEMPTS && e.is_retryable() => {
+                    logging::warn(
+                        "upload",
+                        format!(
+                            "Upload of {} interrupted, retrying from the start: {}",
+                            path, e
+                        ),
+                    )
+                    .path(path)
+                    .emit();
+                    std::thread::sleep(TRANSFER_RETRY_DELAY * attempt);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Stores `data` for `path` in the overlay; returns false when offline writes are off.
+    ///
+    /// Blocks while the overlay queue is full.
+    pub fn save_offline(
+        &self,
+        path: &str,
+        mut data: impl Read + Seek,
+    ) -> Result<bool, RemoteError> {
+        let Some(overlay) = &self.overlay else {
+            return Ok(false);
+        };
+        let path = &normalize_path(path);
+        let base = self
+            .cached_listing(&parent_of(path), false)
+            .and_then(|entries| {
+                entries
+                    .into_iter()
+                    .find(|e| e.name == file_name(path) && !e.is_dir())
+                    .and_then(|e| e.etag)
+            });
+        let size = data.seek(SeekFrom::End(0))?;
+        data.seek(SeekFrom::Start(0))?;
+        overlay.store(path, data, size, base)?;
+        Ok(true)
+    }
+
+    pub fn delete_remote(&self, path: &str) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("files", path);
+        let pending = match &self.overlay {
+            Some(overlay) if overlay.contains(path) => {
+                overlay.remove(path);
+                true
+            }
+            _ => false,
+        };
+        self.uploads.cancel(&self.cache_key(path));
+        let resp = self.send(self.client.delete(&url))?;
+        // A file that only ever lived in the overlay has no server copy to delete.
+        if pending && resp.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        resp.check_status()?;
+        Ok(())
+    }
+
+    /// Moves a path into the server trash, from where it can be restored.
+    pub fn trash_remote(&self, path: &str) -> Result<(), RemoteError> {
+        self.require(self.capabilities.trash, "trash")?;
+        let path = &normalize_path(path);
+        if let Some(overlay) = &self.overlay {
+            overlay.remove(path);
+        }
+        self.uploads.cancel(&self.cache_key(path));
+        let url = self.url("files", path);
+        self.send(self.client.delete(&url).query(&[("trash", "true")]))?
+            .check_status()?;
         Ok(())
     }
 
-    pub fn delete_remote(&self, path: &str) -> Result<(), anyhow::Error> {
-        let url = format!("{}/files/{}", self.base_url, path);
-        self.client.delete(&url).send()?.error_for_status()?;
+    /// Deletes a path on behalf of the user: soft-deleted with `--use-trash`, permanent otherwise.
+    pub fn remove_entry(&self, path: &str) -> Result<(), RemoteError> {
+        if self.use_trash {
+            self.trash_remote(path)
+        } else {
+            self.delete_remote(path)
+        }
+    }
+
+    pub fn list_trash(&self) -> Result<Vec<TrashEntry>, RemoteError> {
+        self.require(self.capabilities.trash, "trash")?;
+        let url = format!("{}/trash", self.base_url.trim_end_matches('/'));
+        Ok(self.send(self.client.get(&url))?.check_status()?.json()?)
+    }
+
+    pub fn restore_trash(&self, id: &str) -> Result<(), RemoteError> {
+        self.require(self.capabilities.trash, "trash")?;
+        let url = format!("{}/trash/restore", self.base_url.trim_end_matches('/'));
+        self.send(self.client.post(&url).query(&[("id", id)]))?
+            .check_status()?;
+        Ok(())
+    }
+
+    /// Queries `/capabilities` once; on failure keeps the base-endpoint defaults.
+    pub fn probe_capabilities(&mut self) {
+        let url = format!("{}/capabilities", self.base_url.trim_end_matches('/'));
+        let probed = self
+            .send(self.client.get(&url))
+            .and_then(|resp| Ok(resp.check_status()?.json::<Capabilities>()?));
+        self.capabilities = match probed {
+            Ok(caps) => caps,
+            Err(
+                e @ (RemoteError::Unreachable | RemoteError::Timeout | RemoteError::Transport(_)),
+            ) => {
+                logging::warn(
+                    "probe",
+                    format!(
+                        "Server {} is not reachable ({}); operations will fail until it is",
+                        self.base_url, e
+                    ),
+                )
+                .emit();
+                Capabilities {
+                    assumed: true,
+                    unreachable: Some(e.to_string()),
+                    ..Capabilities::default()
+                }
+            }
+            Err(e) => {
+                logging::warn(
+                    "probe",
+                    format!(
+                        "Capability probe failed ({}), assuming base endpoints only",
+                        e
+                    ),
+                )
+                .emit();
+                Capabilities {
+                    assumed: true,
+                    ..Capabilities::default()
+                }
+            }
+        };
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// Sends a cheap `GET /health` on a background thread, so the server name
+    /// is resolved and a kept-alive connection waits in the pool before the
+    /// first filesystem operation needs one.
+    pub fn warm_up(&self) {
+        let rc = self.clone();
+        std::thread::spawn(move || {
+            let url = format!("{}/health", rc.base_url.trim_end_matches('/'));
+            let started = Instant::now();
+            // Any answer will do, even a 404 from an older server; reading
+            // the body hands the connection back to the pool.
+            match rc
+                .send(rc.client.get(&url))
+                .and_then(|resp| Ok(resp.bytes()?))
+            {
+                Ok(_) => logging::debug("warmup", "Connection to the server ready")
+                    .duration(started.elapsed())
+                    .emit(),
+                Err(e) => {
+                    logging::debug("warmup", format!("Connection warm-up failed: {}", e)).emit()
+                }
+            }
+        });
+    }
+
+    /// Fails early when the server lacks an optional feature.
+    fn require(&self, supported: bool, feature: &str) -> Result<(), RemoteError> {
+        if !supported {
+            return Err(RemoteError::Unsupported(feature.to_string()));
+        }
         Ok(())
     }
 
-    pub fn mkdir_remote(&self, path: &str) -> Result<(), anyhow::Error> {
-        let url = format!("{}/mkdir/{}", self.base_url, path);
-        self.client.post(&url).send()?.error_for_status()?;
+    /// Files below the mount root whose name contains `query`, ignoring case.
+    ///
+    /// Encrypted names cannot be matched by the server, so search is refused with them.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchHit>, RemoteError> {
+        self.require(self.capabilities.search, "search")?;
+        if self.names.is_some() {
+            return Err(RemoteError::Unsupported(
+                "search with encrypted names".to_string(),
+            ));
+        }
+        let url = format!(
+            "{}?q={}",
+            self.url("search", ""),
+            raw_names::url_path(query)
+        );
+        Ok(self.send(self.read_request(&url))?.check_status()?.json()?)
+    }
+
+    /// Usage of the server quota over the mount, when `--remote-root` points
+    /// into a top-level directory that has one; refreshed at the directory TTL.
+    pub fn quota(&self) -> Option<QuotaInfo> {
+        if !self.capabilities.quota {
+            return None;
+        }
+        let top = self
+            .remote_root
+            .split('/')
+            .next()
+            .filter(|s| !s.is_empty())?;
+        if let Some((fetched_at, info)) = &self.caches.lock().unwrap().quota {
+            if fetched_at.elapsed() < self.cache_config.dir_ttl {
+                return Some(info.clone());
+            }
+        }
+        let url = format!(
+            "{}/quota/{}",
+            self.base_url.trim_end_matches('/'),
+            raw_names::url_path(top)
+        );
+        let info: QuotaInfo = self
+            .send(self.client.get(&url))
+            .and_then(|resp| resp.check_status())
+            .and_then(|resp| Ok(resp.json()?))
+            .ok()?;
+        self.caches.lock().unwrap().quota = Some((Instant::now(), info.clone()));
+        Some(info)
+    }
+
+    /// Audit records newer than `since` (epoch seconds), oldest first; needs an admin token.
+    pub fn audit_since(&self, since: f64) -> Result<Vec<AuditRecord>, RemoteError> {
+        self.require(self.capabilities.audit, "audit")?;
+        let url = format!("{}/audit", self.base_url.trim_end_matches('/'));
+        Ok(self
+            .send(self.client.get(&url).query(&[("since", since)]))?
+            .check_status()?
+            .json()?)
+    }
+
+    pub fn list_snapshots(&self) -> Result<Vec<SnapshotInfo>, RemoteError> {
+        self.require(self.capabilities.snapshots, "snapshots")?;
+        let url = format!("{}/snapshots", self.base_url.trim_end_matches('/'));
+        Ok(self.send(self.client.get(&url))?.check_status()?.json()?)
+    }
+
+    pub fn create_snapshot(&self, name: Option<&str>) -> Result<SnapshotInfo, RemoteError> {
+        self.require(self.capabilities.snapshots, "snapshots")?;
+        let url = format!("{}/snapshots", self.base_url.trim_end_matches('/'));
+        let mut request = self.client.post(&url);
+        if let Some(name) = name {
+            request = request.query(&[("name", name)]);
+        }
+        Ok(self.send(request)?.check_status()?.json()?)
+    }
+
+    pub fn empty_trash(&self) -> Result<(), RemoteError> {
+        self.require(self.capabilities.trash, "trash")?;
+        let url = format!("{}/trash", self.base_url.trim_end_matches('/'));
+        self.send(self.client.delete(&url))?.check_status()?;
         Ok(())
     }
 
-    pub fn rename_dir_recursive(
+    /// Sets the server-side modification time (epoch seconds) and/or permission bits.
+    #[cfg_attr(unix, allow(dead_code))]
+    pub fn set_attrs(
+        &self,
+        path: &str,
+        mtime: Option<f64>,
+        mode: Option<u32>,
+    ) -> Result<(), RemoteError> {
+        self.require(self.capabilities.attrs, "attrs")?;
+        let path = &normalize_path(path);
+        let url = self.url("attrs", path);
+        let mut request = self.client.post(&url);
+        if let Some(mtime) = mtime {
+            request = request.query(&[("mtime", mtime)]);
+        }
+        if let Some(mode) = mode {
+            request = request.query(&[("mode", mode)]);
+        }
+        self.send(request)?.check_status()?;
+        Ok(())
+    }
+
+    /// Creates a directory with permission bits `mode` (server default when `None`).
+    pub fn mkdir_remote(&self, path: &str, mode: Option<u32>) -> Result<(), RemoteError> {
+        let path = &normalize_path(path);
+        let url = self.url("mkdir", path);
+        let mut request = self.client.post(&url);
+        if let Some(mode) = mode {
+            request = request.query(&[("mode", mode)]);
+        }
+        self.send(request)?.check_status()?;
+        Ok(())
+    }
+
+    /// Renames a file or a whole directory tree on the server in one request.
+    pub fn move_remote(&self, old_path: &str, new_path: &str) -> Result<(), RemoteError> {
+        self.require(self.capabilities.rename, "rename")?;
+        let (old_path, new_path) = (&normalize_path(old_path), &normalize_path(new_path));
+        // Encoded by hand so names that are not UTF-8 keep their raw bytes.
+        let url = format!(
+            "{}?to={}",
+            self.url("move", old_path),
+            raw_names::url_path(&self.server_path(new_path))
+        );
+        self.send(self.client.post(&url))?.check_status()?;
+        Ok(())
+    }
+
+    /// Renames `old_path` to `new_path`, replacing a file already there.
+    ///
+    /// Without the server `/move` endpoint a file is copied and deleted, which
+    /// transfers its content twice; a directory fails with `Unsupported`, since
+    /// copying a tree file by file is neither atomic nor cheap.
+    pub fn rename_remote(
         &mut self,
         old_path: &str,
         new_path: &str,
-    ) -> Result<(), anyhow::Error> {
-        self.mkdir_remote(new_path)?;
-        let entries = self.list_dir(old_path)?;
-        for entry in entries {
-            let old_child = format!("{}/{}", old_path, entry.name);
-            let new_child = format!("{}/{}", new_path, entry.name);
-            if entry.is_dir {
-                self.rename_dir_recursive(&old_child, &new_child)?;
-            } else {
-                let data = self.fetch_file(&old_child)?;
-                self.upload(&new_child, data)?;
-            }
+        is_dir: bool,
+    ) -> Result<(), RemoteError> {
+        // An upload in flight finishes under the old name before it moves.
+        let old_key = self.cache_key(&normalize_path(old_path));
+        self.uploads.wait(&old_key);
+        // Edits still waiting in the overlay only exist locally; copy them over.
+        let pending = self
+            .overlay
+            .as_ref()
+            .is_some_and(|o| o.contains(&normalize_path(old_path)));
+        if is_dir || (self.capabilities.rename && !pending) {
+            self.move_remote(old_path, new_path)?;
+        } else {
+            let data = self.fetch_file(old_path)?;
+            self.upload(new_path, data)?;
+            self.delete_remote(old_path)?;
         }
+        self.invalidate_tree(old_path);
+        self.invalidate(new_path);
         Ok(())
     }
 
     pub fn invalidate(&mut self, path: &str) {
-        self.dir_cache.remove(&parent_of(path));
-        self.dir_cache.remove(path);
-        if let Some(evicted) = self.file_cache.remove(path) {
-            self.file_cache_size -= evicted.data.len();
-        }
+        let path = &normalize_path(path);
+        let mut caches = self.caches.lock().unwrap();
+        caches.dir_cache.remove(&self.cache_key(&parent_of(path)));
+        caches.dir_cache.remove(&self.cache_key(path));
+        caches.remove_file(&self.cache_key(path));
     }
 
-    pub fn cached_file_data(&self, path: &str) -> Option<&[u8]> {
-        if let Some(cached) = self.file_cache.get(path) {
+    /// Drops every cached listing, file and quota, so the next access of
+    /// anything goes to the server.
+    pub fn clear_caches(&self) {
+        let mut caches = self.caches.lock().unwrap();
+        caches.dir_cache.clear();
+        caches.file_cache.clear();
+        caches.file_cache_size = 0;
+        caches.quota = None;
+    }
+
+    /// Drops cached listings and files for `path` and everything below it.
+    pub fn invalidate_tree(&mut self, path: &str) {
+        self.invalidate(path);
+        let prefix = format!("{}/", self.cache_key(&normalize_path(path)));
+        let mut caches = self.caches.lock().unwrap();
+        caches.dir_cache.retain(|key, _| !key.starts_with(&prefix));
+        caches.remove_files_under(&prefix);
+    }
+
+    pub fn cached_file_data(&self, path: &str) -> Option<Arc<Vec<u8>>> {
+        let path = &normalize_path(path);
+        if self.overlay.as_ref().is_some_and(|o| o.contains(path)) {
+            return None;
+        }
+        let key = self.cache_key(path);
+        if let Some(cached) = self.caches.lock().unwrap().file_cache.get(&key) {
             if cached.cached_at.elapsed() < self.cache_config.file_ttl {
-                return Some(&cached.data);
+                return Some(Arc::clone(&cached.data));
             }
         }
         None
     }
+
+    /// Bytes currently held by the file cache.
+    pub fn file_cache_bytes(&self) -> usize {
+        self.caches.lock().unwrap().file_cache_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+
+    fn client(server: &TestServer) -> RemoteClient {
+        RemoteClient::new(server.url(), CacheConfig::default())
+    }
+
+    /// Content-Type of the one upload `rc` sent to `server`.
+    fn uploaded_type(server: &TestServer) -> Option<String> {
+        let puts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "PUT")
+            .collect();
+        assert_eq!(puts.len(), 1);
+        puts[0].header("content-type").map(str::to_string)
+    }
+
+    #[test]
+    fn uploads_are_labelled_from_the_extension() {
+        let server = TestServer::start(|_| Response::json("{}"));
+        client(&server)
+            .upload("site/page.html", b"<p>".to_vec())
+            .unwrap();
+        assert_eq!(uploaded_type(&server).as_deref(), Some("text/html"));
+
+        let server = TestServer::start(|_| Response::json("{}"));
+        let mut rc = client(&server);
+        rc.set_default_content_type(Some("text/x-custom".to_string()));
+        rc.upload_streamed("notes.unknown", &b"x"[..], 1).unwrap();
+        assert_eq!(uploaded_type(&server).as_deref(), Some("text/x-custom"));
+    }
+
+    #[test]
+    fn no_content_type_sends_none() {
+        let server = TestServer::start(|_| Response::json("{}"));
+        let mut rc = client(&server);
+        rc.set_content_types(false);
+        rc.set_default_content_type(Some("text/x-custom".to_string()));
+        rc.upload("site/page.html", b"<p>".to_vec()).unwrap();
+        assert_eq!(uploaded_type(&server), None);
+    }
+
+    #[test]
+    fn names_too_long_to_encrypt_are_refused_up_front() {
+        let server = TestServer::start(|_| Response::status(404));
+        let mut rc = client(&server);
+        let name = "x".repeat(200);
+        assert!(!rc.path_too_long(&format!("dir/{}", name)));
+        rc.set_name_cipher(Arc::new(NameCipher::new(b"secret")));
+        assert!(rc.path_too_long(&format!("dir/{}", name)));
+        assert!(!rc.path_too_long("dir/short"));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn dropped_download_resumes_where_it_stopped() {
+        let body: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let served = body.clone();
+        let server = TestServer::start(move |r| match r.header("range") {
+            Some(_) => Response::ranged(r, &served),
+            None => Response::bytes(served.clone()).cut_after(40_000),
+        });
+        let mut out = Vec::new();
+        let written = client(&server).download_to("big.bin", &mut out).unwrap();
+        assert_eq!(written, body.len() as u64);
+        assert!(out == body);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].header("range"), Some("bytes=40000-"));
+    }
+
+    #[test]
+    fn zero_byte_range_sends_nothing() {
+        let server = TestServer::start(|r| Response::ranged(r, b"abc"));
+        let rc = client(&server);
+        assert_eq!(rc.fetch_range("f", 0, 0).unwrap(), b"");
+        assert_eq!(rc.fetch_range("f", 2, 0).unwrap(), b"");
+        assert!(server.requests().is_empty());
+        assert_eq!(rc.fetch_range("f", 1, 5).unwrap(), b"bc");
+    }
+
+    #[test]
+    fn probe_reads_the_advertised_features() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/capabilities" => Response::json(
+                r#"{"version":"1.4","range":false,"rename":true,"list_pages":true,"max_upload_bytes":1000,"future":1}"#,
+            ),
+            _ => Response::status(404),
+        });
+        let mut rc = client(&server);
+        rc.probe_capabilities();
+        let caps = rc.capabilities();
+        assert_eq!(caps.version.as_deref(), Some("1.4"));
+        assert!(!caps.range && caps.rename && caps.list_pages);
+        // Features the server leaves out are off.
+        assert!(!caps.trash && !caps.appends);
+        assert_eq!(caps.max_upload_bytes, Some(1000));
+        assert!(!caps.assumed);
+    }
+
+    #[test]
+    fn failed_probe_assumes_base_endpoints_only() {
+        // An older server without the endpoint, and one answering something else.
+        let answers: [fn() -> Response; 2] =
+            [|| Response::status(404), || Response::bytes("<html>")];
+        for response in answers {
+            let server = TestServer::start(move |_| response());
+            let mut rc = client(&server);
+            rc.probe_capabilities();
+            let caps = rc.capabilities();
+            assert!(caps.assumed);
+            assert!(caps.unreachable.is_none());
+            assert!(caps.range);
+            assert!(!caps.rename && !caps.trash && !caps.list_pages && !caps.appends);
+        }
+    }
+
+    #[test]
+    fn unreachable_server_is_told_apart_from_a_missing_feature() {
+        // Nothing listens on port 1.
+        let mut rc = RemoteClient::new("http://127.0.0.1:1", CacheConfig::default());
+        rc.probe_capabilities();
+        assert!(rc.capabilities().assumed);
+        assert!(rc.capabilities().unreachable.is_some());
+    }
+
+    #[test]
+    fn remote_root_that_is_a_file_is_refused() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/list/my%20docs" => Response::json(
+                r#"[{"name":"report.txt","size":3},{"name":"sub","kind":"dir","size":0}]"#,
+            ),
+            _ => Response::status(404),
+        });
+        let mut rc = client(&server);
+        rc.set_remote_root("my docs/report.txt");
+        match rc.check_remote_root() {
+            Err(RemoteError::Invalid(message)) => assert!(message.contains("is a file")),
+            other => panic!("expected Invalid, got {:?}", other),
+        }
+        rc.set_remote_root("my docs/sub");
+        assert!(rc.check_remote_root().is_ok());
+        rc.set_remote_root("my docs/missing");
+        assert!(matches!(
+            rc.check_remote_root(),
+            Err(RemoteError::Invalid(_))
+        ));
+        assert_eq!(server.count("GET", "/list/my%20docs"), 3);
+    }
+
+    #[test]
+    fn caches_stop_at_their_entry_caps() {
+        let server = TestServer::start(|r| {
+            if r.path.starts_with("/list/") {
+                Response::json("[]")
+            } else {
+                Response::bytes("content")
+            }
+        });
+        let mut rc = RemoteClient::new(
+            server.url(),
+            CacheConfig {
+                max_dir_entries: 8,
+                max_file_entries: 8,
+                ..CacheConfig::default()
+            },
+        );
+        for i in 0..100 {
+            rc.list_dir(&format!("docs/d{}", i)).unwrap();
+            rc.fetch_file(&format!("docs/f{}", i)).unwrap();
+            let caches = rc.caches.lock().unwrap();
+            assert!(caches.dir_cache.len() <= 8);
+            assert!(caches.file_cache.len() <= 8);
+        }
+        let caches = rc.caches.lock().unwrap();
+        assert_eq!((caches.dir_cache.len(), caches.file_cache.len()), (8, 8));
+        drop(caches);
+
+        // The newest entries are the ones kept.
+        rc.list_dir("docs/d99").unwrap();
+        rc.fetch_file("docs/f99").unwrap();
+        assert_eq!(server.count("GET", "/list/docs/d99"), 1);
+        assert_eq!(server.count("GET", "/files/docs/f99"), 1);
+        rc.list_dir("docs/d0").unwrap();
+        assert_eq!(server.count("GET", "/list/docs/d0"), 2);
+    }
 }
@@ -0,0 +1,152 @@
+use crate::error::RemoteError;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Coalesces concurrent calls for the same key into one request.
+///
+/// The first caller for a key runs the request; callers arriving while it is
+/// in flight wait and share its result. Errors are not shared: a waiter whose
+/// leader failed runs the request itself, so it gets an error it can inspect.
+pub struct SingleFlight<T> {
+    calls: Mutex<HashMap<String, Arc<Call<T>>>>,
+}
+
+/// One in-flight request; `result` is `Some` once the leader has finished.
+struct Call<T> {
+    result: Mutex<Option<Option<T>>>,
+    done: Condvar,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `request` for `key`, or waits for the identical call already running.
+    pub fn run(
+        &self,
+        key: &str,
+        request: impl FnOnce() -> Result<T, RemoteError>,
+    ) -> Result<T, RemoteError> {
+        let (call, leader) = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key) {
+                Some(call) => (Arc::clone(call), false),
+                None => {
+                    let call = Arc::new(Call {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    calls.insert(key.to_string(), Arc::clone(&call));
+                    (call, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut result = call.result.lock().unwrap();
+            while result.is_none() {
+                result = call.done.wait(result).unwrap();
+            }
+            let shared = result.as_ref().unwrap().clone();
+            drop(result);
+            return match shared {
+                Some(value) => Ok(value),
+                None => request(),
+            };
+        }
+
+        let mut finish = Finish {
+            flight: self,
+            key,
+            call: &call,
+            value: None,
+        };
+        let outcome = request();
+        finish.value = outcome.as_ref().ok().cloned();
+        outcome
+    }
+}
+
+/// Publishes the leader's result on drop, so waiters wake up even if it panics.
+struct Finish<'a, T> {
+    flight: &'a SingleFlight<T>,
+    key: &'a str,
+    call: &'a Call<T>,
+    value: Option<T>,
+}
+
+impl<T> Drop for Finish<'_, T> {
+    fn drop(&mut self) {
+        self.flight.calls.lock().unwrap().remove(self.key);
+        *self.call.result.lock().unwrap() = Some(self.value.take());
+        self.call.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    const CALLERS: usize = 50;
+
+    /// Runs `request` for one key from `CALLERS` threads at once. The leader
+    /// only finishes once every caller is on its way into `run`.
+    fn stampede(
+        flight: &SingleFlight<u64>,
+        request: impl Fn() -> Result<u64, RemoteError> + Sync,
+    ) -> Vec<Result<u64, RemoteError>> {
+        let arrived = AtomicUsize::new(0);
+        std::thread::scope(|s| {
+            let callers: Vec<_> = (0..CALLERS)
+                .map(|_| {
+                    let (arrived, request) = (&arrived, &request);
+                    s.spawn(move || {
+                        arrived.fetch_add(1, Ordering::SeqCst);
+                        flight.run("key", || {
+                            while arrived.load(Ordering::SeqCst) < CALLERS {
+                                std::thread::yield_now();
+                            }
+                            std::thread::sleep(Duration::from_millis(50));
+                            request()
+                        })
+                    })
+                })
+                .collect();
+            callers.into_iter().map(|c| c.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_execution() {
+        let flight = SingleFlight::new();
+        let runs = AtomicUsize::new(0);
+        let results = stampede(&flight, || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            Ok(42)
+        });
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|r| matches!(r, Ok(42))));
+        // Finished calls are forgotten: the next one runs again.
+        flight.run("key", || Ok(7)).unwrap();
+        assert!(flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn waiters_of_a_failed_leader_run_themselves() {
+        let flight = SingleFlight::new();
+        let runs = AtomicUsize::new(0);
+        let results = stampede(&flight, || {
+            runs.fetch_add(1, Ordering::SeqCst);
+            Err(RemoteError::NotFound)
+        });
+        assert_eq!(runs.load(Ordering::SeqCst), CALLERS);
+        assert!(results
+            .iter()
+            .all(|r| matches!(r, Err(RemoteError::NotFound))));
+    }
+}
@@ -0,0 +1,230 @@
+//! Stub HTTP server for tests: answers each request with a handler and
+//! records what was asked, so tests can count round trips and check headers.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+/// A request as the stub received it.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string, still percent-encoded.
+    pub path: String,
+    pub query: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of header `name`, matched without regard to case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// What the stub answers.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    /// Drop the connection after this many body bytes, as a failing network would.
+    cut_after: Option<usize>,
+}
+
+impl Response {
+    pub fn status(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            cut_after: None,
+        }
+    }
+
+    pub fn bytes(body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            body: body.into(),
+            ..Self::status(200)
+        }
+    }
+
+    /// `body`, or the part of it `request` asks for with a `Range` header,
+    /// answered 206 as a server with Range support would.
+    pub fn ranged(request: &Request, body: &[u8]) -> Self {
+        let Some((start, end)) = request
+            .header("range")
+            .and_then(|v| v.strip_prefix("bytes="))
+            .and_then(|v| v.split_once('-'))
+        else {
+            return Self::bytes(body);
+        };
+        let start: usize = start.parse().unwrap_or(0);
+        let end = end.parse::<usize>().map_or(body.len(), |end| end + 1);
+        let end = end.min(body.len());
+        if start >= end {
+            return Self::status(416);
+        }
+        Self {
+            status: 206,
+            ..Self::bytes(&body[start..end])
+        }
+        .header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", start, end - 1, body.len()),
+        )
+    }
+
+    pub fn json(body: impl Into<String>) -> Self {
+        Self::bytes(body.into()).header("Content-Type", "application/json")
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Announces the whole body but closes the connection after `n` bytes of it.
+    pub fn cut_after(mut self, n: usize) -> Self {
+        self.cut_after = Some(n);
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+/// Server on a free localhost port, serving until the test process exits.
+pub struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+    handler: Arc<Mutex<Arc<Handler>>>,
+}
+
+impl TestServer {
+    pub fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Mutex<Arc<Handler>>> = Arc::new(Mutex::new(Arc::new(handler)));
+        let (log, current) = (Arc::clone(&requests), Arc::clone(&handler));
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (log, current) = (Arc::clone(&log), Arc::clone(&current));
+                std::thread::spawn(move || serve(stream, &log, &current));
+            }
+        });
+        Self {
+            url,
+            requests,
+            handler,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Answers later requests with `handler`, e.g. after the files "changed".
+    pub fn set_handler(&self, handler: impl Fn(&Request) -> Response + Send + Sync + 'static) {
+        *self.handler.lock().unwrap() = Arc::new(handler);
+    }
+
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Requests received so far with `method` and a path starting with `prefix`.
+    pub fn count(&self, method: &str, prefix: &str) -> usize {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.method == method && r.path.starts_with(prefix))
+            .count()
+    }
+}
+
+/// Answers one request on `stream`, then closes it.
+fn serve(stream: TcpStream, log: &Mutex<Vec<Request>>, handler: &Mutex<Arc<Handler>>) {
+    let Some(request) = read_request(&stream) else {
+        return;
+    };
+    log.lock().unwrap().push(request.clone());
+    let handler = Arc::clone(&handler.lock().unwrap());
+    let response = handler(&request);
+    let mut out = &stream;
+    let mut head = format!(
+        "HTTP/1.1 {} Stub\r\nContent-Length: {}\r\nConnection: close\r\n",
+        response.status,
+        response.body.len()
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let sent = response.cut_after.unwrap_or(response.body.len());
+    let _ = out.write_all(head.as_bytes());
+    let _ = out.write_all(&response.body[..sent.min(response.body.len())]);
+    let _ = out.flush();
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+    let mut request = Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body: Vec::new(),
+    };
+    let length = request
+        .header("content-length")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    if request
+        .header("transfer-encoding")
+        .is_some_and(|v| v.eq_ignore_ascii_case("chunked"))
+    {
+        request.body = read_chunked(&mut reader)?;
+    } else {
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).ok()?;
+    }
+    Some(request)
+}
+
+fn read_chunked(reader: &mut impl BufRead) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let size = usize::from_str_radix(line.trim().split(';').next()?, 16).ok()?;
+        let mut chunk = vec![0; size + 2];
+        reader.read_exact(&mut chunk).ok()?;
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
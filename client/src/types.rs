@@ -1,64 +1,408 @@
 use serde::Deserialize;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Deserialize, Clone)]
+/// File type of a listed entry, as reported by the server.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Dir,
+    /// Also stands for any type this client does not know about.
+    #[default]
+    #[serde(other)]
+    File,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDev,
+    BlockDev,
+}
+
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(from = "WireEntry")]
 /// Entry metadata returned by the remote server for a directory listing.
 pub struct RemoteEntry {
     pub name: String,
-    pub is_dir: bool,
+    pub kind: EntryKind,
     pub size: u64,
+    /// Permission bits (`st_mode & 0o777`) when the server reports them.
+    #[serde(default)]
+    pub mode: Option<u32>,
+    /// Last content modification, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub mtime: Option<f64>,
+    /// Last inode change (content, mode or rename), in seconds since the Unix epoch.
+    #[serde(default)]
+    pub ctime: Option<f64>,
+    /// Creation (birth) time, when the server filesystem records one.
+    #[serde(default)]
+    pub crtime: Option<f64>,
+    /// Numeric owner on the server, when it reports one.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
+    /// Number of direct children of a directory, when the server counts them.
+    #[serde(default)]
+    pub entry_count: Option<u64>,
+    /// 512-byte blocks allocated on the server (`st_blocks`), when it reports them.
+    #[serde(default)]
+    pub blocks: Option<u64>,
+    /// Server tag that changes whenever the content does, when it sends one.
+    #[serde(default)]
+    pub etag: Option<String>,
+}
+
+impl RemoteEntry {
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Dir
+    }
+}
+
+/// `RemoteEntry` as sent by the server. Servers that predate `kind` only
+/// send `is_dir`, which is all a missing `kind` falls back to.
+#[derive(Deserialize)]
+struct WireEntry {
+    name: String,
+    #[serde(default)]
+    is_dir: bool,
+    #[serde(default)]
+    kind: Option<EntryKind>,
+    size: u64,
+    #[serde(default)]
+    mode: Option<u32>,
+    #[serde(default)]
+    mtime: Option<f64>,
+    #[serde(default)]
+    ctime: Option<f64>,
+    #[serde(default)]
+    crtime: Option<f64>,
+    #[serde(default)]
+    uid: Option<u32>,
+    #[serde(default)]
+    gid: Option<u32>,
+    #[serde(default)]
+    entry_count: Option<u64>,
+    #[serde(default)]
+    blocks: Option<u64>,
+    #[serde(default)]
+    etag: Option<String>,
+}
+
+impl From<WireEntry> for RemoteEntry {
+    fn from(wire: WireEntry) -> Self {
+        let fallback = if wire.is_dir {
+            EntryKind::Dir
+        } else {
+            EntryKind::File
+        };
+        Self {
+            name: wire.name,
+            kind: wire.kind.unwrap_or(fallback),
+            size: wire.size,
+            mode: wire.mode,
+            mtime: wire.mtime,
+            ctime: wire.ctime,
+            crtime: wire.crtime,
+            uid: wire.uid,
+            gid: wire.gid,
+            entry_count: wire.entry_count,
+            blocks: wire.blocks,
+            etag: wire.etag,
+        }
+    }
+}
+
+/// File found by `/search`: its path below the searched directory and its metadata.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SearchHit {
+    pub path: String,
+    #[serde(flatten)]
+    pub entry: RemoteEntry,
+}
+
+/// Converts a server timestamp (seconds since the epoch) to `SystemTime`.
+/// Negative, NaN or out-of-range values map to the epoch itself.
+pub fn epoch_time(secs: f64) -> SystemTime {
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .and_then(|d| UNIX_EPOCH.checked_add(d))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+#[derive(Debug, Deserialize, Clone)]
+/// Soft-deleted entry returned by the server's `/trash` listing.
+pub struct TrashEntry {
+    pub id: String,
+    pub path: String,
+    pub is_dir: bool,
+    /// Deletion time in seconds since the Unix epoch.
+    pub deleted_at: f64,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+/// Mutating request recorded in the server's audit log (`/audit`).
+pub struct AuditRecord {
+    /// Time of the request in seconds since the Unix epoch.
+    pub ts: f64,
+    /// Authenticated principal; `None` on servers without tokens.
+    pub principal: Option<String>,
+    /// Client IP address.
+    pub client: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// Destination of a move.
+    pub to: Option<String>,
+    /// Announced upload size in bytes.
+    pub size: Option<u64>,
+    pub status: u16,
+    /// `X-Request-ID` the client sent, matching its own log.
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+/// Limits and usage of a top-level server directory, returned by `/quota`.
+pub struct QuotaInfo {
+    pub dir: String,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+    pub used_bytes: u64,
+    pub used_files: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+/// What the server stored for a `PUT /files`; empty from servers without checksums.
+pub struct UploadReceipt {
+    /// Hex SHA-256 of the stored content.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub mtime: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+/// Server-side snapshot returned by `/snapshots`.
+pub struct SnapshotInfo {
+    pub id: String,
+    /// Creation time in seconds since the Unix epoch.
+    pub created_at: f64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+/// Optional server features, probed once from `/capabilities` at mount.
+pub struct Capabilities {
+    pub version: Option<String>,
+    /// `/files` answers `Range` requests with 206 partial content.
+    pub range: bool,
+    /// `DELETE ?trash=true` and the `/trash` endpoints.
+    pub trash: bool,
+    /// `/snapshots` and `?snapshot=` reads.
+    pub snapshots: bool,
+    /// `/attrs` for setting modification time and permission bits.
+    pub attrs: bool,
+    /// `/move` for renaming files and directory trees on the server.
+    pub rename: bool,
+    /// `/search` for finding files by name without listing the tree.
+    pub search: bool,
+    /// `/audit`: the server logs mutating requests and serves the log to admins.
+    pub audit: bool,
+    /// `/quota`: some top-level directories have byte or file limits.
+    pub quota: bool,
+    /// `PUT /files` answers with the SHA-256 of the stored content.
+    pub checksums: bool,
+    /// `PUT /files?offset=` appends to a file of exactly that length.
+    pub appends: bool,
+    /// `/list?limit=` returns one page of a listing and an `X-Next-Cursor`
+    /// header naming the next one.
+    pub list_pages: bool,
+    /// Largest upload the server accepts, when it caps them.
+    pub max_upload_bytes: Option<u64>,
+    /// True when the probe failed and these are the assumed defaults.
+    #[serde(skip)]
+    pub assumed: bool,
+    /// Why the probe got no answer at all, when the server was unreachable:
+    /// a missing feature then says nothing about the server.
+    #[serde(skip)]
+    pub unreachable: Option<String>,
+}
+
+impl Default for Capabilities {
+    /// Base endpoints only; `/files` has always honored `Range`.
+    fn default() -> Self {
+        Self {
+            version: None,
+            range: true,
+            trash: false,
+            snapshots: false,
+            attrs: false,
+            rename: false,
+            search: false,
+            audit: false,
+            quota: false,
+            checksums: false,
+            appends: false,
+            list_pages: false,
+            max_upload_bytes: None,
+            assumed: false,
+            unreachable: None,
+        }
+    }
+}
+
+impl Capabilities {
+    /// Names of the supported optional features, for diagnostics.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            ("range", self.range),
+            ("trash", self.trash),
+            ("snapshots", self.snapshots),
+            ("attrs", self.attrs),
+            ("rename", self.rename),
+            ("search", self.search),
+            ("audit", self.audit),
+            ("quota", self.quota),
+            ("checksums", self.checksums),
+            ("appends", self.appends),
+            ("list_pages", self.list_pages),
+        ]
+        .into_iter()
+        .filter_map(|(name, on)| on.then_some(name))
+        .collect()
+    }
+}
+
+/// Readahead limit unless `--readahead-kb` says otherwise.
+pub const DEFAULT_READAHEAD: u64 = 4 * 1024 * 1024;
+
 /// Runtime cache policy used by the client filesystem layer.
+#[derive(Clone, Debug)]
 pub struct CacheConfig {
     pub dir_ttl: Duration,
+    /// TTL of the mount root's listing, which nearly every lookup goes through.
+    pub root_dir_ttl: Duration,
+    /// How long past its TTL a listing is still served while a background
+    /// request refreshes it (zero always waits for the server).
+    pub swr_window: Duration,
     pub file_ttl: Duration,
     pub max_file_cache_bytes: usize,
+    /// Most file bodies kept in the cache, whatever their size (0 = unlimited).
+    pub max_file_entries: usize,
+    /// Most directory listings kept in the cache (0 = unlimited).
+    pub max_dir_entries: usize,
+    /// Files up to this size are read whole and cached instead of by range.
+    pub small_file_bytes: u64,
+    /// Most bytes a sequential read fetches past what was asked for (0 disables readahead).
+    pub max_readahead: u64,
+    /// Drop cached metadata and data for a file whenever it is opened.
+    pub consistent_open: bool,
+    /// How long the kernel may reuse file attributes without asking again.
+    pub attr_timeout: Duration,
+    /// How long the kernel may reuse a name lookup without asking again.
+    pub entry_timeout: Duration,
+    /// How long the kernel may remember a failed lookup; zero asks every time.
+    pub negative_timeout: Duration,
 }
 
 impl Default for CacheConfig {
     fn default() -> Self {
         Self {
             dir_ttl: Duration::from_secs(5),
+            root_dir_ttl: Duration::from_secs(10),
+            swr_window: Duration::ZERO,
             file_ttl: Duration::from_secs(10),
             max_file_cache_bytes: 64 * 1024 * 1024,
+            max_file_entries: 4096,
+            max_dir_entries: 10_000,
+            small_file_bytes: 8 * 1024,
+            max_readahead: DEFAULT_READAHEAD,
+            consistent_open: false,
+            attr_timeout: Duration::from_secs(10),
+            entry_timeout: Duration::from_secs(5),
+            negative_timeout: Duration::ZERO,
         }
     }
 }
 
 impl CacheConfig {
     /// Builds cache settings from CLI flags, including no-cache mode.
-    pub fn from_cli(no_cache: bool, dir_ttl: u64, file_ttl: u64, max_mb: usize) -> Self {
+    pub fn from_cli(
+        no_cache: bool,
+        dir_ttl: u64,
+        file_ttl: u64,
+        max_mb: usize,
+        small_file_bytes: u64,
+    ) -> Self {
         if no_cache {
             Self {
                 dir_ttl: Duration::from_millis(100),
+                root_dir_ttl: Duration::from_millis(100),
+                swr_window: Duration::ZERO,
                 file_ttl: Duration::from_millis(100),
                 max_file_cache_bytes: 0,
+                max_file_entries: 4096,
+                max_dir_entries: 10_000,
+                small_file_bytes: 0,
+                max_readahead: DEFAULT_READAHEAD,
+                consistent_open: false,
+                attr_timeout: Duration::ZERO,
+                entry_timeout: Duration::ZERO,
+                negative_timeout: Duration::ZERO,
             }
         } else {
             Self {
                 dir_ttl: Duration::from_secs(dir_ttl),
+                root_dir_ttl: Duration::from_secs(dir_ttl.saturating_mul(2)),
+                swr_window: Duration::ZERO,
                 file_ttl: Duration::from_secs(file_ttl),
                 max_file_cache_bytes: max_mb * 1024 * 1024,
+                max_file_entries: 4096,
+                max_dir_entries: 10_000,
+                small_file_bytes,
+                max_readahead: DEFAULT_READAHEAD,
+                consistent_open: false,
+                // Kernel caches follow the client caches unless overridden.
+                attr_timeout: Duration::from_secs(file_ttl),
+                entry_timeout: Duration::from_secs(dir_ttl),
+                // Misses are not cached: files made elsewhere must show up at once.
+                negative_timeout: Duration::ZERO,
             }
         }
     }
 }
 
-#[allow(dead_code)]
+/// Canonicalizes a remote path: drops empty and `.` segments, so the root is `""`.
+pub fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .filter(|seg| !seg.is_empty() && *seg != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 /// Joins a parent path and child name using the remote path format.
 pub fn join_path(parent: &str, name: &str) -> String {
-    if parent.is_empty() {
-        name.to_string()
-    } else {
-        format!("{}/{}", parent, name)
-    }
+    normalize_path(&format!("{}/{}", parent, name))
 }
 
 /// Returns the parent directory of a remote path.
 pub fn parent_of(path: &str) -> String {
+    let path = normalize_path(path);
     match path.rfind('/') {
         Some(pos) => path[..pos].to_string(),
         None => String::new(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_path;
+
+    #[test]
+    fn normalize_path_cases() {
+        assert_eq!(normalize_path(""), "");
+        assert_eq!(normalize_path("/"), "");
+        assert_eq!(normalize_path("a//b/"), "a/b");
+        assert_eq!(normalize_path("a/./b"), "a/b");
+    }
+}
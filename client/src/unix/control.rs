@@ -0,0 +1,65 @@
+//! Runtime control of a running mount through `ioctl` on its version file,
+//! and the `control` subcommand that sends them.
+
+use crate::cli::ControlAction;
+use crate::version_file::VERSION_FILE;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+/// Bytes returned by `STATS`; longer reports are cut.
+pub const STATS_SIZE: usize = 4096;
+
+/// Drops every cached listing and file, so the next access of anything goes
+/// to the server. No argument.
+pub const FLUSH_CACHES: u32 = ioc(IOC_NONE, 1, 0);
+/// Copies the version file's report, with the current activity, into a
+/// `STATS_SIZE` buffer, NUL-padded.
+pub const STATS: u32 = ioc(IOC_READ, 2, STATS_SIZE);
+
+/// Group byte of the control codes.
+const MAGIC: u8 = b'R';
+
+/// `_IOC` direction bits, which the kernel uses to copy the argument.
+#[cfg(target_os = "linux")]
+const IOC_NONE: u32 = 0;
+#[cfg(target_os = "linux")]
+const IOC_READ: u32 = 2 << 30;
+#[cfg(target_os = "macos")]
+const IOC_NONE: u32 = 0x2000_0000;
+#[cfg(target_os = "macos")]
+const IOC_READ: u32 = 0x4000_0000;
+
+/// Encodes a control code like the C `_IO`/`_IOR` macros.
+const fn ioc(dir: u32, nr: u8, size: usize) -> u32 {
+    dir | ((size as u32 & 0x1fff) << 16) | ((MAGIC as u32) << 8) | nr as u32
+}
+
+/// Runs `remote-fs <MOUNTPOINT> control <ACTION>` against a running mount.
+pub fn command(mountpoint: &str, action: &ControlAction) -> Result<(), anyhow::Error> {
+    if mountpoint.is_empty() {
+        anyhow::bail!("A mountpoint is required, e.g. `remote-fs /mnt/remote control stats`");
+    }
+    let path = Path::new(mountpoint).join(VERSION_FILE);
+    let file = std::fs::File::open(&path)
+        .map_err(|e| anyhow::anyhow!("{} is not a remote-fs mount: {}", mountpoint, e))?;
+    let mut buf = vec![0u8; STATS_SIZE];
+    let code = match action {
+        ControlAction::FlushCaches => FLUSH_CACHES,
+        ControlAction::Stats => STATS,
+    };
+    // SAFETY: `buf` outlives the call and holds the size encoded in `STATS`;
+    // `FLUSH_CACHES` carries no argument.
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), code as _, buf.as_mut_ptr()) };
+    if result < 0 {
+        let e = std::io::Error::last_os_error();
+        anyhow::bail!("Control request to {} failed: {}", mountpoint, e);
+    }
+    match action {
+        ControlAction::FlushCaches => println!("Flushed the caches of {}", mountpoint),
+        ControlAction::Stats => {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            print!("{}", String::from_utf8_lossy(&buf[..len]));
+        }
+    }
+    Ok(())
+}
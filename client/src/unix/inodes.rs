@@ -0,0 +1,432 @@
+//! Inode numbers handed to the kernel and the mount paths they stand for.
+
+use crate::logging;
+use crate::name_cipher::NameCipher;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Inode of the mount root.
+pub const ROOT_INO: u64 = 1;
+
+/// First line of a saved table; a file starting otherwise is ignored.
+const SAVED_HEADER: &str = "remote-fs inodes v1";
+
+/// Inodes the kernel holds no reference to that are kept, so a path listed
+/// or looked up again gets its old number back; past this the lowest,
+/// oldest numbers are dropped.
+const MAX_IDLE: usize = 100_000;
+
+/// Two-way map between inodes and normalized paths, shared with the
+/// background threads that notify the kernel.
+///
+/// Both directions and the counter sit behind one lock, so concurrent
+/// lookups of the same new path get the same inode and no reader ever sees
+/// one direction updated without the other.
+#[derive(Clone)]
+pub struct InodeTable {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    last_ino: u64,
+    by_ino: HashMap<u64, String>,
+    by_path: HashMap<String, u64>,
+    /// Lookups of each inode the kernel has not forgotten yet.
+    lookups: HashMap<u64, u64>,
+    /// Every other inode but the root, which the table may drop.
+    idle: BTreeSet<u64>,
+}
+
+impl InodeTable {
+    /// A table holding only the root.
+    pub fn new() -> Self {
+        let mut inner = Inner {
+            last_ino: ROOT_INO,
+            by_ino: HashMap::new(),
+            by_path: HashMap::new(),
+            lookups: HashMap::new(),
+            idle: BTreeSet::new(),
+        };
+        inner.by_ino.insert(ROOT_INO, String::new());
+        inner.by_path.insert(String::new(), ROOT_INO);
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// The inode of `path`, numbering it on first sight, for entries the
+    /// kernel only sees listed.
+    pub fn get_or_alloc(&self, path: String) -> u64 {
+        self.inner.lock().unwrap().get_or_alloc(path)
+    }
+
+    /// The inode of `path` for an entry handed to the kernel, which holds
+    /// on to it until it sends `forget`.
+    pub fn look_up(&self, path: String) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let ino = inner.get_or_alloc(path);
+        if ino != ROOT_INO {
+            inner.idle.remove(&ino);
+            *inner.lookups.entry(ino).or_default() += 1;
+        }
+        ino
+    }
+
+    /// Takes back `count` lookups of `ino`; once the kernel holds none the
+    /// inode becomes idle, and may be dropped.
+    pub fn forget(&self, ino: u64, count: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(lookups) = inner.lookups.get_mut(&ino) else {
+            return;
+        };
+        *lookups = lookups.saturating_sub(count);
+        if *lookups == 0 {
+            inner.lookups.remove(&ino);
+            inner.idle.insert(ino);
+            inner.trim();
+        }
+    }
+
+    pub fn path_of(&self, ino: u64) -> Option<String> {
+        self.inner.lock().unwrap().by_ino.get(&ino).cloned()
+    }
+
+    /// The inode of `path`, if the kernel has been given one.
+    pub fn ino_of(&self, path: &str) -> Option<u64> {
+        self.inner.lock().unwrap().by_path.get(path).copied()
+    }
+
+    /// Copy of every inode and its path, for threads that walk them all.
+    pub fn paths(&self) -> HashMap<u64, String> {
+        self.inner.lock().unwrap().by_ino.clone()
+    }
+
+    /// Moves `old` and everything below it to `new`, in one step.
+    ///
+    /// Inodes of whatever the rename replaced at `new` are dropped rather
+    /// than left pointing at the moved entries.
+    pub fn rename_prefix(&self, old: &str, new: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let replaced: Vec<String> = inner
+            .by_path
+            .keys()
+            .filter(|path| moved_to(path, new, new).is_some())
+            .cloned()
+            .collect();
+        for path in replaced {
+            inner.remove(&path);
+        }
+        let moved: Vec<(String, String, u64)> = inner
+            .by_path
+            .iter()
+            .filter_map(|(path, &ino)| moved_to(path, old, new).map(|to| (path.clone(), to, ino)))
+            .collect();
+        for (from, _, _) in &moved {
+            inner.by_path.remove(from);
+        }
+        for (_, to, ino) in moved {
+            inner.by_path.insert(to.clone(), ino);
+            inner.by_ino.insert(ino, to);
+        }
+    }
+
+    /// Forgets `path`; a no-op when it has no inode.
+    pub fn remove(&self, path: &str) {
+        self.inner.lock().unwrap().remove(path);
+    }
+}
+
+impl Default for InodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inner {
+    fn get_or_alloc(&mut self, path: String) -> u64 {
+        if let Some(&ino) = self.by_path.get(&path) {
+            return ino;
+        }
+        self.last_ino += 1;
+        let ino = self.last_ino;
+        self.by_path.insert(path.clone(), ino);
+        self.by_ino.insert(ino, path);
+        self.idle.insert(ino);
+        self.trim();
+        ino
+    }
+
+    fn remove(&mut self, path: &str) {
+        if let Some(ino) = self.by_path.remove(path) {
+            self.by_ino.remove(&ino);
+            self.lookups.remove(&ino);
+            self.idle.remove(&ino);
+        }
+    }
+
+    /// Drops the oldest idle inodes past `MAX_IDLE`.
+    fn trim(&mut self) {
+        while self.idle.len() > MAX_IDLE {
+            let Some(ino) = self.idle.pop_first() else {
+                break;
+            };
+            if let Some(path) = self.by_ino.remove(&ino) {
+                self.by_path.remove(&path);
+            }
+        }
+    }
+}
+
+/// Where a mount keeps its inode table between runs (`--persist-inodes`).
+///
+/// With a name key the paths are stored encrypted, as the server sees them.
+#[derive(Clone)]
+pub struct InodeFile {
+    path: PathBuf,
+    names: Option<Arc<NameCipher>>,
+}
+
+impl InodeFile {
+    pub fn new(path: PathBuf, names: Option<Arc<NameCipher>>) -> Self {
+        Self { path, names }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The table `save` wrote, so paths keep their inodes across mounts; a
+    /// fresh table when the file is missing, corrupted or from another version.
+    pub fn load(&self) -> InodeTable {
+        let saved = std::fs::read_to_string(&self.path).ok();
+        match saved.as_deref().and_then(|s| self.parse(s)) {
+            Some(inner) => InodeTable {
+                inner: Arc::new(Mutex::new(inner)),
+            },
+            None => InodeTable::new(),
+        }
+    }
+
+    /// Writes every inode of `table` and its counter, replacing the file atomically.
+    pub fn save(&self, table: &InodeTable) -> std::io::Result<()> {
+        let mut out = String::new();
+        {
+            let inner = table.inner.lock().unwrap();
+            let _ = writeln!(out, "{}\n{}", SAVED_HEADER, inner.last_ino);
+            // One line per inode; the rare name with a newline is renumbered next time.
+            for (ino, path) in inner.by_ino.iter().filter(|(_, p)| !p.contains('\n')) {
+                let stored = match &self.names {
+                    Some(cipher) => cipher.encrypt_path(path),
+                    None => path.clone(),
+                };
+                let _ = writeln!(out, "{}\t{}", ino, stored);
+            }
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, out)?;
+        std::fs::rename(tmp, &self.path)
+    }
+
+    /// Reads a table written by `save`, or `None` when anything is off.
+    fn parse(&self, saved: &str) -> Option<Inner> {
+        let mut lines = saved.lines();
+        if lines.next()? != SAVED_HEADER {
+            return None;
+        }
+        let mut inner = Inner {
+            last_ino: lines.next()?.parse().ok()?,
+            by_ino: HashMap::new(),
+            by_path: HashMap::new(),
+            lookups: HashMap::new(),
+            idle: BTreeSet::new(),
+        };
+        for line in lines {
+            let (ino, stored) = line.split_once('\t')?;
+            let ino: u64 = ino.parse().ok()?;
+            let path = match &self.names {
+                Some(cipher) => reveal_path(cipher, stored),
+                None => stored.to_string(),
+            };
+            if ino > inner.last_ino
+                || inner.by_ino.insert(ino, path.clone()).is_some()
+                || inner.by_path.insert(path, ino).is_some()
+            {
+                return None;
+            }
+            if ino != ROOT_INO {
+                inner.idle.insert(ino);
+            }
+        }
+        inner.trim();
+        (inner.by_path.get("") == Some(&ROOT_INO)).then_some(inner)
+    }
+}
+
+/// Decrypts a path stored by `InodeFile::save`; components that do not
+/// decrypt were stored in plaintext (mixed mode) and are taken as they are.
+fn reveal_path(cipher: &NameCipher, stored: &str) -> String {
+    stored
+        .split('/')
+        .map(|seg| cipher.decrypt_name(seg).unwrap_or_else(|| seg.to_string()))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Saves the table every `interval` while the mount runs, so a crash loses
+/// at most that much; stops when dropped.
+pub struct Saver {
+    stop: Arc<AtomicBool>,
+}
+
+impl Saver {
+    pub fn spawn(table: InodeTable, file: InodeFile, interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            if flag.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = file.save(&table) {
+                logging::warn("inodes", format!("Cannot save inode table: {}", e))
+                    .path(&file.path().to_string_lossy())
+                    .emit();
+            }
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Where `path` lands when `old` is renamed to `new`, or `None` when it is
+/// neither `old` nor below it.
+pub fn moved_to(path: &str, old: &str, new: &str) -> Option<String> {
+    if path == old {
+        Some(new.to_string())
+    } else {
+        path.strip_prefix(old)
+            .filter(|rest| rest.starts_with('/'))
+            .map(|rest| format!("{}{}", new, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Barrier;
+
+    /// Runs `f(thread index)` on `threads` threads released together.
+    fn race(threads: usize, f: impl Fn(usize) -> u64 + Send + Sync) -> Vec<u64> {
+        let barrier = Barrier::new(threads);
+        std::thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|i| {
+                    let (barrier, f) = (&barrier, &f);
+                    s.spawn(move || {
+                        barrier.wait();
+                        f(i)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+    }
+
+    #[test]
+    fn racing_lookups_of_one_path_share_an_inode() {
+        let table = InodeTable::new();
+        let inos = race(16, |i| {
+            if i % 2 == 0 {
+                table.look_up("shared".to_string())
+            } else {
+                table.get_or_alloc("shared".to_string())
+            }
+        });
+        assert!(inos.iter().all(|&ino| ino == inos[0]));
+        let paths = table.paths();
+        assert_eq!(paths.values().filter(|p| *p == "shared").count(), 1);
+    }
+
+    #[test]
+    fn racing_lookups_of_different_paths_get_distinct_inodes() {
+        let table = InodeTable::new();
+        let inos = race(16, |i| table.look_up(format!("file-{}", i)));
+        assert_eq!(inos.iter().collect::<HashSet<_>>().len(), 16);
+        for (i, ino) in inos.iter().enumerate() {
+            assert_eq!(table.path_of(*ino), Some(format!("file-{}", i)));
+        }
+    }
+
+    #[test]
+    fn forgotten_inodes_stay_until_the_idle_cap() {
+        let table = InodeTable::new();
+        let ino = table.look_up("a".to_string());
+        table.look_up("a".to_string());
+        table.forget(ino, 1);
+        assert_eq!(table.ino_of("a"), Some(ino));
+        table.forget(ino, 1);
+        // Idle now, but kept: the next lookup gets the same number.
+        assert_eq!(table.look_up("a".to_string()), ino);
+
+        table.forget(ino, 1);
+        for i in 0..MAX_IDLE {
+            table.get_or_alloc(format!("d/{}", i));
+        }
+        assert_eq!(table.ino_of("a"), None);
+        assert_eq!(table.paths().len(), MAX_IDLE + 1);
+    }
+
+    #[test]
+    fn referenced_inodes_are_never_dropped() {
+        let table = InodeTable::new();
+        let ino = table.look_up("held".to_string());
+        for i in 0..MAX_IDLE + 10 {
+            table.get_or_alloc(format!("d/{}", i));
+        }
+        assert_eq!(table.path_of(ino).as_deref(), Some("held"));
+        assert_eq!(table.path_of(ROOT_INO).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn saved_table_loads_with_the_same_numbers() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = InodeFile::new(dir.path().join("inodes-test"), None);
+        let table = InodeTable::new();
+        let a = table.look_up("dir/a".to_string());
+        let b = table.get_or_alloc("dir/b".to_string());
+        file.save(&table).unwrap();
+
+        let loaded = file.load();
+        assert_eq!(loaded.ino_of("dir/a"), Some(a));
+        assert_eq!(loaded.ino_of("dir/b"), Some(b));
+        assert!(loaded.get_or_alloc("new".to_string()) > b);
+    }
+
+    #[test]
+    fn encrypted_table_keeps_no_plaintext_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("inodes-test");
+        let cipher = Arc::new(NameCipher::new(b"secret"));
+        let file = InodeFile::new(path.clone(), Some(cipher));
+        let table = InodeTable::new();
+        let ino = table.look_up("private/report.pdf".to_string());
+        file.save(&table).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert!(!saved.contains("private") && !saved.contains("report"));
+        assert_eq!(file.load().ino_of("private/report.pdf"), Some(ino));
+    }
+}
@@ -1,30 +1,47 @@
+pub mod control;
+mod inodes;
+mod mount;
+mod open_file;
 mod remote_fs;
+mod search;
+mod watch;
 mod linux;
 mod macos;
 use daemonize::Daemonize;
 
-/// Dispatches startup to the Unix implementation for the current target OS.
-pub fn run(cli: &crate::cli::Cli) {
-    daemonize_if_requested(cli);
+pub use mount::{spawn_mount, Unmounter};
+pub use remote_fs::{RemoteFS, DEFAULT_BLKSIZE};
 
-    #[cfg(target_os = "linux")]
-    linux::run(cli);
+/// Mounts with the CLI options and serves until the filesystem is unmounted.
+pub fn run(cli: &crate::cli::Cli) -> Result<(), anyhow::Error> {
+    daemonize_if_requested(cli)?;
 
-    #[cfg(target_os = "macos")]
-    macos::run(cli);
+    let config = cli.mount_config()?;
+    let cache = &config.client.cache_config;
+    println!("Mounting at: {}", cli.mountpoint());
+    println!("Server: {}", cli.server_url);
+    println!(
+        "Cache: dir_ttl={}s, file_ttl={}s, max={}MB",
+        cache.dir_ttl.as_secs(),
+        cache.file_ttl.as_secs(),
+        cache.max_file_cache_bytes / 1024 / 1024,
+    );
+    if let Some(limit) = config.client.file_size_limit() {
+        println!("Max file size: {} bytes", limit);
+    }
+
+    crate::mount::mount(config)?.wait()
 }
 
-fn daemonize_if_requested(cli: &crate::cli::Cli) {
+fn daemonize_if_requested(cli: &crate::cli::Cli) -> Result<(), anyhow::Error> {
     if !cli.daemon {
-        return;
+        return Ok(());
     }
 
     let daemonize = Daemonize::new().working_directory(".").umask(0o022);
-    match daemonize.start() {
-        Ok(_) => eprintln!("Daemonized successfully (PID {})", std::process::id()),
-        Err(e) => {
-            eprintln!("Failed to daemonize: {}", e);
-            std::process::exit(1);
-        }
-    }
+    daemonize
+        .start()
+        .map_err(|e| anyhow::anyhow!("Failed to daemonize: {}", e))?;
+    eprintln!("Daemonized successfully (PID {})", std::process::id());
+    Ok(())
 }
@@ -0,0 +1,509 @@
+use super::remote_fs::RemoteFS;
+use super::watch::NotifierSlot;
+use crate::logging;
+use crate::mount::MountConfig;
+use crate::protect::Protected;
+use fuser::{MountOption, Session, SessionUnmounter};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Wait before the first remount; doubles per attempt up to `MAX_BACKOFF`.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A session that stayed up this long resets the attempt count.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// How a FUSE session ended.
+enum Ended {
+    /// The mountpoint was unmounted (`fusermount -u`, `umount`, `Unmounter`).
+    Unmounted,
+    /// The session stopped while the mount was still in place, or never started.
+    Dropped(String),
+}
+
+#[derive(Default)]
+struct Control {
+    stopping: bool,
+    /// Unmounts the current session; `None` between sessions.
+    session: Option<SessionUnmounter>,
+}
+
+/// Unmounts a filesystem started by `mount` from any thread.
+#[derive(Clone, Default)]
+pub struct Unmounter {
+    control: Arc<(Mutex<Control>, Condvar)>,
+}
+
+impl Unmounter {
+    /// Asks the filesystem to unmount; also stops a pending remount.
+    pub fn unmount(&self) {
+        let (control, wake) = &*self.control;
+        let mut control = control.lock().unwrap();
+        control.stopping = true;
+        if let Some(mut session) = control.session.take() {
+            let _ = session.unmount();
+        }
+        wake.notify_all();
+    }
+
+    fn stopping(&self) -> bool {
+        self.control.0.lock().unwrap().stopping
+    }
+
+    /// Registers `session` for `unmount`; false if an unmount came first.
+    fn attach(&self, session: &mut Session<RemoteFS>) -> bool {
+        let mut control = self.control.0.lock().unwrap();
+        if control.stopping {
+            return false;
+        }
+        control.session = Some(session.unmount_callable());
+        true
+    }
+
+    fn detach(&self) {
+        self.control.0.lock().unwrap().session = None;
+    }
+
+    /// Sleeps for `backoff` unless an unmount arrives first; true if it did.
+    fn sleep(&self, backoff: Duration) -> bool {
+        let (control, wake) = &*self.control;
+        let control = control.lock().unwrap();
+        let (control, _) = wake
+            .wait_timeout_while(control, backoff, |c| !c.stopping)
+            .unwrap();
+        control.stopping
+    }
+}
+
+/// Mounts the filesystem and serves it on a background thread.
+///
+/// The first mount happens before this returns, so its failure is reported
+/// to the caller. Later, with `auto_remount`, a session that ends any other
+/// way than an unmount (a channel error, an aborted connection) is mounted
+/// again after a backoff, reusing the client and so its caches.
+pub fn spawn_mount(
+    config: MountConfig,
+) -> Result<(Unmounter, JoinHandle<Result<(), anyhow::Error>>), anyhow::Error> {
+    #[cfg(target_os = "macos")]
+    super::macos::check_installed()?;
+
+    let mountpoint = PathBuf::from(&config.mountpoint);
+    // Resolved up front: a dead mount cannot be canonicalized later.
+    let target = std::fs::canonicalize(&mountpoint).unwrap_or_else(|_| mountpoint.clone());
+    let mut options = mount_options(config.client.read_only());
+    if !allow_other_permitted() {
+        if config.allow_other {
+            anyhow::bail!(
+                "Mount failed: --allow-other needs `{}` in {}",
+                USER_ALLOW_OTHER,
+                FUSE_CONF
+            );
+        }
+        drop_allow_other(&mut options, &config.mountpoint);
+    }
+
+    if config.force && still_mounted(&target) {
+        force_unmount(&target, "--force");
+    }
+
+    let unmounter = Unmounter::default();
+    let session = mount_with_fallback(&mut options, &config, |options| {
+        new_session(&config, options)
+    })
+    .map_err(|e| anyhow::anyhow!("Mount failed: {}", describe_mount_error(&e, &target)))?;
+
+    let serving = unmounter.clone();
+    let thread = std::thread::spawn(move || serve(config, &options, &target, &serving, session));
+    Ok((unmounter, thread))
+}
+
+/// Mounts with `mount`, once more without `allow_other` if that is refused
+/// and the user did not insist on it; `options` ends up as mounted.
+fn mount_with_fallback<T>(
+    options: &mut Vec<MountOption>,
+    config: &MountConfig,
+    mut mount: impl FnMut(&[MountOption]) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    match mount(options) {
+        // fuse.conf said yes, but the mount helper still refused allow_other.
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) && !config.allow_other => {
+            drop_allow_other(options, &config.mountpoint);
+            mount(options)
+        }
+        result => result,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mount_options(read_only: bool) -> Vec<MountOption> {
+    super::linux::mount_options(read_only)
+}
+
+#[cfg(target_os = "macos")]
+fn mount_options(read_only: bool) -> Vec<MountOption> {
+    super::macos::mount_options(read_only)
+}
+
+fn new_session(
+    config: &MountConfig,
+    options: &[MountOption],
+) -> std::io::Result<Session<RemoteFS>> {
+    let mut fs = RemoteFS::new(config.client.clone());
+    fs.set_warm_paths(config.warm.clone());
+    fs.set_warm_depth(config.warm_depth, config.warm_workers);
+    fs.set_warm_up(config.warm_up);
+    fs.set_owner(config.uid, config.gid);
+    fs.set_forced_modes(config.file_mode, config.dir_mode);
+    fs.set_stream_writes(config.stream_chunk);
+    fs.set_blksize(config.blksize);
+    fs.set_direct_io(Protected::new(&config.direct_io_for));
+    if let Some(file) = &config.inode_file {
+        fs.set_inode_file(file.clone());
+    }
+    let notifier = NotifierSlot::default();
+    fs.set_watch(config.watch_interval, Arc::clone(&notifier));
+    let session = Session::new(fs, Path::new(&config.mountpoint), options)?;
+    *notifier.lock().unwrap() = Some(session.notifier());
+    Ok(session)
+}
+
+/// Serves `session`, then remounts as allowed until a clean unmount.
+fn serve(
+    config: MountConfig,
+    options: &[MountOption],
+    target: &Path,
+    unmounter: &Unmounter,
+    first: Session<RemoteFS>,
+) -> Result<(), anyhow::Error> {
+    let mut next = Some(first);
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let session = match next.take() {
+            Some(session) => Ok(session),
+            None => {
+                // A session that died can leave a dead mount behind (ENOTCONN).
+                if still_mounted(target) {
+                    force_unmount(target, "remount");
+                }
+                new_session(&config, options)
+            }
+        };
+        let ended = match session {
+            Ok(mut session) => {
+                if unmounter.attach(&mut session) {
+                    let result = session.run();
+                    // Checked before the session is dropped, which unmounts a dead mount.
+                    let stale = still_mounted(target);
+                    unmounter.detach();
+                    drop(session);
+                    match result {
+                        _ if unmounter.stopping() => Ended::Unmounted,
+                        Ok(()) if !stale => Ended::Unmounted,
+                        Ok(()) => Ended::Dropped("FUSE connection aborted".to_string()),
+                        Err(e) => Ended::Dropped(format!("FUSE channel error: {}", e)),
+                    }
+                } else {
+                    // Unmount requested while remounting.
+                    Ended::Unmounted
+                }
+            }
+            Err(e) => Ended::Dropped(format!("remount failed: {}", e)),
+        };
+
+        let reason = match ended {
+            Ended::Unmounted => return Ok(()),
+            Ended::Dropped(reason) if !config.auto_remount => anyhow::bail!("{}", reason),
+            Ended::Dropped(reason) => reason,
+        };
+        if started.elapsed() >= STABLE_AFTER {
+            attempt = 0;
+        }
+        attempt += 1;
+        if attempt > config.remount_attempts {
+            anyhow::bail!(
+                "{}; giving up after {} remount attempts",
+                reason,
+                config.remount_attempts
+            );
+        }
+        let backoff = FIRST_BACKOFF
+            .saturating_mul(1 << (attempt - 1).min(5))
+            .min(MAX_BACKOFF);
+        logging::warn(
+            "mount",
+            format!(
+                "{}; remounting in {}s (attempt {} of {})",
+                reason,
+                backoff.as_secs(),
+                attempt,
+                config.remount_attempts
+            ),
+        )
+        .path(&config.mountpoint)
+        .emit();
+        if unmounter.sleep(backoff) {
+            return Ok(());
+        }
+    }
+}
+
+/// Setting in `FUSE_CONF` that lets users other than root mount with `allow_other`.
+const USER_ALLOW_OTHER: &str = "user_allow_other";
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+/// False when mounting with `allow_other` is bound to fail: not root, and
+/// `FUSE_CONF` does not enable `USER_ALLOW_OTHER` (stock Ubuntu).
+#[cfg(target_os = "linux")]
+fn allow_other_permitted() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    if unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+    std::fs::read_to_string(FUSE_CONF)
+        .is_ok_and(|conf| conf.lines().any(|line| line.trim() == USER_ALLOW_OTHER))
+}
+
+/// macFUSE allows `allow_other` unless an administrator turned it off,
+/// which only the mount attempt tells.
+#[cfg(target_os = "macos")]
+fn allow_other_permitted() -> bool {
+    true
+}
+
+/// Mounts for the mounting user only, and says how to let other users in.
+///
+/// `AutoUnmount` goes too: fuser adds `allow_other` back for it.
+fn drop_allow_other(options: &mut Vec<MountOption>, mountpoint: &str) {
+    options.retain(|o| !matches!(o, MountOption::AllowOther | MountOption::AutoUnmount));
+    logging::warn(
+        "mount",
+        format!(
+            "Other users are not allowed on FUSE mounts here, so only you can access this \
+             mount. To let them in, add this line to {} and mount again: {}",
+            FUSE_CONF, USER_ALLOW_OTHER
+        ),
+    )
+    .path(mountpoint)
+    .emit();
+}
+
+/// Explains a failed mount at `target` with what the error and the directory tell.
+fn describe_mount_error(e: &std::io::Error, target: &Path) -> String {
+    let shown = target.display();
+    let errno = e.raw_os_error();
+    // A dead FUSE mount answers ENOTCONN ("transport endpoint is not connected").
+    if errno == Some(libc::EBUSY) || errno == Some(libc::ENOTCONN) || still_mounted(target) {
+        return format!(
+            "mount point {} is already mounted; unmount it first ({} {}) or pass --force",
+            shown, UNMOUNT_COMMAND, shown
+        );
+    }
+    if e.kind() == std::io::ErrorKind::NotFound {
+        return format!("mount point {} does not exist", shown);
+    }
+    if errno == Some(libc::ENOTDIR) {
+        return format!("mount point {} is not a directory", shown);
+    }
+    if std::fs::read_dir(target).is_ok_and(|mut entries| entries.next().is_some()) {
+        return format!("{} (mount point {} is not empty)", e, shown);
+    }
+    format!(
+        "{}\nEnsure the mount point exists and you have the necessary permissions.",
+        e
+    )
+}
+
+/// Command suggested for unmounting by hand.
+#[cfg(target_os = "linux")]
+const UNMOUNT_COMMAND: &str = "fusermount -u";
+#[cfg(target_os = "macos")]
+const UNMOUNT_COMMAND: &str = "umount";
+
+/// Lazily detaches whatever is mounted at `target`, for `--force` or before
+/// a remount; `why` labels the log lines.
+fn force_unmount(target: &Path, why: &str) {
+    #[cfg(target_os = "linux")]
+    let attempts: &[(&str, &[&str])] = &[
+        ("fusermount3", &["-u", "-z"]),
+        ("fusermount", &["-u", "-z"]),
+        ("umount", &["-l"]),
+    ];
+    #[cfg(target_os = "macos")]
+    let attempts: &[(&str, &[&str])] = &[("umount", &["-f"]), ("diskutil", &["unmount", "force"])];
+    for (program, args) in attempts {
+        let unmounted = std::process::Command::new(program)
+            .args(*args)
+            .arg(target)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if unmounted {
+            logging::warn("mount", format!("Unmounted the previous mount ({})", why))
+                .path(&target.to_string_lossy())
+                .emit();
+            return;
+        }
+    }
+    logging::warn(
+        "mount",
+        format!("Could not unmount the previous mount ({})", why),
+    )
+    .path(&target.to_string_lossy())
+    .emit();
+}
+
+/// True when `target` is still listed as a mount point of this process.
+#[cfg(target_os = "linux")]
+fn still_mounted(target: &Path) -> bool {
+    let Ok(mountinfo) = std::fs::read_to_string("/proc/self/mountinfo") else {
+        return false;
+    };
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .any(|point| Path::new(&unescape_mountinfo(point)) == target)
+}
+
+/// Decodes the octal escapes (`\040` for a space) used in mountinfo paths.
+#[cfg(target_os = "linux")]
+fn unescape_mountinfo(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes
+            .get(i + 1..i + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u8::from_str_radix(d, 8).ok());
+        match code {
+            Some(c) if bytes[i] == b'\\' => {
+                out.push(c);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// True when `target` is still listed as a mount point.
+#[cfg(target_os = "macos")]
+fn still_mounted(target: &Path) -> bool {
+    let mut mounts: *mut libc::statfs = std::ptr::null_mut();
+    // SAFETY: getmntinfo points `mounts` at a buffer it owns, valid until the next call.
+    let count = unsafe { libc::getmntinfo(&mut mounts, libc::MNT_NOWAIT) };
+    if count <= 0 || mounts.is_null() {
+        return false;
+    }
+    // SAFETY: `count` entries were filled in by getmntinfo above.
+    let mounts = unsafe { std::slice::from_raw_parts(mounts, count as usize) };
+    mounts.iter().any(|m| {
+        // SAFETY: f_mntonname is a NUL-terminated C string.
+        let point = unsafe { std::ffi::CStr::from_ptr(m.f_mntonname.as_ptr()) };
+        Path::new(&*point.to_string_lossy()) == target
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote_client::RemoteClient;
+    use crate::types::CacheConfig;
+    use std::io::Error;
+
+    fn config(allow_other: bool) -> MountConfig {
+        let client = RemoteClient::new("http://127.0.0.1:1", CacheConfig::default());
+        let mut config = MountConfig::new(client, "/mnt/remote");
+        config.allow_other = allow_other;
+        config
+    }
+
+    fn options() -> Vec<MountOption> {
+        vec![
+            MountOption::FSName("remote-fs".to_string()),
+            MountOption::AllowOther,
+            MountOption::AutoUnmount,
+        ]
+    }
+
+    fn has_allow_other(options: &[MountOption]) -> bool {
+        options.iter().any(|o| matches!(o, MountOption::AllowOther))
+    }
+
+    #[test]
+    fn refused_allow_other_mounts_again_without_it() {
+        let mut options = options();
+        let mut tried = Vec::new();
+        let result = mount_with_fallback(&mut options, &config(false), |options| {
+            tried.push(has_allow_other(options));
+            if has_allow_other(options) {
+                Err(Error::from_raw_os_error(libc::EPERM))
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(tried, [true, false]);
+        assert_eq!(options.len(), 1);
+    }
+
+    #[test]
+    fn requested_allow_other_is_not_dropped() {
+        let mut options = options();
+        let mut calls = 0;
+        let result = mount_with_fallback(&mut options, &config(true), |_| {
+            calls += 1;
+            Err::<(), _>(Error::from_raw_os_error(libc::EPERM))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EPERM));
+        assert_eq!(calls, 1);
+        assert!(has_allow_other(&options));
+    }
+
+    #[test]
+    fn mount_errors_are_described_by_cause() {
+        let empty = tempfile::tempdir().unwrap();
+        let full = tempfile::tempdir().unwrap();
+        let file = full.path().join("file");
+        std::fs::write(&file, b"").unwrap();
+        let missing = empty.path().join("missing");
+        let cases: &[(i32, &Path, &str)] = &[
+            (libc::EBUSY, empty.path(), "is already mounted"),
+            (libc::ENOTCONN, empty.path(), "is already mounted"),
+            (libc::ENOENT, &missing, "does not exist"),
+            (libc::ENOTDIR, &file, "is not a directory"),
+            (libc::EPERM, full.path(), "is not empty"),
+            (libc::EACCES, empty.path(), "necessary permissions"),
+            (libc::EPERM, empty.path(), "necessary permissions"),
+        ];
+        for &(errno, target, expected) in cases {
+            let message = describe_mount_error(&Error::from_raw_os_error(errno), target);
+            assert!(
+                message.contains(expected),
+                "errno {}: {:?} lacks {:?}",
+                errno,
+                message,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn other_mount_errors_are_not_retried() {
+        let mut options = options();
+        let mut calls = 0;
+        let result = mount_with_fallback(&mut options, &config(false), |_| {
+            calls += 1;
+            Err::<(), _>(Error::from_raw_os_error(libc::ENOENT))
+        });
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,322 @@
+use fuser::FileType;
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::time::{Duration, Instant};
+
+/// Reads in a row that must start where the previous one ended before the
+/// handle counts as sequential and reads ahead.
+const SEQUENTIAL_AFTER: u32 = 2;
+/// Sequential reads fetch this many times the requested size, up to the
+/// configured readahead limit.
+const READAHEAD_FACTOR: u64 = 8;
+/// Writes shorter than this are collected in memory while they follow one
+/// another, instead of each seeking and writing the local copy.
+const SMALL_WRITE: usize = 4096;
+/// Collected small writes are written to the local copy once they reach this size.
+const COALESCE_LIMIT: usize = 64 * 1024;
+
+/// Directory entry captured at `opendir`: inode, kind and name.
+pub type DirEntry = (u64, FileType, String);
+
+/// Where a directory handle listed page by page stands; `dir` holds only
+/// the current page.
+pub struct DirPages {
+    /// Index of the first entry of `dir` in the whole listing.
+    pub base: usize,
+    /// Cursor of the page after `dir`; `None` once the last page is in.
+    pub next: Option<String>,
+}
+
+/// State of one handle returned by `open`, `create` or `opendir`.
+pub struct OpenFile {
+    pub path: String,
+    /// Flags passed to `open`; 0 for directories.
+    pub flags: i32,
+    /// Opened uncached (`O_DIRECT` or `--direct-io-for`): reads bypass the
+    /// file cache and readahead.
+    pub direct: bool,
+    /// Local copy of the content, for write opens and uncached reads. Call
+    /// `spill` first: the latest small writes may still be in `coalesced`.
+    pub buffer: Option<std::fs::File>,
+    /// Contiguous small writes not in `buffer` yet: (offset in `buffer`, bytes).
+    coalesced: Option<(u64, Vec<u8>)>,
+    /// Byte ranges written or truncated since the last upload, sorted and disjoint.
+    dirty: Vec<Range<u64>>,
+    /// When the handle last went from clean to dirty.
+    dirty_since: Option<Instant>,
+    /// For a streaming write, the bytes already uploaded: `buffer` holds only
+    /// what follows them. `None` when `buffer` is the whole file.
+    streamed: Option<u64>,
+    /// Where the previous read on this handle ended.
+    next_read: u64,
+    /// Reads in a row that started where the previous one ended.
+    sequential_reads: u32,
+    /// Data fetched past the end of the last read: (offset, bytes).
+    readahead: Option<(u64, Vec<u8>)>,
+    /// Listing taken at `opendir`, so one enumeration sees a stable set of entries.
+    pub dir: Option<Vec<DirEntry>>,
+    /// Set when the listing is fetched page by page as `readdir` advances.
+    pub pages: Option<DirPages>,
+}
+
+impl OpenFile {
+    pub fn new(path: String, flags: i32) -> Self {
+        Self {
+            path,
+            flags,
+            direct: false,
+            buffer: None,
+            coalesced: None,
+            dirty: Vec::new(),
+            dirty_since: None,
+            streamed: None,
+            next_read: 0,
+            sequential_reads: 0,
+            readahead: None,
+            dir: None,
+            pages: None,
+        }
+    }
+
+    pub fn directory(path: String, entries: Vec<DirEntry>) -> Self {
+        Self {
+            dir: Some(entries),
+            ..Self::new(path, 0)
+        }
+    }
+
+    pub fn with_buffer(path: String, flags: i32, buffer: std::fs::File) -> Self {
+        Self {
+            buffer: Some(buffer),
+            ..Self::new(path, flags)
+        }
+    }
+
+    /// Writes `data` at `at` in the local copy, holding small writes that
+    /// continue the previous one in memory until they add up.
+    pub fn write_at(&mut self, at: u64, data: &[u8]) -> std::io::Result<()> {
+        if data.len() < SMALL_WRITE {
+            if let Some((start, bytes)) = &mut self.coalesced {
+                if *start + bytes.len() as u64 == at {
+                    bytes.extend_from_slice(data);
+                    if bytes.len() >= COALESCE_LIMIT {
+                        self.spill()?;
+                    }
+                    return Ok(());
+                }
+            }
+            self.spill()?;
+            self.coalesced = Some((at, data.to_vec()));
+            return Ok(());
+        }
+        self.spill()?;
+        let file = self.buffer.as_mut().ok_or(std::io::ErrorKind::NotFound)?;
+        file.seek(SeekFrom::Start(at))?;
+        file.write_all(data)
+    }
+
+    /// Writes the collected small writes to the local copy.
+    pub fn spill(&mut self) -> std::io::Result<()> {
+        let Some((start, bytes)) = self.coalesced.take() else {
+            return Ok(());
+        };
+        let file = self.buffer.as_mut().ok_or(std::io::ErrorKind::NotFound)?;
+        file.seek(SeekFrom::Start(start))?;
+        file.write_all(&bytes)
+    }
+
+    /// The local copy with every write in it; `None` for handles without one.
+    pub fn spilled_buffer(&mut self) -> std::io::Result<Option<&mut std::fs::File>> {
+        self.spill()?;
+        Ok(self.buffer.as_mut())
+    }
+
+    /// Length of the local copy including the small writes not spilled yet,
+    /// and its allocated 512-byte blocks.
+    pub fn buffered_len(&self) -> Option<(u64, u64)> {
+        let meta = self.buffer.as_ref()?.metadata().ok()?;
+        let end = self
+            .coalesced
+            .as_ref()
+            .map_or(0, |(start, bytes)| start + bytes.len() as u64);
+        let len = meta.len().max(end);
+        Some((len, meta.blocks() + (len - meta.len()).div_ceil(512)))
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Records that `range` differs from the server copy.
+    ///
+    /// An empty range still marks the handle dirty: a truncation to the current
+    /// size must be uploaded when the server copy is longer.
+    pub fn mark_dirty(&mut self, range: Range<u64>) {
+        self.readahead = None;
+        self.dirty_since.get_or_insert_with(Instant::now);
+        self.dirty.push(range);
+        self.dirty.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.dirty.len());
+        for r in self.dirty.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        self.dirty = merged;
+    }
+
+    /// Forgets the dirty ranges once their content has been uploaded.
+    pub fn mark_clean(&mut self) {
+        self.dirty.clear();
+        self.dirty_since = None;
+    }
+
+    /// How long the handle has held writes not uploaded yet.
+    pub fn dirty_age(&self) -> Option<Duration> {
+        self.dirty_since.map(|since| since.elapsed())
+    }
+
+    /// Uploads this handle's writes in pieces; see `streamed`.
+    pub fn start_streaming(&mut self) {
+        self.streamed = Some(0);
+    }
+
+    /// Bytes a streaming write already uploaded, or `None` when not streaming.
+    pub fn streamed(&self) -> Option<u64> {
+        self.streamed
+    }
+
+    /// Records that the `len` buffered bytes were uploaded and dropped locally.
+    pub fn advance_stream(&mut self, len: u64) {
+        if let Some(sent) = &mut self.streamed {
+            *sent += len;
+        }
+        self.mark_clean();
+    }
+
+    /// Goes back to buffering the whole file in `buffer`.
+    pub fn stop_streaming(&mut self) {
+        self.streamed = None;
+    }
+
+    /// Returns the readahead bytes covering a read of `size` at `offset`, if any.
+    pub fn readahead_hit(&mut self, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let (start, data) = self.readahead.as_ref()?;
+        let end = start + data.len() as u64;
+        if offset < *start || offset + size as u64 > end {
+            return None;
+        }
+        let from = (offset - start) as usize;
+        let hit = data[from..from + size as usize].to_vec();
+        self.record_read(offset, size);
+        Some(hit)
+    }
+
+    /// Updates the sequential-read tracking and returns how many bytes the
+    /// read of `size` at `offset` should fetch from the server, reading at
+    /// most `max_readahead` bytes ahead (0 fetches exactly `size`).
+    pub fn plan_read(&mut self, offset: u64, size: u32, max_readahead: u64) -> u64 {
+        self.record_read(offset, size);
+        if max_readahead > 0 && self.sequential_reads >= SEQUENTIAL_AFTER {
+            (size as u64 * READAHEAD_FACTOR)
+                .min(max_readahead)
+                .max(size as u64)
+        } else {
+            size as u64
+        }
+    }
+
+    /// Keeps whatever a read fetched beyond the `size` bytes it returns.
+    pub fn keep_readahead(&mut self, offset: u64, size: u32, mut data: Vec<u8>) -> Vec<u8> {
+        if data.len() > size as usize {
+            let rest = data.split_off(size as usize);
+            self.readahead = Some((offset + size as u64, rest));
+        } else {
+            self.readahead = None;
+        }
+        data
+    }
+
+    fn record_read(&mut self, offset: u64, size: u32) {
+        if offset == self.next_read && offset != 0 {
+            self.sequential_reads = self.sequential_reads.saturating_add(1);
+        } else if offset != self.next_read {
+            self.sequential_reads = 0;
+            self.readahead = None;
+        }
+        self.next_read = offset + size as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const KB: u32 = 1024;
+
+    #[test]
+    fn sequential_reads_start_reading_ahead() {
+        let mut handle = OpenFile::new("f".to_string(), libc::O_RDONLY);
+        let max = 1024 * 1024;
+        assert_eq!(handle.plan_read(0, 128 * KB, max), 128 * 1024);
+        assert_eq!(handle.plan_read(128 * 1024, 128 * KB, max), 128 * 1024);
+        assert_eq!(handle.plan_read(256 * 1024, 128 * KB, max), max);
+        // Bounded by the limit, never below the read itself.
+        assert_eq!(handle.plan_read(384 * 1024, 128 * KB, 4096), 128 * 1024);
+        assert_eq!(handle.plan_read(512 * 1024, 4 * KB, 0), 4 * 1024);
+    }
+
+    #[test]
+    fn random_read_resets_detection_and_drops_readahead() {
+        let mut handle = OpenFile::new("f".to_string(), libc::O_RDONLY);
+        for i in 0..3 {
+            handle.plan_read(i * 4096, 4 * KB, 1 << 20);
+        }
+        let data = handle.keep_readahead(8192, 4 * KB, vec![7; 32 * 1024]);
+        assert_eq!(data.len(), 4096);
+        assert_eq!(handle.readahead_hit(12288, 4 * KB), Some(vec![7; 4096]));
+        assert_eq!(handle.plan_read(0, 4 * KB, 1 << 20), 4096);
+        assert_eq!(handle.readahead_hit(16384, 4 * KB), None);
+        assert_eq!(handle.plan_read(4096, 4 * KB, 1 << 20), 4096);
+    }
+
+    #[test]
+    fn dirty_ranges_merge_and_clear() {
+        let mut handle = OpenFile::new("f".to_string(), libc::O_WRONLY);
+        assert!(!handle.is_dirty());
+        handle.mark_dirty(10..20);
+        handle.mark_dirty(0..5);
+        handle.mark_dirty(15..30);
+        handle.mark_dirty(5..5);
+        assert_eq!(handle.dirty, [0..5, 10..30]);
+        assert!(handle.dirty_age().is_some());
+        handle.mark_clean();
+        assert!(!handle.is_dirty());
+        assert!(handle.dirty_age().is_none());
+    }
+
+    #[test]
+    fn writes_invalidate_readahead() {
+        let mut handle = OpenFile::new("f".to_string(), libc::O_RDWR);
+        handle.keep_readahead(0, 4 * KB, vec![0; 8192]);
+        handle.mark_dirty(0..1);
+        assert_eq!(handle.readahead_hit(4096, 4 * KB), None);
+    }
+
+    #[test]
+    fn small_writes_reach_the_buffer_once_spilled() {
+        let file = tempfile::tempfile().unwrap();
+        let mut handle = OpenFile::with_buffer("f".to_string(), libc::O_WRONLY, file);
+        handle.write_at(0, b"hello ").unwrap();
+        handle.write_at(6, b"world").unwrap();
+        assert_eq!(handle.buffered_len().unwrap().0, 11);
+        let file = handle.spilled_buffer().unwrap().unwrap();
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+    }
+}
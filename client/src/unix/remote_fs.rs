@@ -1,13 +1,46 @@
-use crate::remote_client::{ProgressReader, RemoteClient};
-use crate::types::{join_path, parent_of, CacheConfig};
+use super::control;
+use super::inodes::{moved_to, InodeFile, InodeTable, Saver, ROOT_INO};
+use super::open_file::{DirEntry, DirPages, OpenFile};
+use super::search::{self, SearchPath, SEARCH_DIR};
+use super::watch::{ListingInvalidator, NotifierSlot, Watched, Watcher};
+use crate::error::RemoteError;
+use crate::hooks::{EventKind, TransferEvent};
+use crate::logging;
+use crate::overlay::{self, Reconciler, DRAIN_TIMEOUT};
+use crate::protect::Protected;
+use crate::raw_names;
+use crate::remote_client::{RemoteClient, NAME_MAX};
+use crate::types::{
+    epoch_time, join_path, normalize_path, parent_of, EntryKind, RemoteEntry, SearchHit,
+};
+use crate::version_file::{self, Activity, VERSION_FILE};
+use crate::warm::Warmer;
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyStatfs,
+    Request,
 };
 use std::collections::HashMap;
 use std::ffi::OsStr;
-use std::io::{Read, Seek, SeekFrom, Write as IoWrite};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// `renameat2` flags as delivered by the kernel on each platform.
+#[cfg(target_os = "linux")]
+const RENAME_NOREPLACE: u32 = libc::RENAME_NOREPLACE;
+#[cfg(target_os = "linux")]
+const RENAME_EXCHANGE: u32 = libc::RENAME_EXCHANGE;
+#[cfg(target_os = "macos")]
+const RENAME_NOREPLACE: u32 = libc::RENAME_EXCL;
+#[cfg(target_os = "macos")]
+const RENAME_EXCHANGE: u32 = libc::RENAME_SWAP;
+
+/// `O_DIRECT` as delivered by the kernel; macOS has no such open flag.
+#[cfg(target_os = "linux")]
+const O_DIRECT: i32 = libc::O_DIRECT;
+#[cfg(target_os = "macos")]
+const O_DIRECT: i32 = 0;
 
 /// Filters Finder metadata files that should not be mirrored remotely.
 fn is_macos_metadata(name: &OsStr) -> bool {
@@ -15,231 +48,1172 @@ fn is_macos_metadata(name: &OsStr) -> bool {
     s.starts_with("._") || s == ".DS_Store" || s == ".localized"
 }
 
-/// Buffered write state associated with an open file handle.
-struct WriteBuffer {
-    file: std::fs::File,
-    path: String,
-    dirty: bool,
+/// A server without `/search` makes the whole `.search` tree missing.
+fn search_errno(e: &RemoteError) -> i32 {
+    match e {
+        RemoteError::Unsupported(_) => libc::ENOENT,
+        e => e.to_errno(),
+    }
 }
 
-/// Builds FUSE attributes from remote metadata.
-fn make_attr(ino: u64, size: u64, kind: FileType) -> FileAttr {
-    let now = SystemTime::now();
-    FileAttr {
-        ino,
-        size,
-        blocks: (size + 511) / 512,
-        atime: now,
-        mtime: now,
-        ctime: now,
-        crtime: now,
-        kind,
-        perm: if kind == FileType::Directory {
-            0o755
-        } else {
-            0o644
-        },
-        nlink: if kind == FileType::Directory { 2 } else { 1 },
-        uid: unsafe { libc::getuid() },
-        gid: unsafe { libc::getgid() },
-        rdev: 0,
-        blksize: 512,
-        flags: 0,
+fn file_type(kind: EntryKind) -> FileType {
+    match kind {
+        EntryKind::Dir => FileType::Directory,
+        EntryKind::File => FileType::RegularFile,
+        EntryKind::Symlink => FileType::Symlink,
+        EntryKind::Fifo => FileType::NamedPipe,
+        EntryKind::Socket => FileType::Socket,
+        EntryKind::CharDev => FileType::CharDevice,
+        EntryKind::BlockDev => FileType::BlockDevice,
     }
 }
 
+/// Returns the part of `data` covered by a read of `size` bytes at `offset`.
+fn slice_at(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let start = (offset as usize).min(data.len());
+    let end = (start + size as usize).min(data.len());
+    &data[start..end]
+}
+
+/// `st_blksize` unless `--blksize` says otherwise: large enough that
+/// applications sizing their reads by it do not issue one request per page.
+pub const DEFAULT_BLKSIZE: u32 = 128 * 1024;
+
+/// Inode reserved for the synthetic version file at the mount root.
+const VERSION_INO: u64 = u64::MAX;
+
+/// How often a persisted inode table is saved while mounted.
+const INODE_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Checks the owner permission bits against the access mode requested in `open`.
+fn access_permitted(entry: &RemoteEntry, flags: i32) -> bool {
+    let Some(mode) = entry.mode else {
+        return true;
+    };
+    let access = flags & libc::O_ACCMODE;
+    let wants_read = access == libc::O_RDONLY || access == libc::O_RDWR;
+    let wants_write =
+        access == libc::O_WRONLY || access == libc::O_RDWR || (flags & libc::O_TRUNC) != 0;
+    (!wants_read || mode & 0o400 != 0) && (!wants_write || mode & 0o200 != 0)
+}
+
 /// FUSE implementation that maps local VFS operations to the remote HTTP API.
 pub struct RemoteFS {
     rc: RemoteClient,
-    inode_counter: u64,
-    inode_to_path: Arc<Mutex<HashMap<u64, String>>>,
-    path_to_inode: Arc<Mutex<HashMap<String, u64>>>,
-    write_buffers: HashMap<u64, WriteBuffer>,
+    inodes: InodeTable,
+    /// Every handle handed out by `open`, `create` and `opendir`.
+    open_files: HashMap<u64, OpenFile>,
     fh_counter: u64,
+    warm_paths: Vec<String>,
+    warmer: Option<Warmer>,
+    /// `--warm-cache` depth and parallelism, and the warm-up it started.
+    warm_depth: Option<usize>,
+    warm_workers: usize,
+    metadata_warmer: Option<Warmer>,
+    /// Whether `init` opens a server connection in the background.
+    warm_up: bool,
+    reconciler: Option<Reconciler>,
+    /// Content of the synthetic version file, rendered at mount.
+    version_info: Vec<u8>,
+    /// Times reported for the root and synthetic files, so they stay stable.
+    mounted_at: SystemTime,
+    /// Ownership forced with `--uid`/`--gid`.
+    uid: Option<u32>,
+    gid: Option<u32>,
+    /// Creation modes forced with `--force-file-mode`/`--force-dir-mode`.
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    /// Piece size of `--stream-writes`; `None` uploads whole files at flush.
+    stream_chunk: Option<u64>,
+    /// Preferred I/O size reported in `st_blksize` and `f_bsize`.
+    blksize: u32,
+    /// Paths always opened uncached (`--direct-io-for`).
+    direct_io: Protected,
+    /// Where the inode table is kept between mounts (`--persist-inodes`).
+    inode_file: Option<InodeFile>,
+    inode_saver: Option<Saver>,
+    /// Latest results of each query looked up under `.search`.
+    searches: HashMap<String, Vec<SearchHit>>,
+    /// Cached read handles re-checked for growth; see `set_watch`.
+    watched: Watched,
+    watch_interval: Duration,
+    notifier: NotifierSlot,
+    watcher: Option<Watcher>,
 }
 
 impl RemoteFS {
-    pub fn new(base_url: &str, cache_config: CacheConfig) -> Self {
-        let mut inode_to_path = HashMap::new();
-        let mut path_to_inode = HashMap::new();
-        inode_to_path.insert(1, String::new());
-        path_to_inode.insert(String::new(), 1);
-
+    pub fn new(rc: RemoteClient) -> Self {
         Self {
-            rc: RemoteClient::new(base_url, cache_config),
-            inode_counter: 1,
-            inode_to_path: Arc::new(Mutex::new(inode_to_path)),
-            path_to_inode: Arc::new(Mutex::new(path_to_inode)),
-            write_buffers: HashMap::new(),
+            rc,
+            inodes: InodeTable::new(),
+            open_files: HashMap::new(),
             fh_counter: 0,
+            warm_paths: Vec::new(),
+            warmer: None,
+            warm_depth: None,
+            warm_workers: 1,
+            metadata_warmer: None,
+            warm_up: true,
+            reconciler: None,
+            version_info: Vec::new(),
+            mounted_at: SystemTime::now(),
+            uid: None,
+            gid: None,
+            file_mode: None,
+            dir_mode: None,
+            stream_chunk: None,
+            blksize: DEFAULT_BLKSIZE,
+            direct_io: Protected::default(),
+            inode_file: None,
+            inode_saver: None,
+            searches: HashMap::new(),
+            watched: Watched::default(),
+            watch_interval: Duration::ZERO,
+            notifier: NotifierSlot::default(),
+            watcher: None,
+        }
+    }
+
+    /// Reports every file as owned by `uid`/`gid` instead of the server or mounting user.
+    pub fn set_owner(&mut self, uid: Option<u32>, gid: Option<u32>) {
+        self.uid = uid;
+        self.gid = gid;
+    }
+
+    /// Re-checks files open for reading every `interval` (zero disables),
+    /// invalidating the kernel cache through `notifier` when one grows.
+    pub fn set_watch(&mut self, interval: Duration, notifier: NotifierSlot) {
+        self.watch_interval = interval;
+        self.notifier = notifier;
+    }
+
+    /// Permission bits given to every file and directory created through the
+    /// mount, whatever mode the creating process asked for.
+    pub fn set_forced_modes(&mut self, file_mode: Option<u32>, dir_mode: Option<u32>) {
+        self.file_mode = file_mode;
+        self.dir_mode = dir_mode;
+    }
+
+    /// Uploads files created or truncated write-only in pieces of `chunk`
+    /// bytes as they are written, instead of buffering them whole until flush.
+    pub fn set_stream_writes(&mut self, chunk: Option<u64>) {
+        self.stream_chunk = chunk;
+    }
+
+    /// Preferred I/O size reported to applications (`--blksize`). Block
+    /// counts stay in 512-byte units whatever it is.
+    pub fn set_blksize(&mut self, blksize: u32) {
+        self.blksize = blksize;
+    }
+
+    /// Opens files matching these patterns as if with `O_DIRECT`, using the
+    /// same glob rules as `--protect`.
+    pub fn set_direct_io(&mut self, paths: Protected) {
+        self.direct_io = paths;
+    }
+
+    /// Numbers paths as the mount saved at `file` did, and keeps `file`
+    /// current while mounted and at unmount.
+    pub fn set_inode_file(&mut self, file: PathBuf) {
+        let file = InodeFile::new(file, self.rc.name_cipher());
+        self.inodes = file.load();
+        self.inode_file = Some(file);
+    }
+
+    /// True when an open of `path` with `flags` bypasses every cache: the
+    /// kernel page cache, the client file cache and readahead.
+    fn opens_direct(&self, path: &str, flags: i32) -> bool {
+        flags & O_DIRECT != 0 || self.direct_io.covers(path)
+    }
+
+    /// Open file handles and the writes they hold that the server has not seen.
+    fn activity(&self) -> Activity {
+        let mut activity = Activity::default();
+        let files = self
+            .open_files
+            .values()
+            .filter(|h| h.dir.is_none() && h.path != VERSION_FILE);
+        for handle in files {
+            activity.open_handles += 1;
+            let Some(age) = handle.dirty_age() else {
+                continue;
+            };
+            activity.dirty_buffers += 1;
+            activity.dirty_bytes += handle.buffered_len().map_or(0, |(len, _)| len);
+            activity.oldest_dirty = activity.oldest_dirty.max(Some(age));
+            activity.pending_uploads.push(handle.path.clone());
+        }
+        activity.pending_uploads.sort();
+        activity.pending_uploads.dedup();
+        activity.warm_files = self.warmer.as_ref().map(Warmer::progress);
+        activity.warm_dirs = self.metadata_warmer.as_ref().map(Warmer::progress);
+        activity
+    }
+
+    /// Whether a new handle with `flags` and an empty buffer streams its writes.
+    fn streams(&self, flags: i32) -> bool {
+        self.stream_chunk.is_some() && flags & libc::O_ACCMODE == libc::O_WRONLY
+    }
+
+    /// Appends the buffer of streaming handle `fh` to what the server already
+    /// has and empties it, so the local copy never grows much past one piece.
+    fn upload_piece(&mut self, fh: u64) -> Result<(), RemoteError> {
+        let Some(handle) = self.open_files.get_mut(&fh) else {
+            return Ok(());
+        };
+        if !handle.is_dirty() {
+            return Ok(());
+        }
+        handle.spill()?;
+        let (Some(offset), Some(file)) = (handle.streamed(), handle.buffer.as_mut()) else {
+            return Ok(());
+        };
+        let size = file.metadata()?.len();
+        file.seek(SeekFrom::Start(0))?;
+        let started = Instant::now();
+        let result = self
+            .rc
+            .upload_at(&handle.path, offset, file.try_clone()?, size);
+        self.rc.emit(TransferEvent {
+            kind: EventKind::Upload,
+            path: handle.path.clone(),
+            size,
+            duration: started.elapsed(),
+            success: result.is_ok(),
+        });
+        result?;
+        file.set_len(0)?;
+        handle.advance_stream(size);
+        self.rc.invalidate(&handle.path);
+        Ok(())
+    }
+
+    /// Turns streaming handle `fh` back into a buffer of the whole file, for
+    /// a write or truncation that reaches into what was already uploaded.
+    fn restore_full_copy(&mut self, fh: u64) -> Result<(), RemoteError> {
+        let Some(handle) = self.open_files.get_mut(&fh) else {
+            return Ok(());
+        };
+        handle.spill()?;
+        let (Some(sent), Some(file)) = (handle.streamed(), handle.buffer.as_mut()) else {
+            return Ok(());
+        };
+        let mut copy = tempfile::tempfile()?;
+        if sent > 0 {
+            self.rc.download_to(&handle.path, &mut copy)?;
+            copy.set_len(sent)?;
+            copy.seek(SeekFrom::Start(sent))?;
         }
+        file.seek(SeekFrom::Start(0))?;
+        let size = sent + std::io::copy(file, &mut copy)?;
+        handle.buffer = Some(copy);
+        handle.stop_streaming();
+        handle.mark_dirty(0..size);
+        logging::debug(
+            "write",
+            "Write behind the uploaded part, buffering the whole file",
+        )
+        .path(&handle.path)
+        .emit();
+        Ok(())
+    }
+
+    /// Permission bits for a new file or directory: the forced mode, else the
+    /// requested `mode` less the caller's `umask`.
+    fn creation_mode(&self, mode: u32, umask: u32, is_dir: bool) -> u32 {
+        let forced = if is_dir {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        forced.unwrap_or(mode & !umask) & 0o777
+    }
+
+    /// Remote subtrees to prefetch in the background once the mount is up.
+    pub fn set_warm_paths(&mut self, paths: Vec<String>) {
+        self.warm_paths = paths;
+    }
+
+    /// Lists the tree `depth` levels deep into the caches once the mount is up.
+    pub fn set_warm_depth(&mut self, depth: Option<usize>, workers: usize) {
+        self.warm_depth = depth;
+        self.warm_workers = workers;
+    }
+
+    /// Opens a server connection in the background once the mount is up.
+    pub fn set_warm_up(&mut self, enabled: bool) {
+        self.warm_up = enabled;
     }
 
     fn inode_path(&self, ino: u64) -> Option<String> {
-        self.inode_to_path.lock().unwrap().get(&ino).cloned()
+        self.inodes.path_of(ino)
     }
 
     fn child_path(&self, parent: u64, name: &OsStr) -> (String, String) {
         let parent_path = self.inode_path(parent).unwrap_or_default();
-        let full = join_path(&parent_path, &name.to_string_lossy());
+        let full = join_path(&parent_path, &raw_names::from_os(name));
         (parent_path, full)
     }
 
     fn alloc_inode(&mut self, path: String) -> u64 {
-        let mut p2i = self.path_to_inode.lock().unwrap();
-        if let Some(&ino) = p2i.get(&path) {
-            return ino;
+        self.inodes.get_or_alloc(path)
+    }
+
+    /// `alloc_inode` for an entry about to be handed to the kernel, which
+    /// keeps the inode until it forgets it.
+    fn lookup_inode(&mut self, path: String) -> u64 {
+        self.inodes.look_up(path)
+    }
+
+    /// Moves the inodes and open handles of `old` and everything below it to `new`.
+    fn rename_paths(&mut self, old: &str, new: &str) {
+        self.inodes.rename_prefix(old, new);
+        for handle in self.open_files.values_mut() {
+            if let Some(to) = moved_to(&handle.path, old, new) {
+                handle.path = to;
+            }
         }
-        self.inode_counter += 1;
-        let ino = self.inode_counter;
-        p2i.insert(path.clone(), ino);
-        drop(p2i);
-        self.inode_to_path.lock().unwrap().insert(ino, path);
-        ino
     }
 
     fn remove_inode(&mut self, path: &str) {
-        let mut p2i = self.path_to_inode.lock().unwrap();
-        if let Some(ino) = p2i.remove(path) {
-            drop(p2i);
-            self.inode_to_path.lock().unwrap().remove(&ino);
+        self.inodes.remove(path);
+    }
+
+    /// Looks up a single entry through its parent's (cached) listing.
+    fn stat(&mut self, path: &str) -> Option<RemoteEntry> {
+        self.try_stat(path).ok().flatten()
+    }
+
+    /// `stat` that tells a missing entry from a parent listing that failed,
+    /// for callbacks whose answer must not turn an outage into `ENOENT`.
+    fn try_stat(&mut self, path: &str) -> Result<Option<RemoteEntry>, RemoteError> {
+        let filename = path.rsplit('/').next().unwrap_or("");
+        let entries = self.rc.list_dir(&parent_of(path))?;
+        Ok(entries.into_iter().find(|e| e.name == filename))
+    }
+
+    /// Builds FUSE attributes owned by `--uid`/`--gid`, or the mounting user.
+    fn make_attr(&self, ino: u64, size: u64, kind: FileType) -> FileAttr {
+        let now = SystemTime::now();
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            kind,
+            perm: if kind == FileType::Directory {
+                0o755
+            } else {
+                0o644
+            },
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid: self.uid.unwrap_or_else(|| unsafe { libc::getuid() }),
+            gid: self.gid.unwrap_or_else(|| unsafe { libc::getgid() }),
+            rdev: 0,
+            blksize: self.blksize,
+            flags: 0,
+        }
+    }
+
+    /// Builds FUSE attributes for a listed entry, keeping server-side permission bits, times and owner.
+    fn entry_attr(&self, ino: u64, entry: &RemoteEntry) -> FileAttr {
+        let kind = file_type(entry.kind);
+        // A directory's size is its number of entries, as on many local filesystems.
+        let size = match entry.entry_count {
+            Some(count) if entry.is_dir() => count,
+            _ => entry.size,
+        };
+        let mut attr = self.make_attr(ino, size, kind);
+        if let Some(mode) = entry.mode {
+            attr.perm = (mode & 0o777) as u16;
+        }
+        // Explicit --uid/--gid win over the server's ownership.
+        if let Some(uid) = self.uid.or(entry.uid) {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.gid.or(entry.gid) {
+            attr.gid = gid;
+        }
+        if let Some(mtime) = entry.mtime.map(epoch_time) {
+            attr.atime = mtime;
+            attr.mtime = mtime;
+            // Without a birth time the last modification is the best lower bound we have.
+            attr.crtime = entry.crtime.map_or(mtime, epoch_time);
+        }
+        if let Some(ctime) = entry.ctime {
+            attr.ctime = epoch_time(ctime);
         }
+        // What the server actually allocated, so `du` sees sparse and compressed files as they are.
+        if let Some(blocks) = entry.blocks {
+            attr.blocks = blocks;
+        }
+        if let Some((size, blocks)) = self.pending_size(ino).filter(|_| !entry.is_dir()) {
+            attr.size = size;
+            attr.blocks = blocks;
+        }
+        attr
+    }
+
+    /// Size and allocated 512-byte blocks of `ino` as written through an
+    /// open handle but not uploaded yet. Blocks come from the local copy, so
+    /// holes left by writes past the end are not counted.
+    fn pending_size(&self, ino: u64) -> Option<(u64, u64)> {
+        if !self.open_files.values().any(|h| h.is_dirty()) {
+            return None;
+        }
+        let path = self.inode_path(ino)?;
+        self.open_files
+            .values()
+            .filter(|h| h.is_dirty() && h.path == path)
+            .filter_map(|h| {
+                let (len, blocks) = h.buffered_len()?;
+                let sent = h.streamed().unwrap_or(0);
+                Some((sent + len, sent.div_ceil(512) + blocks))
+            })
+            .max()
+    }
+
+    /// Builds attributes stamped with the mount time instead of the current time.
+    fn mount_time_attr(&self, ino: u64, size: u64, kind: FileType) -> FileAttr {
+        FileAttr {
+            atime: self.mounted_at,
+            mtime: self.mounted_at,
+            ctime: self.mounted_at,
+            crtime: self.mounted_at,
+            ..self.make_attr(ino, size, kind)
+        }
+    }
+
+    fn version_attr(&self) -> FileAttr {
+        let mut attr = self.mount_time_attr(
+            VERSION_INO,
+            self.version_info.len() as u64,
+            FileType::RegularFile,
+        );
+        attr.perm = 0o444;
+        attr
     }
 
     fn next_fh(&mut self) -> u64 {
         self.fh_counter += 1;
         self.fh_counter
     }
-    fn ttl(&self) -> Duration {
-        self.rc.cache_config.dir_ttl.max(Duration::from_millis(100))
+    /// Kernel cache lifetime for attributes returned by getattr/setattr.
+    fn attr_ttl(&self) -> Duration {
+        self.rc.cache_config.attr_timeout
+    }
+
+    /// Kernel cache lifetime for lookup/create/mkdir entries.
+    fn entry_ttl(&self) -> Duration {
+        self.rc.cache_config.entry_timeout
+    }
+
+    /// True for paths in the synthetic `.search` tree, which exists only when
+    /// the server can search.
+    fn is_search_path(&self, path: &str) -> bool {
+        self.rc.capabilities().search && search::parse(path).is_some()
+    }
+
+    /// Paths that refuse changes: `--protect`, read-only mounts and `.search`.
+    fn refuses_changes(&self, path: &str) -> bool {
+        self.rc.is_protected(path) || self.is_search_path(path)
+    }
+
+    /// Hits of `query`, asking the server again when `refresh` is set or none are known.
+    fn search_hits(&mut self, query: &str, refresh: bool) -> Result<Vec<SearchHit>, RemoteError> {
+        if refresh || !self.searches.contains_key(query) {
+            let hits = self.rc.search(query)?;
+            self.searches.insert(query.to_string(), hits);
+        }
+        Ok(self.searches[query].clone())
+    }
+
+    /// `.search` and its query directories: read-only, with the mount's times.
+    fn search_dir_attr(&self, ino: u64) -> FileAttr {
+        let mut attr = self.mount_time_attr(ino, 0, FileType::Directory);
+        attr.perm = 0o555;
+        attr
+    }
+
+    fn lookup_search(&mut self, full_path: String, reply: ReplyEntry) {
+        let hit = match search::parse(&full_path) {
+            Some(SearchPath::Hit { query, name }) => {
+                let hits = match self.search_hits(query, false) {
+                    Ok(hits) => hits,
+                    Err(e) => {
+                        reply.error(search_errno(&e));
+                        return;
+                    }
+                };
+                let found = hits
+                    .into_iter()
+                    .find(|hit| search::display_name(&hit.path) == name);
+                let Some(hit) = found else {
+                    self.reply_missing(reply);
+                    return;
+                };
+                Some(hit)
+            }
+            Some(SearchPath::Query(query)) => {
+                // Running the query here makes the lookup fail when the server cannot search.
+                if let Err(e) = self.search_hits(query, true) {
+                    reply.error(search_errno(&e));
+                    return;
+                }
+                None
+            }
+            Some(SearchPath::Root) | None => None,
+        };
+        match hit {
+            // A hit is the real file, so opening it reads the file itself.
+            Some(hit) => {
+                let ino = self.lookup_inode(normalize_path(&hit.path));
+                reply.entry(&self.entry_ttl(), &self.entry_attr(ino, &hit.entry), 0);
+            }
+            None => {
+                let ino = self.lookup_inode(full_path);
+                reply.entry(&self.entry_ttl(), &self.search_dir_attr(ino), 0);
+            }
+        }
+    }
+
+    fn opendir_search(&mut self, ino: u64, path: String) -> Result<u64, i32> {
+        let mut snapshot: Vec<DirEntry> = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (
+                self.parent_inode(&path),
+                FileType::Directory,
+                "..".to_string(),
+            ),
+        ];
+        // `.search` itself lists nothing: any name under it is a query.
+        if let Some(SearchPath::Query(query)) = search::parse(&path) {
+            let hits = self
+                .search_hits(query, true)
+                .map_err(|e| search_errno(&e))?;
+            for hit in hits {
+                let child_ino = self.alloc_inode(normalize_path(&hit.path));
+                snapshot.push((
+                    child_ino,
+                    FileType::RegularFile,
+                    search::display_name(&hit.path),
+                ));
+            }
+        }
+        let fh = self.next_fh();
+        self.open_files
+            .insert(fh, OpenFile::directory(path, snapshot));
+        Ok(fh)
+    }
+
+    /// Opens file `ino` with `flags` and returns the handle with its
+    /// `FOPEN_*` flags.
+    fn open_handle(&mut self, ino: u64, flags: i32) -> Result<(u64, u32), i32> {
+        if ino == VERSION_INO {
+            if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+                return Err(libc::EACCES);
+            }
+            // Rendered per open, with the activity at that moment; direct
+            // I/O keeps the kernel from cutting reads at an older size.
+            let mut info = version_file::render(&self.rc);
+            version_file::render_activity(&self.rc, &self.activity(), &mut info);
+            self.version_info = info;
+            let fh = self.next_fh();
+            self.open_files
+                .insert(fh, OpenFile::new(VERSION_FILE.to_string(), flags));
+            return Ok((fh, fuser::consts::FOPEN_DIRECT_IO));
+        }
+        let path = self.inode_path(ino).ok_or(libc::ENOENT)?;
+        // Close-to-open consistency: whatever another client wrote is seen on open.
+        if self.rc.cache_config.consistent_open {
+            self.rc.invalidate(&path);
+        }
+        let entry = self.stat(&path);
+        if entry.as_ref().is_some_and(|e| !access_permitted(e, flags)) {
+            return Err(libc::EACCES);
+        }
+
+        let access = flags & libc::O_ACCMODE;
+        let writable = access == libc::O_WRONLY || access == libc::O_RDWR;
+        let truncate = (flags & libc::O_TRUNC) != 0;
+        if (writable || truncate) && self.refuses_changes(&path) {
+            return Err(libc::EROFS);
+        }
+
+        // Write opens edit a local copy; with no file cache, reads are served
+        // from one too, unless the open asked to see the server every time.
+        let direct = self.opens_direct(&path, flags);
+        let snapshot = self.rc.cache_config.file_ttl.is_zero() && !direct;
+        let mut handle = if writable || truncate || snapshot {
+            let mut tmp = tempfile::tempfile().map_err(|_| libc::EIO)?;
+            // Without the current content, writes at an offset would
+            // upload zeros in place of the bytes before it.
+            if !truncate {
+                self.rc
+                    .download_to(&path, &mut tmp)
+                    .map_err(|e| e.to_errno())?;
+                let _ = tmp.seek(SeekFrom::Start(0));
+            }
+            let mut handle = OpenFile::with_buffer(path, flags, tmp);
+            // The empty copy must reach the server even if nothing is written.
+            if truncate {
+                handle.mark_dirty(0..0);
+                if self.streams(flags) {
+                    handle.start_streaming();
+                }
+            }
+            handle
+        } else {
+            OpenFile::new(path, flags)
+        };
+        handle.direct = direct;
+
+        let fh = self.next_fh();
+        // Buffered handles read a snapshot; only cache-backed reads can follow
+        // growth, and direct reads go to the server anyway.
+        if let (None, false, Some(entry)) = (&handle.buffer, direct, &entry) {
+            self.watched.add(fh, ino, entry.size, entry.mtime);
+        }
+        self.open_files.insert(fh, handle);
+        let open_flags = if writable || truncate || direct {
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+        Ok((fh, open_flags))
+    }
+
+    /// Reads up to `size` bytes of file `ino` at `offset` through handle `fh`.
+    fn read_data(&mut self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>, i32> {
+        if let Some(handle) = self.open_files.get_mut(&fh).filter(|h| h.buffer.is_some()) {
+            let Ok(Some(file)) = handle.spilled_buffer() else {
+                return Err(libc::EIO);
+            };
+            file.seek(SeekFrom::Start(offset as u64))
+                .map_err(|_| libc::EIO)?;
+            let mut data = vec![0u8; size as usize];
+            let n = file.read(&mut data).map_err(|_| libc::EIO)?;
+            data.truncate(n);
+            return Ok(data);
+        }
+
+        if ino == VERSION_INO {
+            return Ok(slice_at(&self.version_info, offset, size).to_vec());
+        }
+
+        let path = self.inode_path(ino).ok_or(libc::ENOENT)?;
+
+        let direct = self.open_files.get(&fh).is_some_and(|h| h.direct);
+        if !direct {
+            if let Some(cached) = self.rc.cached_file_data(&path) {
+                return Ok(slice_at(&cached, offset, size).to_vec());
+            }
+
+            // Tiny files are fetched whole and cached, so later reads skip the network.
+            let threshold = self.rc.cache_config.small_file_bytes;
+            if threshold > 0 && self.stat(&path).is_some_and(|e| e.size <= threshold) {
+                if let Ok(data) = self.rc.fetch_file(&path) {
+                    return Ok(slice_at(&data, offset, size).to_vec());
+                }
+            }
+        }
+
+        let offset = offset as u64;
+        // Direct reads fetch exactly the range asked for and keep nothing.
+        let Some(handle) = self.open_files.get_mut(&fh).filter(|h| !h.direct) else {
+            return self
+                .rc
+                .fetch_range(&path, offset, size)
+                .map_err(|e| e.to_errno());
+        };
+        if let Some(data) = handle.readahead_hit(offset, size) {
+            return Ok(data);
+        }
+        let fetch = handle.plan_read(offset, size, self.rc.cache_config.max_readahead);
+        let fetch = u32::try_from(fetch).unwrap_or(size);
+        match self.rc.fetch_range(&path, offset, fetch) {
+            Ok(data) => Ok(handle.keep_readahead(offset, size, data)),
+            // The file was deleted on the server after this handle opened it.
+            Err(RemoteError::NotFound) => Err(libc::ESTALE),
+            Err(e) => Err(e.to_errno()),
+        }
+    }
+
+    /// Renames `name` in `parent` to `newname` in `newparent`, honouring the
+    /// `RENAME_NOREPLACE` flag.
+    fn rename_entry(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<(), i32> {
+        if self.rc.read_only() {
+            return Err(libc::EROFS);
+        }
+        // The server has no atomic swap, and emulating one could lose data on failure.
+        if flags & RENAME_EXCHANGE != 0 {
+            return Err(libc::ENOSYS);
+        }
+        if flags & !RENAME_NOREPLACE != 0 {
+            return Err(libc::EINVAL);
+        }
+        let (_, old_path) = self.child_path(parent, name);
+        let (_, new_path) = self.child_path(newparent, newname);
+        if self.rc.path_too_long(&new_path) {
+            return Err(libc::ENAMETOOLONG);
+        }
+        if self.refuses_changes(&old_path) || self.refuses_changes(&new_path) {
+            return Err(libc::EROFS);
+        }
+        if version_file::is_version_file(&old_path) || version_file::is_version_file(&new_path) {
+            return Err(libc::EPERM);
+        }
+
+        if flags & RENAME_NOREPLACE != 0 {
+            self.rc.invalidate(&new_path);
+            if self
+                .try_stat(&new_path)
+                .map_err(|e| e.to_errno())?
+                .is_some()
+            {
+                return Err(libc::EEXIST);
+            }
+        }
+
+        if old_path.is_empty() || new_path.is_empty() {
+            return Ok(());
+        }
+
+        self.rc.invalidate(&old_path);
+        self.rc.invalidate(&new_path);
+        let entry = self
+            .try_stat(&old_path)
+            .map_err(|e| e.to_errno())?
+            .ok_or(libc::ENOENT)?;
+
+        // Directories move server-side or not at all (ENOTSUP without `/move`).
+        self.rc
+            .rename_remote(&old_path, &new_path, entry.is_dir())
+            .map_err(|e| e.to_errno())?;
+        self.rename_paths(&old_path, &new_path);
+        Ok(())
+    }
+
+    /// Opens directory `ino` for `readdir` and returns the handle. The handle
+    /// holds a snapshot of the listing, so one enumeration sees one state of
+    /// the directory however the server changes meanwhile; a huge directory
+    /// is read a page at a time instead of held whole.
+    fn open_dir(&mut self, ino: u64) -> Result<u64, i32> {
+        let parent_path = self.inode_path(ino).ok_or(libc::ENOENT)?;
+        if self.is_search_path(&parent_path) {
+            return self.opendir_search(ino, parent_path);
+        }
+        let handle = if self.rc.pages_listing(&parent_path) {
+            let (page, next) = self
+                .listing_page(ino, &parent_path, None)
+                .map_err(|e| e.to_errno())?;
+            let mut handle = OpenFile::directory(parent_path, page);
+            handle.pages = Some(DirPages { base: 0, next });
+            handle
+        } else {
+            let entries = self.rc.list_dir(&parent_path).map_err(|e| e.to_errno())?;
+            let mut snapshot = self.leading_entries(ino, &parent_path);
+            snapshot.extend(self.dir_entries(ino, &parent_path, entries));
+            OpenFile::directory(parent_path, snapshot)
+        };
+        let fh = self.next_fh();
+        self.open_files.insert(fh, handle);
+        Ok(fh)
+    }
+
+    /// Passes the entries of directory handle `fh` from `offset` on to `add`,
+    /// with the offset of the entry after each, until `add` returns true
+    /// because the reply is full.
+    fn read_dir(
+        &mut self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut add: impl FnMut(u64, i64, FileType, &str) -> bool,
+    ) -> Result<(), i32> {
+        let offset = offset as usize;
+        self.load_page(fh, ino, offset).map_err(|e| e.to_errno())?;
+        let handle = self.open_files.get(&fh).ok_or(libc::EBADF)?;
+        let snapshot = handle.dir.as_ref().ok_or(libc::EBADF)?;
+        // Offsets index the whole listing, so chunked reads resume where
+        // they stopped; a paged handle holds the page `offset` falls in.
+        let base = handle.pages.as_ref().map_or(0, |p| p.base);
+        let skip = offset.saturating_sub(base);
+        for (i, (child_ino, kind, name)) in snapshot.iter().enumerate().skip(skip) {
+            if add(*child_ino, (base + i + 1) as i64, *kind, name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// `.` and `..`, plus the version file at the root: what a listing of
+    /// directory `ino` starts with before its server entries.
+    fn leading_entries(&self, ino: u64, path: &str) -> Vec<DirEntry> {
+        let mut entries = vec![
+            (ino, FileType::Directory, ".".to_string()),
+            (
+                self.parent_inode(path),
+                FileType::Directory,
+                "..".to_string(),
+            ),
+        ];
+        if ino == ROOT_INO {
+            entries.push((VERSION_INO, FileType::RegularFile, VERSION_FILE.to_string()));
+        }
+        entries
+    }
+
+    /// Numbers the server entries of directory `ino` for `readdir`.
+    fn dir_entries(
+        &mut self,
+        ino: u64,
+        path: &str,
+        mut entries: Vec<RemoteEntry>,
+    ) -> Vec<DirEntry> {
+        if ino == ROOT_INO {
+            // The synthetic file hides a real one with the same name at the root.
+            entries.retain(|e| e.name != VERSION_FILE);
+            // `.search` is reachable by name but never listed.
+            if self.rc.capabilities().search {
+                entries.retain(|e| e.name != SEARCH_DIR);
+            }
+        }
+        entries
+            .into_iter()
+            .map(|entry| {
+                let child_ino = self.alloc_inode(join_path(path, &entry.name));
+                (child_ino, file_type(entry.kind), entry.name)
+            })
+            .collect()
+    }
+
+    /// Fetches the page of directory `ino` after `cursor`, or the first page
+    /// with its leading entries, and the cursor of the page after it.
+    fn listing_page(
+        &mut self,
+        ino: u64,
+        path: &str,
+        cursor: Option<&str>,
+    ) -> Result<(Vec<DirEntry>, Option<String>), RemoteError> {
+        let (entries, next) = self.rc.fetch_listing_page(path, cursor)?;
+        let mut page = match cursor {
+            None => self.leading_entries(ino, path),
+            Some(_) => Vec::new(),
+        };
+        page.extend(self.dir_entries(ino, path, entries));
+        Ok((page, next))
+    }
+
+    /// Brings the page holding `offset` into a paged directory handle: the
+    /// next pages as `readdir` advances, the first again after a rewind.
+    /// Handles listed whole are left alone.
+    fn load_page(&mut self, fh: u64, ino: u64, offset: usize) -> Result<(), RemoteError> {
+        loop {
+            let Some(handle) = self.open_files.get(&fh) else {
+                return Ok(());
+            };
+            let (Some(pages), Some(dir)) = (&handle.pages, &handle.dir) else {
+                return Ok(());
+            };
+            let end = pages.base + dir.len();
+            let (base, cursor) = if offset < pages.base {
+                (0, None)
+            } else if offset < end {
+                return Ok(());
+            } else {
+                match &pages.next {
+                    Some(next) => (end, Some(next.clone())),
+                    None => return Ok(()),
+                }
+            };
+            let path = handle.path.clone();
+            let (page, next) = self.listing_page(ino, &path, cursor.as_deref())?;
+            if let Some(handle) = self.open_files.get_mut(&fh) {
+                handle.dir = Some(page);
+                handle.pages = Some(DirPages { base, next });
+            }
+        }
+    }
+
+    fn parent_inode(&self, path: &str) -> u64 {
+        self.inodes.ino_of(&parent_of(path)).unwrap_or(ROOT_INO)
+    }
+
+    /// Carries out control request `cmd`, answering with at most `out_size`
+    /// bytes; unknown codes fail with `ENOTTY`.
+    fn control(&mut self, cmd: u32, out_size: u32) -> Result<Vec<u8>, i32> {
+        match cmd {
+            control::FLUSH_CACHES => {
+                self.rc.clear_caches();
+                self.searches.clear();
+                logging::info("ioctl", "Caches flushed on request").emit();
+                Ok(Vec::new())
+            }
+            control::STATS => {
+                let mut info = version_file::render(&self.rc);
+                version_file::render_activity(&self.rc, &self.activity(), &mut info);
+                info.truncate((out_size as usize).min(control::STATS_SIZE));
+                Ok(info)
+            }
+            _ => Err(libc::ENOTTY),
+        }
+    }
+
+    /// Answers a lookup for a missing name, letting the kernel cache the miss
+    /// when `--negative-timeout` allows it.
+    fn reply_missing(&self, reply: ReplyEntry) {
+        let ttl = self.rc.cache_config.negative_timeout;
+        if ttl.is_zero() {
+            reply.error(libc::ENOENT);
+        } else {
+            // Inode 0 marks a negative entry.
+            reply.entry(&ttl, &self.make_attr(0, 0, FileType::RegularFile), 0);
+        }
     }
 }
 
 impl Filesystem for RemoteFS {
+    fn init(
+        &mut self,
+        _req: &Request<'_>,
+        config: &mut fuser::KernelConfig,
+    ) -> Result<(), libc::c_int> {
+        // O_TRUNC is handled in `open`, instead of a separate truncating setattr.
+        let _ = config.add_capabilities(fuser::consts::FUSE_ATOMIC_O_TRUNC);
+        self.version_info = version_file::render(&self.rc);
+        if !self.rc.cache_config.swr_window.is_zero() {
+            self.rc.set_listing_hook(Arc::new(ListingInvalidator::new(
+                self.inodes.clone(),
+                Arc::clone(&self.notifier),
+            )));
+        }
+        if self.warm_up {
+            self.rc.warm_up();
+        }
+        let paths = std::mem::take(&mut self.warm_paths);
+        self.warmer = Warmer::spawn(self.rc.clone(), paths);
+        if let Some(depth) = self.warm_depth {
+            self.metadata_warmer = Some(Warmer::spawn_metadata(
+                self.rc.clone(),
+                depth,
+                self.warm_workers,
+            ));
+        }
+        if let Some(overlay) = self.rc.overlay() {
+            self.reconciler = Some(Reconciler::spawn(self.rc.clone(), overlay));
+        }
+        if !self.watch_interval.is_zero() {
+            self.watcher = Some(Watcher::spawn(
+                self.rc.clone(),
+                self.watched.clone(),
+                self.inodes.clone(),
+                Arc::clone(&self.notifier),
+                self.watch_interval,
+            ));
+        }
+        if let Some(file) = &self.inode_file {
+            self.inode_saver = Some(Saver::spawn(
+                self.inodes.clone(),
+                file.clone(),
+                INODE_SAVE_INTERVAL,
+            ));
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        if let Some(warmer) = self.warmer.take() {
+            warmer.cancel();
+        }
+        self.metadata_warmer = None;
+        self.reconciler = None;
+        self.watcher = None;
+        self.inode_saver = None;
+        if let Some(file) = &self.inode_file {
+            if let Err(e) = file.save(&self.inodes) {
+                logging::warn("unmount", format!("Cannot save inode table: {}", e))
+                    .path(&file.path().to_string_lossy())
+                    .emit();
+            }
+        }
+        let activity = self.activity();
+        if activity.dirty_buffers > 0 {
+            logging::warn(
+                "unmount",
+                format!(
+                    "Unmounting with {} unflushed write buffers ({} bytes), their changes are lost: {}",
+                    activity.dirty_buffers,
+                    activity.dirty_bytes,
+                    activity.pending_uploads.join(", ")
+                ),
+            )
+            .emit();
+        }
+        let skipped = self.rc.uploads_skipped();
+        if skipped > 0 {
+            logging::info(
+                "upload",
+                format!("Skipped {} uploads of unchanged content", skipped),
+            )
+            .emit();
+        }
+        if let Some(overlay) = self.rc.overlay() {
+            overlay::drain(&mut self.rc, &overlay, DRAIN_TIMEOUT);
+        }
+    }
+
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         if is_macos_metadata(name) {
             reply.error(libc::ENOENT);
             return;
         }
         let (parent_path, full_path) = self.child_path(parent, name);
-        let name_str = name.to_string_lossy();
+        // No such name can exist; do not ask the server.
+        if self.rc.path_too_long(&full_path) {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        let name_str = raw_names::from_os(name);
+        if parent == 1 && name_str == VERSION_FILE {
+            reply.entry(&self.entry_ttl(), &self.version_attr(), 0);
+            return;
+        }
+        if self.is_search_path(&full_path) {
+            self.lookup_search(full_path, reply);
+            return;
+        }
 
-        if let Ok(entries) = self.rc.list_dir(&parent_path) {
-            if let Some(entry) = entries.iter().find(|e| e.name == *name_str) {
-                let ino = self.alloc_inode(full_path);
-                let kind = if entry.is_dir {
-                    FileType::Directory
-                } else {
-                    FileType::RegularFile
-                };
-                reply.entry(&self.ttl(), &make_attr(ino, entry.size, kind), 0);
+        let entries = match self.rc.list_dir(&parent_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                reply.error(e.to_errno());
                 return;
             }
+        };
+        match entries.iter().find(|e| e.name == *name_str) {
+            Some(entry) => {
+                let ino = self.lookup_inode(full_path);
+                reply.entry(&self.entry_ttl(), &self.entry_attr(ino, entry), 0);
+            }
+            None => self.reply_missing(reply),
         }
-        reply.error(libc::ENOENT);
+    }
+
+    /// The kernel dropped `nlookup` references to `ino`; inodes it no longer
+    /// holds may leave the table.
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        self.inodes.forget(ino, nlookup);
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
         if ino == 1 {
-            reply.attr(&self.ttl(), &make_attr(1, 0, FileType::Directory));
+            reply.attr(
+                &self.attr_ttl(),
+                &self.mount_time_attr(1, 0, FileType::Directory),
+            );
+            return;
+        }
+        if ino == VERSION_INO {
+            reply.attr(&self.attr_ttl(), &self.version_attr());
             return;
         }
 
-        if let Some(path) = self.inode_path(ino) {
-            let parent = parent_of(&path);
-            let filename = path.split('/').last().unwrap_or("");
+        let path = self.inode_path(ino);
+        if path.as_deref().is_some_and(|p| self.is_search_path(p)) {
+            reply.attr(&self.attr_ttl(), &self.search_dir_attr(ino));
+            return;
+        }
+        let Some(path) = path else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        match self.try_stat(&path) {
+            Ok(Some(entry)) => reply.attr(&self.attr_ttl(), &self.entry_attr(ino, &entry)),
+            Ok(None) => reply.error(libc::ENOENT),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
 
-            if let Ok(entries) = self.rc.list_dir(&parent) {
-                if let Some(entry) = entries.iter().find(|e| e.name == filename) {
-                    let kind = if entry.is_dir {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    };
-                    reply.attr(&self.ttl(), &make_attr(ino, entry.size, kind));
-                    return;
-                }
-            }
+    /// Reports the server quota over `--remote-root` so `df` shows the project's space.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        const BLOCK: u64 = 4096;
+        let Some(quota) = self.rc.quota() else {
+            // Nothing known about the server's space: fuser's default answer.
+            reply.statfs(0, 0, 0, 0, 0, self.blksize, NAME_MAX as u32, 0);
+            return;
+        };
+        let (blocks, bfree) = match quota.max_bytes {
+            Some(max) => (max / BLOCK, max.saturating_sub(quota.used_bytes) / BLOCK),
+            None => (0, 0),
+        };
+        let (files, ffree) = match quota.max_files {
+            Some(max) => (max, max.saturating_sub(quota.used_files)),
+            None => (0, 0),
+        };
+        reply.statfs(
+            blocks,
+            bfree,
+            bfree,
+            files,
+            ffree,
+            self.blksize,
+            NAME_MAX as u32,
+            BLOCK as u32,
+        );
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+        match self.open_dir(ino) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
         }
-        reply.error(libc::ENOENT);
     }
 
     fn readdir(
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        let parent_path = self.inode_path(ino).unwrap_or_default();
-
-        if offset == 0 {
-            let _ = reply.add(ino, 1, FileType::Directory, ".");
-            let _ = reply.add(ino, 2, FileType::Directory, "..");
-
-            if let Ok(entries) = self.rc.list_dir(&parent_path) {
-                for (i, entry) in entries.iter().enumerate() {
-                    let child = join_path(&parent_path, &entry.name);
-                    let child_ino = self.alloc_inode(child);
-                    let kind = if entry.is_dir {
-                        FileType::Directory
-                    } else {
-                        FileType::RegularFile
-                    };
-                    if reply.add(child_ino, (i + 3) as i64, kind, &entry.name) {
-                        break;
-                    }
-                }
-            }
+        let result = self.read_dir(ino, fh, offset, |child_ino, next, kind, name| {
+            reply.add(child_ino, next, kind, raw_names::to_os(name))
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
-        reply.ok();
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
-        let fh = self.next_fh();
-        let access = flags & libc::O_ACCMODE;
-        let writable = access == libc::O_WRONLY || access == libc::O_RDWR;
-        let truncate = (flags & libc::O_TRUNC) != 0;
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
+    ) {
+        self.open_files.remove(&fh);
+        reply.ok();
+    }
 
-        if writable || truncate {
-            if let Some(path) = self.inode_path(ino) {
-                let mut tmp = tempfile::tempfile().unwrap();
-                if !truncate {
-                    if let Ok(data) = self.rc.fetch_file(&path) {
-                        let _ = tmp.write_all(&data);
-                        let _ = tmp.seek(SeekFrom::Start(0));
-                    }
-                }
-                self.write_buffers.insert(
-                    fh,
-                    WriteBuffer {
-                        file: tmp,
-                        path,
-                        dirty: false,
-                    },
-                );
-            }
-            reply.opened(fh, 1);
-            return;
-        } else if self.rc.cache_config.file_ttl.is_zero() {
-            if let Some(path) = self.inode_path(ino) {
-                let mut tmp = tempfile::tempfile().unwrap();
-                if let Ok(data) = self.rc.fetch_file(&path) {
-                    let _ = tmp.write_all(&data);
-                    let _ = tmp.seek(SeekFrom::Start(0));
-                }
-                self.write_buffers.insert(
-                    fh,
-                    WriteBuffer {
-                        file: tmp,
-                        path,
-                        dirty: false,
-                    },
-                );
-            }
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        match self.open_handle(ino, flags) {
+            Ok((fh, open_flags)) => reply.opened(fh, open_flags),
+            Err(errno) => reply.error(errno),
         }
-        reply.opened(fh, 0);
     }
 
     fn read(
@@ -253,41 +1227,9 @@ impl Filesystem for RemoteFS {
         _lock: Option<u64>,
         reply: ReplyData,
     ) {
-        if let Some(buf) = self.write_buffers.get_mut(&fh) {
-            if buf.file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-            let mut data = vec![0u8; size as usize];
-            match buf.file.read(&mut data) {
-                Ok(n) => reply.data(&data[..n]),
-                Err(_) => reply.error(libc::EIO),
-            }
-            return;
-        }
-
-        let path = match self.inode_path(ino) {
-            Some(p) => p,
-            None => {
-                reply.error(libc::ENOENT);
-                return;
-            }
-        };
-
-        if let Some(cached) = self.rc.cached_file_data(&path) {
-            let start = offset as usize;
-            let end = std::cmp::min(start + size as usize, cached.len());
-            reply.data(if start >= cached.len() {
-                &[]
-            } else {
-                &cached[start..end]
-            });
-            return;
-        }
-
-        match self.rc.fetch_range(&path, offset as u64, size) {
+        match self.read_data(ino, fh, offset, size) {
             Ok(data) => reply.data(&data),
-            Err(_) => reply.error(libc::ENOENT),
+            Err(errno) => reply.error(errno),
         }
     }
 
@@ -296,41 +1238,61 @@ impl Filesystem for RemoteFS {
         _req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
+        mode: u32,
+        umask: u32,
+        flags: i32,
         reply: fuser::ReplyCreate,
     ) {
+        if self.rc.read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         if is_macos_metadata(name) {
             reply.error(libc::EPERM);
             return;
         }
         let (_, full_path) = self.child_path(parent, name);
+        if self.rc.path_too_long(&full_path) {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        if self.refuses_changes(&full_path) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if version_file::is_version_file(&full_path) {
+            reply.error(libc::EEXIST);
+            return;
+        }
 
-        match self.rc.upload(&full_path, Vec::new()) {
+        let mode = self.creation_mode(mode, umask, false);
+        match self.rc.create_file(&full_path, Some(mode)) {
             Ok(_) => {
                 self.rc.invalidate(&full_path);
-                let ino = self.alloc_inode(full_path.clone());
+                let Ok(tmp) = tempfile::tempfile() else {
+                    reply.error(libc::EIO);
+                    return;
+                };
+                let ino = self.lookup_inode(full_path.clone());
                 let fh = self.next_fh();
-                let tmp = tempfile::tempfile().unwrap();
-                self.write_buffers.insert(
-                    fh,
-                    WriteBuffer {
-                        file: tmp,
-                        path: full_path,
-                        dirty: false,
-                    },
-                );
-                reply.created(
-                    &self.ttl(),
-                    &make_attr(ino, 0, FileType::RegularFile),
-                    0,
-                    fh,
-                    0,
-                );
+                let direct = self.opens_direct(&full_path, flags);
+                let mut handle = OpenFile::with_buffer(full_path, flags, tmp);
+                handle.direct = direct;
+                if self.streams(flags) {
+                    handle.start_streaming();
+                }
+                self.open_files.insert(fh, handle);
+                let mut attr = self.make_attr(ino, 0, FileType::RegularFile);
+                attr.perm = mode as u16;
+                let open_flags = if direct {
+                    fuser::consts::FOPEN_DIRECT_IO
+                } else {
+                    0
+                };
+                reply.created(&self.entry_ttl(), &attr, 0, fh, open_flags);
             }
-            Err(_) => {
-                reply.error(libc::EIO);
+            Err(e) => {
+                reply.error(e.to_errno());
             }
         }
     }
@@ -347,21 +1309,58 @@ impl Filesystem for RemoteFS {
         _lock: Option<u64>,
         reply: fuser::ReplyWrite,
     ) {
-        if let Some(buf) = self.write_buffers.get_mut(&fh) {
-            if buf.file.seek(SeekFrom::Start(offset as u64)).is_err() {
-                reply.error(libc::EIO);
+        let Some(handle) = self.open_files.get(&fh) else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        // Also covers handles opened before a rename into a protected path.
+        if self.refuses_changes(&handle.path) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        let appends = handle.flags & libc::O_APPEND != 0;
+        if !appends && handle.streamed().is_some_and(|sent| (offset as u64) < sent) {
+            if let Err(e) = self.restore_full_copy(fh) {
+                reply.error(e.to_errno());
                 return;
             }
-            match buf.file.write_all(data) {
-                Ok(_) => {
-                    buf.dirty = true;
-                    reply.written(data.len() as u32);
-                }
-                Err(_) => reply.error(libc::EIO),
-            }
-        } else {
+        }
+        let Some(handle) = self.open_files.get_mut(&fh) else {
             reply.error(libc::EBADF);
+            return;
+        };
+        let Some((buffered, _)) = handle.buffered_len() else {
+            reply.error(libc::EBADF);
+            return;
+        };
+        // A streaming buffer starts at the first byte not uploaded yet.
+        let sent = handle.streamed().unwrap_or(0);
+        // O_APPEND writes land at the end, whatever offset the kernel passed.
+        let start = if appends {
+            buffered
+        } else {
+            offset as u64 - sent
+        };
+        let end = start + data.len() as u64;
+        if let Err(e) = self.rc.check_file_size(sent + end) {
+            reply.error(e.to_errno());
+            return;
         }
+        if handle.write_at(start, data).is_err() {
+            reply.error(libc::EIO);
+            return;
+        }
+        let buffered = buffered.max(end);
+        handle.mark_dirty(start..end);
+        let piece_full =
+            handle.streamed().is_some() && self.stream_chunk.is_some_and(|chunk| buffered >= chunk);
+        if piece_full {
+            if let Err(e) = self.upload_piece(fh) {
+                reply.error(e.to_errno());
+                return;
+            }
+        }
+        reply.written(data.len() as u32);
     }
 
     fn flush(
@@ -372,47 +1371,92 @@ impl Filesystem for RemoteFS {
         _lock: u64,
         reply: fuser::ReplyEmpty,
     ) {
-        let upload_info = if let Some(buf) = self.write_buffers.get_mut(&fh) {
-            if !buf.dirty {
-                reply.ok();
-                return;
+        if self
+            .open_files
+            .get(&fh)
+            .is_some_and(|h| h.streamed().is_some())
+        {
+            match self.upload_piece(fh) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e.to_errno()),
             }
-            if buf.file.seek(SeekFrom::Start(0)).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-            let size = buf.file.metadata().map(|m| m.len()).unwrap_or(0);
-            match buf.file.try_clone() {
-                Ok(file) => {
-                    buf.dirty = false;
-                    Some((buf.path.clone(), file, size))
-                }
-                Err(_) => {
+            return;
+        }
+        let upload_info = match self.open_files.get_mut(&fh) {
+            Some(handle) if handle.is_dirty() => {
+                let file = match handle.spilled_buffer() {
+                    Ok(Some(file)) => file,
+                    Ok(None) => {
+                        reply.ok();
+                        return;
+                    }
+                    Err(_) => {
+                        reply.error(libc::EIO);
+                        return;
+                    }
+                };
+                if file.seek(SeekFrom::Start(0)).is_err() {
                     reply.error(libc::EIO);
                     return;
                 }
+                let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                if let Err(e) = self.rc.check_file_size(size) {
+                    reply.error(e.to_errno());
+                    return;
+                }
+                match file.try_clone() {
+                    Ok(file) => {
+                        handle.mark_clean();
+                        Some((handle.path.clone(), file, size))
+                    }
+                    Err(_) => {
+                        reply.error(libc::EIO);
+                        return;
+                    }
+                }
+            }
+            _ => {
+                reply.ok();
+                return;
             }
-        } else {
-            reply.ok();
-            return;
         };
 
-        if let Some((path, file, size)) = upload_info {
-            let name = path.split('/').last().unwrap_or(&path).to_string();
-            let reader = ProgressReader {
-                inner: file,
-                total: size,
-                sent: 0,
-                name: name.clone(),
-                last_pct: u64::MAX,
+        if let Some((path, mut file, size)) = upload_info {
+            let path = match self.rc.upload_target(&path) {
+                Ok(target) => target,
+                Err(e) => {
+                    // Keep the edits so a later flush can still deliver them.
+                    if let Some(handle) = self.open_files.get_mut(&fh) {
+                        handle.mark_dirty(0..size);
+                    }
+                    reply.error(e.to_errno());
+                    return;
+                }
             };
-            match self.rc.upload_streamed(&path, reader, size) {
+            if self.rc.unchanged_since_upload(&path, &mut file) {
+                self.rc.invalidate(&path);
+                reply.ok();
+                return;
+            }
+            let started = Instant::now();
+            let result = self.rc.upload_file(&path, file, size);
+            self.rc.emit(TransferEvent {
+                kind: EventKind::Upload,
+                path: path.clone(),
+                size,
+                duration: started.elapsed(),
+                success: result.is_ok(),
+            });
+            match result {
                 Ok(_) => {
                     self.rc.invalidate(&path);
                     reply.ok();
                 }
-                Err(_) => {
-                    reply.error(libc::EIO);
+                Err(e) => {
+                    if let Some(handle) = self.open_files.get_mut(&fh) {
+                        handle.mark_dirty(0..size);
+                    }
+                    reply.error(e.to_errno());
                 }
             }
         }
@@ -428,45 +1472,103 @@ impl Filesystem for RemoteFS {
         _flush: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        self.write_buffers.remove(&fh);
+        self.open_files.remove(&fh);
+        self.watched.remove(fh);
         reply.ok();
     }
 
+    /// Puts collected small writes in the local copy; as before, nothing
+    /// reaches the server until `flush`.
+    fn fsync(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _datasync: bool,
+        reply: fuser::ReplyEmpty,
+    ) {
+        match self.open_files.get_mut(&fh).map(|h| h.spill()) {
+            Some(Err(_)) => reply.error(libc::EIO),
+            _ => reply.ok(),
+        }
+    }
+
     fn mkdir(
         &mut self,
         _req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
+        if self.rc.read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         if is_macos_metadata(name) {
             reply.error(libc::EPERM);
             return;
         }
         let (_, full_path) = self.child_path(parent, name);
+        if self.rc.path_too_long(&full_path) {
+            reply.error(libc::ENAMETOOLONG);
+            return;
+        }
+        if self.refuses_changes(&full_path) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if version_file::is_version_file(&full_path) {
+            reply.error(libc::EEXIST);
+            return;
+        }
 
-        match self.rc.mkdir_remote(&full_path) {
+        let mode = self.creation_mode(mode, umask, true);
+        match self.rc.mkdir_remote(&full_path, Some(mode)) {
             Ok(_) => {
                 self.rc.invalidate(&full_path);
-                let ino = self.alloc_inode(full_path);
-                reply.entry(&self.ttl(), &make_attr(ino, 0, FileType::Directory), 0);
+                let ino = self.lookup_inode(full_path);
+                let mut attr = self.make_attr(ino, 0, FileType::Directory);
+                attr.perm = mode as u16;
+                reply.entry(&self.entry_ttl(), &attr, 0);
             }
-            Err(_) => reply.error(libc::EIO),
+            Err(e) => reply.error(e.to_errno()),
         }
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        if self.rc.read_only() {
+            reply.error(libc::EROFS);
+            return;
+        }
         let (_, full_path) = self.child_path(parent, name);
+        if self.refuses_changes(&full_path) {
+            reply.error(libc::EROFS);
+            return;
+        }
+        if version_file::is_version_file(&full_path) {
+            reply.error(libc::EPERM);
+            return;
+        }
+        let size = self.stat(&full_path).map(|e| e.size).unwrap_or(0);
 
-        match self.rc.delete_remote(&full_path) {
+        let started = Instant::now();
+        let result = self.rc.remove_entry(&full_path);
+        self.rc.emit(TransferEvent {
+            kind: EventKind::Delete,
+            path: full_path.clone(),
+            size,
+            duration: started.elapsed(),
+            success: result.is_ok(),
+        });
+        match result {
             Ok(_) => {
                 self.rc.invalidate(&full_path);
                 self.remove_inode(&full_path);
                 reply.ok();
             }
-            Err(_) => reply.error(libc::EIO),
+            Err(e) => reply.error(e.to_errno()),
         }
     }
 
@@ -474,6 +1576,21 @@ impl Filesystem for RemoteFS {
         self.unlink(_req, parent, name, reply);
     }
 
+    /// The server stores every path as its own file, so hard links cannot exist.
+    ///
+    /// There is no anonymous-file case to serve either: with this FUSE ABI the
+    /// kernel fails `O_TMPFILE` opens itself, before any request reaches us.
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _newparent: u64,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        reply.error(libc::EPERM);
+    }
+
     fn rename(
         &mut self,
         _req: &Request<'_>,
@@ -481,100 +1598,13 @@ impl Filesystem for RemoteFS {
         name: &OsStr,
         newparent: u64,
         newname: &OsStr,
-        _flags: u32,
+        flags: u32,
         reply: fuser::ReplyEmpty,
     ) {
-        let (_, old_path) = self.child_path(parent, name);
-        let (_, new_path) = self.child_path(newparent, newname);
-
-        if old_path.is_empty() || new_path.is_empty() {
-            reply.ok();
-            return;
-        }
-
-        self.rc.invalidate(&old_path);
-        self.rc.invalidate(&new_path);
-
-        let parent_path = parent_of(&old_path);
-        let entry_name = old_path.split('/').last().unwrap_or("");
-        let is_dir = self
-            .rc
-            .list_dir(&parent_path)
-            .ok()
-            .and_then(|entries| {
-                entries
-                    .iter()
-                    .find(|e| e.name == entry_name)
-                    .map(|e| e.is_dir)
-            })
-            .unwrap_or(false);
-
-        if is_dir {
-            if self.rc.rename_dir_recursive(&old_path, &new_path).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-            if self.rc.delete_remote(&old_path).is_err() {
-                reply.error(libc::EIO);
-                return;
-            }
-            let prefix = format!("{}/", old_path);
-            let new_prefix = format!("{}/", new_path);
-            let mut p2i = self.path_to_inode.lock().unwrap();
-            let to_remap: Vec<(String, u64)> = p2i
-                .iter()
-                .filter(|(p, _)| *p == &old_path || p.starts_with(&prefix))
-                .map(|(p, &ino)| (p.clone(), ino))
-                .collect();
-            let mut new_entries: Vec<(String, u64)> = Vec::new();
-            for (old, _) in &to_remap {
-                p2i.remove(old);
-            }
-            for (old, ino) in &to_remap {
-                let new = if old == &old_path {
-                    new_path.clone()
-                } else {
-                    format!("{}{}", new_prefix, &old[prefix.len()..])
-                };
-                p2i.insert(new.clone(), *ino);
-                new_entries.push((new, *ino));
-            }
-            drop(p2i);
-            let mut i2p = self.inode_to_path.lock().unwrap();
-            for (new, ino) in new_entries {
-                i2p.insert(ino, new);
-            }
-            drop(i2p);
-            self.rc.invalidate(&old_path);
-            self.rc.invalidate(&new_path);
-            reply.ok();
-            return;
-        }
-
-        let data = match self.rc.fetch_file(&old_path) {
-            Ok(d) => d,
-            Err(_) => {
-                reply.error(libc::EIO);
-                return;
-            }
-        };
-
-        if let Err(_) = self.rc.upload(&new_path, data) {
-            reply.error(libc::EIO);
-            return;
-        }
-        if let Err(_) = self.rc.delete_remote(&old_path) {
-            reply.error(libc::EIO);
-            return;
-        }
-
-        let mut p2i = self.path_to_inode.lock().unwrap();
-        if let Some(ino) = p2i.remove(&old_path) {
-            p2i.insert(new_path.clone(), ino);
-            drop(p2i);
-            self.inode_to_path.lock().unwrap().insert(ino, new_path);
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
-        reply.ok();
     }
 
     fn setattr(
@@ -597,21 +1627,46 @@ impl Filesystem for RemoteFS {
     ) {
         if let Some(new_size) = size {
             let path = self.inode_path(ino);
+            if self.rc.read_only() || path.as_ref().is_some_and(|p| self.refuses_changes(p)) {
+                reply.error(libc::EROFS);
+                return;
+            }
+            if let Err(e) = self.rc.check_file_size(new_size) {
+                reply.error(e.to_errno());
+                return;
+            }
             let mut buf_found = false;
             if let Some(ref p) = path {
-                for buf in self.write_buffers.values_mut() {
-                    if &buf.path == p {
-                        let _ = buf.file.set_len(new_size);
-                        let _ = buf.file.seek(SeekFrom::End(0));
-                        buf.dirty = true;
-                        buf_found = true;
+                // Truncating into the uploaded part of a streaming write needs the whole file.
+                let behind: Vec<u64> = self
+                    .open_files
+                    .iter()
+                    .filter(|(_, h)| &h.path == p && h.streamed().is_some_and(|s| new_size < s))
+                    .map(|(fh, _)| *fh)
+                    .collect();
+                for fh in behind {
+                    if let Err(e) = self.restore_full_copy(fh) {
+                        reply.error(e.to_errno());
+                        return;
                     }
                 }
+                for handle in self.open_files.values_mut().filter(|h| &h.path == p) {
+                    let sent = handle.streamed().unwrap_or(0);
+                    let Ok(Some(file)) = handle.spilled_buffer() else {
+                        continue;
+                    };
+                    let old_size = file.metadata().map(|m| m.len()).unwrap_or(0);
+                    let new_len = new_size - sent;
+                    let _ = file.set_len(new_len);
+                    let _ = file.seek(SeekFrom::End(0));
+                    handle.mark_dirty(new_len.min(old_size)..new_len.max(old_size));
+                    buf_found = true;
+                }
             }
             if buf_found {
                 reply.attr(
-                    &self.ttl(),
-                    &make_attr(ino, new_size, FileType::RegularFile),
+                    &self.attr_ttl(),
+                    &self.make_attr(ino, new_size, FileType::RegularFile),
                 );
                 return;
             }
@@ -619,7 +1674,10 @@ impl Filesystem for RemoteFS {
                 if let Some(p) = path {
                     if self.rc.upload(&p, Vec::new()).is_ok() {
                         self.rc.invalidate(&p);
-                        reply.attr(&self.ttl(), &make_attr(ino, 0, FileType::RegularFile));
+                        reply.attr(
+                            &self.attr_ttl(),
+                            &self.make_attr(ino, 0, FileType::RegularFile),
+                        );
                         return;
                     }
                 }
@@ -627,4 +1685,359 @@ impl Filesystem for RemoteFS {
         }
         self.getattr(_req, ino, None, reply);
     }
+
+    /// Control requests of `remote-fs control`, accepted on the version file
+    /// only; see `control` for the codes.
+    fn ioctl(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        _in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        if ino != VERSION_INO {
+            reply.error(libc::ENOTTY);
+            return;
+        }
+        match self.control(cmd, out_size) {
+            Ok(data) => reply.ioctl(0, &data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Files have no local blocks to map.
+    fn bmap(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        _blocksize: u32,
+        _idx: u64,
+        reply: fuser::ReplyBmap,
+    ) {
+        reply.error(libc::ENOSYS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use crate::types::CacheConfig;
+    use std::sync::Mutex;
+
+    /// A mount of `server` with the default cache settings.
+    fn mount(server: &TestServer) -> RemoteFS {
+        RemoteFS::new(RemoteClient::new(server.url(), CacheConfig::default()))
+    }
+
+    /// Up to `max` names from directory handle `fh`, starting at `offset`,
+    /// as one `readdir` call with a small reply buffer would return them.
+    fn read_names(fs: &mut RemoteFS, ino: u64, fh: u64, offset: i64, max: usize) -> Vec<String> {
+        let mut names = Vec::new();
+        fs.read_dir(ino, fh, offset, |_, _, _, name| {
+            names.push(name.to_string());
+            names.len() == max
+        })
+        .unwrap();
+        names
+    }
+
+    #[test]
+    fn listing_change_between_readdir_offsets_keeps_the_snapshot() {
+        let server = TestServer::start(|_| {
+            Response::json(r#"[{"name":"a","size":1},{"name":"b","size":1},{"name":"c","size":1}]"#)
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("d".to_string());
+        let fh = fs.open_dir(ino).unwrap();
+        assert_eq!(read_names(&mut fs, ino, fh, 0, 3), [".", "..", "a"]);
+
+        // "a" is renamed away and "b" deleted before the next chunk is asked for.
+        server.set_handler(|_| Response::json(r#"[{"name":"c","size":1},{"name":"z","size":1}]"#));
+        fs.rc.clear_caches();
+        assert_eq!(read_names(&mut fs, ino, fh, 3, 3), ["b", "c"]);
+        assert_eq!(read_names(&mut fs, ino, fh, 5, 3), Vec::<String>::new());
+        assert_eq!(server.count("GET", "/list/d"), 1);
+    }
+
+    #[test]
+    fn each_directory_handle_enumerates_its_own_listing() {
+        let server = TestServer::start(|_| {
+            Response::json(r#"[{"name":"a","size":1},{"name":"b","size":1}]"#)
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("d".to_string());
+        let old = fs.open_dir(ino).unwrap();
+        assert_eq!(read_names(&mut fs, ino, old, 0, 3), [".", "..", "a"]);
+
+        server
+            .set_handler(|_| Response::json(r#"[{"name":"new","size":1},{"name":"a","size":1}]"#));
+        fs.rc.clear_caches();
+        let new = fs.open_dir(ino).unwrap();
+        assert_ne!(old, new);
+        // Read one entry per call, as a tiny reply buffer would.
+        let mut names = Vec::new();
+        for offset in 0.. {
+            match read_names(&mut fs, ino, new, offset, 1).pop() {
+                Some(name) => names.push(name),
+                None => break,
+            }
+        }
+        assert_eq!(names, [".", "..", "new", "a"]);
+        assert_eq!(read_names(&mut fs, ino, old, 3, 3), ["b"]);
+        assert_eq!(server.count("GET", "/list/d"), 2);
+    }
+
+    #[test]
+    fn read_waits_for_a_slow_server() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/list/" => Response::json(r#"[{"name":"slow.txt","size":5}]"#),
+            _ => {
+                std::thread::sleep(Duration::from_secs(1));
+                Response::ranged(r, b"hello")
+            }
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("slow.txt".to_string());
+        let started = Instant::now();
+        let (fh, _) = fs.open_handle(ino, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read_data(ino, fh, 0, 4096), Ok(b"hello".to_vec()));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn ten_megabyte_file_reads_whole() {
+        const SIZE: usize = 10 * 1024 * 1024;
+        let body: Arc<Vec<u8>> = Arc::new((0..SIZE).map(|i| (i % 251) as u8).collect());
+        let served = Arc::clone(&body);
+        let server = TestServer::start(move |r| match r.path.as_str() {
+            "/list/" => Response::json(format!(r#"[{{"name":"big.bin","size":{}}}]"#, SIZE)),
+            _ => Response::ranged(r, &served),
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("big.bin".to_string());
+        let (fh, _) = fs.open_handle(ino, libc::O_RDONLY).unwrap();
+        // In the 128 KiB pieces the kernel asks for, up to the size it knows.
+        let mut read = Vec::with_capacity(SIZE);
+        while read.len() < SIZE {
+            let chunk = fs
+                .read_data(ino, fh, read.len() as i64, 128 * 1024)
+                .unwrap();
+            assert!(!chunk.is_empty(), "short read at {}", read.len());
+            read.extend_from_slice(&chunk);
+        }
+        assert!(read == *body);
+    }
+
+    #[test]
+    fn access_follows_the_owner_bits() {
+        let file = |mode| RemoteEntry {
+            name: "f".to_string(),
+            mode: Some(mode),
+            ..Default::default()
+        };
+        assert!(access_permitted(&file(0o444), libc::O_RDONLY));
+        assert!(!access_permitted(&file(0o444), libc::O_WRONLY));
+        assert!(!access_permitted(&file(0o444), libc::O_RDWR));
+        assert!(!access_permitted(
+            &file(0o444),
+            libc::O_RDONLY | libc::O_TRUNC
+        ));
+        assert!(!access_permitted(&file(0o200), libc::O_RDONLY));
+        assert!(access_permitted(&file(0o200), libc::O_WRONLY));
+        assert!(access_permitted(&file(0o600), libc::O_RDWR));
+        // Servers that do not report modes leave the decision to them.
+        let unknown = RemoteEntry::default();
+        assert!(access_permitted(&unknown, libc::O_RDWR));
+    }
+
+    #[test]
+    fn write_open_of_read_only_file_is_refused_up_front() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/list/" => Response::json(r#"[{"name":"ro.txt","size":3,"mode":292}]"#),
+            _ => Response::bytes("abc"),
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("ro.txt".to_string());
+        assert_eq!(fs.open_handle(ino, libc::O_WRONLY), Err(libc::EACCES));
+        assert!(fs.open_files.is_empty());
+        // Nothing was downloaded for the refused open.
+        assert_eq!(server.count("GET", "/files"), 0);
+        assert!(fs.open_handle(ino, libc::O_RDONLY).is_ok());
+    }
+
+    fn rename(fs: &mut RemoteFS, old: &str, new: &str, flags: u32) -> Result<(), i32> {
+        fs.rename_entry(ROOT_INO, OsStr::new(old), ROOT_INO, OsStr::new(new), flags)
+    }
+
+    /// Requests that change something on the server.
+    fn changes(server: &TestServer) -> usize {
+        server
+            .requests()
+            .iter()
+            .filter(|r| r.method != "GET" && r.method != "HEAD")
+            .count()
+    }
+
+    #[test]
+    fn noreplace_rename_onto_existing_name_is_eexist() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/list/" => {
+                Response::json(r#"[{"name":"old.txt","size":3},{"name":"new.txt","size":1}]"#)
+            }
+            _ => Response::bytes("abc"),
+        });
+        let mut fs = mount(&server);
+        assert_eq!(
+            rename(&mut fs, "old.txt", "new.txt", RENAME_NOREPLACE),
+            Err(libc::EEXIST)
+        );
+        assert_eq!(changes(&server), 0);
+    }
+
+    #[test]
+    fn noreplace_rename_to_free_name_moves() {
+        let server = TestServer::start(|r| match (r.method.as_str(), r.path.as_str()) {
+            ("GET", "/list/") => Response::json(r#"[{"name":"old.txt","size":3}]"#),
+            ("GET", _) => Response::ranged(r, b"abc"),
+            _ => Response::json("{}"),
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("old.txt".to_string());
+        assert_eq!(
+            rename(&mut fs, "old.txt", "free.txt", RENAME_NOREPLACE),
+            Ok(())
+        );
+        // Without `/move` the file is copied, then the old name deleted.
+        assert_eq!(server.count("PUT", "/files/free.txt"), 1);
+        assert_eq!(server.count("DELETE", "/files/old.txt"), 1);
+        assert_eq!(fs.inode_path(ino).as_deref(), Some("free.txt"));
+    }
+
+    #[test]
+    fn exchange_rename_is_refused_untouched() {
+        let server = TestServer::start(|_| Response::json("[]"));
+        let mut fs = mount(&server);
+        assert_eq!(
+            rename(&mut fs, "a", "b", RENAME_EXCHANGE),
+            Err(libc::ENOSYS)
+        );
+        assert_eq!(rename(&mut fs, "a", "b", 0x80), Err(libc::EINVAL));
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn small_file_read_twice_is_fetched_once() {
+        let content = vec![b'x'; 1024];
+        let served = content.clone();
+        let server = TestServer::start(move |r| match r.path.as_str() {
+            "/list/" => Response::json(r#"[{"name":"small.txt","size":1024}]"#),
+            _ => Response::ranged(r, &served),
+        });
+        let mut fs = mount(&server);
+        let ino = fs.alloc_inode("small.txt".to_string());
+        for _ in 0..2 {
+            let (fh, _) = fs.open_handle(ino, libc::O_RDONLY).unwrap();
+            assert_eq!(fs.read_data(ino, fh, 0, 4096), Ok(content.clone()));
+        }
+        assert_eq!(server.count("GET", "/files/small.txt"), 1);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    /// Server holding one file, `f.txt`, whose content the test can change.
+    fn changing_file(content: &str) -> (TestServer, Arc<Mutex<Vec<u8>>>) {
+        let current = Arc::new(Mutex::new(content.as_bytes().to_vec()));
+        let served = Arc::clone(&current);
+        let server = TestServer::start(move |r| {
+            let content = served.lock().unwrap();
+            match r.path.as_str() {
+                "/list/" => {
+                    Response::json(format!(r#"[{{"name":"f.txt","size":{}}}]"#, content.len()))
+                }
+                _ => Response::ranged(r, &content),
+            }
+        });
+        (server, current)
+    }
+
+    /// Opens `f.txt` with `flags` and reads all of it through the handle.
+    fn open_and_read(fs: &mut RemoteFS, flags: i32) -> Vec<u8> {
+        let ino = fs.alloc_inode("f.txt".to_string());
+        let (fh, _) = fs.open_handle(ino, flags).unwrap();
+        fs.read_data(ino, fh, 0, 4096).unwrap()
+    }
+
+    #[test]
+    fn consistent_open_sees_changes_made_elsewhere() {
+        let (server, content) = changing_file("old");
+        let mut fs = mount(&server);
+        fs.rc.cache_config.consistent_open = true;
+        assert_eq!(open_and_read(&mut fs, libc::O_RDONLY), b"old");
+
+        *content.lock().unwrap() = b"changed".to_vec();
+        // The edit buffer starts from the current server content, not the cache.
+        assert_eq!(open_and_read(&mut fs, libc::O_RDWR), b"changed");
+        assert_eq!(open_and_read(&mut fs, libc::O_RDONLY), b"changed");
+    }
+
+    #[test]
+    fn without_consistent_open_cached_content_is_served() {
+        let (server, content) = changing_file("old");
+        let mut fs = mount(&server);
+        assert_eq!(open_and_read(&mut fs, libc::O_RDONLY), b"old");
+        *content.lock().unwrap() = b"changed".to_vec();
+        assert_eq!(open_and_read(&mut fs, libc::O_RDONLY), b"old");
+    }
+
+    #[test]
+    fn server_timestamps_are_kept_apart() {
+        let server = TestServer::start(|_| Response::status(404));
+        let fs = mount(&server);
+        let entry: RemoteEntry = serde_json::from_str(
+            r#"{"name":"f","size":1,"crtime":1000.5,"mtime":2000.0,"ctime":3000.25}"#,
+        )
+        .unwrap();
+        let attr = fs.entry_attr(2, &entry);
+        assert_eq!(attr.crtime, epoch_time(1000.5));
+        assert_eq!(attr.mtime, epoch_time(2000.0));
+        assert_eq!(attr.ctime, epoch_time(3000.25));
+        assert!(attr.crtime < attr.mtime);
+
+        // Without a birth time, creation is no later than the last change.
+        let entry = RemoteEntry {
+            crtime: None,
+            ..entry
+        };
+        assert_eq!(fs.entry_attr(2, &entry).crtime, epoch_time(2000.0));
+    }
+
+    #[test]
+    fn flush_caches_control_sends_next_reads_to_server() {
+        let server = TestServer::start(|r| match r.path.as_str() {
+            "/list/" => Response::json(r#"[{"name":"a","size":3}]"#),
+            _ => Response::bytes("abc"),
+        });
+        let mut fs = mount(&server);
+        fs.rc.list_dir("").unwrap();
+        fs.rc.fetch_file("a").unwrap();
+        fs.rc.list_dir("").unwrap();
+        assert_eq!(server.count("GET", "/list"), 1);
+        assert!(fs.rc.cached_file_data("a").is_some());
+
+        assert_eq!(fs.control(control::FLUSH_CACHES, 0), Ok(Vec::new()));
+        assert!(fs.rc.cached_file_data("a").is_none());
+        assert_eq!(fs.rc.file_cache_bytes(), 0);
+        fs.rc.list_dir("").unwrap();
+        assert_eq!(server.count("GET", "/list"), 2);
+    }
+
+    #[test]
+    fn unknown_control_code_is_enotty() {
+        let server = TestServer::start(|_| Response::status(404));
+        assert_eq!(mount(&server).control(0, 0), Err(libc::ENOTTY));
+    }
 }
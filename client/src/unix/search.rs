@@ -0,0 +1,34 @@
+//! The synthetic `.search` directory: listing `.search/<query>` shows the
+//! files whose name contains `<query>`, found by the server's `/search`.
+
+/// Directory at the mount root holding one synthetic directory per query.
+pub const SEARCH_DIR: &str = ".search";
+
+/// Where a normalized mount path falls inside the search namespace.
+pub enum SearchPath<'a> {
+    /// `.search` itself.
+    Root,
+    /// `.search/<query>`.
+    Query(&'a str),
+    /// `.search/<query>/<name>`, a hit listed under its display name.
+    Hit { query: &'a str, name: &'a str },
+}
+
+/// Classifies `path`, or returns `None` for paths outside `.search`.
+pub fn parse(path: &str) -> Option<SearchPath<'_>> {
+    let rest = path.strip_prefix(SEARCH_DIR)?;
+    if rest.is_empty() {
+        return Some(SearchPath::Root);
+    }
+    let rest = rest.strip_prefix('/')?;
+    Some(match rest.split_once('/') {
+        None => SearchPath::Query(rest),
+        Some((query, name)) => SearchPath::Hit { query, name },
+    })
+}
+
+/// Name of a hit inside a query directory: its whole path, with each `/`
+/// shown as U+2215 so hits from different directories never collide.
+pub fn display_name(path: &str) -> String {
+    path.replace('/', "\u{2215}")
+}
@@ -0,0 +1,242 @@
+//! Re-checks files held open for reading, so a reader that waits for more
+//! data (`tail -f`) sees what other clients append on the server, and
+//! passes changes found by background listing refreshes on to the kernel.
+
+use super::inodes::InodeTable;
+use crate::hooks::ListingHook;
+use crate::logging;
+use crate::raw_names;
+use crate::remote_client::RemoteClient;
+use crate::types::{join_path, parent_of, RemoteEntry};
+use fuser::Notifier;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Server size and mtime of a watched file when last seen.
+type Seen = (u64, Option<f64>);
+
+/// Read handles being watched, shared by the filesystem and the watcher thread.
+#[derive(Clone, Default)]
+pub struct Watched {
+    /// File handle -> (inode, last seen state).
+    handles: Arc<Mutex<HashMap<u64, (u64, Seen)>>>,
+}
+
+impl Watched {
+    /// Watches `ino` while handle `fh` stays open.
+    pub fn add(&self, fh: u64, ino: u64, size: u64, mtime: Option<f64>) {
+        self.handles
+            .lock()
+            .unwrap()
+            .insert(fh, (ino, (size, mtime)));
+    }
+
+    /// Stops watching through `fh`; a no-op for handles never watched.
+    pub fn remove(&self, fh: u64) {
+        self.handles.lock().unwrap().remove(&fh);
+    }
+
+    /// One entry per watched inode.
+    fn inodes(&self) -> HashMap<u64, Seen> {
+        let handles = self.handles.lock().unwrap();
+        handles.values().copied().collect()
+    }
+
+    fn update(&self, ino: u64, seen: Seen) {
+        for (watched, last) in self.handles.lock().unwrap().values_mut() {
+            if *watched == ino {
+                *last = seen;
+            }
+        }
+    }
+}
+
+/// Kernel notification channel, available once the FUSE session exists.
+pub type NotifierSlot = Arc<Mutex<Option<Notifier>>>;
+
+/// Handle to the background watcher thread; stops it when dropped.
+pub struct Watcher {
+    stop: Arc<AtomicBool>,
+}
+
+impl Watcher {
+    /// Compares every watched file with the server each `interval`.
+    ///
+    /// A file whose size or mtime moved is dropped from the client caches and
+    /// its kernel page cache and attributes are invalidated, so the next
+    /// `fstat` and `read` go back to the server.
+    pub fn spawn(
+        rc: RemoteClient,
+        watched: Watched,
+        inodes: InodeTable,
+        notifier: NotifierSlot,
+        interval: Duration,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            let mut rc = rc;
+            while !flag.load(Ordering::SeqCst) {
+                std::thread::sleep(interval);
+                let paths = inodes.paths();
+                for (ino, seen) in check_once(&mut rc, &watched, &paths) {
+                    watched.update(ino, seen);
+                    if let Some(notifier) = &*notifier.lock().unwrap() {
+                        // Offset 0, length 0: the whole page cache plus attributes.
+                        let _ = notifier.inval_inode(ino, 0, 0);
+                    }
+                }
+            }
+        });
+        Self { stop }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Invalidates the kernel's attributes and cached pages for a directory whose
+/// listing a stale-while-revalidate refresh found changed, and for each child
+/// that changed or went away. Names that appeared or disappeared are also
+/// dropped from the dentry cache, so neither a stale positive entry nor a
+/// remembered miss outlives the change.
+pub struct ListingInvalidator {
+    inodes: InodeTable,
+    notifier: NotifierSlot,
+}
+
+impl ListingInvalidator {
+    pub fn new(inodes: InodeTable, notifier: NotifierSlot) -> Self {
+        Self { inodes, notifier }
+    }
+}
+
+impl ListingHook for ListingInvalidator {
+    fn on_listing_changed(&self, path: &str, old: &[RemoteEntry], new: &[RemoteEntry]) {
+        let Some(parent) = self.inodes.ino_of(path) else {
+            return;
+        };
+        let inodes: Vec<u64> = old
+            .iter()
+            .filter(|entry| !new.contains(entry))
+            .filter_map(|entry| self.inodes.ino_of(&join_path(path, &entry.name)))
+            .collect();
+        let named = |entries: &[RemoteEntry], name: &str| entries.iter().any(|e| e.name == name);
+        let added = new.iter().filter(|e| !named(old, &e.name));
+        let removed = old.iter().filter(|e| !named(new, &e.name));
+        if let Some(notifier) = &*self.notifier.lock().unwrap() {
+            for entry in added.chain(removed) {
+                let _ = notifier.inval_entry(parent, &raw_names::to_os(&entry.name));
+            }
+            for ino in std::iter::once(parent).chain(inodes) {
+                let _ = notifier.inval_inode(ino, 0, 0);
+            }
+        }
+    }
+}
+
+/// Lists the parent of each watched file once and returns the inodes that changed.
+fn check_once(
+    rc: &mut RemoteClient,
+    watched: &Watched,
+    paths: &HashMap<u64, String>,
+) -> Vec<(u64, Seen)> {
+    let mut by_parent: HashMap<String, Vec<(u64, String, Seen)>> = HashMap::new();
+    for (ino, seen) in watched.inodes() {
+        if let Some(path) = paths.get(&ino) {
+            by_parent
+                .entry(parent_of(path))
+                .or_default()
+                .push((ino, path.clone(), seen));
+        }
+    }
+
+    let mut changed = Vec::new();
+    for (parent, files) in by_parent {
+        let entries = match rc.fetch_listing(&parent) {
+            Ok(entries) => entries,
+            Err(e) => {
+                logging::debug("watch", format!("Cannot list: {}", e))
+                    .path(&parent)
+                    .emit();
+                continue;
+            }
+        };
+        for (ino, path, seen) in files {
+            let Some(entry) = entries.iter().find(|e| join_path(&parent, &e.name) == path) else {
+                continue;
+            };
+            let now = (entry.size, entry.mtime);
+            if now != seen {
+                rc.invalidate(&path);
+                changed.push((ino, now));
+            }
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use crate::types::CacheConfig;
+
+    /// A server whose `log.txt` holds `content`, changeable by the test.
+    fn server(content: &Arc<Mutex<String>>) -> TestServer {
+        let content = Arc::clone(content);
+        TestServer::start(move |r| {
+            let data = content.lock().unwrap().clone();
+            match r.path.as_str() {
+                "/list/" => Response::json(format!(
+                    r#"[{{"name":"log.txt","size":{},"mtime":{}}}]"#,
+                    data.len(),
+                    data.len()
+                )),
+                _ => Response::bytes(data),
+            }
+        })
+    }
+
+    #[test]
+    fn appended_file_is_reported_and_reread() {
+        let content = Arc::new(Mutex::new("one\n".to_string()));
+        let server = server(&content);
+        let mut rc = RemoteClient::new(server.url(), CacheConfig::default());
+        let watched = Watched::default();
+        let paths = HashMap::from([(2, "log.txt".to_string())]);
+        watched.add(7, 2, 4, Some(4.0));
+        assert_eq!(rc.fetch_file("log.txt").unwrap(), b"one\n");
+        assert!(check_once(&mut rc, &watched, &paths).is_empty());
+
+        // Another client appends, as `tail -f` waits for.
+        content.lock().unwrap().push_str("two\n");
+        let changed = check_once(&mut rc, &watched, &paths);
+        assert_eq!(changed, vec![(2, (8, Some(8.0)))]);
+        assert!(rc.cached_file_data("log.txt").is_none());
+        assert_eq!(rc.fetch_file("log.txt").unwrap(), b"one\ntwo\n");
+
+        for (ino, seen) in changed {
+            watched.update(ino, seen);
+        }
+        assert!(check_once(&mut rc, &watched, &paths).is_empty());
+    }
+
+    #[test]
+    fn closed_handles_are_not_checked() {
+        let content = Arc::new(Mutex::new("one\n".to_string()));
+        let server = server(&content);
+        let mut rc = RemoteClient::new(server.url(), CacheConfig::default());
+        let watched = Watched::default();
+        watched.add(7, 2, 4, Some(4.0));
+        watched.remove(7);
+        let paths = HashMap::from([(2, "log.txt".to_string())]);
+        assert!(check_once(&mut rc, &watched, &paths).is_empty());
+        assert_eq!(server.count("GET", "/list"), 0);
+    }
+}
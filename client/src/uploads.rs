@@ -0,0 +1,115 @@
+//! Uploads in flight, so a delete can abort the upload of the file it removes
+//! instead of racing it.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+/// Uploads running across all client clones, by cache key.
+pub struct Uploads {
+    active: Mutex<HashMap<String, Active>>,
+    finished: Condvar,
+}
+
+/// Uploads of one path; cancelling sets the flag they all share.
+struct Active {
+    count: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Registration of one upload; released on drop.
+pub struct Upload<'a> {
+    owner: &'a Uploads,
+    key: String,
+    cancel: Arc<AtomicBool>,
+}
+
+/// Request body that fails its next read once the upload is cancelled,
+/// which makes reqwest drop the request.
+pub struct Cancellable<R> {
+    inner: R,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Uploads {
+    pub fn new() -> Self {
+        Self {
+            active: Mutex::new(HashMap::new()),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Registers an upload of `key`. One started while `key` is being
+    /// cancelled is cancelled as well.
+    pub fn begin(&self, key: &str) -> Upload<'_> {
+        let mut active = self.active.lock().unwrap();
+        let entry = active.entry(key.to_string()).or_insert_with(|| Active {
+            count: 0,
+            cancel: Arc::new(AtomicBool::new(false)),
+        });
+        entry.count += 1;
+        Upload {
+            owner: self,
+            key: key.to_string(),
+            cancel: Arc::clone(&entry.cancel),
+        }
+    }
+
+    /// Aborts every upload of `key` and waits until they have stopped.
+    pub fn cancel(&self, key: &str) {
+        let active = self.active.lock().unwrap();
+        if let Some(entry) = active.get(key) {
+            entry.cancel.store(true, Ordering::SeqCst);
+        }
+        self.wait_idle(active, key);
+    }
+
+    /// Waits for the uploads of `key` to finish, without aborting them.
+    pub fn wait(&self, key: &str) {
+        let active = self.active.lock().unwrap();
+        self.wait_idle(active, key);
+    }
+
+    fn wait_idle(&self, mut active: MutexGuard<'_, HashMap<String, Active>>, key: &str) {
+        while active.contains_key(key) {
+            active = self.finished.wait(active).unwrap();
+        }
+    }
+}
+
+impl Upload<'_> {
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// Wraps the body of this upload so cancelling it stops the transfer.
+    pub fn body<R: Read>(&self, inner: R) -> Cancellable<R> {
+        Cancellable {
+            inner,
+            cancel: Arc::clone(&self.cancel),
+        }
+    }
+}
+
+impl Drop for Upload<'_> {
+    fn drop(&mut self) {
+        let mut active = self.owner.active.lock().unwrap();
+        if let Some(entry) = active.get_mut(&self.key) {
+            entry.count -= 1;
+            if entry.count == 0 {
+                active.remove(&self.key);
+            }
+        }
+        self.owner.finished.notify_all();
+    }
+}
+
+impl<R: Read> Read for Cancellable<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.cancel.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("upload cancelled"));
+        }
+        self.inner.read(buf)
+    }
+}
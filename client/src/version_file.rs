@@ -0,0 +1,138 @@
+use crate::remote_client::RemoteClient;
+#[cfg(unix)]
+use crate::warm::WarmProgress;
+use std::fmt::Write;
+#[cfg(unix)]
+use std::time::Duration;
+
+/// Paths with pending uploads listed before the rest are only counted.
+#[cfg(unix)]
+const MAX_LISTED_PATHS: usize = 20;
+
+/// What the mount is doing when the version file is opened, so operators can
+/// tell whether unmounting now would lose writes.
+#[cfg(unix)]
+#[derive(Default)]
+pub struct Activity {
+    pub open_handles: usize,
+    /// Write buffers holding data not uploaded yet, and their total size.
+    pub dirty_buffers: usize,
+    pub dirty_bytes: u64,
+    /// How long the oldest of them has been waiting.
+    pub oldest_dirty: Option<Duration>,
+    pub pending_uploads: Vec<String>,
+    /// `--warm` and `--warm-cache` warm-ups, when running or run.
+    pub warm_files: Option<WarmProgress>,
+    pub warm_dirs: Option<WarmProgress>,
+}
+
+/// Read-only synthetic file at the mount root describing the client and server.
+pub const VERSION_FILE: &str = ".remotefs-version";
+
+/// True when a normalized mount path names the synthetic version file.
+pub fn is_version_file(path: &str) -> bool {
+    // Windows mounts resolve names case-insensitively.
+    if cfg!(windows) {
+        path.eq_ignore_ascii_case(VERSION_FILE)
+    } else {
+        path == VERSION_FILE
+    }
+}
+
+/// Renders the version file from the capabilities probed at mount.
+pub fn render(rc: &RemoteClient) -> Vec<u8> {
+    let mut out = String::new();
+    let _ = writeln!(out, "client_version: {}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(out, "server_url: {}", rc.base_url());
+    let caps = rc.capabilities();
+    let _ = writeln!(
+        out,
+        "server_version: {}",
+        caps.version.as_deref().unwrap_or("unknown")
+    );
+    let _ = writeln!(
+        out,
+        "server_capabilities: {}{}",
+        caps.names().join(", "),
+        if caps.assumed {
+            " (assumed, probe failed)"
+        } else {
+            ""
+        }
+    );
+    let cache = &rc.cache_config;
+    let _ = writeln!(out, "dir_cache_ttl_secs: {}", cache.dir_ttl.as_secs());
+    let _ = writeln!(out, "root_cache_ttl_secs: {}", cache.root_dir_ttl.as_secs());
+    let _ = writeln!(out, "file_cache_ttl_secs: {}", cache.file_ttl.as_secs());
+    let _ = writeln!(
+        out,
+        "max_cache_mb: {}",
+        cache.max_file_cache_bytes / 1024 / 1024
+    );
+    let _ = writeln!(out, "max_cache_files: {}", cache.max_file_entries);
+    let _ = writeln!(out, "max_cache_dirs: {}", cache.max_dir_entries);
+    let _ = writeln!(out, "small_file_threshold: {}", cache.small_file_bytes);
+    let _ = writeln!(out, "readahead_kb: {}", cache.max_readahead / 1024);
+    let _ = writeln!(out, "attr_timeout_secs: {}", cache.attr_timeout.as_secs());
+    let _ = writeln!(out, "entry_timeout_secs: {}", cache.entry_timeout.as_secs());
+    let _ = writeln!(
+        out,
+        "negative_timeout_secs: {}",
+        cache.negative_timeout.as_secs()
+    );
+    let _ = writeln!(out, "snapshot: {}", rc.snapshot().unwrap_or("none"));
+    let _ = writeln!(out, "remote_root: /{}", rc.remote_root());
+    let _ = writeln!(out, "offline_writes: {}", rc.overlay().is_some());
+    let max_file_size = rc
+        .file_size_limit()
+        .map_or_else(|| "unlimited".to_string(), |limit| limit.to_string());
+    let _ = writeln!(out, "max_file_size: {}", max_file_size);
+    out.into_bytes()
+}
+
+/// Appends the live counters of `activity` and `rc` to a rendered version file.
+#[cfg(unix)]
+pub fn render_activity(rc: &RemoteClient, activity: &Activity, out: &mut Vec<u8>) {
+    let mut text = String::new();
+    let _ = writeln!(text, "open_handles: {}", activity.open_handles);
+    let _ = writeln!(text, "dirty_buffers: {}", activity.dirty_buffers);
+    let _ = writeln!(text, "dirty_bytes: {}", activity.dirty_bytes);
+    let oldest = activity
+        .oldest_dirty
+        .map_or_else(|| "none".to_string(), |age| age.as_secs().to_string());
+    let _ = writeln!(text, "oldest_dirty_secs: {}", oldest);
+    let mut pending = activity
+        .pending_uploads
+        .iter()
+        .take(MAX_LISTED_PATHS)
+        .map(|path| format!("/{}", path))
+        .collect::<Vec<_>>()
+        .join(", ");
+    if activity.pending_uploads.len() > MAX_LISTED_PATHS {
+        let more = activity.pending_uploads.len() - MAX_LISTED_PATHS;
+        let _ = write!(pending, " (+{} more)", more);
+    }
+    let _ = writeln!(text, "pending_uploads: {}", pending);
+    let (active, queued) = rc.inflight_counts();
+    let _ = writeln!(text, "requests_in_flight: {}", active);
+    let _ = writeln!(text, "requests_queued: {}", queued);
+    let _ = writeln!(text, "circuit_breaker: {}", rc.breaker_state());
+    let _ = writeln!(text, "uploads_skipped: {}", rc.uploads_skipped());
+    for (name, warm) in [
+        ("warm_files", &activity.warm_files),
+        ("warm_dirs", &activity.warm_dirs),
+    ] {
+        if let Some(warm) = warm {
+            let _ = writeln!(
+                text,
+                "{}: {}/{} ({})",
+                name, warm.done, warm.total, warm.state
+            );
+        }
+    }
+    if let Some(overlay) = rc.overlay() {
+        let _ = writeln!(text, "offline_pending: {}", overlay.pending_count());
+        let _ = writeln!(text, "offline_conflicts: {}", overlay.conflict_count());
+    }
+    out.extend_from_slice(text.as_bytes());
+}
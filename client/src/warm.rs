@@ -0,0 +1,295 @@
+use crate::logging;
+use crate::progress::NoProgress;
+use crate::remote_client::RemoteClient;
+use crate::types::join_path;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Listings a metadata warm-up requests at most, however deep it may go.
+const MAX_METADATA_REQUESTS: u64 = 10_000;
+/// Directories listed between two metadata warm-up progress lines.
+const PROGRESS_EVERY: u64 = 500;
+
+/// Handle to the background warm-up thread; cancels it when asked or dropped.
+pub struct Warmer {
+    cancel: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+}
+
+/// Counters the warm-up thread updates as it goes.
+#[derive(Default)]
+struct Progress {
+    /// Files fetched or passed over, or directories listed.
+    done: AtomicU64,
+    /// Of those found so far: the total grows as listings come in.
+    total: AtomicU64,
+    finished: AtomicBool,
+}
+
+/// Snapshot of a warm-up's progress, as shown in the stats file.
+#[cfg_attr(windows, allow(dead_code))]
+pub struct WarmProgress {
+    pub done: u64,
+    pub total: u64,
+    pub state: &'static str,
+}
+
+impl Warmer {
+    /// Starts prefetching `paths` into the shared caches of `rc` on a background thread.
+    pub fn spawn(rc: RemoteClient, paths: Vec<String>) -> Option<Self> {
+        if paths.is_empty() {
+            return None;
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&cancel);
+        let progress = Arc::new(Progress::default());
+        let counters = Arc::clone(&progress);
+        std::thread::spawn(move || {
+            let mut rc = rc;
+            rc.set_progress(Arc::new(NoProgress));
+            let mut stats = WarmStats::default();
+            for path in &paths {
+                let path = path.trim_matches('/');
+                warm_tree(&mut rc, path, &flag, &mut stats, &counters);
+                if flag.load(Ordering::SeqCst) {
+                    logging::info("warm", "Cache warm-up cancelled").emit();
+                    return;
+                }
+            }
+            counters.finished.store(true, Ordering::SeqCst);
+            logging::info(
+                "warm",
+                format!(
+                    "Cache warm-up done: {} dirs, {} files, {}MB ({} skipped)",
+                    stats.dirs,
+                    stats.files,
+                    stats.bytes / 1024 / 1024,
+                    stats.skipped,
+                ),
+            )
+            .emit();
+        });
+        Some(Self { cancel, progress })
+    }
+
+    /// Lists the tree breadth-first down to `depth` levels below the mount root
+    /// with `workers` parallel requests, filling the listing (and so attribute)
+    /// caches without downloading any file.
+    pub fn spawn_metadata(rc: RemoteClient, depth: usize, workers: usize) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let progress = Arc::new(Progress::default());
+        progress.total.store(1, Ordering::SeqCst);
+        let walk = Arc::new(Walk {
+            queue: Mutex::new(WalkQueue {
+                pending: VecDeque::from([(String::new(), 0)]),
+                busy: 0,
+                requested: 0,
+            }),
+            wake: Condvar::new(),
+            cancel: Arc::clone(&cancel),
+            depth,
+            progress: Arc::clone(&progress),
+        });
+        std::thread::spawn(move || {
+            let started = Instant::now();
+            let threads: Vec<_> = (0..workers.max(1))
+                .map(|_| {
+                    let walk = Arc::clone(&walk);
+                    let mut rc = rc.clone();
+                    rc.set_progress(Arc::new(NoProgress));
+                    std::thread::spawn(move || walk.run(&mut rc))
+                })
+                .collect();
+            for thread in threads {
+                let _ = thread.join();
+            }
+            let listed = walk.progress.done.load(Ordering::SeqCst);
+            let outcome = if walk.cancel.load(Ordering::SeqCst) {
+                "cancelled"
+            } else if walk.queue.lock().unwrap().requested >= MAX_METADATA_REQUESTS {
+                "stopped at the request cap"
+            } else {
+                "done"
+            };
+            logging::info(
+                "warm",
+                format!(
+                    "Metadata warm-up {}: {} dirs in {:.1}s",
+                    outcome,
+                    listed,
+                    started.elapsed().as_secs_f64()
+                ),
+            )
+            .emit();
+            if outcome != "cancelled" {
+                walk.progress.finished.store(true, Ordering::SeqCst);
+            }
+        });
+        Self { cancel, progress }
+    }
+
+    /// Stops warming after the request currently in flight; never blocks.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    /// Files (or, for a metadata warm-up, directories) done of those found so far.
+    #[cfg_attr(windows, allow(dead_code))]
+    pub fn progress(&self) -> WarmProgress {
+        let state = if self.progress.finished.load(Ordering::SeqCst) {
+            "done"
+        } else if self.cancel.load(Ordering::SeqCst) {
+            "cancelled"
+        } else {
+            "running"
+        };
+        WarmProgress {
+            done: self.progress.done.load(Ordering::SeqCst),
+            total: self.progress.total.load(Ordering::SeqCst),
+            state,
+        }
+    }
+}
+
+impl Drop for Warmer {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Breadth-first listing shared by the metadata warm-up workers.
+struct Walk {
+    queue: Mutex<WalkQueue>,
+    /// Signalled when directories are queued or a worker finishes one.
+    wake: Condvar,
+    cancel: Arc<AtomicBool>,
+    depth: usize,
+    progress: Arc<Progress>,
+}
+
+struct WalkQueue {
+    /// Directories still to list, with their depth below the mount root.
+    pending: VecDeque<(String, usize)>,
+    /// Workers with a listing in flight; their children may still be queued.
+    busy: usize,
+    requested: u64,
+}
+
+impl Walk {
+    fn run(&self, rc: &mut RemoteClient) {
+        while let Some((path, level)) = self.next() {
+            let children = match rc.list_dir(&path) {
+                Ok(entries) if level < self.depth => entries
+                    .iter()
+                    .filter(|e| e.is_dir())
+                    .map(|e| (join_path(&path, &e.name), level + 1))
+                    .collect(),
+                Ok(_) => Vec::new(),
+                Err(e) => {
+                    logging::debug("warm", format!("Metadata warm-up: cannot list: {}", e))
+                        .path(&path)
+                        .emit();
+                    Vec::new()
+                }
+            };
+            self.finish(children);
+        }
+    }
+
+    /// Takes the next directory to list; `None` once the walk is over.
+    fn next(&self) -> Option<(String, usize)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if self.cancel.load(Ordering::SeqCst) || queue.requested >= MAX_METADATA_REQUESTS {
+                return None;
+            }
+            if let Some(next) = queue.pending.pop_front() {
+                queue.busy += 1;
+                queue.requested += 1;
+                return Some(next);
+            }
+            if queue.busy == 0 {
+                return None;
+            }
+            // Timed, so a cancel is noticed even when no worker signals.
+            queue = self
+                .wake
+                .wait_timeout(queue, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn finish(&self, children: Vec<(String, usize)>) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.busy -= 1;
+        self.progress
+            .total
+            .fetch_add(children.len() as u64, Ordering::SeqCst);
+        queue.pending.extend(children);
+        self.wake.notify_all();
+        drop(queue);
+        let listed = self.progress.done.fetch_add(1, Ordering::SeqCst) + 1;
+        if listed % PROGRESS_EVERY == 0 {
+            logging::info("warm", format!("Metadata warm-up: {} dirs listed", listed)).emit();
+        }
+    }
+}
+
+/// Counters printed when warm-up finishes.
+#[derive(Default)]
+struct WarmStats {
+    dirs: u64,
+    files: u64,
+    bytes: u64,
+    skipped: u64,
+}
+
+/// Recursively lists `path` and fetches its files while the byte budget allows.
+fn warm_tree(
+    rc: &mut RemoteClient,
+    path: &str,
+    cancel: &AtomicBool,
+    stats: &mut WarmStats,
+    progress: &Progress,
+) {
+    if cancel.load(Ordering::SeqCst) {
+        return;
+    }
+    let entries = match rc.list_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            logging::warn(
+                "warm",
+                format!("Cache warm-up: cannot list '{}': {}", path, e),
+            )
+            .path(path)
+            .emit();
+            return;
+        }
+    };
+    stats.dirs += 1;
+    let files = entries.iter().filter(|e| !e.is_dir()).count();
+    progress.total.fetch_add(files as u64, Ordering::SeqCst);
+
+    for entry in entries {
+        if cancel.load(Ordering::SeqCst) {
+            return;
+        }
+        let child = join_path(path, &entry.name);
+        if entry.is_dir() {
+            warm_tree(rc, &child, cancel, stats, progress);
+            continue;
+        }
+        let budget = rc.cache_config.max_file_cache_bytes;
+        if rc.file_cache_bytes() + entry.size as usize > budget {
+            stats.skipped += 1;
+        } else if rc.fetch_file(&child).is_ok() {
+            stats.files += 1;
+            stats.bytes += entry.size;
+        }
+        progress.done.fetch_add(1, Ordering::SeqCst);
+    }
+}
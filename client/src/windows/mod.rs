@@ -1,40 +1,72 @@
 mod remote_fs;
 mod mount;
+mod pattern;
+mod security;
+pub mod service;
 
 use crate::cli::Cli;
 
-/// Builds cache settings from CLI and starts the Windows filesystem backend.
+pub use mount::{spawn_mount, Unmounter};
+pub use remote_fs::{RemoteFS, VolumeSize};
+pub use security::SecurityDescriptor;
+
+/// Builds the remote client from CLI and starts the Windows filesystem backend.
 /// Handles unmount requests if the --unmount flag is present.
-pub fn run(cli: &Cli) {
+pub fn run(cli: &Cli) -> Result<(), anyhow::Error> {
     if cli.unmount {
-        request_unmount(&cli.mountpoint);
-        return;
+        return request_unmount(cli.mountpoint());
+    }
+
+    if cli.service {
+        return service::run(cli);
     }
 
-    daemonize_if_requested(cli);
+    daemonize_if_requested(cli)?;
 
-    let cache = cli.cache_config();
-    mount::run(&cli.mountpoint, &cli.server_url, cache);
+    let handle = mount(cli)?;
+    println!("Press Ctrl+C for a clean unmount and exit.");
+    let unmounter = handle.unmounter();
+    if let Err(e) = ctrlc::set_handler(move || unmounter.unmount()) {
+        eprintln!("Warning: failed to install Ctrl+C handler: {}", e);
+    }
+    handle.wait()
+}
+
+/// Mounts with the CLI options; the filesystem is served until the handle unmounts.
+fn mount(cli: &Cli) -> Result<crate::mount::MountHandle, anyhow::Error> {
+    let config = cli.mount_config()?;
+    let cache = &config.client.cache_config;
+    println!("Mounting at: {}", cli.mountpoint());
+    println!("Server: {}", cli.server_url);
+    println!(
+        "Cache: dir_ttl={}s, file_ttl={}s, max={}MB",
+        cache.dir_ttl.as_secs(),
+        cache.file_ttl.as_secs(),
+        cache.max_file_cache_bytes / 1024 / 1024,
+    );
+    if let Some(limit) = config.client.file_size_limit() {
+        println!("Max file size: {} bytes", limit);
+    }
+    let handle = crate::mount::mount(config)?;
+    println!("Filesystem mounted successfully at {}", cli.mountpoint());
+    Ok(handle)
 }
 
 /// Sends an unmount request to a running Windows daemon instance.
-fn request_unmount(mountpoint: &str) {
+fn request_unmount(mountpoint: &str) -> Result<(), anyhow::Error> {
     match mount::request_unmount(mountpoint) {
-        Ok(true) => println!("Unmount requested for {}", mountpoint),
-        Ok(false) => {
-            eprintln!("No active daemon mount found for {}", mountpoint);
-            std::process::exit(1);
-        }
-        Err(e) => {
-            eprintln!("Failed to request unmount for {}: {}", mountpoint, e);
-            std::process::exit(1);
+        Ok(true) => {
+            println!("Unmount requested for {}", mountpoint);
+            Ok(())
         }
+        Ok(false) => anyhow::bail!("No active daemon mount found for {}", mountpoint),
+        Err(e) => anyhow::bail!("Failed to request unmount for {}: {}", mountpoint, e),
     }
 }
 
-fn daemonize_if_requested(cli: &Cli) {
+fn daemonize_if_requested(cli: &Cli) -> Result<(), anyhow::Error> {
     if !cli.daemon {
-        return;
+        return Ok(());
     }
 
     use std::fs;
@@ -48,10 +80,8 @@ fn daemonize_if_requested(cli: &Cli) {
     const CREATE_NEW_PROCESS_GROUP: u32 = 0x00000200;
     const CREATE_NO_WINDOW: u32 = 0x08000000;
 
-    let exe = std::env::current_exe().unwrap_or_else(|e| {
-        eprintln!("Failed to get executable path: {}", e);
-        std::process::exit(1);
-    });
+    let exe = std::env::current_exe()
+        .map_err(|e| anyhow::anyhow!("Failed to get executable path: {}", e))?;
 
     let args: Vec<_> = std::env::args_os()
         .skip(1)
@@ -61,10 +91,8 @@ fn daemonize_if_requested(cli: &Cli) {
     // Spawn daemon from a temp copy to avoid locking target/debug/client.exe.
     let mut daemon_exe: PathBuf = std::env::temp_dir();
     daemon_exe.push("remote-fs-daemon");
-    if let Err(e) = fs::create_dir_all(&daemon_exe) {
-        eprintln!("Failed to prepare daemon temp directory: {}", e);
-        std::process::exit(1);
-    }
+    fs::create_dir_all(&daemon_exe)
+        .map_err(|e| anyhow::anyhow!("Failed to prepare daemon temp directory: {}", e))?;
 
     let ts = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -72,10 +100,8 @@ fn daemonize_if_requested(cli: &Cli) {
         .as_millis();
     daemon_exe.push(format!("client-daemon-{}-{}.exe", std::process::id(), ts));
 
-    if let Err(e) = fs::copy(&exe, &daemon_exe) {
-        eprintln!("Failed to stage daemon executable: {}", e);
-        std::process::exit(1);
-    }
+    fs::copy(&exe, &daemon_exe)
+        .map_err(|e| anyhow::anyhow!("Failed to stage daemon executable: {}", e))?;
 
     let mut child = Command::new(&daemon_exe);
     child
@@ -85,14 +111,10 @@ fn daemonize_if_requested(cli: &Cli) {
         .stdout(Stdio::null())
         .stderr(Stdio::null());
 
-    match child.spawn() {
-        Ok(_) => {
-            eprintln!("Daemonized successfully");
-            std::process::exit(0);
-        }
-        Err(e) => {
-            eprintln!("Failed to daemonize on Windows: {}", e);
-            std::process::exit(1);
-        }
-    }
+    child
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to daemonize on Windows: {}", e))?;
+    // The detached child carries on with the mount; the parent is done.
+    eprintln!("Daemonized successfully");
+    std::process::exit(0);
 }
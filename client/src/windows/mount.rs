@@ -1,9 +1,15 @@
 use super::remote_fs::RemoteFS;
-use crate::types::CacheConfig;
+use super::security::SecurityDescriptor;
+use crate::logging;
+use crate::mount::MountConfig;
+use crate::overlay::{self, Reconciler, DRAIN_TIMEOUT};
+use crate::warm::Warmer;
 use std::ffi::OsStr;
 use std::os::windows::ffi::OsStrExt;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
 use std::time::Duration;
 use winfsp::host::{FileSystemHost, VolumeParams};
 use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT};
@@ -62,48 +68,111 @@ pub fn request_unmount(mountpoint: &str) -> Result<bool, String> {
     }
 }
 
-/// Starts the WinFSP dispatcher and keeps it alive until shutdown is requested.
-pub fn run(mountpoint: &str, server_url: &str, cache: CacheConfig) {
-    println!("Mounting at: {}", mountpoint);
-    println!("Server: {}", server_url);
-    println!(
-        "Cache: dir_ttl={}s, file_ttl={}s, max={}MB",
-        cache.dir_ttl.as_secs(),
-        cache.file_ttl.as_secs(),
-        cache.max_file_cache_bytes / 1024 / 1024,
-    );
+/// Unmounts a filesystem started by `mount` from any thread.
+#[derive(Clone, Default)]
+pub struct Unmounter {
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Unmounter {
+    /// Asks the dispatcher to unmount; it notices within 250 ms.
+    pub fn unmount(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+}
 
-    let _init = winfsp::winfsp_init_or_die();
+/// Mounts the filesystem and serves it on a background thread until
+/// `Unmounter::unmount` or an `--unmount` request from another process.
+///
+/// Returns once the WinFSP dispatcher is running, or with the error that
+/// kept it from starting.
+pub fn spawn_mount(
+    config: MountConfig,
+) -> Result<(Unmounter, JoinHandle<Result<(), anyhow::Error>>), anyhow::Error> {
+    let unmounter = Unmounter::default();
+    let shutdown = Arc::clone(&unmounter.shutdown);
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+        let served = serve(config, shutdown, &ready_tx);
+        // Errors before the mount went up are reported through `ready`.
+        if let Err(e) = &served {
+            let _ = ready_tx.send(Err(e.to_string()));
+        }
+        served
+    });
+    match ready_rx.recv() {
+        Ok(Ok(())) => Ok((unmounter, thread)),
+        Ok(Err(e)) => {
+            let _ = thread.join();
+            Err(anyhow::anyhow!("{}", e))
+        }
+        Err(_) => Err(anyhow::anyhow!("filesystem thread exited during mount")),
+    }
+}
 
-    let ctx = RemoteFS::new(server_url, cache);
+/// Starts the WinFSP dispatcher, signals `ready`, and keeps it alive until shutdown.
+fn serve(
+    config: MountConfig,
+    shutdown: Arc<AtomicBool>,
+    ready: &mpsc::Sender<Result<(), String>>,
+) -> Result<(), anyhow::Error> {
+    let MountConfig {
+        client: rc,
+        mountpoint,
+        warm,
+        warm_depth,
+        warm_workers,
+        warm_up,
+        security,
+        volume,
+    } = config;
+    let mountpoint = mountpoint.as_str();
+    let security = match security {
+        Some(security) => security,
+        None => SecurityDescriptor::from_options(None, "current-user")
+            .map_err(|e| anyhow::anyhow!("Failed to build security descriptor: {}", e))?,
+    };
+
+    let _init =
+        winfsp::winfsp_init().map_err(|e| anyhow::anyhow!("WinFSP is not available: {:?}", e))?;
+
+    let warm_rc = rc.clone();
+    let ctx = RemoteFS::new(rc, security, volume);
+
+    let attr_ms = warm_rc.cache_config.attr_timeout.as_millis();
+    let file_info_timeout = u32::try_from(attr_ms).unwrap_or(u32::MAX);
 
     let mut params = VolumeParams::new();
     params
         .filesystem_name("remote-fs")
-        .file_info_timeout(1000)
+        .file_info_timeout(file_info_timeout)
         .case_sensitive_search(false)
         .case_preserved_names(true)
-        .unicode_on_disk(true);
+        .unicode_on_disk(true)
+        .read_only_volume(warm_rc.read_only());
 
-    let mut host =
-        FileSystemHost::new(params, ctx).expect("Failed to create WinFSP filesystem host");
+    let mut host = FileSystemHost::new(params, ctx)
+        .map_err(|e| anyhow::anyhow!("Failed to create WinFSP filesystem host: {:?}", e))?;
 
     let mp = std::ffi::OsString::from(mountpoint);
-    host.mount(mp).expect("Failed to mount filesystem");
-    host.start().expect("Failed to start filesystem dispatcher");
+    host.mount(mp)
+        .map_err(|e| anyhow::anyhow!("Failed to mount filesystem: {:?}", e))?;
+    host.start()
+        .map_err(|e| anyhow::anyhow!("Failed to start filesystem dispatcher: {:?}", e))?;
 
-    println!("Filesystem mounted successfully at {}", mountpoint);
-    println!("Press Ctrl+C for a clean unmount and exit.");
+    if warm_up {
+        warm_rc.warm_up();
+    }
+    let reconciler = warm_rc
+        .overlay()
+        .map(|overlay| Reconciler::spawn(warm_rc.clone(), overlay));
+    let mut drain_rc = warm_rc.clone();
+    let metadata_warmer =
+        warm_depth.map(|depth| Warmer::spawn_metadata(warm_rc.clone(), depth, warm_workers));
+    let warmer = Warmer::spawn(warm_rc, warm);
 
-    let shutdown = Arc::new(AtomicBool::new(false));
-    let shutdown_handler = Arc::clone(&shutdown);
     let shutdown_event = create_shutdown_event(mountpoint).ok();
-
-    if let Err(e) = ctrlc::set_handler(move || {
-        shutdown_handler.store(true, Ordering::SeqCst);
-    }) {
-        eprintln!("Warning: failed to install Ctrl+C handler: {}", e);
-    }
+    let _ = ready.send(Ok(()));
 
     while !shutdown.load(Ordering::SeqCst) {
         if let Some(event) = shutdown_event {
@@ -121,12 +190,29 @@ pub fn run(mountpoint: &str, server_url: &str, cache: CacheConfig) {
     }
 
     println!("Shutdown requested. Unmounting filesystem...");
+    if let Some(warmer) = warmer {
+        warmer.cancel();
+    }
+    drop(metadata_warmer);
+    drop(reconciler);
     host.unmount();
     host.stop();
+    if let Some(overlay) = drain_rc.overlay() {
+        overlay::drain(&mut drain_rc, &overlay, DRAIN_TIMEOUT);
+    }
+    let skipped = drain_rc.uploads_skipped();
+    if skipped > 0 {
+        logging::info(
+            "upload",
+            format!("Skipped {} uploads of unchanged content", skipped),
+        )
+        .emit();
+    }
     if let Some(event) = shutdown_event {
         unsafe {
             CloseHandle(event);
         }
     }
     println!("Filesystem unmounted.");
+    Ok(())
 }
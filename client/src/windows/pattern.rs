@@ -0,0 +1,75 @@
+//! DOS-style wildcard matching for directory enumeration patterns.
+
+use crate::glob::{self, Token};
+
+/// DOS_STAR: any run of characters up to the final `.` of the name.
+const DOS_STAR: char = '<';
+/// DOS_QM: any single character, or nothing before a `.` or at the end.
+const DOS_QM: char = '>';
+/// DOS_DOT: a `.`, or nothing at the end of the name.
+const DOS_DOT: char = '"';
+
+/// Matches `name` against a WinFSP search pattern, case-insensitively,
+/// with the semantics of `FsRtlIsNameInExpression`.
+pub(super) fn matches(pattern: &str, name: &str) -> bool {
+    let tokens: Vec<Token> = pattern
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            '*' => Token::Run { stop: None },
+            '?' => Token::One { stop: None },
+            DOS_STAR => Token::RunToLastDot,
+            DOS_QM => Token::OneOrNone,
+            DOS_DOT => Token::DotOrEnd,
+            c => Token::Char(c),
+        })
+        .collect();
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    glob::matches(&tokens, &name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn case_table() {
+        let cases = [
+            ("*", "", true),
+            ("*", "notes.txt", true),
+            ("*.txt", "Notes.TXT", true),
+            ("*.txt", "notes.txt.bak", false),
+            ("n?tes.txt", "notes.txt", true),
+            ("?", "", false),
+            ("a*b*c", "axxbyyc", true),
+            ("a*b*c", "axxbyy", false),
+            ("<.txt", "a.b.txt", true),
+            ("<", "ab", true),
+            ("<", "a.b", false),
+            ("*<", "a.b", true),
+            ("a>", "a", true),
+            ("a>", "ab", true),
+            ("a>>.txt", "a.txt", true),
+            ("a>.txt", "abc.txt", false),
+            ("a\"", "a", true),
+            ("a\"", "a.", true),
+            ("a\"b", "ab", false),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(
+                matches(pattern, name),
+                expected,
+                "{:?} ~ {:?}",
+                pattern,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn many_stars_stay_fast() {
+        let name = "a".repeat(255);
+        assert!(!matches("*a*a*a*a*a*a*a*a*a*a*b", &name));
+        assert!(matches("*a*a*a*a*a*a*a*a*a*a*", &name));
+    }
+}
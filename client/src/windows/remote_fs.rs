@@ -1,25 +1,50 @@
 //! WinFSP filesystem backend for the remote HTTP storage service.
 
+use super::pattern;
+use super::security::SecurityDescriptor;
+use crate::error::RemoteError;
+use crate::hooks::{EventKind, TransferEvent};
+use crate::logging;
 use crate::remote_client::RemoteClient;
-use crate::types::{CacheConfig, RemoteEntry, parent_of};
+use crate::types::{EntryKind, RemoteEntry, epoch_time, join_path, parent_of};
+use crate::version_file::{self, VERSION_FILE, is_version_file};
 
 use std::ffi::c_void;
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::HashMap;
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
 
 use winfsp::filesystem::*;
 use winfsp::{U16CStr, U16CString};
 
 /// Windows file attribute flags used to build FileInfo values.
+const FILE_ATTRIBUTE_READONLY: u32 = 0x1;
 const FILE_ATTRIBUTE_DIRECTORY: u32 = 0x10;
 const FILE_ATTRIBUTE_NORMAL: u32 = 0x80;
+/// `set_basic_info` passes this for attributes that should stay unchanged.
+const INVALID_FILE_ATTRIBUTES: u32 = u32::MAX;
+/// Access rights that modify file content.
+const FILE_WRITE_DATA: u32 = 0x2;
+const FILE_APPEND_DATA: u32 = 0x4;
+/// `FILE_CREATE` disposition, found in the top byte of `create_options`:
+/// create a new file and fail if one already exists.
+const FILE_CREATE: u32 = 0x2;
+/// FILETIME of the Unix epoch, in 100ns ticks since 1601.
+const EPOCH_DIFF: u64 = 116_444_736_000_000_000;
 
 /// NTSTATUS values returned for common filesystem failures.
+const STATUS_OBJECT_NAME_INVALID: i32 = 0xC000_0033_u32 as i32;
 const STATUS_OBJECT_NAME_NOT_FOUND: i32 = 0xC000_0034_u32 as i32;
+const STATUS_OBJECT_NAME_COLLISION: i32 = 0xC000_0035_u32 as i32;
 const STATUS_UNSUCCESSFUL: i32 = 0xC000_0001_u32 as i32;
 const STATUS_INVALID_DEVICE_REQUEST: i32 = 0xC000_0010_u32 as i32;
 const STATUS_DIRECTORY_NOT_EMPTY: i32 = 0xC000_0101_u32 as i32;
+const STATUS_MEDIA_WRITE_PROTECTED: i32 = 0xC000_00A2_u32 as i32;
+const STATUS_ACCESS_DENIED: i32 = 0xC000_0022_u32 as i32;
+const STATUS_FILE_DELETED: i32 = 0xC000_0123_u32 as i32;
+const STATUS_DISK_FULL: i32 = 0xC000_007F_u32 as i32;
 const FSP_CLEANUP_DELETE_FLAG: u32 = winfsp_sys::FspCleanupDelete as u32;
 
 fn nt(code: i32) -> winfsp::FspError {
@@ -42,16 +67,32 @@ fn win_name_eq(left: &str, right: &str) -> bool {
     left.eq_ignore_ascii_case(right)
 }
 
-/// Returns the current timestamp encoded as Windows FILETIME.
-fn filetime_now() -> u64 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    const EPOCH_DIFF: u64 = 116_444_736_000_000_000;
-    let dur = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
+/// Encodes a timestamp as Windows FILETIME (100ns ticks since 1601).
+fn filetime(time: std::time::SystemTime) -> u64 {
+    let dur = time
+        .duration_since(std::time::UNIX_EPOCH)
         .unwrap_or_default();
     EPOCH_DIFF + (dur.as_nanos() / 100) as u64
 }
 
+/// True when `path` is `dir` itself or lies below it.
+fn is_same_or_below(path: &str, dir: &str) -> bool {
+    path == dir
+        || path
+            .strip_prefix(dir)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Converts a FILETIME back to seconds since the Unix epoch.
+fn filetime_to_unix(ft: u64) -> f64 {
+    ft.saturating_sub(EPOCH_DIFF) as f64 / 10_000_000.0
+}
+
+/// Returns the current timestamp encoded as Windows FILETIME.
+fn filetime_now() -> u64 {
+    filetime(std::time::SystemTime::now())
+}
+
 pub(super) fn make_file_info(is_dir: bool, size: u64) -> FileInfo {
     let now = filetime_now();
     FileInfo {
@@ -70,6 +111,48 @@ pub(super) fn make_file_info(is_dir: bool, size: u64) -> FileInfo {
     }
 }
 
+/// Builds FileInfo for a listed entry, using the server timestamps when present.
+fn entry_file_info(entry: &RemoteEntry) -> FileInfo {
+    let mut info = make_file_info(entry.is_dir(), entry.size);
+    if let Some(mtime) = entry.mtime.map(|t| filetime(epoch_time(t))) {
+        info.last_access_time = mtime;
+        info.last_write_time = mtime;
+        info.creation_time = entry.crtime.map_or(mtime, |t| filetime(epoch_time(t)));
+    }
+    if let Some(ctime) = entry.ctime {
+        info.change_time = filetime(epoch_time(ctime));
+    }
+    if entry.mode.is_some_and(|mode| mode & 0o200 == 0) {
+        set_attribute_bits(&mut info, FILE_ATTRIBUTE_READONLY);
+    }
+    info
+}
+
+/// Adds attribute bits, dropping FILE_ATTRIBUTE_NORMAL which must stand alone.
+fn set_attribute_bits(info: &mut FileInfo, bits: u32) {
+    if bits != 0 {
+        info.file_attributes = (info.file_attributes & !FILE_ATTRIBUTE_NORMAL) | bits;
+    }
+}
+
+/// Basic info set by applications that the server cannot store.
+#[derive(Default, Clone, Copy)]
+struct LocalAttrs {
+    /// Attribute bits beyond directory/normal (hidden, archive, ...).
+    attributes: u32,
+    creation_time: Option<u64>,
+    /// Only kept here when the server has no `/attrs` endpoint.
+    last_write_time: Option<u64>,
+}
+
+/// Volume sizes in bytes from `--volume-total-gb` and `--volume-free-gb`;
+/// unset ones come from the server quota or fixed defaults.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VolumeSize {
+    pub total: Option<u64>,
+    pub free: Option<u64>,
+}
+
 /// Per-handle state for open files, including buffered writes.
 pub struct FileCtx {
     pub path: String,
@@ -78,38 +161,199 @@ pub struct FileCtx {
     pub write_buf: Mutex<Option<std::fs::File>>,
     pub dirty: AtomicBool,
     pub delete_on_close: AtomicBool,
+    /// Modification time set while writes were pending; reapplied after upload.
+    pub pending_mtime: Mutex<Option<f64>>,
 }
 
 /// WinFSP filesystem context that forwards operations to the remote server.
 pub struct RemoteFS {
     rc: Mutex<RemoteClient>,
+    /// Set for snapshot mounts; every mutation is refused.
+    read_only: bool,
+    /// Lowercased path -> file index number, the WinFSP counterpart of the unix inode map.
+    file_ids: Mutex<HashMap<String, u64>>,
+    next_file_id: AtomicU64,
+    /// Content of the synthetic version file, rendered at mount.
+    version_info: Vec<u8>,
+    /// Lowercased path -> basic info kept for the lifetime of the mount.
+    local_attrs: Mutex<HashMap<String, LocalAttrs>>,
+    /// Descriptor reported for every file, built from the CLI options at startup.
+    security: SecurityDescriptor,
+    volume: VolumeSize,
 }
 
 impl RemoteFS {
-    pub fn new(base_url: &str, cache: CacheConfig) -> Self {
+    pub fn new(rc: RemoteClient, security: SecurityDescriptor, volume: VolumeSize) -> Self {
         Self {
-            rc: Mutex::new(RemoteClient::new(base_url, cache)),
+            read_only: rc.read_only(),
+            version_info: version_file::render(&rc),
+            rc: Mutex::new(rc),
+            file_ids: Mutex::new(HashMap::from([(String::new(), 1)])),
+            next_file_id: AtomicU64::new(2),
+            local_attrs: Mutex::new(HashMap::new()),
+            security,
+            volume,
         }
     }
 
+    /// Refuses to grow a file from `current` to `new_size` bytes when that
+    /// takes more than the free space given with `--volume-free-gb`.
+    fn check_volume_room(&self, current: u64, new_size: u64) -> winfsp::Result<()> {
+        match self.volume.free {
+            Some(free) if new_size.saturating_sub(current) > free => Err(nt(STATUS_DISK_FULL)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Builds FileInfo for `path`, including its id and locally stored basic info.
+    fn file_info(&self, path: &str, entry: &RemoteEntry) -> FileInfo {
+        let mut info = entry_file_info(entry);
+        info.index_number = self.file_id(path);
+        if let Some(local) = self.local_attrs.lock().unwrap().get(&path.to_lowercase()) {
+            set_attribute_bits(&mut info, local.attributes);
+            if let Some(t) = local.creation_time {
+                info.creation_time = t;
+            }
+            if let Some(t) = local.last_write_time {
+                info.last_write_time = t;
+            }
+        }
+        info
+    }
+
+    /// Rejects content changes to files marked FILE_ATTRIBUTE_READONLY.
+    fn check_not_readonly(&self, path: &str) -> winfsp::Result<()> {
+        let readonly = self
+            .stat(path)
+            .is_some_and(|entry| self.file_info(path, &entry).file_attributes & FILE_ATTRIBUTE_READONLY != 0);
+        if readonly {
+            return Err(nt(STATUS_ACCESS_DENIED));
+        }
+        Ok(())
+    }
+
+    /// Returns the stable index number for `path`, assigning one on first sight.
+    fn file_id(&self, path: &str) -> u64 {
+        *self
+            .file_ids
+            .lock()
+            .unwrap()
+            .entry(path.to_lowercase())
+            .or_insert_with(|| self.next_file_id.fetch_add(1, Ordering::SeqCst))
+    }
+
+    /// Moves the ids of `old` and everything below it to `new`.
+    fn rename_file_ids(&self, old: &str, new: &str) {
+        let (old, new) = (old.to_lowercase(), new.to_lowercase());
+        let mut ids = self.file_ids.lock().unwrap();
+        // Whatever `new` pointed to before is gone now.
+        ids.retain(|path, _| !is_same_or_below(path, &new));
+        let moved: Vec<(String, u64)> = ids
+            .iter()
+            .filter(|(path, _)| is_same_or_below(path, &old))
+            .map(|(path, id)| (path.clone(), *id))
+            .collect();
+        for (path, id) in moved {
+            ids.remove(&path);
+            ids.insert(format!("{}{}", new, &path[old.len()..]), id);
+        }
+        let mut attrs = self.local_attrs.lock().unwrap();
+        attrs.retain(|path, _| !is_same_or_below(path, &new));
+        if let Some(local) = attrs.remove(&old) {
+            attrs.insert(new, local);
+        }
+    }
+
+    fn forget_file_ids(&self, path: &str) {
+        let path = path.to_lowercase();
+        self.local_attrs
+            .lock()
+            .unwrap()
+            .retain(|p, _| !is_same_or_below(p, &path));
+        self.file_ids
+            .lock()
+            .unwrap()
+            .retain(|p, _| !is_same_or_below(p, &path));
+    }
+
+    /// Refuses mutations on snapshot mounts, `--protect` paths and the synthetic version file.
+    fn check_writable(&self, path: &str) -> winfsp::Result<()> {
+        if self.read_only || self.rc.lock().unwrap().is_protected(path) {
+            return Err(nt(STATUS_MEDIA_WRITE_PROTECTED));
+        }
+        if is_version_file(path) {
+            return Err(nt(STATUS_ACCESS_DENIED));
+        }
+        Ok(())
+    }
+
+    fn version_entry(&self) -> RemoteEntry {
+        RemoteEntry {
+            name: VERSION_FILE.to_string(),
+            kind: EntryKind::File,
+            size: self.version_info.len() as u64,
+            mode: Some(0o444),
+            ..Default::default()
+        }
+    }
+
+    /// Entries `read_directory` reports for `path`, with `.` and `..` first,
+    /// keeping those that match the search `pattern`.
+    fn directory_entries(
+        &self,
+        path: &str,
+        pattern: Option<&str>,
+    ) -> winfsp::Result<Vec<RemoteEntry>> {
+        let entries = self
+            .rc
+            .lock()
+            .unwrap()
+            .list_dir(path)
+            .map_err(|e| nt(e.to_ntstatus()))?;
+
+        let dot = |name: &str| RemoteEntry {
+            name: name.into(),
+            kind: EntryKind::Dir,
+            ..Default::default()
+        };
+        let mut all = vec![dot("."), dot("..")];
+        if path.is_empty() {
+            // The synthetic file hides a real one with the same name at the root.
+            all.push(self.version_entry());
+            all.extend(entries.into_iter().filter(|e| !is_version_file(&e.name)));
+        } else {
+            all.extend(entries);
+        }
+
+        // WinFSP filters again on its side; this only avoids marshalling misses.
+        if let Some(pattern) = pattern {
+            all.retain(|entry| pattern::matches(pattern, &entry.name));
+        }
+        Ok(all)
+    }
+
     /// Returns metadata for a path, or None if it does not exist remotely.
     fn stat(&self, path: &str) -> Option<RemoteEntry> {
+        self.try_stat(path).ok().flatten()
+    }
+
+    /// `stat` that tells a missing entry from a parent listing that failed,
+    /// so an outage is not reported as a missing file.
+    fn try_stat(&self, path: &str) -> Result<Option<RemoteEntry>, RemoteError> {
+        if is_version_file(path) {
+            return Ok(Some(self.version_entry()));
+        }
         if path.is_empty() {
-            return Some(RemoteEntry {
+            return Ok(Some(RemoteEntry {
                 name: String::new(),
-                is_dir: true,
-                size: 0,
-            });
+                kind: EntryKind::Dir,
+                ..Default::default()
+            }));
         }
         let parent = parent_of(path);
         let name = filename_of(path);
-        self.rc
-            .lock()
-            .unwrap()
-            .list_dir(&parent)
-            .ok()?
-            .into_iter()
-            .find(|e| win_name_eq(&e.name, name))
+        let entries = self.rc.lock().unwrap().list_dir(&parent)?;
+        Ok(entries.into_iter().find(|e| win_name_eq(&e.name, name)))
     }
 }
 
@@ -119,15 +363,16 @@ impl FileSystemContext for RemoteFS {
     fn get_security_by_name(
         &self,
         file_name: &U16CStr,
-        _security_descriptor: Option<&mut [c_void]>,
+        security_descriptor: Option<&mut [c_void]>,
         resolve: impl FnOnce(&U16CStr) -> Option<FileSecurity>,
     ) -> winfsp::Result<FileSecurity> {
         let path = wide_to_path(file_name);
         let entry = self
-            .stat(&path)
+            .try_stat(&path)
+            .map_err(|e| nt(e.to_ntstatus()))?
             .ok_or_else(|| nt(STATUS_OBJECT_NAME_NOT_FOUND))?;
 
-        let attrs = if entry.is_dir {
+        let attrs = if entry.is_dir() {
             FILE_ATTRIBUTE_DIRECTORY
         } else {
             FILE_ATTRIBUTE_NORMAL
@@ -141,41 +386,71 @@ impl FileSystemContext for RemoteFS {
         Ok(FileSecurity {
             attributes: attrs,
             reparse: false,
-            sz_security_descriptor: 0,
+            sz_security_descriptor: self.security.copy_to(security_descriptor),
         })
     }
 
+    fn get_security(
+        &self,
+        _context: &Self::FileContext,
+        security_descriptor: Option<&mut [c_void]>,
+    ) -> winfsp::Result<u64> {
+        Ok(self.security.copy_to(security_descriptor))
+    }
+
     fn open(
         &self,
         file_name: &U16CStr,
         _create_options: u32,
-        _granted_access: winfsp_sys::FILE_ACCESS_RIGHTS,
+        granted_access: winfsp_sys::FILE_ACCESS_RIGHTS,
         file_info: &mut OpenFileInfo,
     ) -> winfsp::Result<Self::FileContext> {
         let path = wide_to_path(file_name);
+        // Close-to-open consistency: whatever another client wrote is seen on open.
+        {
+            let mut rc = self.rc.lock().unwrap();
+            if rc.cache_config.consistent_open {
+                rc.invalidate(&path);
+            }
+        }
         let entry = self
-            .stat(&path)
+            .try_stat(&path)
+            .map_err(|e| nt(e.to_ntstatus()))?
             .ok_or_else(|| nt(STATUS_OBJECT_NAME_NOT_FOUND))?;
+        if granted_access & (FILE_WRITE_DATA | FILE_APPEND_DATA) != 0 {
+            self.check_not_readonly(&path)?;
+        }
 
-        let write_buf = if entry.is_dir {
+        let write_buf = if entry.is_dir() {
             None
+        } else if is_version_file(&path) {
+            let mut tmp = tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+            tmp.write_all(&self.version_info)
+                .and_then(|_| tmp.seek(SeekFrom::Start(0)))
+                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+            Some(tmp)
         } else {
             let mut tmp = tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-            if let Ok(data) = self.rc.lock().unwrap().fetch_file(&path) {
-                tmp.write_all(&data).map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-                tmp.seek(SeekFrom::Start(0))
-                    .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-            }
+            // An empty buffer would read as an empty file and, once written
+            // at an offset, upload zeros in place of the missing bytes.
+            self.rc
+                .lock()
+                .unwrap()
+                .download_to(&path, &mut tmp)
+                .map_err(|e| nt(e.to_ntstatus()))?;
+            tmp.seek(SeekFrom::Start(0))
+                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
             Some(tmp)
         };
 
-        *file_info.as_mut() = make_file_info(entry.is_dir, entry.size);
+        *file_info.as_mut() = self.file_info(&path, &entry);
         Ok(FileCtx {
             path,
-            is_dir: entry.is_dir,
+            is_dir: entry.is_dir(),
             write_buf: Mutex::new(write_buf),
             dirty: AtomicBool::new(false),
             delete_on_close: AtomicBool::new(false),
+            pending_mtime: Mutex::new(None),
         })
     }
 
@@ -186,18 +461,27 @@ impl FileSystemContext for RemoteFS {
         context: &Self::FileContext,
         file_info: &mut FileInfo,
     ) -> winfsp::Result<()> {
-        let size = if context.is_dir {
-            0
-        } else {
-            self.stat(&context.path).map(|e| e.size).unwrap_or(0)
+        *file_info = match self.stat(&context.path) {
+            Some(entry) if !context.is_dir => self.file_info(&context.path, &entry),
+            Some(entry) => self.file_info(&context.path, &RemoteEntry { size: 0, ..entry }),
+            None => {
+                let mut info = make_file_info(context.is_dir, 0);
+                info.index_number = self.file_id(&context.path);
+                info
+            }
         };
-        *file_info = make_file_info(context.is_dir, size);
         Ok(())
     }
 
     fn get_volume_info(&self, out: &mut VolumeInfo) -> winfsp::Result<()> {
-        out.total_size = 1024 * 1024 * 1024;
-        out.free_size = 512 * 1024 * 1024;
+        let quota = self.rc.lock().unwrap().quota();
+        let (total, free) = match quota.and_then(|q| Some((q.max_bytes?, q.used_bytes))) {
+            // The project quota over `--remote-root`.
+            Some((max, used)) => (max, max.saturating_sub(used)),
+            None => (1024 * 1024 * 1024, 512 * 1024 * 1024),
+        };
+        out.total_size = self.volume.total.unwrap_or(total);
+        out.free_size = self.volume.free.unwrap_or(free).min(out.total_size);
         out.set_volume_label("RemoteFS");
         Ok(())
     }
@@ -205,29 +489,18 @@ impl FileSystemContext for RemoteFS {
     fn read_directory(
         &self,
         context: &Self::FileContext,
-        _pattern: Option<&U16CStr>,
+        pattern: Option<&U16CStr>,
         marker: DirMarker,
         buffer: &mut [u8],
     ) -> winfsp::Result<u32> {
-        let entries = self
-            .rc
-            .lock()
-            .unwrap()
-            .list_dir(&context.path)
-            .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-
-        let mut all: Vec<(String, bool, u64)> = vec![
-            (".".into(), true, 0),
-            ("..".into(), true, 0),
-        ];
-        for e in &entries {
-            all.push((e.name.clone(), e.is_dir, e.size));
-        }
+        let pattern = pattern.map(|p| p.to_string_lossy());
+        let all = self.directory_entries(&context.path, pattern.as_deref())?;
 
         let mut cursor: u32 = 0;
         let mut past_marker = marker.is_none();
 
-        for (name, is_dir, size) in &all {
+        for entry in &all {
+            let name = &entry.name;
             if !past_marker {
                 if let Some(m) = marker.inner_as_cstr() {
                     if let Ok(wide) = U16CString::from_str(name) {
@@ -240,7 +513,12 @@ impl FileSystemContext for RemoteFS {
             }
 
             let mut di = DirInfo::<255>::new();
-            *di.file_info_mut() = make_file_info(*is_dir, *size);
+            let entry_path = match name.as_str() {
+                "." => context.path.clone(),
+                ".." => parent_of(&context.path),
+                _ => join_path(&context.path, name),
+            };
+            *di.file_info_mut() = self.file_info(&entry_path, entry);
             if di.set_name(name.as_str()).is_err() {
                 continue;
             }
@@ -288,7 +566,11 @@ impl FileSystemContext for RemoteFS {
 
         let data = rc
             .fetch_range(&context.path, offset, buffer.len() as u32)
-            .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+            .map_err(|e| match e {
+                // The file was deleted on the server after this handle opened it.
+                RemoteError::NotFound => nt(STATUS_FILE_DELETED),
+                e => nt(e.to_ntstatus()),
+            })?;
         let n = data.len().min(buffer.len());
         buffer[..n].copy_from_slice(&data[..n]);
         Ok(n as u32)
@@ -297,7 +579,7 @@ impl FileSystemContext for RemoteFS {
     fn create(
         &self,
         file_name: &U16CStr,
-        _create_options: u32,
+        create_options: u32,
         _granted_access: winfsp_sys::FILE_ACCESS_RIGHTS,
         file_attributes: winfsp_sys::FILE_FLAGS_AND_ATTRIBUTES,
         _security_descriptor: Option<&[c_void]>,
@@ -307,21 +589,33 @@ impl FileSystemContext for RemoteFS {
         file_info: &mut OpenFileInfo,
     ) -> winfsp::Result<Self::FileContext> {
         let path = wide_to_path(file_name);
+        self.check_writable(&path)?;
         let is_dir = (file_attributes & FILE_ATTRIBUTE_DIRECTORY) != 0;
+        if self.rc.lock().unwrap().path_too_long(&path) {
+            return Err(nt(STATUS_OBJECT_NAME_INVALID));
+        }
+        // CREATE_NEW must not replace a file that appeared since the kernel looked.
+        if create_options >> 24 == FILE_CREATE {
+            self.rc.lock().unwrap().invalidate(&path);
+            if self.stat(&path).is_some() {
+                return Err(nt(STATUS_OBJECT_NAME_COLLISION));
+            }
+        }
 
         {
             let mut rc = self.rc.lock().unwrap();
             if is_dir {
-                rc.mkdir_remote(&path)
-                    .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+                rc.mkdir_remote(&path, None)
+                    .map_err(|e| nt(e.to_ntstatus()))?;
             } else {
                 rc.upload(&path, Vec::new())
-                    .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+                    .map_err(|e| nt(e.to_ntstatus()))?;
             }
             rc.invalidate(&path);
         }
 
         *file_info.as_mut() = make_file_info(is_dir, 0);
+        file_info.as_mut().index_number = self.file_id(&path);
         let write_buf = if !is_dir {
             Some(tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?)
         } else {
@@ -333,6 +627,7 @@ impl FileSystemContext for RemoteFS {
             write_buf: Mutex::new(write_buf),
             dirty: AtomicBool::new(false),
             delete_on_close: AtomicBool::new(false),
+            pending_mtime: Mutex::new(None),
         })
     }
 
@@ -345,6 +640,8 @@ impl FileSystemContext for RemoteFS {
         _constrained_io: bool,
         file_info: &mut FileInfo,
     ) -> winfsp::Result<u32> {
+        self.check_writable(&context.path)?;
+        self.check_not_readonly(&context.path)?;
         let mut guard = context.write_buf.lock().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         if guard.is_none() {
             *guard = Some(tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?);
@@ -353,12 +650,20 @@ impl FileSystemContext for RemoteFS {
             .as_ref()
             .ok_or_else(|| nt(STATUS_INVALID_DEVICE_REQUEST))?;
         let mut f = wb.try_clone().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+        self.rc
+            .lock()
+            .unwrap()
+            .check_file_size(offset + buf.len() as u64)
+            .map_err(|e| nt(e.to_ntstatus()))?;
+        let current = f.metadata().map(|m| m.len()).unwrap_or(0);
+        self.check_volume_room(current, offset + buf.len() as u64)?;
         f.seek(SeekFrom::Start(offset))
             .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         f.write_all(buf).map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         let size = f.metadata().map(|m| m.len()).unwrap_or(0);
         context.dirty.store(true, Ordering::SeqCst);
         *file_info = make_file_info(false, size);
+        file_info.index_number = self.file_id(&context.path);
         Ok(buf.len() as u32)
     }
 
@@ -371,6 +676,8 @@ impl FileSystemContext for RemoteFS {
         _extra_buffer: Option<&[u8]>,
         file_info: &mut FileInfo,
     ) -> winfsp::Result<()> {
+        self.check_writable(&context.path)?;
+        self.check_not_readonly(&context.path)?;
         let mut guard = context.write_buf.lock().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         if guard.is_none() {
             *guard = Some(tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?);
@@ -380,6 +687,7 @@ impl FileSystemContext for RemoteFS {
         }
         context.dirty.store(true, Ordering::SeqCst);
         *file_info = make_file_info(false, 0);
+        file_info.index_number = self.file_id(&context.path);
         Ok(())
     }
 
@@ -390,9 +698,25 @@ impl FileSystemContext for RemoteFS {
         flags: u32,
     ) {
         if (flags & FSP_CLEANUP_DELETE_FLAG) != 0 || context.delete_on_close.load(Ordering::SeqCst) {
+            let size = if context.is_dir {
+                0
+            } else {
+                self.stat(&context.path).map(|e| e.size).unwrap_or(0)
+            };
             let mut rc = self.rc.lock().unwrap();
-            let _ = rc.delete_remote(&context.path);
+            let started = Instant::now();
+            let result = rc.remove_entry(&context.path);
+            rc.emit(TransferEvent {
+                kind: EventKind::Delete,
+                path: context.path.clone(),
+                size,
+                duration: started.elapsed(),
+                success: result.is_ok(),
+            });
             rc.invalidate(&context.path);
+            if result.is_ok() {
+                self.forget_file_ids(&context.path);
+            }
             return;
         }
 
@@ -400,20 +724,51 @@ impl FileSystemContext for RemoteFS {
             return;
         }
 
-        if let Ok(guard) = context.write_buf.lock() {
-            if let Some(ref wb) = *guard {
-                if let Ok(mut f) = wb.try_clone() {
-                    if f.seek(SeekFrom::Start(0)).is_ok() {
-                        let mut data = Vec::new();
-                        if f.read_to_end(&mut data).is_ok() {
-                            let mut rc = self.rc.lock().unwrap();
-                            let _ = rc.upload(&context.path, data);
-                            rc.invalidate(&context.path);
-                        }
-                    }
-                }
+        // A clone of the buffer handle is streamed, so memory stays flat however large the file.
+        let file = match context.write_buf.lock() {
+            Ok(guard) => guard.as_ref().and_then(|wb| wb.try_clone().ok()),
+            Err(_) => None,
+        };
+        let Some(mut file) = file else {
+            return;
+        };
+        let Ok(size) = file.seek(SeekFrom::End(0)) else {
+            return;
+        };
+        if file.seek(SeekFrom::Start(0)).is_err() {
+            return;
+        }
+        let mut rc = self.rc.lock().unwrap();
+        // Close cannot fail, so a refused upload is only reported.
+        let target = match rc.upload_target(&context.path) {
+            Ok(target) => target,
+            Err(e) => {
+                logging::warn("upload", format!("Not uploading {}: {}", context.path, e))
+                    .path(&context.path)
+                    .emit();
+                return;
+            }
+        };
+        let result = if rc.unchanged_since_upload(&target, &mut file) {
+            Ok(())
+        } else {
+            let started = Instant::now();
+            let result = rc.upload_file(&target, file, size);
+            rc.emit(TransferEvent {
+                kind: EventKind::Upload,
+                path: target.clone(),
+                size,
+                duration: started.elapsed(),
+                success: result.is_ok(),
+            });
+            result
+        };
+        if result.is_ok() {
+            if let Some(mtime) = context.pending_mtime.lock().unwrap().take() {
+                let _ = rc.set_attrs(&target, Some(mtime), None);
             }
         }
+        rc.invalidate(&target);
     }
 
     fn flush(
@@ -431,6 +786,7 @@ impl FileSystemContext for RemoteFS {
             let size = local_size
                 .unwrap_or_else(|| self.stat(&ctx.path).map(|e| e.size).unwrap_or(0));
             *file_info = make_file_info(ctx.is_dir, size);
+            file_info.index_number = self.file_id(&ctx.path);
         }
         Ok(())
     }
@@ -438,13 +794,56 @@ impl FileSystemContext for RemoteFS {
     fn set_basic_info(
         &self,
         context: &Self::FileContext,
-        _file_attributes: u32,
-        _creation_time: u64,
+        file_attributes: u32,
+        creation_time: u64,
         _last_access_time: u64,
-        _last_write_time: u64,
+        last_write_time: u64,
         _last_change_time: u64,
         file_info: &mut FileInfo,
     ) -> winfsp::Result<()> {
+        self.check_writable(&context.path)?;
+        let key = context.path.to_lowercase();
+        let server_attrs = self.rc.lock().unwrap().capabilities().attrs;
+        // Zero times and INVALID_FILE_ATTRIBUTES mean "leave unchanged".
+        let mtime = (last_write_time != 0).then(|| filetime_to_unix(last_write_time));
+        let mut local = self.local_attrs.lock().unwrap().get(&key).copied().unwrap_or_default();
+        if creation_time != 0 {
+            local.creation_time = Some(creation_time);
+        }
+
+        let mut mode = None;
+        if file_attributes != INVALID_FILE_ATTRIBUTES {
+            local.attributes =
+                file_attributes & !(FILE_ATTRIBUTE_DIRECTORY | FILE_ATTRIBUTE_NORMAL | FILE_ATTRIBUTE_READONLY);
+            let readonly = file_attributes & FILE_ATTRIBUTE_READONLY != 0;
+            if server_attrs {
+                // The read-only bit maps to the owner write permission on the server.
+                let base = self
+                    .stat(&context.path)
+                    .and_then(|e| e.mode)
+                    .unwrap_or(if context.is_dir { 0o755 } else { 0o644 });
+                mode = Some(if readonly { base & !0o222 } else { base | 0o200 });
+            } else if readonly {
+                local.attributes |= FILE_ATTRIBUTE_READONLY;
+            }
+        }
+
+        if server_attrs {
+            if mtime.is_some() || mode.is_some() {
+                let mut rc = self.rc.lock().unwrap();
+                rc.set_attrs(&context.path, mtime, mode)
+                    .map_err(|e| nt(e.to_ntstatus()))?;
+                rc.invalidate(&context.path);
+            }
+            // The upload on close would reset the time; reapply it afterwards.
+            if mtime.is_some() && context.dirty.load(Ordering::SeqCst) {
+                *context.pending_mtime.lock().unwrap() = mtime;
+            }
+        } else if last_write_time != 0 {
+            local.last_write_time = Some(last_write_time);
+        }
+        self.local_attrs.lock().unwrap().insert(key, local);
+
         self.get_file_info(context, file_info)
     }
 
@@ -455,16 +854,26 @@ impl FileSystemContext for RemoteFS {
         _set_allocation_size: bool,
         file_info: &mut FileInfo,
     ) -> winfsp::Result<()> {
+        self.check_writable(&context.path)?;
+        self.check_not_readonly(&context.path)?;
+        self.rc
+            .lock()
+            .unwrap()
+            .check_file_size(new_size)
+            .map_err(|e| nt(e.to_ntstatus()))?;
         let mut guard = context.write_buf.lock().map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         if guard.is_none() {
             *guard = Some(tempfile::tempfile().map_err(|_| nt(STATUS_UNSUCCESSFUL))?);
         }
         if let Some(ref wb) = *guard {
+            let current = wb.metadata().map(|m| m.len()).unwrap_or(0);
+            self.check_volume_room(current, new_size)?;
             wb.set_len(new_size)
                 .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
         }
         context.dirty.store(true, Ordering::SeqCst);
         *file_info = make_file_info(context.is_dir, new_size);
+        file_info.index_number = self.file_id(&context.path);
         Ok(())
     }
 
@@ -473,27 +882,24 @@ impl FileSystemContext for RemoteFS {
         context: &Self::FileContext,
         file_name: &U16CStr,
         new_file_name: &U16CStr,
-        _replace_if_exists: bool,
+        replace_if_exists: bool,
     ) -> winfsp::Result<()> {
         let old = wide_to_path(file_name);
         let new = wide_to_path(new_file_name);
+        self.check_writable(&old)?;
+        self.check_writable(&new)?;
+        // A case-only rename finds the source itself, which is not a collision.
+        if !replace_if_exists && !win_name_eq(&old, &new) && self.stat(&new).is_some() {
+            return Err(nt(STATUS_OBJECT_NAME_COLLISION));
+        }
         let mut rc = self.rc.lock().unwrap();
-        if context.is_dir {
-            rc.rename_dir_recursive(&old, &new)
-                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-            rc.delete_remote(&old)
-                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-        } else {
-            let data = rc
-                .fetch_file(&old)
-                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-            rc.upload(&new, data)
-                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
-            rc.delete_remote(&old)
-                .map_err(|_| nt(STATUS_UNSUCCESSFUL))?;
+        if rc.path_too_long(&new) {
+            return Err(nt(STATUS_OBJECT_NAME_INVALID));
         }
-        rc.invalidate(&old);
-        rc.invalidate(&new);
+        // Directories move server-side or not at all (STATUS_NOT_SUPPORTED without `/move`).
+        rc.rename_remote(&old, &new, context.is_dir)
+            .map_err(|e| nt(e.to_ntstatus()))?;
+        self.rename_file_ids(&old, &new);
         Ok(())
     }
 
@@ -503,6 +909,7 @@ impl FileSystemContext for RemoteFS {
         _file_name: &U16CStr,
         delete_file: bool,
     ) -> winfsp::Result<()> {
+        self.check_writable(&context.path)?;
         if delete_file && context.is_dir {
             let has_children = self
                 .rc
@@ -510,7 +917,7 @@ impl FileSystemContext for RemoteFS {
                 .unwrap()
                 .list_dir(&context.path)
                 .map(|entries| !entries.is_empty())
-                .unwrap_or(false);
+                .map_err(|e| nt(e.to_ntstatus()))?;
             if has_children {
                 return Err(nt(STATUS_DIRECTORY_NOT_EMPTY));
             }
@@ -520,3 +927,190 @@ impl FileSystemContext for RemoteFS {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Response, TestServer};
+    use crate::types::CacheConfig;
+
+    fn mount(url: &str) -> RemoteFS {
+        RemoteFS::new(
+            RemoteClient::new(url, CacheConfig::default()),
+            SecurityDescriptor::from_options(None, "everyone").unwrap(),
+            VolumeSize::default(),
+        )
+    }
+
+    /// A mount whose server is never reached by the tests using it.
+    fn offline_fs() -> RemoteFS {
+        mount("http://127.0.0.1:1")
+    }
+
+    fn names(entries: &[RemoteEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn search_pattern_filters_the_listing() {
+        let server = TestServer::start(|_| {
+            Response::json(
+                r#"[{"name":"a.txt","size":1},{"name":"B.TXT","size":1},{"name":"c.md","size":1}]"#,
+            )
+        });
+        let fs = mount(server.url());
+        let all = fs.directory_entries("docs", None).unwrap();
+        assert_eq!(names(&all), [".", "..", "a.txt", "B.TXT", "c.md"]);
+        let txt = fs.directory_entries("docs", Some("*.txt")).unwrap();
+        assert_eq!(names(&txt), ["a.txt", "B.TXT"]);
+        let one = fs.directory_entries("docs", Some("c.m?")).unwrap();
+        assert_eq!(names(&one), ["c.md"]);
+    }
+
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    /// Serves `docs/a.txt` as a writable file, with `/attrs` when `attrs` is set.
+    fn server_with_file(attrs: bool) -> TestServer {
+        TestServer::start(move |r| match r.path.as_str() {
+            "/capabilities" => Response::json(format!(r#"{{"attrs":{}}}"#, attrs)),
+            "/list/docs" => Response::json(r#"[{"name":"a.txt","size":3,"mode":420}]"#),
+            p if attrs && p.starts_with("/attrs/") => Response::status(200),
+            _ => Response::status(404),
+        })
+    }
+
+    fn handle(path: &str, dirty: bool) -> FileCtx {
+        FileCtx {
+            path: path.to_string(),
+            is_dir: false,
+            write_buf: Mutex::new(None),
+            dirty: AtomicBool::new(dirty),
+            delete_on_close: AtomicBool::new(false),
+            pending_mtime: Mutex::new(None),
+        }
+    }
+
+    fn unix_filetime(secs: u64) -> u64 {
+        filetime(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn basic_info_is_kept_for_the_mount_without_server_attrs() {
+        let server = server_with_file(false);
+        let fs = mount(server.url());
+        fs.rc.lock().unwrap().probe_capabilities();
+        let ctx = handle("docs/a.txt", false);
+        let (created, written) = (unix_filetime(1_600_000_000), unix_filetime(1_700_000_000));
+        let mut info = FileInfo::default();
+        fs.set_basic_info(
+            &ctx,
+            FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN,
+            created,
+            0,
+            written,
+            0,
+            &mut info,
+        )
+        .unwrap();
+        assert_eq!(
+            info.file_attributes,
+            FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN
+        );
+        assert_eq!(
+            (info.creation_time, info.last_write_time),
+            (created, written)
+        );
+        assert!(fs.check_not_readonly("docs/a.txt").is_err());
+
+        // Zero times and INVALID_FILE_ATTRIBUTES leave everything as it was.
+        fs.set_basic_info(&ctx, INVALID_FILE_ATTRIBUTES, 0, 0, 0, 0, &mut info)
+            .unwrap();
+        assert_eq!(
+            info.file_attributes,
+            FILE_ATTRIBUTE_READONLY | FILE_ATTRIBUTE_HIDDEN
+        );
+        assert_eq!(
+            (info.creation_time, info.last_write_time),
+            (created, written)
+        );
+
+        fs.set_basic_info(&ctx, FILE_ATTRIBUTE_NORMAL, 0, 0, 0, 0, &mut info)
+            .unwrap();
+        assert_eq!(info.file_attributes, FILE_ATTRIBUTE_NORMAL);
+        assert!(fs.check_not_readonly("docs/a.txt").is_ok());
+        assert_eq!(server.count("POST", "/attrs/"), 0);
+    }
+
+    #[test]
+    fn time_and_read_only_bit_go_to_the_server() {
+        let server = server_with_file(true);
+        let fs = mount(server.url());
+        fs.rc.lock().unwrap().probe_capabilities();
+        let ctx = handle("docs/a.txt", true);
+        let mut info = FileInfo::default();
+        fs.set_basic_info(
+            &ctx,
+            FILE_ATTRIBUTE_READONLY,
+            0,
+            0,
+            unix_filetime(1_700_000_000),
+            0,
+            &mut info,
+        )
+        .unwrap();
+        let posts: Vec<_> = server
+            .requests()
+            .into_iter()
+            .filter(|r| r.method == "POST")
+            .collect();
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].path, "/attrs/docs/a.txt");
+        // 0o644 without its write bits.
+        assert!(posts[0].query.contains("mode=292"));
+        assert!(posts[0].query.contains("mtime=1700000000"));
+        // The upload on close would reset the time, so it is applied again then.
+        assert_eq!(*ctx.pending_mtime.lock().unwrap(), Some(1_700_000_000.0));
+        let local = fs.local_attrs.lock().unwrap()["docs/a.txt"];
+        assert_eq!(local.attributes, 0);
+        assert_eq!(local.last_write_time, None);
+    }
+
+    #[test]
+    fn file_ids_ignore_case_and_stay_put() {
+        let fs = offline_fs();
+        assert_eq!(fs.file_id(""), 1);
+        let id = fs.file_id("Docs/Report.txt");
+        assert_eq!(fs.file_id("docs/report.TXT"), id);
+        assert_eq!(fs.file_id("Docs/Report.txt"), id);
+        assert_ne!(fs.file_id("Docs/Other.txt"), id);
+    }
+
+    #[test]
+    fn rename_carries_the_ids_of_the_whole_subtree() {
+        let fs = offline_fs();
+        let dir = fs.file_id("old");
+        let file = fs.file_id("old/sub/a.txt");
+        let sibling = fs.file_id("older/b.txt");
+        let replaced = fs.file_id("new/c.txt");
+        fs.rename_file_ids("Old", "New");
+        assert_eq!(fs.file_id("new"), dir);
+        assert_eq!(fs.file_id("new/sub/a.txt"), file);
+        // A name that merely starts with the renamed one is left alone.
+        assert_eq!(fs.file_id("older/b.txt"), sibling);
+        // What the destination held before is gone, not merged.
+        assert_ne!(fs.file_id("new/c.txt"), replaced);
+        assert_ne!(fs.file_id("old"), dir);
+    }
+
+    #[test]
+    fn deleted_paths_get_fresh_ids_when_recreated() {
+        let fs = offline_fs();
+        let dir = fs.file_id("tmp");
+        let file = fs.file_id("tmp/a.txt");
+        let kept = fs.file_id("tmp2");
+        fs.forget_file_ids("TMP");
+        assert_ne!(fs.file_id("tmp"), dir);
+        assert_ne!(fs.file_id("tmp/a.txt"), file);
+        assert_eq!(fs.file_id("tmp2"), kept);
+    }
+}
@@ -0,0 +1,190 @@
+//! Security descriptor reported for every file, built once at startup from SDDL.
+
+use std::ffi::c_void;
+use std::os::windows::ffi::OsStrExt;
+
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE, LocalFree};
+use windows_sys::Win32::Security::Authorization::{
+    ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+};
+use windows_sys::Win32::Security::{GetTokenInformation, TOKEN_QUERY, TOKEN_USER, TokenUser};
+use windows_sys::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+
+/// Self-relative security descriptor shared by all files of the mount.
+pub struct SecurityDescriptor {
+    bytes: Vec<u8>,
+}
+
+impl SecurityDescriptor {
+    /// Builds the descriptor from `--windows-owner` and `--windows-dacl`.
+    ///
+    /// `dacl` is `everyone`, `current-user` or `custom-sddl=<D:...>`; the owner
+    /// (and group) default to the mounting user.
+    pub fn from_options(owner: Option<&str>, dacl: &str) -> Result<Self, String> {
+        let sddl = descriptor_sddl(owner, &current_user_sid()?, dacl)?;
+        Ok(Self {
+            bytes: sddl_to_bytes(&sddl)?,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.bytes.len() as u64
+    }
+
+    /// Copies the descriptor into a WinFSP buffer when it fits and returns the
+    /// required size, which WinFSP reports back when the buffer was too small.
+    pub fn copy_to(&self, buffer: Option<&mut [c_void]>) -> u64 {
+        if let Some(buffer) = buffer {
+            if buffer.len() >= self.bytes.len() {
+                unsafe {
+                    std::ptr::copy_nonoverlapping(
+                        self.bytes.as_ptr(),
+                        buffer.as_mut_ptr() as *mut u8,
+                        self.bytes.len(),
+                    );
+                }
+            }
+        }
+        self.len()
+    }
+}
+
+/// SDDL for the `--windows-owner`/`--windows-dacl` options, with `user` the
+/// SID of the mounting user.
+fn descriptor_sddl(owner: Option<&str>, user: &str, dacl: &str) -> Result<String, String> {
+    let owner = owner.unwrap_or(user);
+    let dacl = match dacl {
+        "everyone" => "D:P(A;OICI;FA;;;WD)".to_string(),
+        "current-user" => format!("D:P(A;OICI;FA;;;{})(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)", user),
+        other => match other.strip_prefix("custom-sddl=") {
+            Some(sddl) if sddl.starts_with("D:") => sddl.to_string(),
+            Some(sddl) => format!("D:{}", sddl),
+            None => {
+                return Err(format!(
+                    "invalid --windows-dacl '{}': expected everyone, current-user or custom-sddl=<SDDL>",
+                    other
+                ));
+            }
+        },
+    };
+    Ok(format!("O:{}G:{}{}", owner, owner, dacl))
+}
+
+fn to_wide_null(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+/// Parses SDDL into a self-relative descriptor owned by Rust.
+fn sddl_to_bytes(sddl: &str) -> Result<Vec<u8>, String> {
+    let wide = to_wide_null(sddl);
+    let mut descriptor: *mut c_void = std::ptr::null_mut();
+    let mut size: u32 = 0;
+    let ok = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            wide.as_ptr(),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            &mut size,
+        )
+    };
+    if ok == 0 || descriptor.is_null() {
+        return Err(format!(
+            "invalid security descriptor '{}': {}",
+            sddl,
+            std::io::Error::last_os_error()
+        ));
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(descriptor as *const u8, size as usize) }.to_vec();
+    unsafe {
+        LocalFree(descriptor);
+    }
+    Ok(bytes)
+}
+
+/// Returns the string SID of the user running the client.
+fn current_user_sid() -> Result<String, String> {
+    let mut token: HANDLE = std::ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) } == 0 {
+        return Err(format!("cannot open process token: {}", std::io::Error::last_os_error()));
+    }
+
+    let mut needed: u32 = 0;
+    unsafe {
+        GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut needed);
+    }
+    // u64 storage keeps TOKEN_USER suitably aligned.
+    let mut buf = vec![0u64; (needed as usize).div_ceil(8)];
+    let ok = unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            buf.as_mut_ptr() as *mut c_void,
+            needed,
+            &mut needed,
+        )
+    };
+    unsafe {
+        CloseHandle(token);
+    }
+    if ok == 0 {
+        return Err(format!("cannot query token user: {}", std::io::Error::last_os_error()));
+    }
+
+    let user = unsafe { &*(buf.as_ptr() as *const TOKEN_USER) };
+    let mut wide: *mut u16 = std::ptr::null_mut();
+    if unsafe { ConvertSidToStringSidW(user.User.Sid, &mut wide) } == 0 {
+        return Err(format!("cannot format user SID: {}", std::io::Error::last_os_error()));
+    }
+    let len = (0..).take_while(|&i| unsafe { *wide.add(i) } != 0).count();
+    let sid = String::from_utf16_lossy(unsafe { std::slice::from_raw_parts(wide, len) });
+    unsafe {
+        LocalFree(wide as *mut c_void);
+    }
+    Ok(sid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = "S-1-5-21-1-2-3-1001";
+
+    #[test]
+    fn dacl_options_expand_to_sddl() {
+        let cases = [
+            (None, "everyone", "O:{u}G:{u}D:P(A;OICI;FA;;;WD)"),
+            (
+                None,
+                "current-user",
+                "O:{u}G:{u}D:P(A;OICI;FA;;;{u})(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)",
+            ),
+            (Some("BA"), "everyone", "O:BAG:BAD:P(A;OICI;FA;;;WD)"),
+            (None, "custom-sddl=D:(A;;FR;;;WD)", "O:{u}G:{u}D:(A;;FR;;;WD)"),
+            // The `D:` prefix may be left out.
+            (None, "custom-sddl=(A;;FR;;;WD)", "O:{u}G:{u}D:(A;;FR;;;WD)"),
+        ];
+        for (owner, dacl, expected) in cases {
+            assert_eq!(
+                descriptor_sddl(owner, USER, dacl).unwrap(),
+                expected.replace("{u}", USER),
+                "{}",
+                dacl
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_dacl_option_is_refused() {
+        let err = descriptor_sddl(None, USER, "nobody").unwrap_err();
+        assert!(err.contains("--windows-dacl 'nobody'"));
+    }
+
+    #[test]
+    fn descriptor_is_built_for_the_current_user() {
+        assert!(SecurityDescriptor::from_options(None, "current-user").unwrap().len() > 0);
+        assert!(SecurityDescriptor::from_options(None, "custom-sddl=(A;;FA;;;NOT-A-SID)").is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Windows service mode: runs a mount under the service control manager.
+
+use super::Unmounter;
+use crate::cli::{Cli, ServiceAction};
+use crate::overlay::DRAIN_TIMEOUT;
+use clap::Parser;
+use std::ffi::c_void;
+use std::fs::OpenOptions;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::IntoRawHandle;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::OnceLock;
+use windows_sys::core::PWSTR;
+use windows_sys::Win32::Foundation::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR};
+use windows_sys::Win32::System::Console::{SetStdHandle, STD_ERROR_HANDLE, STD_OUTPUT_HANDLE};
+use windows_sys::Win32::System::Services::{
+    CloseServiceHandle, ControlService, CreateServiceW, DeleteService, OpenSCManagerW,
+    OpenServiceW, RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+    SC_HANDLE, SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN,
+    SERVICE_ACCEPT_STOP, SERVICE_ALL_ACCESS, SERVICE_AUTO_START, SERVICE_CONTROL_INTERROGATE,
+    SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP, SERVICE_ERROR_NORMAL, SERVICE_QUERY_STATUS,
+    SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STATUS, SERVICE_STATUS_HANDLE, SERVICE_STOP,
+    SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+};
+
+/// Standard DELETE access right, needed to remove a service definition.
+const DELETE: u32 = 0x0001_0000;
+
+/// Used by the control handler when the SCM asks the service to stop.
+static UNMOUNTER: OnceLock<Unmounter> = OnceLock::new();
+static STATUS_HANDLE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+/// Service name for a mountpoint, so each drive letter can have its own service.
+fn service_name(mountpoint: &str) -> String {
+    let suffix: String = mountpoint
+        .chars()
+        .filter(char::is_ascii_alphanumeric)
+        .collect();
+    format!("remote-fs-{}", suffix.to_ascii_uppercase())
+}
+
+fn to_wide_null(s: &str) -> Vec<u16> {
+    std::ffi::OsStr::new(s)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+/// Handles `service install` and `service uninstall`.
+pub fn command(cli: &Cli, action: &ServiceAction) -> Result<(), anyhow::Error> {
+    if cli.mountpoint().is_empty() {
+        anyhow::bail!("A mountpoint is required, e.g. `remote-fs R: service install`");
+    }
+    let name = service_name(cli.mountpoint());
+    let result = match action {
+        ServiceAction::Install => install(&name),
+        ServiceAction::Uninstall => uninstall(&name),
+    };
+    result.map_err(|e| anyhow::anyhow!("Service {} failed: {}", name, e))?;
+    match action {
+        ServiceAction::Install => println!("Installed service {}", name),
+        ServiceAction::Uninstall => println!("Removed service {}", name),
+    }
+    Ok(())
+}
+
+/// Quotes one argument for a Windows command line.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+                continue;
+            }
+            _ => backslashes = 0,
+        }
+        quoted.push(c);
+    }
+    quoted.extend(std::iter::repeat_n('\\', backslashes));
+    quoted.push('"');
+    quoted
+}
+
+/// Command line the SCM starts: this executable with the current mount
+/// options (everything before `service install`) plus `--service`.
+fn service_command_line() -> Result<String, String> {
+    let exe = std::env::current_exe().map_err(|e| format!("cannot locate executable: {}", e))?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let end = args
+        .windows(2)
+        .rposition(|pair| pair[0] == "service" && pair[1] == "install")
+        .unwrap_or(args.len());
+
+    let mut line = quote_arg(&exe.to_string_lossy());
+    for arg in args[..end]
+        .iter()
+        .filter(|arg| *arg != "--daemon" && *arg != "--unmount")
+    {
+        line.push(' ');
+        line.push_str(&quote_arg(arg));
+    }
+    line.push_str(" --service");
+    Ok(line)
+}
+
+/// Owns an SCM handle and closes it on drop.
+struct ScHandle(SC_HANDLE);
+
+impl ScHandle {
+    fn check(handle: SC_HANDLE, what: &str) -> Result<Self, String> {
+        if handle.is_null() {
+            Err(format!("{}: {}", what, std::io::Error::last_os_error()))
+        } else {
+            Ok(Self(handle))
+        }
+    }
+}
+
+impl Drop for ScHandle {
+    fn drop(&mut self) {
+        unsafe {
+            CloseServiceHandle(self.0);
+        }
+    }
+}
+
+fn install(name: &str) -> Result<(), String> {
+    let command_line = service_command_line()?;
+    let manager = ScHandle::check(
+        unsafe {
+            OpenSCManagerW(
+                std::ptr::null(),
+                std::ptr::null(),
+                SC_MANAGER_CREATE_SERVICE,
+            )
+        },
+        "cannot open service manager",
+    )?;
+    let name_w = to_wide_null(name);
+    let display_w = to_wide_null(&format!("Remote FS ({})", name));
+    let command_w = to_wide_null(&command_line);
+    let _service = ScHandle::check(
+        unsafe {
+            CreateServiceW(
+                manager.0,
+                name_w.as_ptr(),
+                display_w.as_ptr(),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command_w.as_ptr(),
+                std::ptr::null(),
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                std::ptr::null(),
+                std::ptr::null(),
+            )
+        },
+        "cannot create service",
+    )?;
+    println!("Service command line: {}", command_line);
+    Ok(())
+}
+
+fn uninstall(name: &str) -> Result<(), String> {
+    let manager = ScHandle::check(
+        unsafe { OpenSCManagerW(std::ptr::null(), std::ptr::null(), SC_MANAGER_CONNECT) },
+        "cannot open service manager",
+    )?;
+    let name_w = to_wide_null(name);
+    let service = ScHandle::check(
+        unsafe {
+            OpenServiceW(
+                manager.0,
+                name_w.as_ptr(),
+                SERVICE_STOP | SERVICE_QUERY_STATUS | DELETE,
+            )
+        },
+        "cannot open service",
+    )?;
+
+    // Stop a running instance first; it drains pending uploads before exiting.
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    unsafe {
+        ControlService(service.0, SERVICE_CONTROL_STOP, &mut status);
+    }
+
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(format!(
+            "cannot delete service: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Connects to the SCM and blocks until the service has stopped.
+pub fn run(cli: &Cli) -> Result<(), anyhow::Error> {
+    let name_w = to_wide_null(&service_name(cli.mountpoint()));
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name_w.as_ptr() as PWSTR,
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: std::ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        anyhow::bail!(
+            "--service must be started by the service control manager: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Log file for a service instance, under %ProgramData%\remote-fs.
+fn log_path(name: &str) -> PathBuf {
+    let mut path = std::env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    path.push("remote-fs");
+    path.push(format!("{}.log", name));
+    path
+}
+
+/// Points stdout and stderr at the service log, since services have no console.
+fn redirect_output(name: &str) {
+    let path = log_path(name);
+    if let Some(dir) = path.parent() {
+        let _ = std::fs::create_dir_all(dir);
+    }
+    let Ok(file) = OpenOptions::new().create(true).append(true).open(&path) else {
+        return;
+    };
+    let handle = file.into_raw_handle();
+    unsafe {
+        SetStdHandle(STD_OUTPUT_HANDLE, handle);
+        SetStdHandle(STD_ERROR_HANDLE, handle);
+    }
+}
+
+fn report(state: u32, wait_hint_ms: u32) {
+    let handle: SERVICE_STATUS_HANDLE = STATUS_HANDLE.load(Ordering::SeqCst);
+    if handle.is_null() {
+        return;
+    }
+    let accepted = if state == SERVICE_RUNNING {
+        SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+    } else {
+        0
+    };
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: accepted,
+        dwWin32ExitCode: NO_ERROR,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: wait_hint_ms,
+    };
+    unsafe {
+        SetServiceStatus(handle, &status);
+    }
+}
+
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut c_void,
+    _context: *mut c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            // Unmounting drains pending uploads, so give the SCM a matching hint.
+            report(
+                SERVICE_STOP_PENDING,
+                (DRAIN_TIMEOUT.as_millis() as u32).saturating_add(10_000),
+            );
+            if let Some(unmounter) = UNMOUNTER.get() {
+                unmounter.unmount();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    // The SCM passes start parameters here, not the installed command line,
+    // so the mount options are read from the process arguments.
+    let cli = Cli::parse();
+    let name = service_name(cli.mountpoint());
+    redirect_output(&name);
+
+    let name_w = to_wide_null(&name);
+    let handle = unsafe {
+        RegisterServiceCtrlHandlerExW(name_w.as_ptr(), Some(control_handler), std::ptr::null())
+    };
+    if handle.is_null() {
+        eprintln!(
+            "Failed to register service control handler: {}",
+            std::io::Error::last_os_error()
+        );
+        return;
+    }
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+
+    report(SERVICE_START_PENDING, 30_000);
+    let result = super::mount(&cli).and_then(|handle| {
+        let _ = UNMOUNTER.set(handle.unmounter());
+        report(SERVICE_RUNNING, 0);
+        handle.wait()
+    });
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+    }
+    report(SERVICE_STOPPED, 0);
+}
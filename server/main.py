@@ -1,52 +1,517 @@
-from fastapi import FastAPI, HTTPException, Request, Header
-from fastapi.responses import FileResponse, Response
+from fastapi import Depends, FastAPI, HTTPException, Request, Header
+from fastapi.exceptions import RequestValidationError
+from fastapi.responses import FileResponse, JSONResponse, Response
+from starlette.exceptions import HTTPException as StarletteHTTPException
+from starlette.requests import ClientDisconnect
 from pydantic import BaseModel
 from pathlib import Path
+import errno
+import hashlib
+import json
+import logging
 import shutil
 import os
+import stat
+import tempfile
+import threading
+import time
+import tomllib
 import uvicorn
+from logging.handlers import RotatingFileHandler
+from urllib.parse import parse_qs, quote_from_bytes, unquote_to_bytes
 from dotenv import load_dotenv
 
+try:
+    import msgpack
+except ImportError:  # Listings are then always JSON.
+    msgpack = None
+
+MSGPACK_TYPE = "application/msgpack"
+
 # Runtime configuration loaded from environment variables.
 HOST = os.getenv("HOST", "127.0.0.1")
 PORT = int(os.getenv("PORT", 8000))
 BASE_DIR = Path(os.getenv("BASE_DIR", "./storage"))
 DEBUG = os.getenv("DEBUG", "false").lower() == "true"
+# Largest accepted upload in bytes (0 = unlimited).
+MAX_UPLOAD_BYTES = int(os.getenv("MAX_UPLOAD_BYTES", 0))
+# Free space an upload must leave on the storage disk, in bytes.
+MIN_FREE_BYTES = int(os.getenv("MIN_FREE_BYTES", 0))
+# Most hits returned by one /search request.
+MAX_SEARCH_RESULTS = int(os.getenv("MAX_SEARCH_RESULTS", 1000))
+# TOML file with tokens and per-path grants; unset leaves the server open.
+AUTH_CONFIG = os.getenv("AUTH_CONFIG")
+# TOML file with byte/file limits per top-level directory; unset disables quotas.
+QUOTA_CONFIG = os.getenv("QUOTA_CONFIG")
+# JSON-lines log of every mutating request; unset disables auditing.
+AUDIT_LOG = os.getenv("AUDIT_LOG")
+# Size at which the audit log rotates, and how many rotated files are kept.
+AUDIT_MAX_BYTES = int(os.getenv("AUDIT_MAX_BYTES", 10 * 1024 * 1024))
+AUDIT_BACKUPS = int(os.getenv("AUDIT_BACKUPS", 5))
 
 app = FastAPI()
 
 BASE_DIR = Path("./storage")
 BASE_DIR.mkdir(exist_ok=True)
 
+logger = logging.getLogger("uvicorn.error")
+
+
+# Echoes the client's X-Request-ID and logs it, so client and server logs correlate.
+@app.middleware("http")
+async def request_id(request: Request, call_next):
+    rid = request.headers.get("x-request-id", "-")
+    response = await call_next(request)
+    response.headers["X-Request-ID"] = rid
+    logger.info("[%s] %s %s -> %d", rid, request.method, request.url.path, response.status_code)
+    return response
+
+
+# Decodes URL paths as raw bytes, so names that are not UTF-8 reach the filesystem intact.
+@app.middleware("http")
+async def raw_paths(request: Request, call_next):
+    raw = request.scope.get("raw_path")
+    if raw:
+        path = unquote_to_bytes(raw.split(b"?", 1)[0])
+        request.scope["path"] = path.decode("utf-8", "surrogateescape")
+    return await call_next(request)
+
+
+def raw_query(request: Request, name: str) -> str | None:
+    """Query parameter `name`, decoded like `raw_paths` decodes the path."""
+    query = request.scope.get("query_string", b"").decode("latin-1")
+    values = parse_qs(query, encoding="utf-8", errors="surrogateescape")
+    return values.get(name, [None])[0]
+
+
+# The OS hands back bytes that are not UTF-8 as surrogate escapes; JSON carries
+# each as U+10FF00 + byte, which clients turn back into the raw byte. A name
+# that really contains U+10FF80..U+10FFFF sends that character's UTF-8 bytes
+# escaped the same way, so clients don't mistake it for a single raw byte.
+def wire_char(c: str) -> str:
+    if 0xDC80 <= ord(c) <= 0xDCFF:
+        return chr(0x10FF00 + ord(c) - 0xDC00)
+    if 0x10FF80 <= ord(c) <= 0x10FFFF:
+        return "".join(chr(0x10FF00 + b) for b in c.encode())
+    return c
+
+
+def wire_name(name: str) -> str:
+    return "".join(wire_char(c) for c in name)
+
+
+audit_logger = logging.getLogger("remotefs.audit")
+audit_logger.propagate = False
+if AUDIT_LOG:
+    audit_handler = RotatingFileHandler(
+        AUDIT_LOG, maxBytes=AUDIT_MAX_BYTES, backupCount=AUDIT_BACKUPS, encoding="utf-8"
+    )
+    audit_handler.setFormatter(logging.Formatter("%(message)s"))
+    audit_logger.addHandler(audit_handler)
+    audit_logger.setLevel(logging.INFO)
+
+MUTATING_METHODS = {"PUT", "POST", "DELETE"}
+
+
+# Records who changed what: one JSON line per mutating request, whatever its outcome.
+@app.middleware("http")
+async def audit(request: Request, call_next):
+    if not AUDIT_LOG or request.method not in MUTATING_METHODS:
+        return await call_next(request)
+    status = 500
+    try:
+        response = await call_next(request)
+        status = response.status_code
+        return response
+    finally:
+        principal = getattr(request.state, "principal", None)
+        length = request.headers.get("content-length")
+        record = {
+            "ts": time.time(),
+            # None when the request never authenticated, e.g. on an open server.
+            "principal": principal.name if principal and principal is not OPEN else None,
+            "client": request.client.host if request.client else None,
+            "method": request.method,
+            "path": request.scope["path"],
+            "to": raw_query(request, "to"),
+            "size": int(length) if length and length.isdigit() else None,
+            "status": status,
+            "result": "ok" if status < 400 else "error",
+            "request_id": request.headers.get("x-request-id"),
+        }
+        audit_logger.info(json.dumps(record))
+
+
+# Machine-readable `code` of each error status, sent in the error envelope.
+ERROR_CODES = {
+    400: "bad_request",
+    401: "unauthorized",
+    403: "forbidden",
+    404: "not_found",
+    405: "method_not_allowed",
+    409: "conflict",
+    412: "precondition_failed",
+    413: "too_large",
+    416: "bad_range",
+    422: "invalid_request",
+    500: "internal",
+    507: "no_space",
+}
+
+
+# Every error is answered as {code, message, path, request_id}.
+def error_response(request: Request, status: int, message: str, headers: dict | None = None):
+    rid = request.headers.get("x-request-id", "-")
+    return JSONResponse(
+        status_code=status,
+        content={
+            "code": ERROR_CODES.get(status, "error"),
+            "message": message,
+            "path": request.path_params.get("subpath", request.url.path),
+            "request_id": rid,
+        },
+        headers={**(headers or {}), "X-Request-ID": rid},
+    )
+
+
+@app.exception_handler(StarletteHTTPException)
+async def http_error(request: Request, exc: StarletteHTTPException):
+    return error_response(request, exc.status_code, str(exc.detail), exc.headers)
+
+
+@app.exception_handler(RequestValidationError)
+async def validation_error(request: Request, exc: RequestValidationError):
+    problems = "; ".join(
+        f"{'.'.join(str(part) for part in err['loc'])}: {err['msg']}" for err in exc.errors()
+    )
+    return error_response(request, 422, f"Invalid request: {problems}")
+
+
+@app.exception_handler(Exception)
+async def unhandled_error(request: Request, exc: Exception):
+    rid = request.headers.get("x-request-id", "-")
+    logger.exception("[%s] unhandled error on %s %s", rid, request.method, request.url.path)
+    return error_response(request, 500, f"Internal error: {type(exc).__name__}")
+
+
+def clean_path(path: str) -> str:
+    return "/".join(seg for seg in path.split("/") if seg and seg != ".")
+
+
+# Whoever sent a request, with read ("r") or read-write ("rw") grants on path prefixes.
+class Principal:
+    def __init__(self, name: str, grants: dict[str, str], admin: bool = False):
+        self.name = name
+        self.grants = grants
+        # Admins may read the audit log.
+        self.admin = admin
+
+    def access(self, path: str) -> str | None:
+        """Grant of the longest prefix covering `path`, if any."""
+        best = None
+        for prefix, mode in self.grants.items():
+            if prefix == "" or path == prefix or path.startswith(prefix + "/"):
+                if best is None or len(prefix) > len(best[0]):
+                    best = (prefix, mode)
+        return best[1] if best else None
+
+    def sees(self, path: str) -> bool:
+        """True for granted paths and for the directories leading to them."""
+        if self.access(path) is not None:
+            return True
+        return any(path == "" or prefix.startswith(path + "/") for prefix in self.grants)
+
+
+def load_auth(path: str) -> tuple[dict[str, Principal], Principal | None]:
+    """Reads AUTH_CONFIG: [[users]] with name/token/read/write/admin, optional [anonymous]."""
+    with open(path, "rb") as f:
+        config = tomllib.load(f)
+
+    def grants(section: dict) -> dict[str, str]:
+        result = {clean_path(p): "r" for p in section.get("read", [])}
+        result.update({clean_path(p): "rw" for p in section.get("write", [])})
+        return result
+
+    users = {
+        u["token"]: Principal(u["name"], grants(u), u.get("admin", False))
+        for u in config.get("users", [])
+    }
+    anonymous = config.get("anonymous")
+    return users, Principal("anonymous", grants(anonymous)) if anonymous is not None else None
+
+
+AUTH = load_auth(AUTH_CONFIG) if AUTH_CONFIG else None
+# Without AUTH_CONFIG every request may do everything.
+OPEN = Principal("anonymous", {"": "rw"}, admin=True)
+
+
+def resolve_principal(authorization: str | None) -> Principal:
+    if AUTH is None:
+        return OPEN
+    users, anonymous = AUTH
+    scheme, _, token = (authorization or "").partition(" ")
+    if scheme.lower() == "bearer" and token:
+        if token.strip() in users:
+            return users[token.strip()]
+    elif anonymous is not None:
+        return anonymous
+    raise HTTPException(
+        status_code=401, detail="Missing or unknown token", headers={"WWW-Authenticate": "Bearer"}
+    )
+
+
+def current_principal(request: Request, authorization: str | None = Header(None)) -> Principal:
+    principal = resolve_principal(authorization)
+    # Kept on the request for the audit log.
+    request.state.principal = principal
+    return principal
+
+
+def tree_path(root: Path, target: Path) -> str:
+    """`target` relative to `root`; paths resolving outside the tree do not exist."""
+    try:
+        path = target.relative_to(root.resolve()).as_posix()
+    except ValueError:
+        raise HTTPException(status_code=404, detail="Path not found")
+    return "" if path == "." else path
+
+
+def require_read(principal: Principal, root: Path, target: Path, listing: bool = False):
+    """Paths outside the principal's grants look missing: 404, not 403."""
+    path = tree_path(root, target)
+    allowed = principal.sees(path) if listing else principal.access(path) is not None
+    if not allowed:
+        raise HTTPException(status_code=404, detail="Path not found")
+
+
+def require_write(principal: Principal, target: Path):
+    path = tree_path(BASE_DIR, target)
+    if principal.access(path) != "rw":
+        raise HTTPException(status_code=403, detail=f"{principal.name} may not modify /{path}")
+
+# Soft-deleted entries live in .trash/<id>/, with the original path in .origin.
+TRASH_DIR = BASE_DIR / ".trash"
+# Read-only copies of the whole tree live in .snapshots/<id>/.
+SNAPSHOTS_DIR = BASE_DIR / ".snapshots"
+HIDDEN_DIRS = {TRASH_DIR.resolve(), SNAPSHOTS_DIR.resolve()}
+
+
+def read_root(snapshot: str | None) -> Path:
+    """Returns the tree reads are served from: live storage or a snapshot."""
+    if snapshot is None:
+        return BASE_DIR
+    root = (SNAPSHOTS_DIR / snapshot).resolve()
+    if root.parent != SNAPSHOTS_DIR.resolve() or not root.is_dir():
+        raise HTTPException(status_code=404, detail="Snapshot not found")
+    return root
+
+
+SERVER_VERSION = "0.1.0"
+
+
+# GET /health: cheap unauthenticated check, which clients also use to open a
+# connection ahead of their first real request.
+@app.get("/health")
+def health():
+    return {"status": "ok"}
+
+
+# GET /capabilities: version and optional features, probed by clients at mount.
+@app.get("/capabilities")
+def capabilities():
+    return {
+        "version": SERVER_VERSION,
+        "range": True,
+        "trash": True,
+        "snapshots": True,
+        "attrs": True,
+        "rename": True,
+        "search": True,
+        "audit": bool(AUDIT_LOG),
+        "quota": bool(QUOTAS),
+        "checksums": True,
+        "appends": True,
+        "list_pages": True,
+        "max_upload_bytes": MAX_UPLOAD_BYTES or None,
+    }
+
 
 # Directory entry returned to clients for /list responses.
 class RemoteEntry(BaseModel):
     name: str
     is_dir: bool
+    # File type: dir, file, symlink, fifo, socket, chardev or blockdev.
+    kind: str = "file"
     size: int
+    mode: int
+    mtime: float
+    ctime: float
+    crtime: float | None = None
+    uid: int
+    gid: int
+    # Number of direct children, for directories only.
+    entry_count: int | None = None
+    # 512-byte blocks allocated on disk, where the platform reports them.
+    blocks: int | None = None
+    # Changes whenever the file is rewritten or replaced; clients compare it
+    # to detect edits made behind their back.
+    etag: str | None = None
+
+
+ENTRY_KINDS = (
+    (stat.S_ISDIR, "dir"),
+    (stat.S_ISLNK, "symlink"),
+    (stat.S_ISFIFO, "fifo"),
+    (stat.S_ISSOCK, "socket"),
+    (stat.S_ISCHR, "chardev"),
+    (stat.S_ISBLK, "blockdev"),
+)
+
+
+def entry_kind(mode: int) -> str:
+    """RemoteEntry kind for an st_mode; anything unrecognised is a regular file."""
+    return next((kind for test, kind in ENTRY_KINDS if test(mode)), "file")
+
+
+def stat_fields(st: os.stat_result) -> dict:
+    """RemoteEntry fields taken from a stat result."""
+    return {
+        "kind": entry_kind(st.st_mode),
+        "size": st.st_size,
+        "mode": st.st_mode & 0o777,
+        "mtime": st.st_mtime,
+        "ctime": st.st_ctime,
+        # Birth time is only exposed on macOS/BSD and Windows.
+        "crtime": getattr(st, "st_birthtime", None),
+        "uid": st.st_uid,
+        "gid": st.st_gid,
+        "blocks": getattr(st, "st_blocks", None),
+        "etag": f'"{st.st_ino:x}-{st.st_mtime_ns:x}-{st.st_size:x}"',
+    }
 
 # GET /list/{subpath}: returns direct children metadata for a directory.
+# Sent as MessagePack when the client accepts it and the msgpack package is installed.
+# With ?limit=<n>, returns at most n entries in name order, after the entry
+# named by ?cursor= when given, and names the next page's cursor in
+# X-Next-Cursor while entries remain.
 @app.get("/list/{subpath:path}")
-def list_dir(subpath: str):
-    target = (BASE_DIR / subpath).resolve()
+def list_dir(
+    subpath: str,
+    request: Request,
+    snapshot: str | None = None,
+    cursor: str | None = None,
+    limit: int | None = None,
+    principal: Principal = Depends(current_principal),
+):
+    root = read_root(snapshot)
+    target = (root / subpath).resolve()
+    require_read(principal, root, target, listing=True)
     if not target.exists() or not target.is_dir():
         raise HTTPException(status_code=404, detail="Directory not found")
 
+    def visible(entry: Path) -> bool:
+        return entry not in HIDDEN_DIRS and principal.sees(tree_path(root, entry.resolve()))
+
+    children = target.iterdir()
+    if limit is not None:
+        if limit < 1:
+            raise HTTPException(status_code=400, detail="limit must be positive")
+        children = sorted(children, key=lambda p: p.name)
+        if cursor:
+            after = os.fsdecode(unquote_to_bytes(cursor))
+            children = [child for child in children if child.name > after]
+
     entries = []
-    for entry in target.iterdir():
+    next_cursor = None
+    for entry in children:
+        if entry.name.startswith(UPLOAD_PREFIX) or not visible(entry):
+            continue
+        if limit is not None and len(entries) == limit:
+            next_cursor = quote_from_bytes(os.fsencode(last_name), safe="")
+            break
+        last_name = entry.name
+        st = entry.stat()
+        entry_count = None
+        if entry.is_dir():
+            try:
+                entry_count = sum(1 for child in entry.iterdir() if visible(child))
+            except OSError:
+                pass
         entries.append(
             RemoteEntry(
-                name=entry.name,
+                name=wire_name(entry.name),
                 is_dir=entry.is_dir(),
-                size=entry.stat().st_size,
+                entry_count=entry_count,
+                **stat_fields(st),
             )
         )
+    headers = {"X-Next-Cursor": next_cursor} if next_cursor else None
+    if msgpack is not None and MSGPACK_TYPE in request.headers.get("accept", ""):
+        body = msgpack.packb([entry.model_dump() for entry in entries])
+        return Response(content=body, media_type=MSGPACK_TYPE, headers=headers)
+    if headers:
+        return JSONResponse([entry.model_dump() for entry in entries], headers=headers)
     return entries
 
+
+# Search hit: a file's path below the searched directory, plus its entry fields.
+class SearchHit(RemoteEntry):
+    path: str
+
+
+# GET /search/{subpath}?q=<text>: files below subpath whose name contains text, ignoring case.
+@app.get("/search/{subpath:path}")
+def search(
+    subpath: str,
+    q: str,
+    request: Request,
+    snapshot: str | None = None,
+    principal: Principal = Depends(current_principal),
+):
+    needle = (raw_query(request, "q") or q).lower()
+    root = read_root(snapshot)
+    base = (root / subpath).resolve()
+    require_read(principal, root, base, listing=True)
+    if not base.is_dir():
+        raise HTTPException(status_code=404, detail="Directory not found")
+
+    def visible(entry: Path) -> bool:
+        resolved = entry.resolve()
+        return resolved not in HIDDEN_DIRS and principal.sees(tree_path(root, resolved))
+
+    hits = []
+    for dirpath, dirnames, filenames in os.walk(base):
+        here = Path(dirpath)
+        dirnames[:] = sorted(d for d in dirnames if visible(here / d))
+        for name in sorted(filenames):
+            entry = here / name
+            if needle not in name.lower() or name.startswith(UPLOAD_PREFIX):
+                continue
+            if principal.access(tree_path(root, entry.resolve())) is None:
+                continue
+            hits.append(
+                SearchHit(
+                    path=wire_name(entry.relative_to(base).as_posix()),
+                    name=wire_name(name),
+                    is_dir=False,
+                    **stat_fields(entry.stat()),
+                )
+            )
+            if len(hits) >= MAX_SEARCH_RESULTS:
+                return hits
+    return hits
+
 # GET /files/{subpath}: downloads a file; supports HTTP Range for partial reads.
 @app.get("/files/{subpath:path}")
-def read_file(subpath: str, range: str = Header(None)):
-    target = (BASE_DIR / subpath).resolve()
+def read_file(
+    subpath: str,
+    range: str = Header(None),
+    snapshot: str | None = None,
+    principal: Principal = Depends(current_principal),
+):
+    root = read_root(snapshot)
+    target = (root / subpath).resolve()
+    require_read(principal, root, target)
     if not target.exists() or not target.is_file():
         raise HTTPException(status_code=404, detail="File not found")
 
@@ -73,54 +538,519 @@ def read_file(subpath: str, range: str = Header(None)):
                 "Content-Length": str(length),
                 "Accept-Ranges": "bytes",
             },
-            media_type="application/octet-stream",
+            media_type=stored_content_type(target) or "application/octet-stream",
+        )
+
+    return FileResponse(target, media_type=stored_content_type(target))
+
+# Prefix of in-progress uploads; hidden from listings.
+UPLOAD_PREFIX = ".upload-"
+
+# Extended attribute holding the Content-Type a file was uploaded with.
+TYPE_XATTR = "user.remote_fs.content_type"
+
+
+def store_content_type(path: Path, content_type: str | None):
+    """Records an upload's Content-Type on `path`; skipped where xattrs are unavailable."""
+    if not content_type or not hasattr(os, "setxattr"):
+        return
+    try:
+        os.setxattr(path, TYPE_XATTR, content_type.encode())
+    except OSError:
+        pass
+
+
+def stored_content_type(path: Path) -> str | None:
+    """Content-Type recorded by `store_content_type`, if any."""
+    if not hasattr(os, "getxattr"):
+        return None
+    try:
+        return os.getxattr(path, TYPE_XATTR).decode()
+    except OSError:
+        return None
+
+
+def check_upload_room(size: int):
+    """Rejects an upload of `size` bytes with 413 or 507 before it is stored."""
+    if MAX_UPLOAD_BYTES and size > MAX_UPLOAD_BYTES:
+        raise HTTPException(
+            status_code=413,
+            detail=f"Upload of {size} bytes exceeds the {MAX_UPLOAD_BYTES} byte limit",
+        )
+    free = shutil.disk_usage(BASE_DIR).free
+    if size + MIN_FREE_BYTES > free:
+        raise HTTPException(
+            status_code=507,
+            detail=f"Insufficient storage: {size} bytes requested, {free} bytes free",
+        )
+
+
+# Limits and current usage of one top-level directory.
+class Quota:
+    def __init__(self, name: str, max_bytes: int | None, max_files: int | None):
+        self.name = name
+        self.max_bytes = max_bytes
+        self.max_files = max_files
+        self.used_bytes = 0
+        self.used_files = 0
+
+    def left(self) -> tuple[int | None, int | None]:
+        return (
+            None if self.max_bytes is None else max(self.max_bytes - self.used_bytes, 0),
+            None if self.max_files is None else max(self.max_files - self.used_files, 0),
+        )
+
+
+# Guards every Quota's usage counters; handlers run on several threads.
+quota_lock = threading.Lock()
+
+
+def tree_usage(path: Path) -> tuple[int, int]:
+    """Bytes and number of files in `path`, a file or a directory tree."""
+    if path.is_file():
+        return path.stat().st_size, 1
+    size = files = 0
+    for dirpath, _, filenames in os.walk(path):
+        for name in filenames:
+            if name.startswith(UPLOAD_PREFIX):
+                continue
+            try:
+                size += (Path(dirpath) / name).stat().st_size
+                files += 1
+            except OSError:
+                pass
+    return size, files
+
+
+def load_quotas(path: str) -> dict[str, Quota]:
+    """Reads QUOTA_CONFIG: [quota.<dir>] tables with max_bytes and/or max_files."""
+    with open(path, "rb") as f:
+        config = tomllib.load(f)
+    quotas = {}
+    for name, limits in config.get("quota", {}).items():
+        quota = Quota(clean_path(name), limits.get("max_bytes"), limits.get("max_files"))
+        # Counted once at startup, then kept up to date by every change.
+        quota.used_bytes, quota.used_files = tree_usage(BASE_DIR / quota.name)
+        quotas[quota.name] = quota
+    return quotas
+
+
+QUOTAS = load_quotas(QUOTA_CONFIG) if QUOTA_CONFIG else {}
+
+
+def quota_for(target: Path) -> Quota | None:
+    """Quota of the top-level directory holding `target`, if it has one."""
+    try:
+        path = target.relative_to(BASE_DIR.resolve()).as_posix()
+    except ValueError:
+        return None
+    return QUOTAS.get(path.split("/", 1)[0])
+
+
+def charge(target: Path, size: int, files: int, commit: bool = True):
+    """Adds `size` bytes and `files` files to the quota over `target`.
+
+    Growth past a limit fails with 507 and changes nothing; with `commit`
+    false the usage is only checked.
+    """
+    quota = quota_for(target)
+    if quota is None:
+        return
+    with quota_lock:
+        over_bytes = (
+            size > 0 and quota.max_bytes is not None and quota.used_bytes + size > quota.max_bytes
+        )
+        over_files = (
+            files > 0 and quota.max_files is not None and quota.used_files + files > quota.max_files
         )
+        if over_bytes or over_files:
+            bytes_left, files_left = quota.left()
+            raise HTTPException(
+                status_code=507,
+                detail=f"Quota of /{quota.name} exceeded: "
+                f"{'unlimited' if bytes_left is None else bytes_left} bytes and "
+                f"{'unlimited' if files_left is None else files_left} files left",
+            )
+        if commit:
+            quota.used_bytes += size
+            quota.used_files += files
+
+
+def rescan(target: Path):
+    """Recounts the quota over `target` after a change that may have half-failed."""
+    quota = quota_for(target)
+    if quota is not None:
+        usage = tree_usage(BASE_DIR / quota.name)
+        with quota_lock:
+            quota.used_bytes, quota.used_files = usage
 
-    return FileResponse(target)
 
 # PUT /files/{subpath}: writes or replaces a file with the request body.
+# ?mode= sets the permission bits; without it a replaced file keeps its own.
+# Oversized uploads fail with 413 and a full disk with 507, both with a JSON detail.
+# Answers with the SHA-256, size and mtime of what was stored, so clients can skip
+# re-uploading identical content.
+# ?offset=N appends the body to a file that is exactly N bytes long (412 otherwise),
+# so a long stream can be uploaded in pieces; the answer then carries no SHA-256.
 @app.put("/files/{subpath:path}")
-async def write_file(subpath: str, request: Request):
+async def write_file(
+    subpath: str,
+    request: Request,
+    mode: int | None = None,
+    offset: int = 0,
+    principal: Principal = Depends(current_principal),
+):
     target = (BASE_DIR / subpath).resolve()
+    require_write(principal, target)
+    if offset:
+        return await append_file(target, request, offset)
+    # A replaced file gives its bytes back to the quota.
+    old_size = target.stat().st_size if target.is_file() else None
+    added_files = 0 if old_size is not None else 1
+    old_size = old_size or 0
+    # Refuse up front when the announced size is already too big.
+    length = request.headers.get("content-length")
+    if length is not None and length.isdigit():
+        check_upload_room(int(length))
+        charge(target, int(length) - old_size, added_files, commit=False)
     target.parent.mkdir(parents=True, exist_ok=True)
+    # Stream into a temp file next to the target, so a refused upload leaves the old file intact.
+    fd, tmp = tempfile.mkstemp(dir=target.parent, prefix=UPLOAD_PREFIX)
     try:
-        body = await request.body()
-        with open(target, "wb") as f:
-            f.write(body)
-    except Exception as e:
+        written = 0
+        digest = hashlib.sha256()
+        with os.fdopen(fd, "wb") as f:
+            async for chunk in request.stream():
+                written += len(chunk)
+                if MAX_UPLOAD_BYTES and written > MAX_UPLOAD_BYTES:
+                    check_upload_room(written)
+                # Bodies without a usable length are held to the quota as they arrive.
+                charge(target, written - old_size, added_files, commit=False)
+                digest.update(chunk)
+                f.write(chunk)
+        if mode is None:
+            mode = target.stat().st_mode if target.exists() else 0o644
+        os.chmod(tmp, mode & 0o777)
+        # Kept with the file, so downloads are served with the type it was uploaded as.
+        store_content_type(Path(tmp), request.headers.get("content-type"))
+        charge(target, written - old_size, added_files)
+        try:
+            os.replace(tmp, target)
+        except OSError:
+            charge(target, old_size - written, -added_files)
+            raise
+    # A client that gives up mid-upload (a cancelled write) leaves nothing behind.
+    except (HTTPException, ClientDisconnect):
+        os.unlink(tmp)
+        raise
+    except OSError as e:
+        os.unlink(tmp)
+        if e.errno == errno.ENOSPC:
+            raise HTTPException(status_code=507, detail="Insufficient storage: disk full")
+        raise HTTPException(status_code=500, detail=f"Write error: {e}")
+    return {
+        "status": "ok",
+        "sha256": digest.hexdigest(),
+        "size": written,
+        "mtime": target.stat().st_mtime,
+    }
+
+async def append_file(target: Path, request: Request, offset: int):
+    """Appends the request body to `target`, which must be `offset` bytes long."""
+    if not target.is_file() or target.stat().st_size != offset:
+        raise HTTPException(status_code=412, detail=f"File is not {offset} bytes long")
+    length = request.headers.get("content-length")
+    if length is not None and length.isdigit():
+        check_upload_room(int(length))
+        charge(target, int(length), 0, commit=False)
+    written = 0
+    try:
+        with open(target, "ab") as f:
+            async for chunk in request.stream():
+                written += len(chunk)
+                # The size limit applies to the whole file, not to one piece.
+                if MAX_UPLOAD_BYTES and offset + written > MAX_UPLOAD_BYTES:
+                    check_upload_room(offset + written)
+                charge(target, written, 0, commit=False)
+                f.write(chunk)
+        charge(target, written, 0)
+    except (HTTPException, ClientDisconnect):
+        os.truncate(target, offset)
+        raise
+    except OSError as e:
+        os.truncate(target, offset)
+        if e.errno == errno.ENOSPC:
+            raise HTTPException(status_code=507, detail="Insufficient storage: disk full")
         raise HTTPException(status_code=500, detail=f"Write error: {e}")
+    return {"status": "ok", "size": offset + written, "mtime": target.stat().st_mtime}
+
+
+# POST /attrs/{subpath}: sets the modification time and/or permission bits.
+@app.post("/attrs/{subpath:path}")
+def set_attrs(
+    subpath: str,
+    mtime: float | None = None,
+    mode: int | None = None,
+    principal: Principal = Depends(current_principal),
+):
+    target = (BASE_DIR / subpath).resolve()
+    require_write(principal, target)
+    if not target.exists():
+        raise HTTPException(status_code=404, detail="Path not found")
+    try:
+        if mtime is not None:
+            os.utime(target, (target.stat().st_atime, mtime))
+        if mode is not None:
+            target.chmod(mode & 0o777)
+    except Exception as e:
+        raise HTTPException(status_code=500, detail=f"Attribute error: {e}")
     return {"status": "ok"}
 
 # POST /mkdir/{subpath}: creates a directory path recursively.
+# ?mode= sets the permission bits of the last directory when it is created.
 @app.post("/mkdir/{subpath:path}")
-def create_dir(subpath: str):
+def create_dir(
+    subpath: str, mode: int | None = None, principal: Principal = Depends(current_principal)
+):
     target = (BASE_DIR / subpath).resolve()
+    require_write(principal, target)
     try:
+        created = not target.exists()
         target.mkdir(parents=True, exist_ok=True)
+        if created and mode is not None:
+            target.chmod(mode & 0o777)
     except Exception as e:
         raise HTTPException(status_code=500, detail=f"Create dir error: {e}")
     return {"status": "ok"}
 
 
-# DELETE /files/{subpath}: deletes a file or a directory tree.
+# POST /move/{subpath}?to=<path>: renames a file or a whole directory tree in place.
+@app.post("/move/{subpath:path}")
+def move_path(
+    subpath: str, to: str, request: Request, principal: Principal = Depends(current_principal)
+):
+    to = raw_query(request, "to") or to
+    source = (BASE_DIR / subpath).resolve()
+    target = (BASE_DIR / to).resolve()
+    require_write(principal, source)
+    require_write(principal, target)
+    if not source.exists():
+        raise HTTPException(status_code=404, detail="Path not found")
+    if target == source or source in target.parents:
+        raise HTTPException(status_code=400, detail="Cannot move a path into itself")
+    if target.is_dir() and not source.is_dir():
+        raise HTTPException(status_code=409, detail="Target is a directory")
+    across_quotas = quota_for(source) is not quota_for(target)
+    moved = tree_usage(source) if across_quotas else (0, 0)
+    replaced = tree_usage(target) if target.exists() else (0, 0)
+    charge(target, moved[0] - replaced[0], moved[1] - replaced[1])
+    try:
+        target.parent.mkdir(parents=True, exist_ok=True)
+        # Same semantics as rename(2): replaces a file or an empty directory.
+        os.replace(source, target)
+    except OSError as e:
+        rescan(target)
+        raise HTTPException(status_code=409, detail=f"Move error: {e}")
+    charge(source, -moved[0], -moved[1])
+    return {"status": "ok"}
+
+
+# DELETE /files/{subpath}: deletes a file or a directory tree (?trash=true moves it to the trash).
 @app.delete("/files/{subpath:path}")
-def delete_path(subpath: str):
+def delete_path(
+    subpath: str, trash: bool = False, principal: Principal = Depends(current_principal)
+):
     target = (BASE_DIR / subpath).resolve()
+    require_write(principal, target)
     if not target.exists():
         raise HTTPException(status_code=404, detail="Path not found")
+    freed = tree_usage(target)
     try:
-        if target.is_file():
+        if trash:
+            entry_id = str(time.time_ns())
+            slot = TRASH_DIR / entry_id
+            slot.mkdir(parents=True)
+            (slot / ".origin").write_text(subpath.strip("/"), errors="surrogateescape")
+            shutil.move(str(target), str(slot / target.name))
+        elif target.is_file():
             target.unlink()
         else:
             shutil.rmtree(target)
     except Exception as e:
+        rescan(target)
         raise HTTPException(status_code=500, detail=f"Delete error: {e}")
+    charge(target, -freed[0], -freed[1])
     return {"status": "ok"}
 
+# Entry of the trash listing returned by GET /trash.
+class TrashEntry(BaseModel):
+    id: str
+    path: str
+    is_dir: bool
+    deleted_at: float
+
+
+def trash_slot(entry_id: str) -> Path:
+    slot = (TRASH_DIR / entry_id).resolve()
+    if slot.parent != TRASH_DIR.resolve() or not (slot / ".origin").is_file():
+        raise HTTPException(status_code=404, detail="Trash entry not found")
+    return slot
+
+
+# GET /trash: lists soft-deleted entries, oldest first.
+@app.get("/trash")
+def list_trash(principal: Principal = Depends(current_principal)):
+    if not TRASH_DIR.is_dir():
+        return []
+    entries = []
+    for slot in sorted(TRASH_DIR.iterdir(), key=lambda p: p.name):
+        origin = slot / ".origin"
+        if not origin.is_file():
+            continue
+        path = origin.read_text(errors="surrogateescape")
+        if principal.access(path) is None:
+            continue
+        item = slot / Path(path).name
+        entries.append(
+            TrashEntry(
+                id=slot.name,
+                path=wire_name(path),
+                is_dir=item.is_dir(),
+                deleted_at=int(slot.name) / 1e9,
+            )
+        )
+    return entries
+
+
+# POST /trash/restore?id=<id>: moves a trashed entry back to its original path.
+@app.post("/trash/restore")
+def restore_trash(id: str, principal: Principal = Depends(current_principal)):
+    slot = trash_slot(id)
+    path = (slot / ".origin").read_text(errors="surrogateescape")
+    target = (BASE_DIR / path).resolve()
+    require_write(principal, target)
+    if target.exists():
+        raise HTTPException(status_code=409, detail="Original path already exists")
+    item = slot / Path(path).name
+    restored = tree_usage(item)
+    charge(target, restored[0], restored[1])
+    target.parent.mkdir(parents=True, exist_ok=True)
+    try:
+        shutil.move(str(item), str(target))
+    except OSError:
+        rescan(target)
+        raise
+    shutil.rmtree(slot)
+    return {"status": "ok"}
+
+
+# DELETE /trash: permanently removes everything in the trash.
+@app.delete("/trash")
+def empty_trash(principal: Principal = Depends(current_principal)):
+    require_write(principal, BASE_DIR.resolve())
+    if TRASH_DIR.exists():
+        shutil.rmtree(TRASH_DIR)
+    return {"status": "ok"}
+
+# Snapshot metadata returned by /snapshots.
+class SnapshotInfo(BaseModel):
+    id: str
+    created_at: float
+
+# GET /snapshots: lists the read-only snapshots of the tree.
+@app.get("/snapshots")
+def list_snapshots():
+    if not SNAPSHOTS_DIR.is_dir():
+        return []
+    return [
+        SnapshotInfo(id=slot.name, created_at=slot.stat().st_mtime)
+        for slot in sorted(SNAPSHOTS_DIR.iterdir(), key=lambda p: p.name)
+        if slot.is_dir()
+    ]
+
+# POST /snapshots: copies the current tree into a new snapshot (?name= picks its id).
+@app.post("/snapshots")
+def create_snapshot(
+    name: str | None = None, principal: Principal = Depends(current_principal)
+):
+    require_write(principal, BASE_DIR.resolve())
+    entry_id = name or str(time.time_ns())
+    slot = (SNAPSHOTS_DIR / entry_id).resolve()
+    if slot.parent != SNAPSHOTS_DIR.resolve():
+        raise HTTPException(status_code=400, detail="Invalid snapshot name")
+    if slot.exists():
+        raise HTTPException(status_code=409, detail="Snapshot already exists")
+    try:
+        shutil.copytree(
+            BASE_DIR,
+            slot,
+            ignore=lambda d, names: [
+                n for n in names if (Path(d) / n).resolve() in HIDDEN_DIRS
+            ],
+        )
+    except Exception as e:
+        raise HTTPException(status_code=500, detail=f"Snapshot error: {e}")
+    return SnapshotInfo(id=entry_id, created_at=slot.stat().st_mtime)
+
+# Usage of a quota'd top-level directory, returned by /quota.
+class QuotaInfo(BaseModel):
+    dir: str
+    max_bytes: int | None
+    max_files: int | None
+    used_bytes: int
+    used_files: int
+
+
+# GET /quota/{dir}: limits and current usage of a top-level directory.
+@app.get("/quota/{name:path}")
+def read_quota(name: str, principal: Principal = Depends(current_principal)):
+    require_read(principal, BASE_DIR, (BASE_DIR / name).resolve(), listing=True)
+    quota = QUOTAS.get(clean_path(name))
+    if quota is None:
+        raise HTTPException(status_code=404, detail=f"No quota on /{clean_path(name)}")
+    with quota_lock:
+        return QuotaInfo(
+            dir=quota.name,
+            max_bytes=quota.max_bytes,
+            max_files=quota.max_files,
+            used_bytes=quota.used_bytes,
+            used_files=quota.used_files,
+        )
+
+
+# GET /audit?since=<epoch seconds>: audit records newer than `since`, oldest first (admins only).
+@app.get("/audit")
+def read_audit(
+    since: float = 0, limit: int = 1000, principal: Principal = Depends(current_principal)
+):
+    if not principal.admin:
+        raise HTTPException(status_code=403, detail="The audit log needs an admin token")
+    if not AUDIT_LOG:
+        raise HTTPException(status_code=404, detail="Auditing is not enabled")
+    records = []
+    # Rotated files hold older records: AUDIT_LOG.<n> is the oldest.
+    for name in [f"{AUDIT_LOG}.{i}" for i in range(AUDIT_BACKUPS, 0, -1)] + [AUDIT_LOG]:
+        try:
+            with open(name, encoding="utf-8") as f:
+                lines = f.readlines()
+        except FileNotFoundError:
+            continue
+        for line in lines:
+            try:
+                record = json.loads(line)
+            except ValueError:
+                continue
+            if record.get("ts", 0) > since:
+                records.append(record)
+                if len(records) >= limit:
+                    return records
+    return records
+
+
 if __name__ == "__main__":
     uvicorn.run(
         "main:app",
         host=HOST,
         port=PORT,
         reload=DEBUG
-    )
\ No newline at end of file
+    )
@@ -0,0 +1,108 @@
+"""AUTH_CONFIG grants, checked with a read-only and a read-write principal
+against the same tree.
+
+Run from this directory with the packages in requirements-test.txt:
+
+    python -m unittest test_auth
+"""
+
+import os
+import sys
+import tempfile
+import unittest
+from pathlib import Path
+
+# The server keeps its storage under the working directory it starts in.
+sys.path.insert(0, str(Path(__file__).resolve().parent))
+os.chdir(tempfile.mkdtemp(prefix="remote-fs-test-"))
+
+from fastapi.testclient import TestClient  # noqa: E402
+
+import main  # noqa: E402
+
+AUTH_TOML = """
+[[users]]
+name = "reader"
+token = "read-token"
+read = ["/a", "/public"]
+
+[[users]]
+name = "team-a"
+token = "write-token"
+read = ["/public"]
+write = ["/a"]
+"""
+
+READER = {"Authorization": "Bearer read-token"}
+WRITER = {"Authorization": "Bearer write-token"}
+
+
+def names(response) -> set[str]:
+    return {entry["name"] for entry in response.json()}
+
+
+class GrantsTest(unittest.TestCase):
+    @classmethod
+    def setUpClass(cls):
+        config = Path("auth.toml")
+        config.write_text(AUTH_TOML)
+        main.AUTH = main.load_auth(str(config))
+        for path, content in {
+            "a/notes.txt": "team a",
+            "public/readme.txt": "everyone",
+            "private/secret.txt": "nobody",
+        }.items():
+            target = main.BASE_DIR / path
+            target.parent.mkdir(parents=True, exist_ok=True)
+            target.write_text(content)
+        cls.client = TestClient(main.app)
+
+    @classmethod
+    def tearDownClass(cls):
+        main.AUTH = None
+
+    def test_listings_hide_ungranted_paths(self):
+        for headers in (READER, WRITER):
+            response = self.client.get("/list/", headers=headers)
+            self.assertEqual(response.status_code, 200)
+            self.assertEqual(names(response), {"a", "public"})
+            self.assertEqual(self.client.get("/list/private", headers=headers).status_code, 404)
+
+    def test_both_read_granted_files(self):
+        for headers in (READER, WRITER):
+            response = self.client.get("/files/public/readme.txt", headers=headers)
+            self.assertEqual(response.status_code, 200)
+            self.assertEqual(response.text, "everyone")
+        response = self.client.get("/files/a/notes.txt", headers=READER)
+        self.assertEqual(response.text, "team a")
+
+    def test_ungranted_file_looks_missing(self):
+        for headers in (READER, WRITER):
+            response = self.client.get("/files/private/secret.txt", headers=headers)
+            self.assertEqual(response.status_code, 404)
+
+    def test_read_only_principal_cannot_write(self):
+        response = self.client.put("/files/a/by-reader.txt", content=b"x", headers=READER)
+        self.assertEqual(response.status_code, 403)
+        self.assertFalse((main.BASE_DIR / "a/by-reader.txt").exists())
+        response = self.client.delete("/files/a/notes.txt", headers=READER)
+        self.assertEqual(response.status_code, 403)
+        self.assertTrue((main.BASE_DIR / "a/notes.txt").exists())
+
+    def test_read_write_principal_writes_only_its_prefix(self):
+        response = self.client.put("/files/a/by-writer.txt", content=b"x", headers=WRITER)
+        self.assertEqual(response.status_code, 200)
+        self.assertEqual((main.BASE_DIR / "a/by-writer.txt").read_bytes(), b"x")
+        self.assertIn("by-writer.txt", names(self.client.get("/list/a", headers=READER)))
+        response = self.client.put("/files/public/readme.txt", content=b"y", headers=WRITER)
+        self.assertEqual(response.status_code, 403)
+        self.assertEqual((main.BASE_DIR / "public/readme.txt").read_text(), "everyone")
+
+    def test_unknown_or_missing_token_is_refused(self):
+        self.assertEqual(self.client.get("/list/").status_code, 401)
+        response = self.client.get("/list/", headers={"Authorization": "Bearer nope"})
+        self.assertEqual(response.status_code, 401)
+
+
+if __name__ == "__main__":
+    unittest.main()